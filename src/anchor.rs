@@ -0,0 +1,97 @@
+//! The ids that links, tables of contents and media overlays point to, so
+//! the converter can check that every one of them still leads somewhere
+//! once the markup is rewritten.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use xmltree::Element;
+
+use crate::{elem::ElementExt, href};
+
+/// Ids pointed to, by the normalized href of the document holding them
+pub type Targets = BTreeMap<String, BTreeSet<String>>;
+
+/// Adds the targets of `other` to `targets`
+pub fn merge(targets: &mut Targets, other: Targets) {
+    for (doc, ids) in other {
+        targets.entry(doc).or_default().extend(ids);
+    }
+}
+
+/// The fragments the links of `root`, a document at `doc_href`, point to:
+/// the `href` and `src` attributes of every element, which covers
+/// `<a>`, the `<content>` of an NCX and the `<reference>`s of a package
+/// guide. Hrefs are relative to the same directory as `doc_href`, links to
+/// other books and sites are left out
+pub fn link_targets(root: &Element, doc_href: &str) -> Targets {
+    let mut targets = Targets::new();
+    for e in root.descendants() {
+        for link in ["href", "src"].iter().filter_map(|a| e.attributes.get(*a)) {
+            let (path, Some(id)) = href::split_fragment(link) else {
+                continue;
+            };
+            if path.contains(':') || id.is_empty() {
+                continue;
+            }
+            let doc = match path {
+                "" => href::normalize(doc_href),
+                p => href::resolve(doc_href, p),
+            };
+            targets
+                .entry(doc)
+                .or_default()
+                .insert(href::percent_decode(id));
+        }
+    }
+    return targets;
+}
+
+/// The ids of `ids` that no element of `root` has
+pub fn missing_ids<'a>(root: &Element, ids: &'a BTreeSet<String>) -> Vec<&'a str> {
+    let found = root
+        .descendants()
+        .filter_map(|e| e.attributes.get("id"))
+        .collect::<BTreeSet<_>>();
+    return ids
+        .iter()
+        .filter(|id| !found.contains(id))
+        .map(String::as_str)
+        .collect();
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use xmltree::Element;
+
+    use super::{link_targets, missing_ids};
+
+    #[test]
+    fn test_link_targets() {
+        let doc = Element::parse(
+            r##"<html><body><p id="top">See <a href="ch3.xhtml#fn12">12</a>,
+            <a href="#top">top</a>, <a href="../notes/n%201.xhtml#n1">n1</a>,
+            <a href="ch3.xhtml">ch3</a>, <a href="https://example.com/#x">site</a>
+            and <a href="#">nothing</a>.</p></body></html>"##
+                .as_bytes(),
+        )
+        .unwrap();
+        let targets = link_targets(&doc, "text/ch1.xhtml");
+        assert_eq!(
+            targets.keys().collect::<Vec<_>>(),
+            ["notes/n 1.xhtml", "text/ch1.xhtml", "text/ch3.xhtml"]
+        );
+        assert_eq!(
+            targets["text/ch1.xhtml"],
+            BTreeSet::from(["top".to_string()])
+        );
+        assert_eq!(
+            targets["text/ch3.xhtml"],
+            BTreeSet::from(["fn12".to_string()])
+        );
+
+        let ids = BTreeSet::from(["top".to_string(), "gone".to_string()]);
+        assert_eq!(missing_ids(&doc, &ids), ["gone"]);
+    }
+}
@@ -0,0 +1,151 @@
+//! A memory ceiling for converting several books at once, so that a batch
+//! of large books waits for memory instead of running out of it.
+//!
+//! What a book needs is estimated from its archive before it is extracted:
+//! parsed markup takes several times its size in memory, while other files
+//! are copied through and only one is held at a time.
+
+use std::{
+    path::Path,
+    sync::{Condvar, Mutex},
+};
+
+use zip::ZipArchive;
+
+use crate::{errors::ConverterError, logger::debug};
+
+/// Memory taken by a parsed document, as a multiple of its size on disk
+const MARKUP_OVERHEAD: u64 = 8;
+
+/// Extensions of the files that are parsed while converting
+const MARKUP_EXTENSIONS: [&str; 8] = ["xhtml", "html", "htm", "xml", "opf", "ncx", "css", "svg"];
+
+/// Shares a number of bytes between the books converted at the same time
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: u64,
+    used: Mutex<u64>,
+    freed: Condvar,
+}
+
+/// Memory reserved for one book, given back when dropped
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        return Self {
+            limit,
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+        };
+    }
+
+    /// Reserves `bytes`, waiting until that many are free. A book needing
+    /// more than the whole budget waits until nothing else runs, and then
+    /// runs alone
+    pub fn reserve(&self, bytes: u64) -> Reservation<'_> {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        if *used > 0 && *used + bytes > self.limit {
+            debug!(
+                "Waiting for {} bytes of memory, {} of {} in use",
+                bytes, *used, self.limit
+            );
+        }
+        while *used > 0 && *used + bytes > self.limit {
+            used = self.freed.wait(used).unwrap_or_else(|e| e.into_inner());
+        }
+        *used += bytes;
+        return Reservation {
+            budget: self,
+            bytes,
+        };
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut used = self.budget.used.lock().unwrap_or_else(|e| e.into_inner());
+        *used -= self.bytes;
+        self.budget.freed.notify_all();
+    }
+}
+
+/// Estimated memory needed to convert the epub at `path`: its markup
+/// files, as parsed, and the largest of its other files
+pub fn footprint(path: &Path) -> Result<u64, ConverterError> {
+    let mut zip = ZipArchive::new(std::fs::File::open(path)?)?;
+    let mut markup = 0;
+    let mut largest = 0;
+    for i in 0..zip.len() {
+        let entry = zip.by_index_raw(i)?;
+        let ext = Path::new(entry.name())
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if MARKUP_EXTENSIONS.contains(&ext.as_str()) {
+            markup += entry.size();
+        } else {
+            largest = largest.max(entry.size());
+        }
+    }
+    return Ok(markup * MARKUP_OVERHEAD + largest);
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::{footprint, MemoryBudget, MARKUP_OVERHEAD};
+
+    #[test]
+    fn test_reserve() {
+        let budget = MemoryBudget::new(100);
+        let first = budget.reserve(60);
+        let started = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _second = budget.reserve(60);
+                started.store(true, Ordering::SeqCst);
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!started.load(Ordering::SeqCst));
+            drop(first);
+        });
+        assert!(started.load(Ordering::SeqCst));
+
+        // too large for the budget, but nothing else runs
+        let _huge = budget.reserve(500);
+    }
+
+    #[test]
+    fn test_footprint() {
+        let path =
+            std::env::temp_dir().join(format!("kepub-rs-footprint-{}.epub", std::process::id()));
+        let mut zip = ZipWriter::new(std::fs::File::create(&path).unwrap());
+        for (name, size) in [
+            ("OEBPS/ch1.xhtml", 100),
+            ("OEBPS/content.opf", 10),
+            ("OEBPS/a.jpg", 300),
+            ("OEBPS/b.PNG", 200),
+        ] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(&vec![b'x'; size]).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let bytes = footprint(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(bytes, 110 * MARKUP_OVERHEAD + 300);
+    }
+}
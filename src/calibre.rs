@@ -0,0 +1,160 @@
+//! Removal of what calibre leaves in the books it edits: its metadata, its
+//! bookmark files and the attributes and classes it adds to the markup.
+
+use std::collections::HashSet;
+
+use xmltree::Element;
+
+use crate::elem::{ElementExt, Walk};
+
+/// Bookmark files calibre writes into the archive, relative to its root
+pub const BOOKMARK_FILES: [&str; 2] = ["calibre_bookmarks.txt", "META-INF/calibre_bookmarks.txt"];
+
+fn is_calibre_meta(elem: &Element) -> bool {
+    if elem.name != "meta" {
+        return false;
+    }
+    return ["name", "property"].iter().any(|a| {
+        return elem
+            .attributes
+            .get(*a)
+            .is_some_and(|v| v.starts_with("calibre:"));
+    });
+}
+
+/// Removes the `calibre:*` meta elements from the metadata of a package
+/// document. Returns how many were removed
+pub fn strip_metadata(pkg_root: &mut Element) -> usize {
+    let Some(metadata) = pkg_root.get_mut_child("metadata") else {
+        return 0;
+    };
+    let before = metadata.children.len();
+    metadata
+        .children
+        .retain(|c| !c.as_element().is_some_and(is_calibre_meta));
+    return before - metadata.children.len();
+}
+
+/// Whether `class` is one calibre generates: `calibre`, `calibre1`,
+/// `calibre_2`...
+fn is_calibre_class(class: &str) -> bool {
+    let Some(rest) = class.strip_prefix("calibre") else {
+        return false;
+    };
+    return rest.chars().all(|c| c.is_ascii_digit() || c == '_');
+}
+
+/// Calibre classes that `css` has selectors for
+pub fn styled_classes(css: &str) -> HashSet<String> {
+    return css
+        .split('.')
+        .skip(1)
+        .map(|s| {
+            let end = s
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '-')
+                .unwrap_or(s.len());
+            return &s[..end];
+        })
+        .filter(|c| is_calibre_class(c))
+        .map(String::from)
+        .collect();
+}
+
+/// Whether a content document has markup [`strip_markup`] would remove
+pub fn has_markup(root: &Element, styled: &HashSet<String>) -> bool {
+    return root.descendants().any(|e| {
+        return is_calibre_meta(e)
+            || e.attributes.keys().any(|k| k.starts_with("data-calibre"))
+            || e.attributes.get("class").is_some_and(|class| {
+                return class
+                    .split_whitespace()
+                    .any(|c| is_calibre_class(c) && !styled.contains(c));
+            });
+    });
+}
+
+/// Removes the `data-calibre-*` attributes and `calibre:*` meta elements
+/// from a content document, and the calibre classes not in `styled`, which
+/// would change how the book looks. Returns how many were removed
+pub fn strip_markup(root: &mut Element, styled: &HashSet<String>) -> usize {
+    let mut removed = 0;
+    root.walk_mut(|e, _| {
+        let before = e.children.len();
+        e.children
+            .retain(|c| !c.as_element().is_some_and(is_calibre_meta));
+        removed += before - e.children.len();
+
+        let before = e.attributes.len();
+        e.attributes.retain(|k, _| !k.starts_with("data-calibre"));
+        removed += before - e.attributes.len();
+
+        if let Some(class) = e.attributes.get("class") {
+            let classes = class.split_whitespace().collect::<Vec<_>>();
+            let kept = classes
+                .iter()
+                .filter(|c| !is_calibre_class(c) || styled.contains(**c))
+                .copied()
+                .collect::<Vec<_>>();
+            if kept.len() != classes.len() {
+                removed += classes.len() - kept.len();
+                match kept.is_empty() {
+                    true => e.attributes.remove("class"),
+                    false => e.attributes.insert("class".to_string(), kept.join(" ")),
+                };
+            }
+        }
+        return Walk::Descend;
+    });
+    return removed;
+}
+
+#[cfg(test)]
+mod test {
+    use xmltree::Element;
+
+    use super::{has_markup, strip_markup, strip_metadata, styled_classes};
+    use crate::elem::ElementExt;
+
+    #[test]
+    fn test_strip_metadata() {
+        let mut pkg = Element::parse(
+            r#"<package><metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+            <dc:title>Title</dc:title>
+            <meta name="calibre:timestamp" content="2020-01-01"/>
+            <meta property="calibre:series">Series</meta>
+            <meta name="cover" content="img"/>
+            </metadata></package>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(strip_metadata(&mut pkg), 2);
+        assert_eq!(pkg.find_all("meta").len(), 1);
+    }
+
+    #[test]
+    fn test_strip_markup() {
+        let styled = styled_classes(".calibre1 { margin: 0 }\np.calibre_3, a:hover { x: y }");
+        assert_eq!(styled.len(), 2);
+
+        let mut doc = Element::parse(
+            r#"<html><head><meta name="calibre:cover" content="true"/></head>
+            <body class="calibre"><p class="calibre1 calibre2 note" data-calibre-rendered="1">a</p>
+            <p class="calibre_3 calibrated">b</p></body></html>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert!(has_markup(&doc, &styled));
+        assert_eq!(strip_markup(&mut doc, &styled), 4);
+        assert!(!has_markup(&doc, &styled));
+        assert!(doc.find_first("meta").is_none());
+        let body = doc.get_child("body").unwrap();
+        assert!(!body.attributes.contains_key("class"));
+        let classes = body
+            .find_all("p")
+            .iter()
+            .map(|p| p.attributes["class"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(classes, ["calibre1 note", "calibre_3 calibrated"]);
+        assert!(body.find_first("p").unwrap().attributes.len() == 1);
+    }
+}
@@ -0,0 +1,207 @@
+//! Stable codes of the warnings logged while converting and of the problems
+//! found by validation, so that scripts and issue reports can refer to the
+//! exact condition and users can allow the ones they don't care about.
+//!
+//! A code keeps its number and meaning once released. Codes that are no
+//! longer used are not given to new warnings.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WarningCode {
+    /// A content document that is not well-formed XML was parsed as HTML
+    NotWellFormed,
+    /// The book already has kobospans and is copied unchanged
+    AlreadyKepub,
+    /// The book has no cover
+    NoCover,
+    /// The cover named by the package is unusable and another image is used
+    GuessedCover,
+    /// Audio or video the device cannot play
+    UnplayableMedia,
+    /// Every document of the spine is blank
+    AllBlank,
+    /// A table of contents cannot be read
+    UnreadableToc,
+    /// A stylesheet cannot be read
+    UnreadableStylesheet,
+    /// Digital signatures removed, as they no longer match
+    SignaturesRemoved,
+    /// The package document cannot be read and was rebuilt
+    PackageRebuilt,
+    /// No content documents declared, guessed from file extensions
+    GuessedContentDocuments,
+    /// A content document that failed to convert was copied unchanged
+    ChapterSkipped,
+    /// Two manifest items point to the same file
+    DuplicateManifestItem,
+    /// A paragraph or sentence over the length warning
+    LongText,
+    /// Soft hyphens or zero-width characters inside words
+    WordBreaks,
+    /// Words split across kobospans by inline markup
+    SplitWords,
+    /// A content document over the span file size limit
+    SpanFileTooLarge,
+    /// container.xml is missing, unreadable or names no package document
+    BadContainer,
+    /// Several package documents in the archive
+    SeveralPackages,
+    /// The cover meta names its item by href instead of id
+    CoverMetaByHref,
+    /// A manifest item whose media type doesn't match its extension
+    MediaType,
+    /// A manifest item whose file is not in the archive
+    MissingFile,
+    /// The book has no table of contents
+    MissingNav,
+    /// The unique-identifier of the package names no identifier
+    UniqueIdentifier,
+    /// A file of the archive that is not in the manifest
+    Orphan,
+    /// Fixed-layout documents without a viewport
+    MissingViewport,
+    /// A media overlay points to an id the conversion removed
+    OverlayTarget,
+    /// A link points to an id the conversion removed
+    BrokenLink,
+    /// An obfuscated font that cannot be restored was removed
+    ObfuscatedFont,
+    /// The cover image cannot be decoded
+    UnreadableCover,
+}
+
+impl WarningCode {
+    /// Every code, in order
+    pub const ALL: [WarningCode; 30] = [
+        WarningCode::NotWellFormed,
+        WarningCode::AlreadyKepub,
+        WarningCode::NoCover,
+        WarningCode::GuessedCover,
+        WarningCode::UnplayableMedia,
+        WarningCode::AllBlank,
+        WarningCode::UnreadableToc,
+        WarningCode::UnreadableStylesheet,
+        WarningCode::SignaturesRemoved,
+        WarningCode::PackageRebuilt,
+        WarningCode::GuessedContentDocuments,
+        WarningCode::ChapterSkipped,
+        WarningCode::DuplicateManifestItem,
+        WarningCode::LongText,
+        WarningCode::WordBreaks,
+        WarningCode::SplitWords,
+        WarningCode::SpanFileTooLarge,
+        WarningCode::BadContainer,
+        WarningCode::SeveralPackages,
+        WarningCode::CoverMetaByHref,
+        WarningCode::MediaType,
+        WarningCode::MissingFile,
+        WarningCode::MissingNav,
+        WarningCode::UniqueIdentifier,
+        WarningCode::Orphan,
+        WarningCode::MissingViewport,
+        WarningCode::OverlayTarget,
+        WarningCode::BrokenLink,
+        WarningCode::ObfuscatedFont,
+        WarningCode::UnreadableCover,
+    ];
+
+    pub fn number(self) -> u16 {
+        return match self {
+            WarningCode::NotWellFormed => 1,
+            WarningCode::AlreadyKepub => 2,
+            WarningCode::NoCover => 3,
+            WarningCode::GuessedCover => 4,
+            WarningCode::UnplayableMedia => 5,
+            WarningCode::AllBlank => 6,
+            WarningCode::UnreadableToc => 7,
+            WarningCode::UnreadableStylesheet => 8,
+            WarningCode::SignaturesRemoved => 9,
+            WarningCode::PackageRebuilt => 10,
+            WarningCode::GuessedContentDocuments => 11,
+            WarningCode::ChapterSkipped => 12,
+            WarningCode::DuplicateManifestItem => 13,
+            WarningCode::LongText => 14,
+            WarningCode::WordBreaks => 15,
+            WarningCode::SplitWords => 16,
+            WarningCode::SpanFileTooLarge => 17,
+            WarningCode::BadContainer => 18,
+            WarningCode::SeveralPackages => 19,
+            WarningCode::CoverMetaByHref => 20,
+            WarningCode::MediaType => 21,
+            WarningCode::MissingFile => 22,
+            WarningCode::MissingNav => 23,
+            WarningCode::UniqueIdentifier => 24,
+            WarningCode::Orphan => 25,
+            WarningCode::MissingViewport => 26,
+            WarningCode::OverlayTarget => 27,
+            WarningCode::BrokenLink => 28,
+            WarningCode::ObfuscatedFont => 29,
+            WarningCode::UnreadableCover => 30,
+        };
+    }
+}
+
+impl Display for WarningCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "W{:03}", self.number())
+    }
+}
+
+impl Serialize for WarningCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        return serializer.collect_str(self);
+    }
+}
+
+impl<'de> Deserialize<'de> for WarningCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        return s.parse().map_err(serde::de::Error::custom);
+    }
+}
+
+impl FromStr for WarningCode {
+    type Err = String;
+
+    /// Parses codes like `W012`, in any case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = s
+            .strip_prefix(['W', 'w'])
+            .and_then(|n| return n.parse::<u16>().ok());
+        return WarningCode::ALL
+            .iter()
+            .copied()
+            .find(|c| return Some(c.number()) == number)
+            .ok_or_else(|| return format!("Unknown warning code: {}", s));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::WarningCode;
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(WarningCode::ChapterSkipped.to_string(), "W012");
+        assert_eq!(
+            "w012".parse::<WarningCode>(),
+            Ok(WarningCode::ChapterSkipped)
+        );
+        assert!("W999".parse::<WarningCode>().is_err());
+        assert!("12".parse::<WarningCode>().is_err());
+
+        let numbers = WarningCode::ALL
+            .iter()
+            .map(|c| c.number())
+            .collect::<HashSet<_>>();
+        assert_eq!(numbers.len(), WarningCode::ALL.len());
+        for c in WarningCode::ALL {
+            assert_eq!(c.to_string().parse::<WarningCode>(), Ok(c));
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Settings kept in a JSON file rather than given on the command line: the
+//! warnings to allow and the problems to fix, for the whole library and
+//! for the books whose identifier matches a pattern, like
+//!
+//! ```json
+//! {
+//!   "allow": ["W025"],
+//!   "books": [{"identifier": "urn:isbn:978-0-7653-*", "allow": ["W014"], "fix": ["W021"]}]
+//! }
+//! ```
+//!
+//! so that the quirks of one publisher don't bury everything else in the
+//! reports of a batch.
+
+use std::{collections::BTreeSet, path::Path};
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+
+use crate::{codes::WarningCode, errors::ConverterError, validate};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Warnings allowed for every book
+    pub allow: BTreeSet<WarningCode>,
+    /// Validation findings repaired in every book while converting it
+    pub fix: BTreeSet<WarningCode>,
+    /// Rules for single books or groups of them
+    pub books: Vec<BookRules>,
+}
+
+/// Warnings to allow and findings to fix for the books with a matching
+/// identifier, on top of the ones for every book
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BookRules {
+    /// A `dc:identifier` of the book, which can use the `*`, `?` and `[...]`
+    /// wildcards of file name patterns
+    pub identifier: String,
+    pub allow: BTreeSet<WarningCode>,
+    pub fix: BTreeSet<WarningCode>,
+}
+
+impl Config {
+    pub fn open(path: &Path) -> Result<Self, ConverterError> {
+        let json = std::fs::read_to_string(path)?;
+        let config: Config =
+            serde_json::from_str(&json).map_err(|e| ConverterError::Other(e.to_string()))?;
+        config.check()?;
+        return Ok(config);
+    }
+
+    /// Fails if a code that can't be fixed is listed to be fixed
+    fn check(&self) -> Result<(), ConverterError> {
+        let fix = self
+            .fix
+            .iter()
+            .chain(self.books.iter().flat_map(|b| &b.fix));
+        for code in fix {
+            if !validate::REPAIRABLE.contains(code) {
+                let repairable = validate::REPAIRABLE.map(|c| c.to_string());
+                return Err(ConverterError::Other(format!(
+                    "{} cannot be fixed, only {} can",
+                    code,
+                    repairable.join(", ")
+                )));
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl BookRules {
+    /// Whether the rules are for a book with these identifiers
+    pub fn matches(&self, identifiers: &[String]) -> bool {
+        return match Pattern::new(&self.identifier) {
+            Ok(p) => identifiers.iter().any(|i| p.matches(i)),
+            Err(_) => identifiers.contains(&self.identifier),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BookRules, Config};
+    use crate::codes::WarningCode;
+
+    #[test]
+    fn test_config() {
+        let json = r#"{"allow": ["W025"], "books": [
+            {"identifier": "urn:isbn:978-0-7653-*", "allow": ["w014"], "fix": ["W021"]}]}"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+        assert!(config.check().is_ok());
+        assert_eq!(
+            config.allow.iter().collect::<Vec<_>>(),
+            [&WarningCode::Orphan]
+        );
+        let rules = &config.books[0];
+        assert!(rules.allow.contains(&WarningCode::LongText));
+        assert!(rules.matches(&["x".to_string(), "urn:isbn:978-0-7653-1178-8".to_string()]));
+        assert!(!rules.matches(&["urn:isbn:978-0-14-303943-3".to_string()]));
+        let literal = BookRules {
+            identifier: "[unclosed".to_string(),
+            ..BookRules::default()
+        };
+        assert!(literal.matches(&["[unclosed".to_string()]));
+
+        assert!(serde_json::from_str::<Config>(r#"{"allow": ["W999"]}"#).is_err());
+        assert!(serde_json::from_str::<Config>(r#"{"alow": []}"#).is_err());
+        let config: Config = serde_json::from_str(r#"{"fix": ["W012"]}"#).unwrap();
+        assert!(config.check().unwrap_err().to_string().contains("W012"));
+    }
+}
@@ -1,486 +1,5357 @@
-use std::{
-    collections::HashMap,
-    fs::{create_dir_all, read_dir, remove_dir_all, File},
-    io::Write,
-    path::PathBuf,
-    process::Output,
-};
-use xmltree::{Element, EmitterConfig, XMLNode};
-
-use zip::{
-    write::{FileOptions, SimpleFileOptions},
-    CompressionMethod, ZipArchive, ZipWriter,
-};
-
-use crate::{
-    errors::{io_err, xml_err, ConverterError},
-    lmnt::LMNT,
-};
-
-pub struct Converter {
-    working_dir: PathBuf,
-    write_config: EmitterConfig,
-    working_dir_str: String,
-}
-
-impl Converter {
-    /// Will fail if write access to tmp dir is not available
-    pub fn new() -> Result<Self, std::io::Error> {
-        let mut write_config = EmitterConfig::new();
-        write_config.perform_indent = true;
-
-        let (pb, s) = Self::get_tmp_dir()?;
-
-        return Ok(Self {
-            working_dir: pb,
-            working_dir_str: s,
-            write_config: write_config,
-        });
-    }
-
-    // Creates a tmp dir
-    fn get_tmp_dir() -> Result<(PathBuf, String), std::io::Error> {
-        let td = std::env::temp_dir().join("kepub-rs-conv");
-        let s = match td.to_str() {
-            Some(s) => s.to_string(),
-            None => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Could not get valid path to temporary directory",
-                ))
-            }
-        };
-        let _ = remove_dir_all(&td);
-
-        create_dir_all(&td)?;
-        println!("{:?}", td);
-        return Ok((td, s));
-    }
-
-    pub fn convert(
-        &self,
-        epub: &mut ZipArchive<File>,
-        out_path: &str,
-    ) -> Result<(), ConverterError> {
-        epub.extract(&self.working_dir)?;
-        self.convert_opf()?;
-        self.convert_html()?;
-
-        match PathBuf::from(out_path).parent() {
-            Some(p) => std::fs::create_dir_all(p)?,
-            None => {
-                return Err(io_err!(
-                    std::io::ErrorKind::Other,
-                    "Cannot get parent of output path: {}",
-                    out_path
-                ))
-            }
-        };
-        self.write(out_path)?;
-        return Ok(());
-    }
-
-    // Write contents of temporary working dir to kepub
-    fn write(&self, out_path: &str) -> Result<(), std::io::Error> {
-        let outzip_file = File::create(&out_path)?;
-        let mut zip_arch = ZipWriter::new(outzip_file);
-
-        let opts = SimpleFileOptions::default()
-            .compression_method(CompressionMethod::Deflated)
-            .unix_permissions(0o755);
-
-        let walkdir = walkdir::WalkDir::new(&self.working_dir).into_iter();
-
-        for entry in walkdir {
-            let file = match entry {
-                Ok(o) => o,
-                Err(e) => {
-                    println!("Cannot zip file: {}", e);
-                    continue;
-                }
-            };
-            let path = file.path();
-
-            let path_internal = path
-                .strip_prefix(&self.working_dir)
-                .unwrap()
-                .components()
-                .map(|x| x.as_os_str().to_str().unwrap())
-                .collect::<Vec<&str>>()
-                .join("/");
-
-            let name = path.strip_prefix(&self.working_dir).unwrap();
-
-            if path.is_file() {
-                zip_arch.start_file(path_internal, opts)?;
-                let content = std::fs::read(path)?;
-                zip_arch.write_all(&content)?;
-            } else if !name.as_os_str().is_empty() {
-                zip_arch.add_directory(path_internal, opts)?;
-            }
-        }
-
-        zip_arch.finish()?;
-        return Ok(());
-    }
-
-    // Adds `properties='cover-image' attribute to cover image <item> element`
-    fn convert_opf(&self) -> Result<(), ConverterError> {
-        let fpath = match self.get_opt_path() {
-            Some(f) => f,
-            None => return Err(xml_err!("Could not find content.opf in epub archive")),
-        };
-
-        let mut root = Element::parse(std::fs::File::open(&fpath)?)?;
-
-        let cover_id: String;
-        {
-            let meta_elem = match root.find_first_child_with_attrs("meta", &[("name", "cover")]) {
-                Some(e) => e,
-                None => {
-                    return Err(xml_err!(
-                        "Cannot find <meta name='cover'> element in content.opf"
-                    ))
-                }
-            };
-
-            cover_id = match meta_elem.attributes.get("content") {
-                Some(c) => c.clone(),
-                None => {
-                    return Err(xml_err!(
-                    "Cannot read content attribute in <meta name='cover'> element in content.opf"
-                ))
-                }
-            };
-        }
-
-        match root.find_first_child_with_attrs_mut("item", &[("id", &cover_id)]) {
-            Some(e) => e
-                .attributes
-                .insert("properties".to_string(), "cover-image".to_string()),
-            None => {
-                return Err(xml_err!(
-                    "Cannot find <item id='{}'> element in content.opf",
-                    cover_id
-                ))
-            }
-        };
-
-        return match root
-            .write_with_config(std::fs::File::create(&fpath)?, self.write_config.clone())
-        {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
-        };
-    }
-
-    fn get_opt_path(&self) -> Option<PathBuf> {
-        let rd = match read_dir(&self.working_dir) {
-            Ok(rd) => rd,
-            Err(_) => return None,
-        };
-        for entry in rd {
-            match entry {
-                Ok(e) => {
-                    if e.file_name() == "content.opf" {
-                        return Some(e.path());
-                    }
-                }
-                Err(_) => {}
-            }
-        }
-        return None;
-    }
-
-    fn convert_html(&self) -> Result<(), ConverterError> {
-        let fpath = match self.get_opt_path() {
-            Some(f) => f,
-            None => return Err(xml_err!("Could not find content.opf in epub archive")),
-        };
-        let now = std::time::Instant::now();
-
-        let doc = Element::parse(std::fs::File::open(&fpath)?)?;
-
-        let mut hrefs = Vec::new();
-        for d in doc.descendants() {
-            if d.name != "item" {
-                continue;
-            }
-            if d.attributes
-                .get("media-type")
-                .is_some_and(|val| val == "application/xhtml+xml")
-            {
-                match d.attributes.get("href") {
-                    Some(h) => hrefs.push(h),
-                    None => {}
-                }
-            }
-        }
-
-        for h in hrefs {
-            self.convert_html_file(&h)?
-        }
-
-        println!("{}ms", now.elapsed().as_millis());
-        return Ok(());
-    }
-
-    fn convert_html_file(&self, rel_path: &str) -> Result<(), ConverterError> {
-        println!("Converting {}", rel_path);
-        let fpath = self.working_dir.join(rel_path);
-
-        let mut root = Element::parse(std::fs::File::open(&fpath)?)?;
-
-        let body = match root.get_mut_child("body") {
-            Some(e) => e,
-            None => return Err(xml_err!("Cannot find <body> in {}", rel_path)),
-        };
-
-        let mut bk_col = Element::new("div");
-        bk_col
-            .attributes
-            .insert("id".to_string(), "book-columns".to_string());
-        let mut bk_inn = Element::new("div");
-        bk_inn
-            .attributes
-            .insert("id".to_string(), "book-inner".to_string());
-
-        bk_inn.children = body.children.drain(..).collect();
-
-        bk_col.children.push(XMLNode::Element(bk_inn));
-        body.children.push(XMLNode::Element(bk_col));
-
-        self.convert_kobo_spans(body);
-
-        return match root
-            .write_with_config(std::fs::File::create(&fpath)?, self.write_config.clone())
-        {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
-        };
-    }
-
-    /// Convert paragraphs and sentences into kobospans
-    /// Since Rust doesn't play nice with mutable iterators over nested structs
-    /// this calls a recursive method to process the text content
-    fn convert_kobo_spans(&self, root_elem: &mut Element) {
-        if root_elem.descendants().any(|n| {
-            n.attributes
-                .get("class")
-                .is_some_and(|cl| cl.contains("kobospan"))
-        }) {
-            println!("kobo spans found, not converting html content");
-            // kobo spans exist, don't do anything
-            return;
-        }
-
-        let new_children = self._convert_kobo_spans(root_elem, &mut 0, &mut 0, &mut false);
-        root_elem.children = new_children;
-    }
-
-    fn _convert_kobo_spans(
-        &self,
-        parent_elem: &mut Element,
-        para: &mut usize,
-        sent: &mut usize,
-        force_new_para: &mut bool,
-    ) -> Vec<XMLNode> {
-        let mut new_children = Vec::new();
-        for child in parent_elem.children.drain(0..) {
-            match child {
-                XMLNode::Element(mut element) => {
-                    match &*element.name {
-                        // img elements get wrapped in their own para
-                        "img" => {
-                            *para += 1;
-                            *sent = 0;
-                            *force_new_para = false;
-
-                            let mut s = make_span(*para, *sent, None);
-                            s.children.push(XMLNode::Element(element.clone()));
-                            new_children.push(XMLNode::Element(s));
-                        }
-                        // force start a new para after these elems
-                        n if ["p", "ol", "ul", "table"].contains(&n)
-                            || (n.len() == 2 && n[0..1] == *"h") =>
-                        {
-                            *force_new_para = true;
-                        }
-                        n if ["math", "svg"].contains(&n) => continue,
-                        _ => {}
-                    }
-
-                    new_children.append(&mut self._convert_kobo_spans(
-                        &mut element,
-                        para,
-                        sent,
-                        force_new_para,
-                    ));
-                }
-                XMLNode::Text(t) => {
-                    let sentences = split_sentences(&t);
-
-                    // // wrap each sentence in a span (don't wrap whitespace unless it is
-                    // // directly under a P tag [TODO: are there any other cases we wrap
-                    // // whitespace? ... I need to find a kepub like this]) and add it
-                    // // back to the parent.
-                    for sentence in sentences {
-                        if sentence.trim().len() == 0 && parent_elem.name != "p" {
-                            // whitespace sentence directly inside <p> -- do nothing
-                        } else {
-                            if *force_new_para {
-                                *para += 1;
-                                *sent = 0;
-                                *force_new_para = false;
-                            }
-                            *sent += 1;
-                            new_children.push(XMLNode::Element(make_span(
-                                *para,
-                                *sent,
-                                Some(&sentence),
-                            )));
-                        }
-                    }
-                }
-                _ => {}
-            }
-        }
-        return new_children;
-    }
-}
-
-fn make_span(para: usize, seg: usize, content: Option<&String>) -> Element {
-    let mut e = Element::new("span");
-    e.attributes = HashMap::from([
-        ("class".to_string(), "kobospan".to_string()),
-        ("id".to_string(), format!("kobo.{}.{}", para, seg)),
-    ]);
-    match content {
-        Some(c) => {
-            e.children.push(XMLNode::Text(c.clone()));
-        }
-        None => todo!(),
-    }
-    return e;
-}
-
-/// Splits text content into sentences for kobospans
-/// There's no rules as to how precise this needs to be, but this tries
-/// to split input text into
-fn split_sentences(text: &String) -> Vec<String> {
-    #[derive(PartialEq)]
-    enum Input {
-        PunctStandard,
-        PunctExtra,
-        Whitespace,
-        Other,
-        EOS,
-    }
-
-    enum Output {
-        None,
-        Next,
-        Rest,
-    }
-
-    #[derive(PartialEq)]
-    enum State {
-        Default,
-        AfterPunct,
-        AfterPunctExtra,
-        AfterSpace,
-        Finished,
-    }
-
-    let mut sentences = Vec::new();
-    let characters = text.chars().collect::<Vec<_>>();
-
-    let mut seg_begin = 0;
-    let mut i = 0;
-    let mut state = State::Default;
-    while state != State::Finished {
-        let input: Input;
-
-        if i >= characters.len() {
-            input = Input::EOS;
-        } else {
-            let c = characters[i];
-            input = match c {
-                _ if ['.', '!', '?'].contains(&c) => Input::PunctStandard,
-                _ if ['\'', '"', '”', '’', '“', '…'].contains(&c) => Input::PunctExtra,
-                _ if ['\n', '\r', '\t', ' '].contains(&c) => Input::Whitespace,
-                _ => Input::Other,
-            };
-        }
-
-        let output: Output;
-
-        (output, state) = match state {
-            State::Default => match input {
-                Input::PunctStandard => (Output::None, State::AfterPunct),
-                Input::PunctExtra => (Output::None, State::Default),
-                Input::Whitespace => (Output::None, State::Default),
-                Input::Other => (Output::None, State::Default),
-                Input::EOS => (Output::Rest, State::Finished), //
-            },
-            State::AfterPunct => match input {
-                Input::PunctStandard => (Output::None, State::AfterPunct),
-                Input::PunctExtra => (Output::None, State::AfterPunctExtra),
-                Input::Whitespace => (Output::None, State::AfterSpace),
-                Input::Other => (Output::None, State::Default),
-                Input::EOS => (Output::Rest, State::Finished), //
-            },
-            State::AfterPunctExtra => match input {
-                Input::PunctStandard => (Output::None, State::AfterPunct),
-                Input::PunctExtra => (Output::None, State::Default),
-                Input::Whitespace => (Output::None, State::AfterSpace),
-                Input::Other => (Output::None, State::Default),
-                Input::EOS => (Output::Rest, State::Finished), //
-            },
-            State::AfterSpace => match input {
-                Input::PunctStandard => (Output::Next, State::AfterPunct),
-                Input::PunctExtra => (Output::Next, State::Default),
-                Input::Whitespace => (Output::None, State::AfterSpace),
-                Input::Other => (Output::Next, State::Default),
-                Input::EOS => (Output::Rest, State::Finished), //
-            },
-            State::Finished => (Output::Rest, state),
-        };
-
-        match output {
-            Output::None => i += 1,
-            Output::Next => {
-                sentences.push(
-                    text.chars()
-                        .skip(seg_begin)
-                        .take(i - seg_begin)
-                        .collect::<String>(),
-                );
-                seg_begin = i;
-                i += 1;
-            }
-            Output::Rest => {
-                // if we've reached the end of the string but found no sentences
-                // treat the input text as one sentence and push it
-                if sentences.len() == 0 {
-                    sentences.push(text.clone());
-                } else if i > (seg_begin + 1) {
-                    sentences.push(text.chars().skip(seg_begin).collect::<String>());
-                }
-            }
-        }
-    }
-
-    return sentences;
-}
-
-mod test {
-    use super::split_sentences;
-
-    #[test]
-    fn test_split_sentences() {
-        let text = r#"Left Munich at 8:35 P.M., on 1st May, arriving at Vienna early next morning; should have arrived at 6:46, but train was an hour late. Buda-Pesth seems a wonderful place, from the glimpse which I got of it from the train and the little I could walk through the streets. I feared to go very far from the station, as we had arrived late and would start as near the correct time as possible."#;
-
-        assert_eq!(split_sentences(&text.to_string()).len(), 3);
-    }
-}
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{create_dir_all, File},
+    io::Read,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+use xmltree::{Element, EmitterConfig, XMLNode};
+
+use zip::{CompressionMethod, DateTime, ZipArchive};
+
+use crate::{
+    anchor::{self, Targets},
+    calibre,
+    codes::WarningCode,
+    config::{BookRules, Config},
+    css, disk,
+    elem::{self, El, ElementExt, Rewriter, Walk},
+    errors::{self, io_err, xml_err, ConverterError, Stage},
+    footnote, href, html5,
+    images::{self, ImageOptions},
+    logger::{debug, info, trace, warning},
+    media::{self, MediaPolicy},
+    obfuscation,
+    opf::{self, ManifestItem, Package},
+    overlay::{self, Overlay},
+    pack,
+    report::{self, Histogram, Report},
+    segment::{self, Segmenter, SentenceSegmenter},
+    sink::{OutputEntry, OutputSink, ZipSink},
+    space,
+    spanmap::{ChapterSpans, SpanCounter, SpanMap, SpanRecord},
+    text::{self, Normalization, Replacement},
+    validate::{self, Finding},
+    verify,
+    workdir::WorkDir,
+};
+
+/// A content document copied into the kepub unchanged, because it failed
+/// to convert in lenient mode or converting it panicked
+#[derive(Debug)]
+pub struct UnconvertedFile {
+    /// Path within the archive
+    pub file: String,
+    pub error: ConverterError,
+}
+
+/// An entry of the source archive, as it should be written to the output
+struct SourceEntry {
+    name: String,
+    compression: CompressionMethod,
+    modified: Option<DateTime>,
+}
+
+pub struct Converter {
+    working_dir: WorkDir,
+    chapter: ChapterOptions,
+    intermediate_dir: Option<PathBuf>,
+    span_map: Option<PathBuf>,
+    report: Option<PathBuf>,
+    respan: bool,
+    deterministic: bool,
+    compression_level: Option<i64>,
+    fix_layout: bool,
+    fullscreen_fixes: bool,
+    remove_blank_pages: bool,
+    remove_fonts: bool,
+    images: Option<ImageOptions>,
+    grayscale_images: bool,
+    normalize_cover: bool,
+    title_page: bool,
+    media: MediaPolicy,
+    strip_calibre: bool,
+    skip_cover_fix: bool,
+    max_span_file_size: Option<u64>,
+    lenient: bool,
+    format: OutputFormat,
+    fixes: BTreeSet<WarningCode>,
+    book_rules: Vec<BookRules>,
+    footnote_popups: bool,
+}
+
+/// Content of the `mimetype` entry of every epub
+const EPUB_MIMETYPE: &str = "application/epub+zip";
+
+/// Paragraphs or sentences longer than this many characters are reported
+/// by default. They make page turns sluggish and break highlighting on device
+pub const DEFAULT_LONG_TEXT_WARN: usize = 10_000;
+
+/// Style kepubify adds to content documents, so the wrapper divs don't add
+/// to the margins set on the device
+pub const KOBO_STYLE: &str = "div#book-inner { margin-top: 0; margin-bottom: 0; }";
+
+/// Style that turns hyphenation on for body text, with the limits kepubify
+/// uses, and off for headings and table cells
+pub const HYPHENATE_STYLE: &str = "* { -webkit-hyphens: auto; hyphens: auto; \
+    -webkit-hyphenate-limit-after: 3; -webkit-hyphenate-limit-before: 3; \
+    -webkit-hyphenate-limit-lines: 2; hyphenate-limit-chars: 6 3 3; \
+    hyphenate-limit-lines: 2; adobe-hyphenate: explicit; }
+h1, h2, h3, h4, h5, h6, td { -webkit-hyphens: none !important; hyphens: none !important; \
+    adobe-hyphenate: none !important; }";
+
+/// Style that takes the margins off the body and keeps a little padding
+/// inside the wrapper, for Kobo's fullscreen reading mode
+pub const FULLSCREEN_STYLE: &str = "body { margin: 0 !important; padding: 0 !important; }
+body > div { padding-left: 0.2em !important; padding-right: 0.2em !important; }";
+
+/// Style that turns hyphenation off everywhere, whatever the book asks for
+pub const NO_HYPHENATE_STYLE: &str =
+    "* { -webkit-hyphens: none !important; hyphens: none !important; adobe-hyphenate: none !important; }";
+
+/// Class of the `<style>` element added by [`ChapterOptions::with_style`]
+const KOBO_STYLE_CLASS: &str = "kobostylehacks";
+
+/// How many spine documents are searched for an image when the package
+/// doesn't say which one is the cover
+const COVER_SEARCH_DOCS: usize = 2;
+
+/// Most words a page showing the cover can have and still be a cover or
+/// title page rather than a chapter that starts with the cover
+const TITLE_PAGE_WORDS: usize = 100;
+
+/// Options for adding kobo markup to a content document
+pub struct ChapterOptions {
+    segmenter: Box<dyn Segmenter>,
+    normalization: Normalization,
+    long_text_warn: usize,
+    chunk_length: usize,
+    strip_word_breaks: bool,
+    record_spans: bool,
+    wrapper: bool,
+    spans: bool,
+    style: Option<String>,
+    smarten_punctuation: bool,
+    replacements: Vec<Replacement>,
+    allowed: BTreeSet<WarningCode>,
+}
+
+impl Default for ChapterOptions {
+    fn default() -> Self {
+        return Self {
+            segmenter: Box::new(SentenceSegmenter),
+            normalization: Normalization::None,
+            long_text_warn: DEFAULT_LONG_TEXT_WARN,
+            chunk_length: 0,
+            strip_word_breaks: false,
+            record_spans: false,
+            wrapper: true,
+            spans: true,
+            style: None,
+            smarten_punctuation: false,
+            replacements: Vec::new(),
+            allowed: BTreeSet::new(),
+        };
+    }
+}
+
+impl ChapterOptions {
+    /// Replaces the segmentation used to split text into kobospans
+    pub fn with_segmenter(mut self, segmenter: impl Segmenter + 'static) -> Self {
+        self.segmenter = Box::new(segmenter);
+        return self;
+    }
+
+    /// Sets the unicode normalization applied to text while adding kobospans
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        return self;
+    }
+
+    /// Sets the length, in characters, above which paragraphs and sentences
+    /// are reported while adding kobospans. 0 disables the check
+    pub fn with_long_text_warning(mut self, chars: usize) -> Self {
+        self.long_text_warn = chars;
+        return self;
+    }
+
+    /// Breaks sentences longer than `max_chars` characters into several
+    /// kobospans at word boundaries. 0 (the default) keeps sentences whole
+    pub fn with_chunking(mut self, max_chars: usize) -> Self {
+        self.chunk_length = max_chars;
+        return self;
+    }
+
+    /// Removes soft hyphens and zero-width characters from inside words,
+    /// where they break dictionary lookup. Without it they are only reported
+    pub fn with_word_break_removal(mut self, strip: bool) -> Self {
+        self.strip_word_breaks = strip;
+        return self;
+    }
+
+    /// Whether the `book-columns` and `book-inner` divs are wrapped around
+    /// the body. On by default
+    pub fn with_wrapper(mut self, wrapper: bool) -> Self {
+        self.wrapper = wrapper;
+        return self;
+    }
+
+    /// Whether kobospans are added. On by default. Without them the text is
+    /// left as it is, so normalization and word break removal don't apply
+    pub fn with_spans(mut self, spans: bool) -> Self {
+        self.spans = spans;
+        return self;
+    }
+
+    /// Adds a `<style>` element with `css`, usually [`KOBO_STYLE`] and any
+    /// overrides, to the head of the document. One added by an earlier
+    /// conversion is replaced
+    pub fn with_style(mut self, css: Option<String>) -> Self {
+        self.style = css;
+        return self;
+    }
+
+    /// Replaces straight quotes, double hyphens and three dots in the text
+    /// with typographic quotes, em dashes and ellipses. Code and
+    /// preformatted text are left as they are
+    pub fn with_punctuation_smartening(mut self, smarten: bool) -> Self {
+        self.smarten_punctuation = smarten;
+        return self;
+    }
+
+    /// Regex substitutions run, in order, over the text of the document
+    /// before anything else is done to it
+    pub fn with_replacements(mut self, replacements: Vec<Replacement>) -> Self {
+        self.replacements = replacements;
+        return self;
+    }
+
+    /// Allows the warnings with these codes: they are only logged at debug
+    /// level and left out of the report
+    pub fn with_allowed_warnings(mut self, codes: impl IntoIterator<Item = WarningCode>) -> Self {
+        self.allowed.extend(codes);
+        return self;
+    }
+}
+
+/// Adds the wrapper divs and kobospans to a single XHTML document, without
+/// the rest of the book, and returns the result
+pub fn convert_chapter(xhtml: &str, options: &ChapterOptions) -> Result<String, ConverterError> {
+    let mut root = space::parse(xhtml.as_bytes())?;
+    transform_chapter(
+        &mut root,
+        "chapter",
+        options,
+        options.spans,
+        Layout::default(),
+        &options.allowed,
+        None,
+    )?;
+    return serialize_xml(&root);
+}
+
+/// State of one conversion, loaded once the book is extracted and passed
+/// to every transform
+struct ConversionContext {
+    /// The package document, its path and the directory manifest hrefs are
+    /// relative to
+    pkg: Package,
+    opf_path: PathBuf,
+    opf_dir: PathBuf,
+    /// Set by transforms that edit `pkg`
+    pkg_changed: bool,
+    /// Parsed content documents by path, and the ones that were changed.
+    /// Nothing is written back until [`ConversionContext::flush`]
+    docs: BTreeMap<PathBuf, Element>,
+    changed_docs: BTreeSet<PathBuf>,
+    /// Content documents that failed to convert in lenient mode or
+    /// panicked, with why. They are copied unchanged, so no transform reads
+    /// them again
+    unconverted: BTreeMap<PathBuf, ConverterError>,
+    /// Book-wide totals of the content document counters, and how many
+    /// documents were converted
+    stats: FileStats,
+    converted_docs: usize,
+    /// Every book-wide warning, as logged, and the codes that are only
+    /// logged at debug level
+    warnings: Vec<(WarningCode, String)>,
+    allowed: BTreeSet<WarningCode>,
+    /// How the pages are laid out, which some transforms depend on
+    layout: Layout,
+    /// What the media overlays of the book point to and play, and the ids
+    /// links point to, by hrefs relative to `opf_dir`
+    overlay: Overlay,
+    anchors: Targets,
+    /// The documents of the spine, in reading order
+    chapters: Vec<Chapter>,
+    /// The transforms that changed or removed each file, in the order they
+    /// were applied
+    transforms: BTreeMap<PathBuf, Vec<&'static str>>,
+}
+
+/// How the pages of a book are laid out, see [`Converter::detect_layout`]
+#[derive(Debug, Clone, Copy, Default)]
+struct Layout {
+    /// The text is in a vertical writing mode
+    vertical: bool,
+    /// The pages are pre-paginated, like those of comics and picture books
+    fixed: bool,
+}
+
+/// A document of the spine
+#[derive(Debug, Clone, PartialEq)]
+struct Chapter {
+    /// Normalized manifest href
+    href: String,
+    /// Its label in the table of contents, or else its first heading
+    title: Option<String>,
+}
+
+impl ConversionContext {
+    fn load(opf_path: PathBuf, allowed: &BTreeSet<WarningCode>) -> Result<Self, ConverterError> {
+        let pkg = Package::load(&opf_path)?;
+        return Ok(Self::new(opf_path, pkg, allowed));
+    }
+
+    fn new(opf_path: PathBuf, pkg: Package, allowed: &BTreeSet<WarningCode>) -> Self {
+        let opf_dir = opf_path.parent().map(Path::to_path_buf).unwrap_or_default();
+        return Self {
+            pkg,
+            opf_path,
+            opf_dir,
+            pkg_changed: false,
+            docs: BTreeMap::new(),
+            changed_docs: BTreeSet::new(),
+            unconverted: BTreeMap::new(),
+            stats: FileStats::default(),
+            converted_docs: 0,
+            warnings: Vec::new(),
+            allowed: allowed.clone(),
+            layout: Layout::default(),
+            overlay: Overlay::default(),
+            anchors: Targets::new(),
+            chapters: Vec::new(),
+            transforms: BTreeMap::new(),
+        };
+    }
+
+    /// Logs a warning and keeps it for the end of the conversion
+    fn warn(&mut self, code: WarningCode, args: std::fmt::Arguments) {
+        if self.allowed.contains(&code) {
+            debug!("{}: {}", code, args);
+            return;
+        }
+        warning!("{}: {}", code, args);
+        self.warnings.push((code, args.to_string()));
+    }
+
+    /// The ids of `targets` that the documents holding them lack, by
+    /// document. Documents that can't be read are left out
+    fn missing_targets(&mut self, targets: &Targets) -> Vec<(String, Vec<String>)> {
+        let mut missing = Vec::new();
+        for (doc, ids) in targets {
+            let path = self.resolve(doc);
+            let Ok(root) = self.document(&path) else {
+                continue;
+            };
+            let lacking = anchor::missing_ids(root, ids);
+            if !lacking.is_empty() {
+                missing.push((doc.clone(), lacking.into_iter().map(String::from).collect()));
+            }
+        }
+        return missing;
+    }
+
+    /// `targets` without the ids the book lacks before it is converted,
+    /// which aren't for the conversion to keep. `what` names what points
+    /// to them, for the log
+    fn existing_targets(&mut self, mut targets: Targets, what: &str) -> Targets {
+        for (doc, missing) in self.missing_targets(&targets) {
+            debug!(
+                "{}: {} point to missing ids {}",
+                doc,
+                what,
+                missing.join(", ")
+            );
+            if let Some(ids) = targets.get_mut(&doc) {
+                ids.retain(|id| !missing.contains(id));
+            }
+        }
+        return targets;
+    }
+
+    /// Records that `transform` changed or removed the file at `path`
+    fn touched(&mut self, path: PathBuf, transform: &'static str) {
+        let applied = self.transforms.entry(path).or_default();
+        if !applied.contains(&transform) {
+            applied.push(transform);
+        }
+    }
+
+    /// Title of the chapter at the normalized manifest href `href`
+    fn title(&self, href: &str) -> Option<&str> {
+        return self
+            .chapters
+            .iter()
+            .find(|c| c.href == href)
+            .and_then(|c| c.title.as_deref());
+    }
+
+    /// The nav document and NCX in the manifest
+    fn toc_items(&self) -> Vec<ManifestItem> {
+        return self
+            .pkg
+            .manifest()
+            .into_iter()
+            .filter(|i| {
+                return i.properties.iter().any(|p| p == "nav")
+                    || i.media_type == "application/x-dtbncx+xml";
+            })
+            .collect();
+    }
+
+    /// The nav document in the manifest
+    fn nav_item(&self) -> Option<ManifestItem> {
+        return self
+            .pkg
+            .manifest()
+            .into_iter()
+            .find(|i| return i.properties.iter().any(|p| p == "nav"));
+    }
+
+    /// Whether the nav document has a landmark of the `epub:type` `kind`.
+    /// None when there is no readable nav document
+    fn has_landmark(&mut self, kind: &str) -> Option<bool> {
+        let path = self.resolve(&self.nav_item()?.href);
+        let root = self.document(&path).ok()?;
+        return Some(
+            root.find_where(|e| return e.name == "a" && elem::has_epub_type(e, kind))
+                .next()
+                .is_some(),
+        );
+    }
+
+    /// Adds a landmark of the `epub:type` `kind` to the nav document,
+    /// linking to `page`, a normalized href, unless it has one already
+    fn add_landmark(&mut self, kind: &str, title: &str, page: &str, transform: &'static str) {
+        let Some(nav) = self.nav_item() else {
+            return;
+        };
+        if self.has_landmark(kind) != Some(false) {
+            return;
+        }
+        let path = self.resolve(&nav.href);
+        let link = href::relative(&href::normalize(&nav.href), page);
+        if let Ok(root) = self.document_mut(&path) {
+            add_landmark(root, kind, title, &link);
+            self.touched(path, transform);
+        }
+    }
+
+    /// Path of the file a manifest href points to
+    fn resolve(&self, href: &str) -> PathBuf {
+        return self.opf_dir.join(href::normalize(href));
+    }
+
+    /// Whether the manifest lists the file at `path` as an XHTML content
+    /// document, not the nav document
+    fn is_content_doc(&self, path: &Path) -> bool {
+        return self.pkg.manifest().iter().any(|i| {
+            return i.media_type == "application/xhtml+xml"
+                && !i.properties.iter().any(|p| p == "nav")
+                && self.resolve(&i.href) == path;
+        });
+    }
+
+    /// The parsed document at `path`, read from disk the first time.
+    /// Content documents that aren't well-formed XML are parsed as HTML
+    fn document(&mut self, path: &Path) -> Result<&Element, ConverterError> {
+        if self.unconverted.contains_key(path) {
+            return Err(ConverterError::Other(format!(
+                "{:?} is copied unchanged",
+                path
+            )));
+        }
+        if !self.docs.contains_key(path) {
+            let data = std::fs::read(path)?;
+            let root = match space::parse(data.as_slice()) {
+                Ok(root) => root,
+                Err(e) if !self.is_content_doc(path) => return Err(e.into()),
+                Err(e) => {
+                    let root = html5::parse(data.as_slice())?;
+                    let name = path.strip_prefix(&self.opf_dir).unwrap_or(path);
+                    self.warn(
+                        WarningCode::NotWellFormed,
+                        format_args!(
+                            "{}: not well-formed XML ({}), parsed it as HTML",
+                            name.display(),
+                            ConverterError::from(e)
+                        ),
+                    );
+                    // written back as XHTML even if nothing else changes
+                    self.changed_docs.insert(path.to_path_buf());
+                    root
+                }
+            };
+            self.docs.insert(path.to_path_buf(), root);
+        }
+        return Ok(&self.docs[path]);
+    }
+
+    /// Like [`ConversionContext::document`], marking the document as changed
+    fn document_mut(&mut self, path: &Path) -> Result<&mut Element, ConverterError> {
+        self.document(path)?;
+        self.changed_docs.insert(path.to_path_buf());
+        return Ok(self.docs.get_mut(path).unwrap());
+    }
+
+    /// Writes the package document and every changed content document
+    fn flush(&mut self) -> Result<(), ConverterError> {
+        if self.pkg_changed {
+            self.pkg.save(&self.opf_path)?;
+            self.pkg_changed = false;
+        }
+        for path in std::mem::take(&mut self.changed_docs) {
+            std::fs::write(&path, serialize_xml(&self.docs[&path])?)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Counters collected while converting a content document
+#[derive(Debug, Default)]
+struct FileStats {
+    /// Characters changed by unicode normalization
+    normalized_chars: usize,
+    /// Paragraphs and sentences reported as too long
+    long_texts: usize,
+    /// Soft hyphens and zero-width characters found inside words
+    word_breaks: usize,
+    /// Words split across two kobospans by inline markup
+    split_words: usize,
+    /// Quotes, dashes and ellipses made typographic
+    smartened: usize,
+    /// Matches of the replacements
+    replaced: usize,
+    /// Number of spans added
+    span_count: usize,
+    /// Length of each sentence span and number of them in each paragraph
+    sentence_lengths: Histogram,
+    spans_per_paragraph: Histogram,
+    /// Every span added, when recording spans for a span map
+    spans: Vec<SpanRecord>,
+    /// Whether the wrapper divs were added
+    wrapped: bool,
+}
+
+/// What a [`Converter`] writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Kepub,
+    /// A standard epub, with the cleanup but none of the kobo markup: no
+    /// kobospans, wrapper divs or kobo style, and no cover fix. Kobo
+    /// markup already in the book is removed
+    Epub,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_ascii_lowercase().as_str() {
+            "kepub" => Ok(OutputFormat::Kepub),
+            "epub" => Ok(OutputFormat::Epub),
+            _ => Err(format!("unknown format '{}', expected kepub or epub", s)),
+        };
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            OutputFormat::Kepub => "kepub",
+            OutputFormat::Epub => "epub",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A named set of [`ConverterBuilder`] options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Output that only changes with the content, at maximum compression,
+    /// with no lossy change to the book
+    Archival,
+    /// The kobo style, layout fixes and images scaled down to the screen,
+    /// for reading on device
+    Device,
+    /// Stored uncompressed, without checking text lengths
+    Fast,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_ascii_lowercase().as_str() {
+            "archival" => Ok(Preset::Archival),
+            "device" => Ok(Preset::Device),
+            "fast" => Ok(Preset::Fast),
+            _ => Err(format!(
+                "unknown preset '{}', expected archival, device or fast",
+                s
+            )),
+        };
+    }
+}
+
+impl std::fmt::Display for Preset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Preset::Archival => "archival",
+            Preset::Device => "device",
+            Preset::Fast => "fast",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Options of a [`Converter`]. Every option defaults to the behavior of a
+/// plain conversion
+#[derive(Default)]
+pub struct ConverterBuilder {
+    chapter: ChapterOptions,
+    intermediate_dir: Option<PathBuf>,
+    span_map: Option<PathBuf>,
+    report: Option<PathBuf>,
+    respan: bool,
+    deterministic: bool,
+    compression_level: Option<i64>,
+    fix_layout: bool,
+    fullscreen_fixes: bool,
+    remove_blank_pages: bool,
+    remove_fonts: bool,
+    images: Option<ImageOptions>,
+    grayscale_images: bool,
+    normalize_cover: bool,
+    title_page: bool,
+    media: MediaPolicy,
+    strip_calibre: bool,
+    skip_cover_fix: bool,
+    max_span_file_size: Option<u64>,
+    lenient: bool,
+    format: OutputFormat,
+    fixes: BTreeSet<WarningCode>,
+    book_rules: Vec<BookRules>,
+    footnote_popups: bool,
+}
+
+impl ConverterBuilder {
+    /// See [`ChapterOptions::with_segmenter`]
+    pub fn with_segmenter(mut self, segmenter: impl Segmenter + 'static) -> Self {
+        self.chapter = self.chapter.with_segmenter(segmenter);
+        return self;
+    }
+
+    /// See [`ChapterOptions::with_normalization`]
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.chapter = self.chapter.with_normalization(normalization);
+        return self;
+    }
+
+    /// See [`ChapterOptions::with_long_text_warning`]
+    pub fn with_long_text_warning(mut self, chars: usize) -> Self {
+        self.chapter = self.chapter.with_long_text_warning(chars);
+        return self;
+    }
+
+    /// See [`ChapterOptions::with_chunking`]
+    pub fn with_chunking(mut self, max_chars: usize) -> Self {
+        self.chapter = self.chapter.with_chunking(max_chars);
+        return self;
+    }
+
+    /// See [`ChapterOptions::with_word_break_removal`]
+    pub fn with_word_break_removal(mut self, strip: bool) -> Self {
+        self.chapter = self.chapter.with_word_break_removal(strip);
+        return self;
+    }
+
+    /// Copies every file changed by the conversion to `dir` before zipping,
+    /// along with a `changes.txt` listing them
+    pub fn with_intermediate_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.intermediate_dir = Some(dir.into());
+        return self;
+    }
+
+    /// Writes a JSON [`SpanMap`] of every kobospan added to `path`
+    pub fn with_span_map(mut self, path: impl Into<PathBuf>) -> Self {
+        self.span_map = Some(path.into());
+        self.chapter.record_spans = true;
+        return self;
+    }
+
+    /// Writes a JSON [`Report`] of the conversion to `path`
+    pub fn with_report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.report = Some(path.into());
+        return self;
+    }
+
+    /// Converts books that already are kepubs again, replacing their
+    /// kobospans, instead of copying them unchanged
+    pub fn with_respan(mut self, respan: bool) -> Self {
+        self.respan = respan;
+        return self;
+    }
+
+    /// Gives every entry of the output the same fixed timestamp instead of
+    /// the one it had in the source, or the current time for added files
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        return self;
+    }
+
+    /// Deflate level, 1 to 9, for the entries of the output that aren't
+    /// stored, or 0 to store every entry. None uses the zip library's
+    /// default
+    pub fn with_compression_level(mut self, level: Option<u8>) -> Self {
+        self.compression_level = level.map(|l| l.min(9).into());
+        return self;
+    }
+
+    /// Sets the options of `preset`. Options set after it override or add
+    /// to them
+    pub fn with_preset(self, preset: Preset) -> Self {
+        return match preset {
+            Preset::Archival => self
+                .with_deterministic(true)
+                .with_compression_level(Some(9))
+                .with_image_optimization(None)
+                .with_grayscale_images(false)
+                .with_cover_normalization(false),
+            Preset::Device => self
+                .with_style(Some(KOBO_STYLE.to_string()))
+                .with_layout_fix(true)
+                .with_image_optimization(Some(ImageOptions::default())),
+            Preset::Fast => self
+                .with_compression_level(Some(0))
+                .with_long_text_warning(0),
+        };
+    }
+
+    /// Moves the publisher's line height, margin and font size out of the
+    /// way of the device's reading settings. See [`css::neutralize_layout`]
+    pub fn with_layout_fix(mut self, fix: bool) -> Self {
+        self.fix_layout = fix;
+        return self;
+    }
+
+    /// Removes the margins and widths that box the text into a column, which
+    /// look wrong in Kobo's fullscreen reading mode. See
+    /// [`css::unconstrain_page`]
+    pub fn with_fullscreen_fixes(mut self, fix: bool) -> Self {
+        self.fullscreen_fixes = fix;
+        return self;
+    }
+
+    /// Removes spine documents without any visible content from the spine
+    /// and the table of contents. They are only reported otherwise
+    pub fn with_blank_page_removal(mut self, remove: bool) -> Self {
+        self.remove_blank_pages = remove;
+        return self;
+    }
+
+    /// Removes the embedded fonts of the book, their manifest items and
+    /// their `@font-face` rules, for reading with the device's fonts only
+    pub fn with_font_removal(mut self, remove: bool) -> Self {
+        self.remove_fonts = remove;
+        return self;
+    }
+
+    /// Scales JPEG and PNG images larger than the screen size of `options`
+    /// down to it, see [`images::optimize`]. None leaves them as they are
+    pub fn with_image_optimization(mut self, options: Option<ImageOptions>) -> Self {
+        self.images = options;
+        return self;
+    }
+
+    /// Makes JPEG and PNG images 8-bit grayscale, which is all most Kobo
+    /// screens show
+    pub fn with_grayscale_images(mut self, grayscale: bool) -> Self {
+        self.grayscale_images = grayscale;
+        return self;
+    }
+
+    /// Makes the cover a JPEG of at least [`images::MIN_COVER_SIZE`] and
+    /// adds the page showing it to the guide and the landmarks, so it shows
+    /// in the library grid and on the sleep screen
+    pub fn with_cover_normalization(mut self, normalize: bool) -> Self {
+        self.normalize_cover = normalize;
+        return self;
+    }
+
+    /// Adds a title page with the title, the authors and the cover to the
+    /// start of books that open straight into their first chapter
+    pub fn with_title_page(mut self, add: bool) -> Self {
+        self.title_page = add;
+        return self;
+    }
+
+    /// Sets what happens to audio and video, which Kobo devices can't play.
+    /// They are kept and reported by default
+    pub fn with_media_policy(mut self, policy: MediaPolicy) -> Self {
+        self.media = policy;
+        return self;
+    }
+
+    /// See [`ChapterOptions::with_wrapper`]
+    pub fn with_wrapper(mut self, wrapper: bool) -> Self {
+        self.chapter = self.chapter.with_wrapper(wrapper);
+        return self;
+    }
+
+    /// See [`ChapterOptions::with_spans`]
+    pub fn with_spans(mut self, spans: bool) -> Self {
+        self.chapter = self.chapter.with_spans(spans);
+        return self;
+    }
+
+    /// See [`ChapterOptions::with_style`]
+    pub fn with_style(mut self, css: Option<String>) -> Self {
+        self.chapter = self.chapter.with_style(css);
+        return self;
+    }
+
+    /// See [`ChapterOptions::with_punctuation_smartening`]
+    pub fn with_punctuation_smartening(mut self, smarten: bool) -> Self {
+        self.chapter = self.chapter.with_punctuation_smartening(smarten);
+        return self;
+    }
+
+    /// See [`ChapterOptions::with_replacements`]
+    pub fn with_replacements(mut self, replacements: Vec<Replacement>) -> Self {
+        self.chapter = self.chapter.with_replacements(replacements);
+        return self;
+    }
+
+    /// See [`ChapterOptions::with_allowed_warnings`]
+    pub fn with_allowed_warnings(mut self, codes: impl IntoIterator<Item = WarningCode>) -> Self {
+        self.chapter = self.chapter.with_allowed_warnings(codes);
+        return self;
+    }
+
+    /// Whether the cover image named by `<meta name="cover">` is marked
+    /// with the `cover-image` property. On by default
+    pub fn with_cover_fix(mut self, fix: bool) -> Self {
+        self.skip_cover_fix = !fix;
+        return self;
+    }
+
+    /// Content documents larger than `bytes` get the wrapper divs but no
+    /// kobospans, which could take minutes and millions of elements for
+    /// huge generated files. They are reported. None (the default) has no
+    /// limit
+    pub fn with_max_span_file_size(mut self, bytes: Option<u64>) -> Self {
+        self.max_span_file_size = bytes;
+        return self;
+    }
+
+    /// Content documents that fail to convert, like ones without a
+    /// `<body>`, are copied into the kepub unchanged and reported instead
+    /// of failing the conversion. A package document that can't be read is
+    /// rebuilt from the files of the book
+    pub fn with_lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        return self;
+    }
+
+    /// Sets what is written. With [`OutputFormat::Epub`] the kobo
+    /// transforms are left out whatever the other options say
+    pub fn with_format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        return self;
+    }
+
+    /// Removes calibre's metadata, bookmark files and the attributes and
+    /// unstyled classes it adds to content documents
+    pub fn with_calibre_removal(mut self, strip: bool) -> Self {
+        self.strip_calibre = strip;
+        return self;
+    }
+
+    /// Marks footnotes and the links to them so Kobo devices show the notes
+    /// in pop-ups, see [`footnote`]
+    pub fn with_footnote_popups(mut self, popups: bool) -> Self {
+        self.footnote_popups = popups;
+        return self;
+    }
+
+    /// Allows the warnings and repairs the validation findings that `config`
+    /// lists for every book, and for the books its rules match
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.chapter = self
+            .chapter
+            .with_allowed_warnings(config.allow.iter().copied());
+        self.fixes.extend(&config.fix);
+        self.book_rules.extend(config.books.iter().cloned());
+        return self;
+    }
+
+    /// Creates the converter and its working directory. Will fail if write
+    /// access to the tmp dir is not available
+    pub fn build(self) -> Result<Converter, std::io::Error> {
+        let kepub = self.format == OutputFormat::Kepub;
+        let chapter = match kepub {
+            true => self.chapter,
+            false => self
+                .chapter
+                .with_spans(false)
+                .with_wrapper(false)
+                .with_style(None),
+        };
+        return Ok(Converter {
+            working_dir: Converter::get_tmp_dir()?,
+            chapter,
+            intermediate_dir: self.intermediate_dir,
+            span_map: self.span_map,
+            report: self.report,
+            respan: self.respan,
+            deterministic: self.deterministic,
+            compression_level: self.compression_level,
+            fix_layout: self.fix_layout,
+            fullscreen_fixes: self.fullscreen_fixes,
+            remove_blank_pages: self.remove_blank_pages,
+            remove_fonts: self.remove_fonts,
+            images: self.images,
+            grayscale_images: self.grayscale_images,
+            normalize_cover: self.normalize_cover,
+            title_page: self.title_page,
+            media: self.media,
+            strip_calibre: self.strip_calibre,
+            skip_cover_fix: self.skip_cover_fix || !kepub,
+            max_span_file_size: self.max_span_file_size,
+            lenient: self.lenient,
+            format: self.format,
+            fixes: self.fixes,
+            book_rules: self.book_rules,
+            footnote_popups: self.footnote_popups,
+        });
+    }
+}
+
+impl Converter {
+    /// Starts configuring a conversion. The defaults match
+    /// [`ConverterBuilder::default`]
+    pub fn builder() -> ConverterBuilder {
+        return ConverterBuilder::default();
+    }
+
+    /// Logs a warning found before the package document is loaded, unless
+    /// its code is allowed. It is not part of the report
+    fn warn(&self, code: WarningCode, args: std::fmt::Arguments) {
+        match self.chapter.allowed.contains(&code) {
+            true => debug!("{}: {}", code, args),
+            false => warning!("{}: {}", code, args),
+        }
+    }
+
+    // Creates a private tmp dir, under XDG_RUNTIME_DIR when it is set since
+    // that is only accessible to the current user
+    fn get_tmp_dir() -> Result<WorkDir, std::io::Error> {
+        let base = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute() && p.is_dir())
+            .unwrap_or_else(std::env::temp_dir);
+        if base.to_str().is_none() {
+            return Err(std::io::Error::other(
+                "Could not get valid path to temporary directory",
+            ));
+        }
+
+        let td = WorkDir::create_in(&base, "kepub-rs-conv")?;
+        debug!("Working directory: {:?}", td.path());
+        return Ok(td);
+    }
+
+    /// Converts `epub` to a kepub at `out_path`, returning the content
+    /// documents that were copied unchanged
+    pub fn convert(
+        &self,
+        epub: &mut ZipArchive<File>,
+        out_path: &str,
+    ) -> Result<Vec<UnconvertedFile>, ConverterError> {
+        self.check_output(epub, out_path)?;
+        let (mut ctx, entries) = self.transform(epub)?;
+        self.write_book(out_path, &entries)?;
+        debug!("Wrote {} with {} warnings", out_path, ctx.warnings.len());
+
+        let files = self.transformed_files(&ctx);
+        log_transforms(&files);
+        if let Some(path) = &self.report {
+            let s = &ctx.stats;
+            let stats = report::Stats {
+                documents: ctx.converted_docs,
+                spans: s.span_count,
+                normalized_chars: s.normalized_chars,
+                long_texts: s.long_texts,
+                word_breaks: s.word_breaks,
+                split_words: s.split_words,
+                replaced: s.replaced,
+                smartened: s.smartened,
+                sentence_lengths: s.sentence_lengths.clone(),
+                spans_per_paragraph: s.spans_per_paragraph.clone(),
+            };
+            Report::new(out_path, std::mem::take(&mut ctx.warnings), stats, files)
+                .and_then(|r| r.save(path))
+                .map_err(|e| e.in_stage(Stage::Write))?;
+            debug!("Wrote report to {:?}", path);
+        }
+        let unconverted = std::mem::take(&mut ctx.unconverted)
+            .into_iter()
+            .map(|(path, error)| {
+                return UnconvertedFile {
+                    file: self.internal_name(&path),
+                    error,
+                };
+            })
+            .collect();
+        return Ok(unconverted);
+    }
+
+    /// Converts `epub` like [`Converter::convert`], but hands the kepub to
+    /// `sink` instead of writing it to a file. No report is written, as it
+    /// holds checksums of the kepub file, and content documents copied
+    /// unchanged are only logged
+    pub fn convert_to<S: OutputSink>(
+        &self,
+        epub: &mut ZipArchive<File>,
+        mut sink: S,
+    ) -> Result<S::Output, ConverterError> {
+        let (ctx, entries) = self.transform(epub)?;
+        let output = self
+            .write(&mut sink, &entries)
+            .and_then(|_| return sink.finish())
+            .map_err(|e| ConverterError::from(e).in_stage(Stage::Write))?;
+        debug!("Wrote the kepub with {} warnings", ctx.warnings.len());
+        log_transforms(&self.transformed_files(&ctx));
+        return Ok(output);
+    }
+
+    /// Extracts `epub` and converts it in the working dir, returning the
+    /// entries of the source archive for writing the kepub
+    fn transform(
+        &self,
+        epub: &mut ZipArchive<File>,
+    ) -> Result<(ConversionContext, Vec<SourceEntry>), ConverterError> {
+        let entries = self.extract(epub)?;
+        let opf_path = self.find_opf_path().map_err(|e| e.in_stage(Stage::Opf))?;
+        let mut ctx = match ConversionContext::load(opf_path.clone(), &self.chapter.allowed) {
+            Ok(ctx) => ctx,
+            Err(e) if self.lenient => self
+                .rebuild_package(opf_path, e)
+                .map_err(|e| e.in_stage(Stage::Opf))?,
+            Err(e) => return Err(e.in_stage(Stage::Opf)),
+        };
+        let fixes = self.apply_book_rules(&mut ctx);
+        self.check_content_docs(&mut ctx)
+            .map_err(|e| e.in_stage(Stage::Opf))?;
+        let (spanned, docs) = self.count_kepub_docs(&mut ctx);
+        let is_kepub = spanned * 2 > docs;
+        let kepub = self.format == OutputFormat::Kepub;
+        if is_kepub && !self.respan && kepub {
+            ctx.warn(
+                WarningCode::AlreadyKepub,
+                format_args!(
+                    "Already a kepub, {} of {} content documents have kobospans. \
+                     Copying it unchanged, use --force-respan to convert it again",
+                    spanned, docs
+                ),
+            );
+        } else {
+            if is_kepub && kepub {
+                info!(
+                    "Already a kepub, replacing the kobospans of {} content documents",
+                    spanned
+                );
+            } else if is_kepub {
+                info!(
+                    "Already a kepub, removing the kobospans of {} content documents",
+                    spanned
+                );
+            }
+            // before repairs, which can change the identifier fonts are
+            // obfuscated with
+            self.remove_fonts(&mut ctx)
+                .and_then(|_| self.restore_fonts(&mut ctx))
+                .and_then(|_| self.repair(&mut ctx, &fixes))
+                .and_then(|_| self.convert_opf(&mut ctx))
+                .and_then(|_| self.normalize_cover(&mut ctx))
+                .and_then(|_| self.title_page(&mut ctx))
+                .map_err(|e| e.in_stage(Stage::Opf))?;
+            self.chapter_titles(&mut ctx);
+            self.media_overlays(&mut ctx);
+            self.link_targets(&mut ctx);
+            self.strip_calibre(&mut ctx)
+                .and_then(|_| self.convert_media(&mut ctx))
+                .and_then(|_| self.optimize_images(&mut ctx))
+                .and_then(|_| self.footnote_popups(&mut ctx))
+                .and_then(|_| self.convert_html(&mut ctx, is_kepub))
+                .and_then(|_| self.blank_pages(&mut ctx))
+                .map_err(|e| e.in_stage(Stage::Html))?;
+            self.check_targets(&mut ctx);
+            self.convert_css(&mut ctx)
+                .map_err(|e| e.in_stage(Stage::Css))?;
+            self.clean_meta_inf(&mut ctx)
+                .and_then(|_| ctx.flush())
+                .map_err(|e| e.in_stage(Stage::Write))?;
+        }
+        if let Some(dir) = &self.intermediate_dir {
+            self.emit_intermediate(epub, dir, &entries)
+                .map_err(|e| e.in_stage(Stage::Write))?;
+        }
+        return Ok((ctx, entries));
+    }
+
+    /// The transforms that changed each file, by archive name
+    fn transformed_files(&self, ctx: &ConversionContext) -> BTreeMap<String, Vec<String>> {
+        return ctx
+            .transforms
+            .iter()
+            .map(|(path, t)| {
+                let transforms = t.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+                return (self.internal_name(path), transforms);
+            })
+            .collect();
+    }
+
+    /// Allows the warnings of the book rules matching the identifiers of the
+    /// book, returning every code to repair in it
+    fn apply_book_rules(&self, ctx: &mut ConversionContext) -> BTreeSet<WarningCode> {
+        let mut fixes = self.fixes.clone();
+        let identifiers = ctx.pkg.metadata("identifier");
+        for rules in self.book_rules.iter().filter(|r| r.matches(&identifiers)) {
+            debug!("Applying the rules for {}", rules.identifier);
+            ctx.allowed.extend(&rules.allow);
+            fixes.extend(&rules.fix);
+        }
+        return fixes;
+    }
+
+    /// Repairs the validation findings with a code in `fixes`, as
+    /// `validate --fix` would, and warns about the ones that can't be
+    fn repair(
+        &self,
+        ctx: &mut ConversionContext,
+        fixes: &BTreeSet<WarningCode>,
+    ) -> Result<(), ConverterError> {
+        if fixes.is_empty() {
+            return Ok(());
+        }
+        let findings = validate::check(&self.working_dir, &ctx.opf_path, &ctx.pkg);
+        for f in findings.iter().filter(|f| return fixes.contains(&f.code())) {
+            if !validate::repair(&self.working_dir, &ctx.opf_path, &mut ctx.pkg, f)? {
+                ctx.warn(f.code(), format_args!("{}, cannot fix it", f));
+                continue;
+            }
+            info!("Fixed {}: {}", f.code(), f);
+            if let Finding::Orphan {
+                name,
+                referenced: false,
+            } = f
+            {
+                ctx.touched(self.working_dir.join(name), "repair");
+            }
+            ctx.pkg_changed = true;
+            ctx.touched(ctx.opf_path.clone(), "repair");
+        }
+        return Ok(());
+    }
+
+    /// Checks the package document of `epub` for the problems listed in
+    /// [`validate::check`]. With `fix_path`, the ones that can be repaired
+    /// safely are and the book is written there, without any kepub
+    /// transform. Returns every finding with whether it was repaired
+    pub fn validate(
+        &self,
+        epub: &mut ZipArchive<File>,
+        fix_path: Option<&str>,
+    ) -> Result<Vec<(Finding, bool)>, ConverterError> {
+        if let Some(out_path) = fix_path {
+            self.check_output(epub, out_path)?;
+        }
+        let entries = self.extract(epub)?;
+        let opf_path = self.find_opf_path().map_err(|e| e.in_stage(Stage::Opf))?;
+        let mut pkg = Package::load(&opf_path).map_err(|e| e.in_stage(Stage::Opf))?;
+        let findings = validate::check(&self.working_dir, &opf_path, &pkg)
+            .into_iter()
+            .filter(|f| return !self.chapter.allowed.contains(&f.code()))
+            .collect::<Vec<_>>();
+        let Some(out_path) = fix_path else {
+            return Ok(findings.into_iter().map(|f| (f, false)).collect());
+        };
+
+        let mut results = Vec::with_capacity(findings.len());
+        for f in findings {
+            let fixed = validate::repair(&self.working_dir, &opf_path, &mut pkg, &f)
+                .map_err(|e| e.in_stage(Stage::Opf))?;
+            results.push((f, fixed));
+        }
+        if results.iter().any(|(_, fixed)| *fixed) {
+            pkg.save(&opf_path).map_err(|e| e.in_stage(Stage::Write))?;
+        }
+        self.write_book(out_path, &entries)?;
+        debug!("Wrote {}", out_path);
+        return Ok(results);
+    }
+
+    /// Extracts `epub` to the working dir, returning its entries in their
+    /// original order, so the output can match it
+    fn extract(&self, epub: &mut ZipArchive<File>) -> Result<Vec<SourceEntry>, ConverterError> {
+        debug!(
+            "Extracting {} entries to {:?}",
+            epub.len(),
+            self.working_dir
+        );
+        let mut entries = Vec::with_capacity(epub.len());
+        for i in 0..epub.len() {
+            let e = epub
+                .by_index_raw(i)
+                .map_err(|e| ConverterError::from(e).in_stage(Stage::Extract))?;
+            entries.push(SourceEntry {
+                name: e.name().to_string(),
+                compression: e.compression(),
+                modified: e.last_modified(),
+            });
+        }
+        disk::extracted_size(epub)
+            .and_then(|size| return disk::check_space(&self.working_dir, size))
+            .map_err(|e| e.in_stage(Stage::Extract))?;
+        epub.extract(&self.working_dir)
+            .map_err(|e| ConverterError::from(e).in_stage(Stage::Extract))?;
+        return Ok(entries);
+    }
+
+    /// Fails early if the kepub of `epub` cannot be written to `out_path`,
+    /// because its directory is read-only or short of space. The directory
+    /// is created if needed
+    fn check_output(
+        &self,
+        epub: &mut ZipArchive<File>,
+        out_path: &str,
+    ) -> Result<(), ConverterError> {
+        let dir = match Path::new(out_path).parent() {
+            Some(p) if p.as_os_str().is_empty() => Path::new("."),
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        return create_dir_all(dir)
+            .map_err(|e| return ConverterError::NotWritable(dir.to_path_buf(), e.to_string()))
+            .and_then(|_| return disk::check_writable(dir))
+            .and_then(|_| return disk::output_size(epub))
+            .and_then(|size| return disk::check_space(dir, size))
+            .map_err(|e| e.in_stage(Stage::Setup));
+    }
+
+    /// Writes the working dir to `out_path` with [`Converter::write`],
+    /// creating its directory first
+    fn write_book(&self, out_path: &str, entries: &[SourceEntry]) -> Result<(), ConverterError> {
+        match PathBuf::from(out_path).parent() {
+            Some(p) => std::fs::create_dir_all(p)
+                .map_err(|e| ConverterError::from(e).in_stage(Stage::Write))?,
+            None => {
+                return Err(io_err!(
+                    std::io::ErrorKind::Other,
+                    "Cannot get parent of output path: {}",
+                    out_path
+                )
+                .in_stage(Stage::Write))
+            }
+        };
+        ZipSink::create(Path::new(out_path), self.compression_level)
+            .and_then(|mut sink| {
+                self.write(&mut sink, entries)?;
+                return sink.finish();
+            })
+            .map_err(|e| ConverterError::from(e).in_stage(Stage::Write))?;
+        return Ok(());
+    }
+
+    // Write contents of temporary working dir to `sink`. Entries are written
+    // in the order of the source archive with mimetype first, followed by
+    // any files that weren't in the source archive. Entries that were stored
+    // uncompressed in the source stay that way. The timestamps of the source
+    // are kept unless the output is deterministic
+    fn write<S: OutputSink>(&self, sink: &mut S, entries: &[SourceEntry]) -> std::io::Result<()> {
+        let modified = |t: Option<DateTime>| {
+            return match self.deterministic {
+                true => Some(DateTime::default()),
+                false => t,
+            };
+        };
+
+        // OCF requires the mimetype first, stored, and with nothing else in
+        // it, so readers can identify the file from its first bytes
+        let mimetype = entries.iter().find(|e| e.name == "mimetype");
+        let mimetype_entry = OutputEntry {
+            name: "mimetype",
+            stored: true,
+            modified: modified(mimetype.and_then(|e| e.modified)),
+        };
+        sink.add_file(&mimetype_entry, EPUB_MIMETYPE.as_bytes())?;
+        let mut written = HashSet::from(["mimetype".to_string()]);
+
+        for entry in entries.iter().filter(|e| e.name != "mimetype") {
+            let name = entry.name.as_str();
+            let path = self.working_dir.join(name);
+            let output_entry = OutputEntry {
+                name,
+                stored: entry.compression == CompressionMethod::Stored,
+                modified: modified(entry.modified),
+            };
+            if name.ends_with('/') {
+                if path.is_dir() {
+                    sink.add_directory(&output_entry)?;
+                }
+            } else if path.is_file() {
+                sink.add_file(&output_entry, &std::fs::read(&path)?)?;
+            }
+            written.insert(name.trim_end_matches('/').to_string());
+        }
+
+        let walkdir = walkdir::WalkDir::new(&self.working_dir)
+            .sort_by_file_name()
+            .into_iter();
+
+        for entry in walkdir {
+            let file = match entry {
+                Ok(o) => o,
+                Err(e) => {
+                    warning!("Cannot zip file: {}", e);
+                    continue;
+                }
+            };
+            let path = file.path();
+            if !path.is_file() {
+                continue;
+            }
+
+            let path_internal = self.internal_name(path);
+
+            if !written.contains(&path_internal) {
+                debug!("Adding new file {}", path_internal);
+                let output_entry = OutputEntry {
+                    name: &path_internal,
+                    stored: false,
+                    modified: modified(None),
+                };
+                sink.add_file(&output_entry, &std::fs::read(path)?)?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Name of the archive entry for a file in the working dir
+    fn internal_name(&self, path: &Path) -> String {
+        return path
+            .strip_prefix(&self.working_dir)
+            .unwrap()
+            .components()
+            .map(|x| x.as_os_str().to_str().unwrap())
+            .collect::<Vec<&str>>()
+            .join("/");
+    }
+
+    /// Copies the files that differ from the source archive, or weren't in
+    /// it, to `dir` and lists them in `dir/changes.txt`
+    fn emit_intermediate(
+        &self,
+        epub: &mut ZipArchive<File>,
+        dir: &Path,
+        entries: &[SourceEntry],
+    ) -> Result<(), ConverterError> {
+        let mut changes = Vec::new();
+        for entry in entries {
+            let name = entry.name.as_str();
+            let path = self.working_dir.join(name);
+            // extract() fails on archives with such names already, but
+            // join() would replace the working dir with an absolute name
+            // and read a file outside it
+            let safe = Path::new(name)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+            if !safe || name.ends_with('/') || !path.is_file() {
+                continue;
+            }
+            let mut original = Vec::new();
+            epub.by_name(name)?.read_to_end(&mut original)?;
+            if original != std::fs::read(&path)? {
+                changes.push(("modified", name.to_string()));
+            }
+        }
+
+        let source = entries
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect::<HashSet<_>>();
+        for file in walkdir::WalkDir::new(&self.working_dir)
+            .sort_by_file_name()
+            .into_iter()
+            .flatten()
+        {
+            if !file.file_type().is_file() {
+                continue;
+            }
+            let name = self.internal_name(file.path());
+            if !source.contains(name.as_str()) {
+                changes.push(("added", name));
+            }
+        }
+
+        let mut manifest = String::new();
+        for (status, name) in &changes {
+            let dest = dir.join(name);
+            if let Some(p) = dest.parent() {
+                create_dir_all(p)?;
+            }
+            std::fs::copy(self.working_dir.join(name), &dest)?;
+            manifest.push_str(&format!("{}\t{}\n", status, name));
+        }
+        create_dir_all(dir)?;
+        std::fs::write(dir.join("changes.txt"), manifest)?;
+        info!("Wrote {} changed files to {:?}", changes.len(), dir);
+        return Ok(());
+    }
+
+    // Adds `properties='cover-image' attribute to cover image <item> element`
+    fn convert_opf(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        self.detect_layout(ctx)?;
+        if self.skip_cover_fix {
+            debug!("Not marking the cover image");
+            return Ok(());
+        }
+        let meta_content = ctx
+            .pkg
+            .cover_meta()
+            .and_then(|m| return m.attributes.get("content").cloned());
+        let cover_id = match ctx.pkg.resolve_cover_meta() {
+            Ok(Some(id)) => {
+                if let Some(href) = meta_content.filter(|c| c != &id) {
+                    ctx.warn(
+                        WarningCode::CoverMetaByHref,
+                        format_args!(
+                            "<meta name='cover'> refers to '{}' by href, changing it to the item id '{}'",
+                            href, id
+                        ),
+                    );
+                }
+                id
+            }
+            Ok(None) => match self.guess_cover(ctx) {
+                Some(id) => id,
+                None => {
+                    ctx.warn(
+                        WarningCode::NoCover,
+                        format_args!(
+                            "No <meta name='cover'> element in content.opf and no image \
+                             looks like a cover, book has no cover"
+                        ),
+                    );
+                    return Ok(());
+                }
+            },
+            Err(e) => match self.guess_cover(ctx) {
+                Some(id) => {
+                    ctx.warn(
+                        WarningCode::GuessedCover,
+                        format_args!("{}, using '{}' as the cover instead", e, id),
+                    );
+                    id
+                }
+                None => {
+                    ctx.warn(
+                        WarningCode::NoCover,
+                        format_args!("{}, book has no cover", e),
+                    );
+                    return Ok(());
+                }
+            },
+        };
+        debug!("Marking manifest item '{}' as cover-image", cover_id);
+        ctx.pkg.set_cover(&cover_id)?;
+        ctx.pkg_changed = true;
+        ctx.touched(ctx.opf_path.clone(), "opf-cover");
+        return Ok(());
+    }
+
+    /// Makes the cover image a JPEG of at least [`images::MIN_COVER_SIZE`],
+    /// renaming it and the links to it if it was in another format, and
+    /// points the guide and the landmarks of the navigation document at the
+    /// page showing it
+    fn normalize_cover(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        if !self.normalize_cover {
+            return Ok(());
+        }
+        // a book without a cover was reported when marking it
+        let Some(cover) = ctx.pkg.cover() else {
+            return Ok(());
+        };
+        self.normalize_cover_image(ctx, &cover)?;
+        self.cover_landmarks(ctx);
+        return Ok(());
+    }
+
+    fn normalize_cover_image(
+        &self,
+        ctx: &mut ConversionContext,
+        cover: &ManifestItem,
+    ) -> Result<(), ConverterError> {
+        let Some(format) = images::format(&cover.media_type) else {
+            debug!("Leaving the {} cover as it is", cover.media_type);
+            return Ok(());
+        };
+        let path = ctx.resolve(&cover.href);
+        let quality = self.images.unwrap_or_default().quality;
+        let data = std::fs::read(&path).map_err(|e| e.to_string());
+        let out = match data.and_then(|d| {
+            return images::normalize_cover(&d, format, images::MIN_COVER_SIZE, quality);
+        }) {
+            Ok(Some(out)) => out,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                ctx.warn(
+                    WarningCode::UnreadableCover,
+                    format_args!("Cannot read the cover image {}: {}", cover.href, e),
+                );
+                return Ok(());
+            }
+        };
+        if format == image::ImageFormat::Jpeg {
+            std::fs::write(&path, out)?;
+            info!("Scaled the cover image up to {:?}", images::MIN_COVER_SIZE);
+            ctx.touched(path, "cover");
+            return Ok(());
+        }
+
+        // the JPEG gets its own name, next to the old file
+        let (dir, name) = cover.href.rsplit_once('/').unwrap_or(("", &cover.href));
+        let stem = name.rsplit_once('.').map_or(name, |(s, _)| return s);
+        let new_name = (0..)
+            .map(|i| match i {
+                0 => format!("{}.jpg", stem),
+                i => format!("{}-{}.jpg", stem, i),
+            })
+            .find(|n| return !path.with_file_name(href::percent_decode(n)).exists())
+            .unwrap();
+        let new_path = path.with_file_name(href::percent_decode(&new_name));
+        std::fs::write(&new_path, out)?;
+        std::fs::remove_file(&path)?;
+        let old = href::normalize(&cover.href);
+        // the manifest item and the guide point to it from the package
+        let opf_href = ctx
+            .opf_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        rename_links(ctx.pkg.root_mut(), &opf_href, &old, &new_name);
+        ctx.pkg.set_media_type(&cover.id, "image/jpeg");
+        ctx.pkg_changed = true;
+        ctx.touched(ctx.opf_path.clone(), "cover");
+        for item in ctx.pkg.manifest_by_type("application/xhtml+xml") {
+            let doc = href::normalize(&item.href);
+            let path = ctx.resolve(&item.href);
+            let links = ctx.document(&path).is_ok_and(|root| {
+                return root.descendants().any(|e| return links_to(e, &doc, &old));
+            });
+            if links {
+                rename_links(ctx.document_mut(&path)?, &doc, &old, &new_name);
+                ctx.touched(path, "cover");
+            }
+        }
+        info!(
+            "Made the cover image {} a JPEG, {}",
+            cover.href,
+            if dir.is_empty() {
+                new_name
+            } else {
+                format!("{}/{}", dir, new_name)
+            }
+        );
+        ctx.touched(path, "cover");
+        ctx.touched(new_path, "cover");
+        return Ok(());
+    }
+
+    /// Adds the first spine document showing the cover image to the guide
+    /// and to the landmarks of the navigation document, unless they have a
+    /// cover already
+    fn cover_landmarks(&self, ctx: &mut ConversionContext) {
+        let Some(cover) = ctx.pkg.cover() else {
+            return;
+        };
+        let image = href::normalize(&cover.href);
+        let mut page = None;
+        for item in ctx.pkg.spine().into_iter().take(COVER_SEARCH_DOCS) {
+            let Some(doc) = ctx.pkg.item(&item.idref) else {
+                continue;
+            };
+            let doc_href = href::normalize(&doc.href);
+            let src = ctx
+                .document(&ctx.resolve(&doc.href))
+                .ok()
+                .and_then(first_image);
+            if src.is_some_and(|src| return href::resolve(&doc_href, &src) == image) {
+                page = Some(doc_href);
+                break;
+            }
+        }
+        let Some(page) = page else {
+            debug!("No page shows the cover image, not adding one to the guide");
+            return;
+        };
+
+        if ctx.pkg.guide_reference("cover").is_none() {
+            debug!("Adding {} to the guide as the cover", page);
+            ctx.pkg.add_guide_reference("cover", "Cover", &page);
+            ctx.pkg_changed = true;
+            ctx.touched(ctx.opf_path.clone(), "cover");
+        }
+        ctx.add_landmark("cover", "Cover", &page, "cover");
+    }
+
+    /// Adds a page with the title, the authors and the cover to the start of
+    /// the spine, the guide and the landmarks, unless the book opens on a
+    /// title or cover page already
+    fn title_page(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        if !self.title_page {
+            return Ok(());
+        }
+        if ctx.pkg.is_fixed_layout() {
+            debug!("Not adding a title page to a fixed-layout book");
+            return Ok(());
+        }
+        if ctx.pkg.guide_reference("title-page").is_some()
+            || ctx.has_landmark("titlepage") == Some(true)
+        {
+            debug!("The book has a title page already");
+            return Ok(());
+        }
+        let first = ctx
+            .pkg
+            .spine()
+            .into_iter()
+            .filter(|i| i.linear)
+            .find_map(|i| return ctx.pkg.item(&i.idref));
+        let Some(first) = first else {
+            return Ok(());
+        };
+        let first_href = href::normalize(&first.href);
+        let cover = ctx.pkg.cover().map(|c| return href::normalize(&c.href));
+        let opens_on_title = ctx.document(&ctx.resolve(&first.href)).is_ok_and(|root| {
+            let marked = root.descendants().any(|e| {
+                return elem::has_epub_type(e, "titlepage") || elem::has_epub_type(e, "cover");
+            });
+            let shows_cover = first_image(root)
+                .zip(cover.as_ref())
+                .is_some_and(|(src, c)| return href::resolve(&first_href, &src) == *c);
+            let words = verify::text_content(root).split_whitespace().count();
+            return marked || (shows_cover && words <= TITLE_PAGE_WORDS);
+        });
+        if opens_on_title {
+            debug!("{} is a title or cover page already", first.href);
+            return Ok(());
+        }
+        let title = ctx
+            .pkg
+            .metadata("title")
+            .into_iter()
+            .find(|t| !t.is_empty());
+        if title.is_none() && cover.is_none() {
+            debug!("No title and no cover to put on a title page");
+            return Ok(());
+        }
+
+        // next to the first document, so it shares its directory
+        let dir = first_href
+            .rsplit_once('/')
+            .map(|(d, _)| return format!("{}/", d))
+            .unwrap_or_default();
+        let page = (1..)
+            .map(|i| match i {
+                1 => format!("{}titlepage.xhtml", dir),
+                i => format!("{}titlepage-{}.xhtml", dir, i),
+            })
+            .find(|p| return !ctx.opf_dir.join(p).exists())
+            .unwrap();
+        let authors = ctx
+            .pkg
+            .metadata("creator")
+            .into_iter()
+            .filter(|a| !a.is_empty())
+            .collect::<Vec<_>>();
+        let language = ctx.pkg.metadata("language").into_iter().next();
+        let doc = title_page_doc(
+            title.as_deref(),
+            &authors,
+            cover.map(|c| return href::relative(&page, &c)).as_deref(),
+            language.as_deref(),
+            ctx.pkg.is_epub3(),
+        );
+        let path = ctx.opf_dir.join(&page);
+        std::fs::write(&path, doc)?;
+
+        let opf_href = ctx
+            .opf_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let item = ManifestItem {
+            id: ctx.pkg.unused_id("titlepage"),
+            href: href::relative(&opf_href, &page),
+            media_type: "application/xhtml+xml".to_string(),
+            properties: Vec::new(),
+        };
+        ctx.pkg.add_item(&item)?;
+        ctx.pkg.prepend_to_spine(&item.id)?;
+        if ctx.pkg.guide_reference("title-page").is_none() {
+            ctx.pkg
+                .add_guide_reference("title-page", "Title Page", &item.href);
+        }
+        ctx.pkg_changed = true;
+        ctx.touched(ctx.opf_path.clone(), "title-page");
+        ctx.touched(path, "title-page");
+        ctx.add_landmark("titlepage", "Title Page", &page, "title-page");
+        info!("Added a title page, {}", item.href);
+        return Ok(());
+    }
+
+    /// Id of the image that looks like the cover, for books without a
+    /// usable `<meta name="cover">`: see [`Package::guess_cover`], or else
+    /// the first image of the first spine documents
+    fn guess_cover(&self, ctx: &mut ConversionContext) -> Option<String> {
+        if let Some(item) = ctx.pkg.guess_cover() {
+            info!("Using '{}' as the cover image", item.href);
+            return Some(item.id);
+        }
+        let images = ctx
+            .pkg
+            .manifest()
+            .into_iter()
+            .filter(|i| i.media_type.starts_with("image/"))
+            .collect::<Vec<_>>();
+        for item in ctx.pkg.spine().into_iter().take(COVER_SEARCH_DOCS) {
+            let Some(doc) = ctx.pkg.item(&item.idref) else {
+                continue;
+            };
+            let path = ctx.resolve(&doc.href);
+            let Some(src) = ctx.document(&path).ok().and_then(first_image) else {
+                continue;
+            };
+            let src = href::resolve(&href::normalize(&doc.href), &src);
+            if let Some(image) = images.iter().find(|i| href::normalize(&i.href) == src) {
+                info!(
+                    "Using '{}', the first image of {}, as the cover image",
+                    image.href, doc.href
+                );
+                return Some(image.id.clone());
+            }
+        }
+        return None;
+    }
+
+    /// Applies the media policy to the audio and video elements of every
+    /// content document. Stripped media files are removed from the book
+    fn convert_media(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        if self.media == MediaPolicy::Keep {
+            return Ok(());
+        }
+
+        let mut found = 0;
+        let mut docs = 0;
+        let mut sources = HashSet::new();
+        for item in ctx.pkg.manifest_by_type("application/xhtml+xml") {
+            let path = ctx.resolve(&item.href);
+            // unreadable documents are reported when converting
+            let count = match ctx.document(&path) {
+                Ok(doc) => media::count_media(doc),
+                Err(_) => continue,
+            };
+            if count == 0 {
+                continue;
+            }
+            debug!("{}: {} audio and video elements", item.href, count);
+            found += count;
+            docs += 1;
+            if self.media == MediaPolicy::Strip {
+                let doc_href = href::normalize(&item.href);
+                let (_, srcs) = media::strip_media(ctx.document_mut(&path)?, &doc_href);
+                sources.extend(srcs);
+                ctx.touched(path, "media");
+            }
+        }
+        if found == 0 {
+            return Ok(());
+        }
+        if self.media == MediaPolicy::Warn {
+            ctx.warn(
+                WarningCode::UnplayableMedia,
+                format_args!(
+                    "Found {} audio and video elements in {} documents, which Kobo \
+                     devices can't play. Use --media strip to remove them",
+                    found, docs
+                ),
+            );
+            return Ok(());
+        }
+
+        let mut removed = 0;
+        for item in ctx.pkg.manifest() {
+            let is_media =
+                item.media_type.starts_with("audio/") || item.media_type.starts_with("video/");
+            let href = href::normalize(&item.href);
+            if !is_media || !sources.contains(&href) {
+                continue;
+            }
+            if ctx.overlay.audio.contains(&href) {
+                debug!("Keeping {}, media overlays play it", href);
+                continue;
+            }
+            let path = ctx.resolve(&item.href);
+            match std::fs::remove_file(&path) {
+                Ok(()) => ctx.touched(path, "media"),
+                Err(e) => debug!("Cannot remove {}: {}", item.href, e),
+            }
+            ctx.pkg.remove_item(&item.id);
+            removed += 1;
+        }
+        if removed > 0 {
+            ctx.pkg_changed = true;
+            ctx.touched(ctx.opf_path.clone(), "media");
+        }
+        info!(
+            "Replaced {} audio and video elements in {} documents, removed {} media files",
+            found, docs, removed
+        );
+        return Ok(());
+    }
+
+    /// Reads the media overlays of the book, so that the transforms after
+    /// it keep the audio they play and the ids they point to
+    fn media_overlays(&self, ctx: &mut ConversionContext) {
+        let items = ctx.pkg.manifest_by_type(overlay::SMIL_TYPE);
+        if items.is_empty() {
+            return;
+        }
+        for item in &items {
+            let path = ctx.resolve(&item.href);
+            let root = std::fs::read(&path)
+                .map_err(ConverterError::from)
+                .and_then(|data| return Ok(Element::parse(data.as_slice())?));
+            match root {
+                Ok(root) => ctx
+                    .overlay
+                    .merge(Overlay::read(&root, &href::normalize(&item.href))),
+                Err(e) => debug!("Cannot read media overlay {}: {}", item.href, e),
+            }
+        }
+        let targets = std::mem::take(&mut ctx.overlay.targets);
+        ctx.overlay.targets = ctx.existing_targets(targets, "media overlays");
+        info!(
+            "Found {} media overlays, which Kobo devices don't play. Keeping them for other readers",
+            items.len()
+        );
+    }
+
+    /// Collects the ids that links, the tables of contents and the guide of
+    /// the package point to, so the conversion can check they are kept
+    fn link_targets(&self, ctx: &mut ConversionContext) {
+        let opf_name = ctx
+            .opf_path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut targets = anchor::link_targets(ctx.pkg.root(), &opf_name);
+        let mut items = ctx.pkg.manifest_by_type("application/xhtml+xml");
+        items.extend(ctx.pkg.manifest_by_type("application/x-dtbncx+xml"));
+        for item in items {
+            let path = ctx.resolve(&item.href);
+            if let Ok(root) = ctx.document(&path) {
+                anchor::merge(
+                    &mut targets,
+                    anchor::link_targets(root, &href::normalize(&item.href)),
+                );
+            }
+        }
+        ctx.anchors = ctx.existing_targets(targets, "links");
+        debug!(
+            "Found links to {} ids",
+            ctx.anchors.values().map(BTreeSet::len).sum::<usize>()
+        );
+    }
+
+    /// Scales the JPEG and PNG images of the manifest that are larger than
+    /// the screen down to it, and makes them grayscale if asked. Images keep
+    /// their href and media type, and those that don't get smaller or can't
+    /// be decoded are left as they are
+    fn optimize_images(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        if self.images.is_none() && !self.grayscale_images {
+            return Ok(());
+        }
+        let mut optimized = 0;
+        let mut saved = 0;
+        for item in ctx.pkg.manifest() {
+            let Some(format) = images::format(&item.media_type) else {
+                continue;
+            };
+            let path = ctx.resolve(&item.href);
+            let Ok(data) = std::fs::read(&path) else {
+                continue;
+            };
+            match images::optimize(&data, format, self.images.as_ref(), self.grayscale_images) {
+                Ok(Some(out)) => {
+                    trace!("{}: {} to {} bytes", item.href, data.len(), out.len());
+                    saved += data.len() - out.len();
+                    optimized += 1;
+                    std::fs::write(&path, out)?;
+                    ctx.touched(path, "images");
+                }
+                Ok(None) => {}
+                Err(e) => debug!("Cannot decode {}, leaving it as it is: {}", item.href, e),
+            }
+        }
+        info!("Re-encoded {} images, saving {} bytes", optimized, saved);
+        return Ok(());
+    }
+
+    /// Rewrites the notes that `noteref` links point to, and the linked
+    /// elements marked as notes, into the shape Kobo devices show in
+    /// pop-ups, and marks every link to them as a `noteref`
+    fn footnote_popups(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        if !self.footnote_popups {
+            return Ok(());
+        }
+        let docs = ctx
+            .pkg
+            .manifest_by_type("application/xhtml+xml")
+            .iter()
+            .map(|i| return href::normalize(&i.href))
+            .collect::<Vec<_>>();
+        let mut notes = Targets::new();
+        for doc in &docs {
+            if let Ok(root) = ctx.document(&ctx.resolve(doc)) {
+                anchor::merge(&mut notes, footnote::noterefs(root, doc));
+            }
+        }
+        for (doc, ids) in ctx.anchors.clone() {
+            if let Ok(root) = ctx.document(&ctx.resolve(&doc)) {
+                let typed = footnote::typed_notes(root, &ids);
+                notes.entry(doc).or_default().extend(typed);
+            }
+        }
+
+        let mut made = 0;
+        let mut linked = 0;
+        let none = BTreeSet::new();
+        for doc in &docs {
+            let path = ctx.resolve(doc);
+            let ids = notes.get(doc).unwrap_or(&none);
+            let Ok(root) = ctx.document(&path) else {
+                continue;
+            };
+            // taking the document mutably has it written back
+            if !footnote::has_notes_to_make(root, ids)
+                && !footnote::has_noterefs_to_mark(root, doc, &notes)
+            {
+                continue;
+            }
+            let root = ctx.document_mut(&path)?;
+            let n = footnote::make_notes(root, ids);
+            let links = footnote::mark_noterefs(root, doc, &notes);
+            if n + links > 0 {
+                ctx.touched(path, "footnotes");
+            }
+            made += n;
+            linked += links;
+        }
+        info!(
+            "Made {} pop-up notes and marked {} more links to notes",
+            made, linked
+        );
+        return Ok(());
+    }
+
+    /// Warns about the ids links and media overlays point to that the
+    /// conversion removed, which leaves them leading nowhere
+    fn check_targets(&self, ctx: &mut ConversionContext) {
+        let checks = [
+            (
+                ctx.overlay.targets.clone(),
+                WarningCode::OverlayTarget,
+                "media overlays",
+            ),
+            (ctx.anchors.clone(), WarningCode::BrokenLink, "links"),
+        ];
+        for (targets, code, what) in checks {
+            for (doc, missing) in ctx.missing_targets(&targets) {
+                ctx.warn(
+                    code,
+                    format_args!(
+                        "{}: {} point to ids lost in the conversion: {}",
+                        doc,
+                        what,
+                        missing.join(", ")
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Finds the spine documents that show nothing, like the blank pages of
+    /// print editions, and removes them from the spine and the table of
+    /// contents if asked to. The files stay in the book, so links to them
+    /// keep working
+    fn blank_pages(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        if ctx.layout.fixed {
+            // blank pages keep the spreads of a fixed-layout book together
+            return Ok(());
+        }
+        let mut blank = Vec::new();
+        for item in ctx.pkg.spine() {
+            let Some(doc) = ctx.pkg.item(&item.idref) else {
+                continue;
+            };
+            let path = ctx.resolve(&doc.href);
+            if ctx.document(&path).is_ok_and(is_blank) {
+                blank.push((item.idref, href::normalize(&doc.href)));
+            }
+        }
+        if blank.is_empty() {
+            return Ok(());
+        }
+
+        let names = blank.iter().map(|(_, h)| h.as_str()).collect::<Vec<_>>();
+        if !self.remove_blank_pages {
+            info!(
+                "Found {} blank pages, use --remove-blank-pages to drop them: {}",
+                blank.len(),
+                names.join(", ")
+            );
+            return Ok(());
+        }
+        if blank.len() == ctx.pkg.spine().len() {
+            ctx.warn(
+                WarningCode::AllBlank,
+                format_args!("Every document in the spine is blank, keeping them"),
+            );
+            return Ok(());
+        }
+
+        for (idref, _) in &blank {
+            ctx.pkg.remove_from_spine(idref);
+        }
+        ctx.pkg_changed = true;
+        ctx.touched(ctx.opf_path.clone(), "blank-pages");
+
+        let hrefs = blank.iter().map(|(_, h)| h.clone()).collect::<HashSet<_>>();
+        let mut toc_entries = 0;
+        ctx.chapters.retain(|c| !hrefs.contains(&c.href));
+        for toc in ctx.toc_items() {
+            let toc_href = href::normalize(&toc.href);
+            let path = ctx.resolve(&toc.href);
+            if ctx.document(&path).is_err() {
+                ctx.warn(
+                    WarningCode::UnreadableToc,
+                    format_args!("Cannot read table of contents {}", toc_href),
+                );
+                continue;
+            }
+            let pruned = prune_toc(ctx.document_mut(&path)?, &toc_href, &hrefs);
+            if pruned > 0 {
+                ctx.touched(path, "blank-pages");
+            }
+            toc_entries += pruned;
+        }
+        info!(
+            "Removed {} blank pages and {} table of contents entries: {}",
+            blank.len(),
+            toc_entries,
+            names.join(", ")
+        );
+        return Ok(());
+    }
+
+    /// Normalizes line endings and trailing whitespace in the stylesheets
+    /// listed in the manifest, and applies the fullscreen and layout fixes
+    /// to them and to the `<style>` elements of content documents. The
+    /// fullscreen fix goes first, so the layout fix doesn't keep what it
+    /// removes
+    fn convert_css(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        let reflowable = !ctx.layout.vertical && !ctx.layout.fixed;
+        let fullscreen_fixes = self.fullscreen_fixes && reflowable;
+        let fix_layout = self.fix_layout && reflowable;
+        let mut moved = 0;
+        let mut unconstrained = 0;
+        let mut font_faces = 0;
+        for item in ctx.pkg.manifest_by_type("text/css") {
+            let path = ctx.resolve(&item.href);
+            let css = match std::fs::read_to_string(&path) {
+                Ok(c) => c,
+                Err(e) => {
+                    ctx.warn(
+                        WarningCode::UnreadableStylesheet,
+                        format_args!("Cannot read stylesheet {:?}: {}", path, e),
+                    );
+                    continue;
+                }
+            };
+            let mut out = text::normalize_lines(&css);
+            if out != css {
+                debug!("Normalized whitespace in {}", item.href);
+                ctx.touched(path.clone(), "line-endings");
+            }
+            if self.remove_fonts {
+                let (fixed, n) = css::remove_font_faces(&out);
+                out = fixed;
+                font_faces += n;
+                if n > 0 {
+                    ctx.touched(path.clone(), "fonts");
+                }
+            }
+            if fullscreen_fixes {
+                let (fixed, n) = css::unconstrain_page(&out);
+                out = fixed;
+                unconstrained += n;
+                if n > 0 {
+                    ctx.touched(path.clone(), "fullscreen");
+                }
+            }
+            if fix_layout {
+                let (fixed, n) = css::neutralize_layout(&out);
+                out = fixed;
+                moved += n;
+                if n > 0 {
+                    ctx.touched(path.clone(), "layout");
+                }
+            }
+            if out != css {
+                std::fs::write(&path, out)?;
+            }
+        }
+
+        for item in ctx.pkg.manifest_by_type("application/xhtml+xml") {
+            let path = ctx.resolve(&item.href);
+            if self.remove_fonts {
+                let n = fix_style_elements(ctx, &path, css::remove_font_faces)?;
+                if n > 0 {
+                    ctx.touched(path.clone(), "fonts");
+                }
+                font_faces += n;
+            }
+            if fullscreen_fixes {
+                let n = fix_style_elements(ctx, &path, css::unconstrain_page)?
+                    + unconstrain_style_attributes(ctx, &path)?;
+                if n > 0 {
+                    ctx.touched(path.clone(), "fullscreen");
+                }
+                unconstrained += n;
+            }
+            if fix_layout {
+                let n = fix_style_elements(ctx, &path, css::neutralize_layout)?;
+                if n > 0 {
+                    ctx.touched(path, "layout");
+                }
+                moved += n;
+            }
+        }
+        if fix_layout {
+            info!(
+                "Moved {} publisher layout declarations out of the way",
+                moved
+            );
+        }
+        if fullscreen_fixes {
+            info!(
+                "Removed {} page width and centering declarations for fullscreen mode",
+                unconstrained
+            );
+        }
+        if self.remove_fonts {
+            info!("Removed {} @font-face rules", font_faces);
+        }
+        return Ok(());
+    }
+
+    /// Finds out whether the book pages right to left, whether its text is
+    /// vertical and whether its pages are fixed, from the package document
+    /// and the stylesheets. The spine attribute and the CSS are kept either
+    /// way, but vertical text is left out of compatibility normalization,
+    /// punctuation smartening and the layout and fullscreen fixes, which
+    /// assume horizontal lines. Fixed-layout documents don't get the
+    /// wrapper divs, kobo style or page fixes either, and the package gets
+    /// the `rendition:layout` Kobo devices look for
+    fn detect_layout(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        if let Some(direction) = ctx.pkg.page_progression() {
+            info!("Page progression direction is {}", direction);
+        }
+        let vertical_meta = ctx
+            .pkg
+            .primary_writing_mode()
+            .is_some_and(|m| m.starts_with("vertical") || m.starts_with("tb"));
+        ctx.layout.vertical = vertical_meta
+            || ctx.pkg.manifest_by_type("text/css").iter().any(|item| {
+                return std::fs::read_to_string(ctx.resolve(&item.href))
+                    .is_ok_and(|css| css::has_vertical_writing_mode(&css));
+            });
+        if ctx.layout.vertical {
+            info!(
+                "Vertical writing mode, leaving its characters, punctuation and layout as they are"
+            );
+        }
+
+        ctx.layout.fixed = ctx.pkg.is_fixed_layout() || self.has_apple_fixed_layout();
+        if !ctx.layout.fixed {
+            return Ok(());
+        }
+        info!("Fixed-layout book, leaving the layout of its pages as it is");
+        if ctx.pkg.meta_property("rendition:layout").as_deref() != Some("pre-paginated") {
+            ctx.pkg
+                .set_meta_property("rendition:layout", "pre-paginated")?;
+            ctx.pkg_changed = true;
+            ctx.touched(ctx.opf_path.clone(), "fixed-layout");
+        }
+
+        // the viewport gives the size of the page, without it the device
+        // has to guess
+        let mut missing = Vec::new();
+        for item in ctx.pkg.spine() {
+            let Some(doc) = ctx.pkg.item(&item.idref) else {
+                continue;
+            };
+            let path = ctx.resolve(&doc.href);
+            if ctx.document(&path).is_ok_and(|root| !has_viewport(root)) {
+                missing.push(href::normalize(&doc.href));
+            }
+        }
+        if !missing.is_empty() {
+            ctx.warn(
+                WarningCode::MissingViewport,
+                format_args!(
+                    "{} fixed-layout documents have no viewport meta, their pages may be sized wrong: {}",
+                    missing.len(),
+                    missing.join(", ")
+                ),
+            );
+        }
+        return Ok(());
+    }
+
+    /// Whether Apple's display options in META-INF mark the book as fixed
+    /// layout, as older fixed-layout books do instead of the package
+    fn has_apple_fixed_layout(&self) -> bool {
+        let path = self
+            .working_dir
+            .join("META-INF")
+            .join("com.apple.ibooks.display-options.xml");
+        let Ok(root) = File::open(path).map(Element::parse) else {
+            return false;
+        };
+        return root.is_ok_and(|root| {
+            return root.find_where(|e| e.name == "option").any(|e| {
+                return e
+                    .attributes
+                    .get("name")
+                    .is_some_and(|n| n == "fixed-layout")
+                    && e.get_text().is_some_and(|t| t.trim() == "true");
+            });
+        });
+    }
+
+    /// Removes calibre's metadata and bookmark files, and the markup it
+    /// added to content documents, if enabled
+    fn strip_calibre(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        if !self.strip_calibre {
+            return Ok(());
+        }
+        let metadata = calibre::strip_metadata(ctx.pkg.root_mut());
+        if metadata > 0 {
+            ctx.pkg_changed = true;
+            ctx.touched(ctx.opf_path.clone(), "cleanup");
+        }
+
+        let mut bookmarks = 0;
+        for name in calibre::BOOKMARK_FILES {
+            let path = self.working_dir.join(name);
+            if !path.is_file() {
+                continue;
+            }
+            std::fs::remove_file(&path)?;
+            bookmarks += 1;
+            ctx.touched(path.clone(), "cleanup");
+            let listed = ctx
+                .pkg
+                .manifest()
+                .into_iter()
+                .find(|i| ctx.resolve(&i.href) == path);
+            if let Some(item) = listed {
+                ctx.pkg.remove_item(&item.id);
+                ctx.pkg_changed = true;
+                ctx.touched(ctx.opf_path.clone(), "cleanup");
+            }
+        }
+
+        // classes calibre moved the book's formatting to have to stay
+        let mut styled = HashSet::new();
+        for item in ctx.pkg.manifest_by_type("text/css") {
+            if let Ok(css) = std::fs::read_to_string(ctx.resolve(&item.href)) {
+                styled.extend(calibre::styled_classes(&css));
+            }
+        }
+        let mut markup = 0;
+        for item in ctx.pkg.manifest_by_type("application/xhtml+xml") {
+            let path = ctx.resolve(&item.href);
+            // unreadable documents are reported when converting
+            let Ok(doc) = ctx.document(&path) else {
+                continue;
+            };
+            let mut doc_styled = styled.clone();
+            for style in doc.find_all("style") {
+                doc_styled.extend(calibre::styled_classes(&verify::text_content(style)));
+            }
+            // documents without calibre markup are left unchanged
+            if !calibre::has_markup(doc, &doc_styled) {
+                continue;
+            }
+            markup += calibre::strip_markup(ctx.document_mut(&path)?, &doc_styled);
+            ctx.touched(path, "cleanup");
+        }
+        info!(
+            "Removed {} calibre metadata elements, {} bookmark files and {} calibre attributes and classes",
+            metadata, bookmarks, markup
+        );
+        return Ok(());
+    }
+
+    /// Lists the documents of the spine with their titles, taken from the
+    /// nav document or NCX, or else from their first heading
+    fn chapter_titles(&self, ctx: &mut ConversionContext) {
+        let mut labels = HashMap::new();
+        for toc in ctx.toc_items() {
+            let toc_href = href::normalize(&toc.href);
+            // unreadable tables of contents are reported later
+            let Ok(root) = ctx.document(&ctx.resolve(&toc.href)) else {
+                continue;
+            };
+            for (h, label) in toc_labels(root, &toc_href) {
+                labels.entry(h).or_insert(label);
+            }
+        }
+
+        let mut chapters = Vec::new();
+        for item in ctx.pkg.spine() {
+            let Some(doc) = ctx.pkg.item(&item.idref) else {
+                continue;
+            };
+            let href = href::normalize(&doc.href);
+            let title = labels.get(&href).cloned().or_else(|| {
+                let path = ctx.resolve(&doc.href);
+                return ctx.document(&path).ok().and_then(first_heading);
+            });
+            trace!("{}: {}", href, title.as_deref().unwrap_or("untitled"));
+            chapters.push(Chapter { href, title });
+        }
+        let titled = chapters.iter().filter(|c| c.title.is_some()).count();
+        debug!("Found titles for {} of {} chapters", titled, chapters.len());
+        ctx.chapters = chapters;
+    }
+
+    /// Removes the font files of the book, with the ones missing from the
+    /// manifest, and their manifest items. Their `@font-face` rules are
+    /// removed with the stylesheet fixes, and their `encryption.xml`
+    /// entries with the other stale ones
+    fn remove_fonts(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        if !self.remove_fonts {
+            return Ok(());
+        }
+        let mut fonts = BTreeSet::new();
+        for item in ctx.pkg.manifest() {
+            if is_font(&item.media_type, &item.href) {
+                fonts.insert(ctx.resolve(&item.href));
+                ctx.pkg.remove_item(&item.id);
+                ctx.pkg_changed = true;
+                ctx.touched(ctx.opf_path.clone(), "fonts");
+            }
+        }
+        fonts.extend(
+            walkdir::WalkDir::new(&self.working_dir)
+                .into_iter()
+                .filter_map(Result::ok)
+                .map(|e| return e.into_path())
+                .filter(|p| return p.is_file() && is_font("", &p.to_string_lossy())),
+        );
+        let mut removed = 0;
+        for path in fonts.into_iter().filter(|p| return p.is_file()) {
+            std::fs::remove_file(&path)?;
+            ctx.touched(path, "fonts");
+            removed += 1;
+        }
+        info!("Removed {} fonts", removed);
+        return Ok(());
+    }
+
+    /// Restores the fonts obfuscated as listed in `META-INF/encryption.xml`
+    /// and drops their entries, see [`obfuscation`]. Fonts that can't be
+    /// restored are removed, so devices use their own fonts instead of
+    /// failing on them
+    fn restore_fonts(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        let encryption = self.working_dir.join("META-INF").join("encryption.xml");
+        if !encryption.is_file() {
+            return Ok(());
+        }
+        let mut root = Element::parse(File::open(&encryption)?)?;
+        let fonts = obfuscation::obfuscated_fonts(&root);
+        if fonts.is_empty() {
+            return Ok(());
+        }
+        let identifiers = ctx.pkg.identifiers();
+        let mut handled = BTreeSet::new();
+        let mut restored = 0;
+        for (uri, algorithm) in fonts {
+            let path = self.working_dir.join(href::normalize(&uri));
+            // entries of missing files are pruned with the other stale ones
+            let Ok(mut data) = std::fs::read(&path) else {
+                continue;
+            };
+            let key = algorithm.key(&identifiers);
+            if key.is_some_and(|key| obfuscation::deobfuscate(&mut data, algorithm, &key)) {
+                std::fs::write(&path, &data)?;
+                restored += 1;
+            } else {
+                std::fs::remove_file(&path)?;
+                let items = ctx
+                    .pkg
+                    .manifest()
+                    .into_iter()
+                    .filter(|i| return ctx.resolve(&i.href) == path)
+                    .collect::<Vec<_>>();
+                for item in items {
+                    ctx.pkg.remove_item(&item.id);
+                    ctx.pkg_changed = true;
+                    ctx.touched(ctx.opf_path.clone(), "deobfuscate");
+                }
+                ctx.warn(
+                    WarningCode::ObfuscatedFont,
+                    format_args!(
+                        "Cannot restore the obfuscated font {}, no identifier of the book gives a key that works. Removed it",
+                        uri
+                    ),
+                );
+            }
+            ctx.touched(path, "deobfuscate");
+            handled.insert(uri);
+        }
+
+        prune_encryption(&mut root, |uri| return !handled.contains(uri));
+        if root.find_children("EncryptedData").next().is_none() {
+            std::fs::remove_file(&encryption)?;
+        } else {
+            std::fs::write(&encryption, serialize_xml(&root)?)?;
+        }
+        ctx.touched(encryption, "deobfuscate");
+        info!("Restored {} obfuscated fonts", restored);
+        return Ok(());
+    }
+
+    /// Drops `META-INF/signatures.xml`, since converting invalidates the
+    /// signatures, and removes entries for files that no longer exist from
+    /// `META-INF/encryption.xml`
+    fn clean_meta_inf(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        let meta_inf = self.working_dir.join("META-INF");
+        let signatures = meta_inf.join("signatures.xml");
+        if signatures.is_file() {
+            std::fs::remove_file(&signatures)?;
+            ctx.touched(signatures, "cleanup");
+            ctx.warn(
+                WarningCode::SignaturesRemoved,
+                format_args!(
+                    "Removed META-INF/signatures.xml, the book's signatures don't \
+                     match the converted content"
+                ),
+            );
+        }
+
+        let encryption = meta_inf.join("encryption.xml");
+        if !encryption.is_file() {
+            return Ok(());
+        }
+        let mut root = Element::parse(File::open(&encryption)?)?;
+        let removed = prune_encryption(&mut root, |uri| {
+            return self.working_dir.join(href::normalize(uri)).is_file();
+        });
+        if removed == 0 {
+            return Ok(());
+        }
+        debug!("Removed {} stale entries from encryption.xml", removed);
+        if root.find_children("EncryptedData").next().is_none() {
+            std::fs::remove_file(&encryption)?;
+        } else {
+            std::fs::write(&encryption, serialize_xml(&root)?)?;
+        }
+        ctx.touched(encryption, "cleanup");
+        return Ok(());
+    }
+
+    /// Finds the package document named by the `rootfile` in
+    /// `META-INF/container.xml`. Without a usable container file, falls
+    /// back to scanning the archive
+    fn find_opf_path(&self) -> Result<PathBuf, ConverterError> {
+        let container = self.working_dir.join("META-INF").join("container.xml");
+        let rootfile = File::open(&container)
+            .map_err(ConverterError::from)
+            .and_then(|f| return Ok(Element::parse(f)?))
+            .map(|root| opf::rootfile_path(&root));
+        match rootfile {
+            Ok(Some(full_path)) => {
+                let path = self.working_dir.join(href::normalize(&full_path));
+                if path.is_file() {
+                    return Ok(path);
+                }
+                self.warn(
+                    WarningCode::BadContainer,
+                    format_args!(
+                        "Package document {:?} named in container.xml is missing",
+                        full_path
+                    ),
+                );
+            }
+            Ok(None) => self.warn(
+                WarningCode::BadContainer,
+                format_args!("container.xml has no rootfile"),
+            ),
+            Err(e) => self.warn(
+                WarningCode::BadContainer,
+                format_args!("Cannot read META-INF/container.xml: {}", e),
+            ),
+        }
+        return self.scan_opf_path();
+    }
+
+    /// Finds the package document by scanning the extracted archive for
+    /// `.opf` files. Files with a `<package>` root element are preferred,
+    /// then the one closest to the archive root
+    fn scan_opf_path(&self) -> Result<PathBuf, ConverterError> {
+        let mut candidates = walkdir::WalkDir::new(&self.working_dir)
+            .sort_by_file_name()
+            .into_iter()
+            .flatten()
+            .filter(|e| {
+                e.file_type().is_file()
+                    && e.path()
+                        .extension()
+                        .is_some_and(|x| x.eq_ignore_ascii_case("opf"))
+            })
+            .map(|e| {
+                (
+                    !has_root_element(e.path(), "package"),
+                    e.depth(),
+                    e.into_path(),
+                )
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(not_pkg, depth, _)| (*not_pkg, *depth));
+
+        if candidates.len() > 1 {
+            self.warn(
+                WarningCode::SeveralPackages,
+                format_args!(
+                    "Found {} .opf files in epub archive, using {:?}",
+                    candidates.len(),
+                    candidates[0].2.strip_prefix(&self.working_dir).unwrap()
+                ),
+            );
+        }
+        return match candidates.into_iter().next() {
+            Some((_, _, p)) => Ok(p),
+            None => Err(xml_err!(
+                "Could not find a .opf package document in epub archive"
+            )),
+        };
+    }
+
+    /// Replaces the package document at `opf_path`, which failed to load
+    /// with `err`, by one listing the files of the book, see
+    /// [`validate::rebuild_package`]
+    fn rebuild_package(
+        &self,
+        opf_path: PathBuf,
+        err: ConverterError,
+    ) -> Result<ConversionContext, ConverterError> {
+        let raw = std::fs::read(&opf_path)?;
+        let pkg = validate::rebuild_package(
+            &self.working_dir,
+            &opf_path,
+            &String::from_utf8_lossy(&raw),
+        )?;
+        let mut ctx = ConversionContext::new(opf_path, pkg, &self.chapter.allowed);
+        ctx.warn(
+            WarningCode::PackageRebuilt,
+            format_args!(
+                "Cannot read the package document ({}). Rebuilt it from the files in \
+                 the book: chapters are in file name order and metadata other than the \
+                 title and language is lost",
+                err
+            ),
+        );
+        ctx.pkg_changed = true;
+        ctx.touched(ctx.opf_path.clone(), "rebuild");
+        return Ok(ctx);
+    }
+
+    /// Makes sure the manifest lists content documents. When none is
+    /// declared as XHTML, manifest items are picked by file extension
+    /// instead, for books that declare them as `text/html` or not at all
+    fn check_content_docs(&self, ctx: &mut ConversionContext) -> Result<(), ConverterError> {
+        if !ctx.pkg.manifest_by_type("application/xhtml+xml").is_empty() {
+            return Ok(());
+        }
+        let guessed = ctx
+            .pkg
+            .manifest()
+            .into_iter()
+            .filter(|i| {
+                let path = ctx.resolve(&i.href);
+                if !href::has_content_extension(&i.href) || i.id.is_empty() || !path.is_file() {
+                    return false;
+                }
+                // .xml is used for other data as well
+                let is_xml = path
+                    .extension()
+                    .is_some_and(|e| e.eq_ignore_ascii_case("xml"));
+                return !is_xml || has_root_element(&path, "html");
+            })
+            .collect::<Vec<_>>();
+        if guessed.is_empty() {
+            return Err(ConverterError::NoContentDocuments);
+        }
+
+        for item in &guessed {
+            ctx.pkg.set_media_type(&item.id, "application/xhtml+xml");
+        }
+        ctx.pkg_changed = true;
+        ctx.touched(ctx.opf_path.clone(), "media-type");
+        ctx.warn(
+            WarningCode::GuessedContentDocuments,
+            format_args!(
+                "The manifest declares no XHTML content documents, treating {} files \
+                 as XHTML by their extension",
+                guessed.len()
+            ),
+        );
+        return Ok(());
+    }
+
+    /// Counts the content documents that already have kobospans, returning
+    /// that and the number of content documents
+    fn count_kepub_docs(&self, ctx: &mut ConversionContext) -> (usize, usize) {
+        let items = ctx.pkg.manifest_by_type("application/xhtml+xml");
+        let mut spanned = 0;
+        for item in &items {
+            // unreadable documents are reported when converting
+            let path = ctx.resolve(&item.href);
+            if ctx.document(&path).is_ok_and(has_kobo_spans) {
+                spanned += 1;
+            }
+        }
+        return (spanned, items.len());
+    }
+
+    /// Adds kobospans to every content document. With `strip_existing`,
+    /// kobospans and wrapper divs already in them are removed first
+    fn convert_html(
+        &self,
+        ctx: &mut ConversionContext,
+        strip_existing: bool,
+    ) -> Result<(), ConverterError> {
+        let now = std::time::Instant::now();
+        let items = ctx.pkg.manifest_by_type("application/xhtml+xml");
+
+        // Items the spine marks as outside the reading order, like pop-up
+        // notes or answer keys. They are still converted
+        let non_linear = ctx
+            .pkg
+            .spine()
+            .into_iter()
+            .filter(|i| !i.linear)
+            .map(|i| i.idref)
+            .collect::<HashSet<_>>();
+
+        // Some generators declare the same file more than once, which would
+        // convert it twice and nest the spans
+        let mut seen = HashMap::new();
+        let mut hrefs = Vec::new();
+        for item in &items {
+            let href = href::normalize(&item.href);
+            let id = item.id.as_str();
+            match seen.get(&href) {
+                Some(first_id) => ctx.warn(
+                    WarningCode::DuplicateManifestItem,
+                    format_args!(
+                        "Manifest items '{}' and '{}' both point to {}, converting it once",
+                        first_id, id, href
+                    ),
+                ),
+                None => {
+                    seen.insert(href.clone(), id);
+                    hrefs.push((href, !non_linear.contains(id)));
+                }
+            }
+        }
+        debug!("Found {} content documents in manifest", hrefs.len());
+
+        let mut non_linear_docs = Vec::new();
+        let mut span_map = SpanMap::default();
+        for (h, linear) in hrefs {
+            if !linear {
+                debug!("{}: not in the linear reading order", h);
+                non_linear_docs.push(h.clone());
+            }
+            // a panic only leaves the document half converted, and it is
+            // dropped like one that failed
+            let res = errors::catch_panic(|| self.convert_html_file(ctx, &h, strip_existing));
+            let stats = match res {
+                Ok(stats) => stats,
+                Err(e) if self.lenient || matches!(e, ConverterError::Panic(_)) => {
+                    ctx.warn(
+                        WarningCode::ChapterSkipped,
+                        format_args!("{}: {}, copying it unchanged", h, e),
+                    );
+                    let path = ctx.opf_dir.join(&h);
+                    ctx.docs.remove(&path);
+                    ctx.changed_docs.remove(&path);
+                    ctx.transforms.remove(&path);
+                    ctx.unconverted.insert(path, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            ctx.stats.normalized_chars += stats.normalized_chars;
+            ctx.stats.long_texts += stats.long_texts;
+            ctx.stats.word_breaks += stats.word_breaks;
+            ctx.stats.split_words += stats.split_words;
+            ctx.stats.smartened += stats.smartened;
+            ctx.stats.replaced += stats.replaced;
+            ctx.stats.span_count += stats.span_count;
+            ctx.stats.sentence_lengths.merge(&stats.sentence_lengths);
+            ctx.stats
+                .spans_per_paragraph
+                .merge(&stats.spans_per_paragraph);
+            ctx.converted_docs += 1;
+            if self.span_map.is_some() {
+                span_map.chapters.push(ChapterSpans {
+                    file: self.internal_name(&ctx.opf_dir.join(&h)),
+                    title: ctx.title(&h).map(String::from),
+                    spans: stats.spans,
+                });
+            }
+        }
+
+        if let Some(path) = &self.span_map {
+            span_map.save(path)?;
+            debug!("Wrote span map to {:?}", path);
+        }
+
+        if !non_linear_docs.is_empty() {
+            info!(
+                "Converted {} non-linear documents: {}",
+                non_linear_docs.len(),
+                non_linear_docs.join(", ")
+            );
+        }
+        let FileStats {
+            long_texts,
+            word_breaks,
+            split_words,
+            ..
+        } = ctx.stats;
+        if long_texts > 0 {
+            ctx.warn(
+                WarningCode::LongText,
+                format_args!(
+                    "Found {} paragraphs or sentences longer than {} characters",
+                    long_texts, self.chapter.long_text_warn
+                ),
+            );
+        }
+
+        if word_breaks > 0 && self.chapter.strip_word_breaks {
+            info!(
+                "Removed {} soft hyphens and zero-width characters from inside words",
+                word_breaks
+            );
+        } else if word_breaks > 0 {
+            ctx.warn(
+                WarningCode::WordBreaks,
+                format_args!(
+                    "Found {} soft hyphens or zero-width characters inside words, \
+                     which break dictionary lookup",
+                    word_breaks
+                ),
+            );
+        }
+        if split_words > 0 {
+            ctx.warn(
+                WarningCode::SplitWords,
+                format_args!(
+                    "Found {} words split across kobospans by inline markup, which \
+                     break dictionary lookup",
+                    split_words
+                ),
+            );
+        }
+
+        if self.chapter.normalization != Normalization::None {
+            info!(
+                "Normalized {} characters to {}",
+                ctx.stats.normalized_chars, self.chapter.normalization
+            );
+        }
+        if !self.chapter.replacements.is_empty() {
+            info!("Replaced {} matches", ctx.stats.replaced);
+        }
+        if self.chapter.smarten_punctuation {
+            info!(
+                "Replaced {} quotes, dashes and ellipses with typographic ones",
+                ctx.stats.smartened
+            );
+        }
+        // for tuning the segmentation
+        for (name, histogram) in [
+            (
+                "Sentence lengths, in characters",
+                &ctx.stats.sentence_lengths,
+            ),
+            ("Sentences per paragraph", &ctx.stats.spans_per_paragraph),
+        ] {
+            if !histogram.0.is_empty() {
+                debug!("{}:", name);
+                for line in histogram.lines() {
+                    debug!("{}", line);
+                }
+            }
+        }
+        info!("{}ms", now.elapsed().as_millis());
+        return Ok(());
+    }
+
+    fn convert_html_file(
+        &self,
+        ctx: &mut ConversionContext,
+        rel_path: &str,
+        strip_existing: bool,
+    ) -> Result<FileStats, ConverterError> {
+        info!("Converting {}", rel_path);
+        let now = std::time::Instant::now();
+        let fpath = ctx.opf_dir.join(rel_path);
+
+        // new kobospans are numbered afresh, which would break the links and
+        // media overlays made for the kepub
+        let linked_spans = [&ctx.overlay.targets, &ctx.anchors]
+            .iter()
+            .filter_map(|t| return t.get(rel_path))
+            .flatten()
+            .any(|id| id.starts_with("kobo."));
+        if strip_existing && linked_spans {
+            info!(
+                "{}: keeping its kobospans, links or media overlays point to them",
+                rel_path
+            );
+        }
+        let strip_existing = strip_existing && !linked_spans;
+
+        let size = std::fs::metadata(&fpath).map_or(0, |m| m.len());
+        let too_big = self.max_span_file_size.is_some_and(|max| size > max);
+        if too_big && self.chapter.spans {
+            ctx.warn(
+                WarningCode::SpanFileTooLarge,
+                format_args!(
+                    "{}: {} bytes is over the span file size limit, not adding kobospans",
+                    rel_path, size
+                ),
+            );
+        }
+        let spans = self.chapter.spans && !too_big;
+
+        let book_lang = ctx.pkg.metadata("language").into_iter().next();
+        let layout = ctx.layout;
+        let allowed = ctx.allowed.clone();
+        let root = ctx.document_mut(&fpath)?;
+        if strip_existing {
+            verify::strip_kobo(root);
+        }
+        let stats = transform_chapter(
+            root,
+            rel_path,
+            &self.chapter,
+            spans,
+            layout,
+            &allowed,
+            book_lang.as_deref(),
+        )?;
+
+        let applied = [
+            (strip_existing, "respan"),
+            (stats.wrapped, "wrapper"),
+            (self.chapter.style.is_some() && !layout.fixed, "kobo-style"),
+            (spans, "spans"),
+            (stats.normalized_chars > 0, "normalize"),
+            (stats.replaced > 0, "replace"),
+            (stats.smartened > 0, "punctuation"),
+            (
+                stats.word_breaks > 0 && self.chapter.strip_word_breaks,
+                "word-breaks",
+            ),
+        ];
+        for (_, t) in applied.into_iter().filter(|(applied, _)| *applied) {
+            ctx.touched(fpath.clone(), t);
+        }
+
+        debug!("{}: done in {}ms", rel_path, now.elapsed().as_millis());
+        return Ok(stats);
+    }
+}
+
+/// Serializes `root` with consistent LF line endings and no trailing
+/// whitespace outside of `<pre>` and `xml:space="preserve"` regions, which
+/// aren't indented either
+pub(crate) fn serialize_xml(root: &Element) -> Result<String, ConverterError> {
+    let mut buf = Vec::new();
+    space::for_output(root)
+        .write_with_config(&mut buf, EmitterConfig::new().perform_indent(true))?;
+    return Ok(text::normalize_lines(&String::from_utf8_lossy(&buf)));
+}
+
+/// Wraps the content of the `<body>` of a content document in the
+/// `book-columns` and `book-inner` divs, adds the kobo style and, with
+/// `spans`, adds the kobospans. Text is split by the rules of its language,
+/// from `xml:lang` or `lang` attributes or else `book_lang`. Warnings with
+/// a code in `allowed` are only logged at debug level
+fn transform_chapter(
+    root: &mut Element,
+    rel_path: &str,
+    options: &ChapterOptions,
+    spans: bool,
+    layout: Layout,
+    allowed: &BTreeSet<WarningCode>,
+    book_lang: Option<&str>,
+) -> Result<FileStats, ConverterError> {
+    // fixed-layout pages are styled for their size, the wrapper and the
+    // kobo style would move their content
+    let wrapper = options.wrapper && !layout.fixed;
+    if let Some(css) = options.style.as_ref().filter(|_| !layout.fixed) {
+        add_style(root, css);
+    }
+    let doc_lang = element_lang(root, book_lang.map(String::from));
+
+    let body = match root.get_mut_child("body") {
+        Some(e) => e,
+        None => return Err(xml_err!("Cannot find <body> in {}", rel_path)),
+    };
+
+    // a body wrapped by an earlier conversion keeps its wrappers as they are
+    let wrapped = body
+        .find_where(|e| {
+            e.name == "div"
+                && e.attributes
+                    .get("id")
+                    .is_some_and(|id| id == "book-columns" || id == "book-inner")
+        })
+        .next()
+        .is_some();
+    if wrapped {
+        debug!("{}: already has wrapper divs, not adding them", rel_path);
+    } else if wrapper {
+        let bk_inn = El::new("div")
+            .id("book-inner")
+            .children(body.children.drain(..));
+        body.children
+            .push(El::new("div").id("book-columns").child(bk_inn).into());
+    }
+
+    let replaced = replace_text(body, &options.replacements);
+    // curly quotes are drawn sideways in vertical text
+    let smartened = match options.smarten_punctuation && !layout.vertical {
+        true => smarten_punctuation(body, &mut None),
+        false => 0,
+    };
+    let mut stats = match spans {
+        true => convert_kobo_spans(
+            rel_path,
+            body,
+            options,
+            layout.vertical,
+            allowed,
+            element_lang(body, doc_lang),
+        ),
+        false => FileStats::default(),
+    };
+    stats.wrapped = !wrapped && wrapper;
+    stats.smartened = smartened;
+    stats.replaced = replaced;
+    return Ok(stats);
+}
+
+/// Runs `replacements` over the text of `elem`, except in scripts and
+/// styles. Text left empty is removed. Returns how many matches were
+/// replaced
+pub(crate) fn replace_text(elem: &mut Element, replacements: &[Replacement]) -> usize {
+    if replacements.is_empty() {
+        return 0;
+    }
+    let mut replaced = 0;
+    for child in elem.children.iter_mut() {
+        match child {
+            XMLNode::Text(t) => {
+                for r in replacements {
+                    let (out, n) = r.apply(t);
+                    if n > 0 {
+                        *t = out;
+                        replaced += n;
+                    }
+                }
+            }
+            XMLNode::Element(e) if !["script", "style"].contains(&e.name.as_str()) => {
+                replaced += replace_text(e, replacements);
+            }
+            _ => {}
+        }
+    }
+    elem.children
+        .retain(|c| !matches!(c, XMLNode::Text(t) if t.is_empty()));
+    return replaced;
+}
+
+/// Elements whose text is left out of punctuation smartening
+const LITERAL_TEXT: [&str; 6] = ["pre", "code", "kbd", "samp", "script", "style"];
+
+/// Elements other than [`ElementKind::Block`] ones that quotes don't run
+/// across
+const TEXT_BREAKS: [&str; 9] = ["div", "li", "dt", "dd", "td", "th", "caption", "br", "hr"];
+
+/// Smartens the punctuation of the text in `elem`, see
+/// [`text::smarten_punctuation`]. `prev` is the last character of text
+/// before it, None at the start of a block. Returns how many replacements
+/// were made
+pub(crate) fn smarten_punctuation(elem: &mut Element, prev: &mut Option<char>) -> usize {
+    let mut replaced = 0;
+    for child in elem.children.iter_mut() {
+        match child {
+            XMLNode::Text(t) => {
+                let (smart, n) = text::smarten_punctuation(t, *prev);
+                if n > 0 {
+                    *t = smart;
+                    replaced += n;
+                }
+                *prev = t.chars().last().or(*prev);
+            }
+            XMLNode::Element(e) => {
+                let name = e.name.to_ascii_lowercase();
+                let breaks =
+                    element_kind(e) == ElementKind::Block || TEXT_BREAKS.contains(&name.as_str());
+                if breaks {
+                    *prev = None;
+                }
+                if LITERAL_TEXT.contains(&name.as_str()) || element_kind(e) == ElementKind::Opaque {
+                    *prev = verify::text_content(e).chars().last().or(*prev);
+                } else {
+                    replaced += smarten_punctuation(e, prev);
+                }
+                if breaks {
+                    *prev = None;
+                }
+            }
+            _ => {}
+        }
+    }
+    return replaced;
+}
+
+/// Adds a `<style>` element with `css` to the head of `root`, creating the
+/// head if needed, in place of the one a previous conversion added
+fn add_style(root: &mut Element, css: &str) {
+    if root.get_child("head").is_none() {
+        root.children.insert(0, El::new("head").into());
+    }
+    let Some(head) = root.get_mut_child("head") else {
+        return;
+    };
+    head.children.retain(|c| {
+        return !c.as_element().is_some_and(|e| {
+            return e.name == "style"
+                && e.attributes
+                    .get("class")
+                    .is_some_and(|c| c == KOBO_STYLE_CLASS);
+        });
+    });
+    let style = El::new("style")
+        .attr("type", "text/css")
+        .class(KOBO_STYLE_CLASS)
+        .text(css);
+    head.children.push(style.into());
+}
+
+/// Applies `fix` to the `<style>` elements of a content document, except
+/// the one with the kobo style. Returns the total of what `fix` returns
+fn fix_style_elements(
+    ctx: &mut ConversionContext,
+    path: &Path,
+    fix: fn(&str) -> (String, usize),
+) -> Result<usize, ConverterError> {
+    // documents that can't be read were reported when converting
+    let Ok(doc) = ctx.document(path) else {
+        return Ok(0);
+    };
+    let is_fixed = |e: &&Element| {
+        return e.name == "style"
+            && e.attributes
+                .get("class")
+                .is_none_or(|c| c != KOBO_STYLE_CLASS);
+    };
+    if doc.find_where(is_fixed).next().is_none() {
+        return Ok(0);
+    }
+
+    let mut fixed = 0;
+    ctx.document_mut(path)?.walk_mut(|e, _| {
+        if !is_fixed(&&*e) {
+            return Walk::Descend;
+        }
+        for c in e.children.iter_mut() {
+            if let XMLNode::Text(t) | XMLNode::CData(t) = c {
+                let (out, n) = fix(t);
+                *t = out;
+                fixed += n;
+            }
+        }
+        return Walk::Skip;
+    });
+    return Ok(fixed);
+}
+
+/// Applies [`css::unconstrain_declarations`] to the `style` attributes of
+/// the `html` and `body` of a content document
+fn unconstrain_style_attributes(
+    ctx: &mut ConversionContext,
+    path: &Path,
+) -> Result<usize, ConverterError> {
+    let Ok(doc) = ctx.document(path) else {
+        return Ok(0);
+    };
+    let styled = |e: &Element| return e.attributes.contains_key("style");
+    if !styled(doc) && !doc.get_child("body").is_some_and(styled) {
+        return Ok(0);
+    }
+
+    let unconstrain = |e: &mut Element| {
+        let Some(style) = e.attributes.get("style") else {
+            return 0;
+        };
+        let (out, n) = css::unconstrain_declarations(style);
+        if n > 0 && out.is_empty() {
+            e.attributes.remove("style");
+        } else if n > 0 {
+            e.attributes.insert("style".to_string(), out);
+        }
+        return n;
+    };
+    let root = ctx.document_mut(path)?;
+    let mut removed = unconstrain(root);
+    if let Some(body) = root.get_mut_child("body") {
+        removed += unconstrain(body);
+    }
+    return Ok(removed);
+}
+
+/// Elements that show something even without any text in them
+const VISIBLE_ELEMENTS: [&str; 12] = [
+    "img", "image", "svg", "math", "video", "audio", "object", "embed", "iframe", "canvas", "hr",
+    "input",
+];
+
+/// Returns true if the `<body>` of `root` has no text and nothing else that
+/// would show on screen
+fn is_blank(root: &Element) -> bool {
+    let Some(body) = root.get_child("body") else {
+        return false;
+    };
+    let has_text = verify::text_content(body)
+        .chars()
+        .any(|c| !c.is_whitespace());
+    let has_visible = body
+        .find_where(|e| VISIBLE_ELEMENTS.contains(&e.name.to_ascii_lowercase().as_str()))
+        .next()
+        .is_some();
+    return !has_text && !has_visible;
+}
+
+/// Whether the head of a content document has a `<meta name="viewport">`
+fn has_viewport(root: &Element) -> bool {
+    return root.get_child("head").is_some_and(|head| {
+        return head.find_children("meta").any(|m| {
+            return m
+                .attributes
+                .get("name")
+                .is_some_and(|n| n.eq_ignore_ascii_case("viewport"));
+        });
+    });
+}
+
+/// Removes the entries of a navigation document or NCX, at `toc_href`, that
+/// point to one of `hrefs` and have no entries below them. Returns how many
+/// were removed
+fn prune_toc(elem: &mut Element, toc_href: &str, hrefs: &HashSet<String>) -> usize {
+    let points_to_blank = |e: &Element| {
+        let target = match e.name.as_str() {
+            "li" => e.get_child("a").and_then(|a| a.attributes.get("href")),
+            "navPoint" => e.get_child("content").and_then(|c| c.attributes.get("src")),
+            _ => return false,
+        };
+        let nested = e.get_child("ol").is_some() || e.get_child("navPoint").is_some();
+        return !nested && target.is_some_and(|t| hrefs.contains(&href::resolve(toc_href, t)));
+    };
+
+    let before = elem.children.len();
+    elem.children
+        .retain(|c| !matches!(c, XMLNode::Element(e) if points_to_blank(e)));
+    let mut removed = before - elem.children.len();
+    for c in elem.children.iter_mut() {
+        if let XMLNode::Element(e) = c {
+            removed += prune_toc(e, toc_href, hrefs);
+        }
+    }
+    return removed;
+}
+
+fn has_kobo_spans(elem: &Element) -> bool {
+    return elem
+        .find_where(|n| {
+            n.attributes
+                .get("class")
+                .is_some_and(|cl| cl.contains("kobospan"))
+        })
+        .next()
+        .is_some();
+}
+
+/// Language of the text in `elem`, given that of its parent. xmltree keeps
+/// `xml:lang` and `lang` under the same name, and an empty value means the
+/// language is unknown
+fn element_lang(elem: &Element, inherited: Option<String>) -> Option<String> {
+    return match elem.attributes.get("lang") {
+        Some(lang) if lang.is_empty() => None,
+        Some(lang) => Some(lang.clone()),
+        None => inherited,
+    };
+}
+
+/// Convert paragraphs and sentences into kobospans, in text of language
+/// `lang` unless tagged otherwise
+fn convert_kobo_spans(
+    rel_path: &str,
+    root_elem: &mut Element,
+    options: &ChapterOptions,
+    vertical: bool,
+    allowed: &BTreeSet<WarningCode>,
+    lang: Option<String>,
+) -> FileStats {
+    if has_kobo_spans(root_elem) {
+        info!("kobo spans found, not converting html content");
+        // kobo spans exist, don't do anything
+        return FileStats::default();
+    }
+
+    let mut spans = KoboSpans::new(rel_path, options);
+    spans.allowed = allowed;
+    if vertical {
+        // full-width letters and digits are upright in vertical text
+        spans.normalization = options.normalization.canonical();
+    }
+    spans.langs.push(lang);
+    spans.start_rewrite(root_elem);
+    root_elem.rewrite(&mut spans);
+    spans.check_para();
+    debug!(
+        "{}: wrapped text in {} kobo paragraphs",
+        rel_path,
+        spans.counter.para()
+    );
+    if spans.stats.normalized_chars > 0 {
+        debug!(
+            "{}: normalized {} characters",
+            rel_path, spans.stats.normalized_chars
+        );
+    }
+    return spans.stats;
+}
+
+/// How the span pass treats an element
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ElementKind {
+    /// Starts a new kobo paragraph
+    Block,
+    /// Wrapped in a span of its own, as a paragraph of its own
+    Image,
+    /// Kept as it is, with nothing inside wrapped in spans
+    Opaque,
+    /// Spans inside continue the current paragraph
+    Inline,
+}
+
+/// Elements that aren't [`ElementKind::Inline`], by local name
+const ELEMENT_KINDS: &[(&str, ElementKind)] = &[
+    ("p", ElementKind::Block),
+    ("h1", ElementKind::Block),
+    ("h2", ElementKind::Block),
+    ("h3", ElementKind::Block),
+    ("h4", ElementKind::Block),
+    ("h5", ElementKind::Block),
+    ("h6", ElementKind::Block),
+    ("ol", ElementKind::Block),
+    ("ul", ElementKind::Block),
+    ("table", ElementKind::Block),
+    ("section", ElementKind::Block),
+    ("aside", ElementKind::Block),
+    ("blockquote", ElementKind::Block),
+    ("figure", ElementKind::Block),
+    // captions become sentence spans of their own, apart from the image
+    ("figcaption", ElementKind::Block),
+    ("img", ElementKind::Image),
+    // a span inside <picture> isn't valid, so the whole element is wrapped
+    // and the <source>s and <img> inside are left alone
+    ("picture", ElementKind::Image),
+    ("math", ElementKind::Opaque),
+    ("svg", ElementKind::Opaque),
+    // code and scripts break when their text is split
+    ("pre", ElementKind::Opaque),
+    ("code", ElementKind::Opaque),
+    ("script", ElementKind::Opaque),
+    ("style", ElementKind::Opaque),
+];
+
+/// Classifies an element by its local name, ignoring case and any
+/// namespace prefix
+fn element_kind(elem: &Element) -> ElementKind {
+    return ELEMENT_KINDS
+        .iter()
+        .find(|(name, _)| elem.name.eq_ignore_ascii_case(name))
+        .map_or(ElementKind::Inline, |(_, kind)| *kind);
+}
+
+/// Rewriter that wraps sentences in kobospans, numbered by a
+/// [`SpanCounter`]
+struct KoboSpans<'a> {
+    rel_path: &'a str,
+    options: &'a ChapterOptions,
+    /// Usually the normalization and allowed warnings of the options
+    normalization: Normalization,
+    allowed: &'a BTreeSet<WarningCode>,
+    stats: FileStats,
+    counter: SpanCounter,
+    force_new_para: bool,
+    /// Characters of text seen so far, used to locate long runs
+    offset: usize,
+    /// Offset and length of the text in the current paragraph
+    para_start: usize,
+    para_len: usize,
+    /// Set once something in the current paragraph was reported
+    para_warned: bool,
+    /// Last character of text seen in the current paragraph
+    last_char: Option<char>,
+    /// Whether whitespace is significant inside each element being rewritten
+    preserve: Vec<bool>,
+    /// Language of the text in each element being rewritten
+    langs: Vec<Option<String>>,
+    /// For each element being rewritten, which of its children are inline
+    /// elements and how many of them were rewritten so far
+    siblings: Vec<(Vec<bool>, usize)>,
+    /// Depth of the inline element after punctuation that got a span of its
+    /// own, with the counter at that span. See [`KoboSpans::join_leading`]
+    leading: Option<(usize, SpanCounter)>,
+}
+
+impl<'a> KoboSpans<'a> {
+    fn new(rel_path: &'a str, options: &'a ChapterOptions) -> Self {
+        return Self {
+            rel_path,
+            options,
+            normalization: options.normalization,
+            allowed: &options.allowed,
+            stats: FileStats::default(),
+            counter: SpanCounter::new(),
+            force_new_para: false,
+            offset: 0,
+            para_start: 0,
+            para_len: 0,
+            para_warned: false,
+            last_char: None,
+            preserve: Vec::new(),
+            langs: Vec::new(),
+            siblings: Vec::new(),
+            leading: None,
+        };
+    }
+
+    fn is_too_long(&self, len: usize) -> bool {
+        let limit = self.options.long_text_warn;
+        return limit > 0 && len > limit;
+    }
+
+    /// Logs a paragraph or sentence that is too long. The book-wide count
+    /// is reported by the converter
+    fn warn_long(&self, args: std::fmt::Arguments) {
+        match self.allowed.contains(&WarningCode::LongText) {
+            true => debug!("{}: {}", WarningCode::LongText, args),
+            false => warning!("{}: {}", WarningCode::LongText, args),
+        }
+    }
+
+    /// Reports the current paragraph if it is too long, and counts its
+    /// sentences
+    fn check_para(&mut self) {
+        if self.counter.sentence() > 0 {
+            self.stats.spans_per_paragraph.add(self.counter.sentence());
+        }
+        if !self.para_warned && self.is_too_long(self.para_len) {
+            self.warn_long(format_args!(
+                "{}: paragraph kobo.{} is {} characters long (at text offset {})",
+                self.rel_path,
+                self.counter.para(),
+                self.para_len,
+                self.para_start
+            ));
+            self.stats.long_texts += 1;
+        }
+    }
+
+    /// Records the span about to be added, which ends at the current offset
+    fn record_span(&mut self, text: &str) {
+        self.stats.span_count += 1;
+        if !self.options.record_spans {
+            return;
+        }
+        self.stats.spans.push(SpanRecord {
+            id: self.counter.id(),
+            start: self.offset - text.chars().count(),
+            end: self.offset,
+            text: text.to_string(),
+        });
+    }
+
+    /// Adds punctuation after an inline element to the span of the last
+    /// sentence, if that span is the last node of `out` or the only span in
+    /// it. The span is moved out to wrap the node. Returns whether it did
+    fn join_trailing(&mut self, t: &str, out: &mut Vec<XMLNode>) -> bool {
+        let id = self.counter.id();
+        let Some(XMLNode::Element(last)) = out.last() else {
+            return false;
+        };
+        let is_span = is_kobo_span(last) && last.attributes.get("id") == Some(&id);
+        if !is_span {
+            if only_kobo_span(last) != Some(id.as_str()) {
+                return false;
+            }
+            let Some(XMLNode::Element(mut last)) = out.pop() else {
+                return false;
+            };
+            verify::strip_kobo(&mut last);
+            let mut span = make_span(&id, None);
+            span.children.push(XMLNode::Element(last));
+            out.push(XMLNode::Element(span));
+        }
+        if let Some(XMLNode::Element(span)) = out.last_mut() {
+            span.children.push(XMLNode::Text(t.to_string()));
+        }
+
+        let len = t.chars().count();
+        self.para_len += len;
+        if let Some(last) = self.stats.spans.last_mut() {
+            last.text.push_str(t);
+            last.end = self.offset + len;
+        }
+        self.offset += len;
+        return true;
+    }
+
+    /// Moves `elem`, the inline element after punctuation that got a span of
+    /// its own at `counter`, into that span if its only span is the one of
+    /// the next sentence. The two spans become one, as if the punctuation
+    /// had been part of that sentence
+    fn join_leading(&mut self, mut elem: Element, counter: SpanCounter, out: &mut Vec<XMLNode>) {
+        let mut next = counter;
+        let next_id = next.next_sentence();
+        if self.counter != next || only_kobo_span(&elem) != Some(next_id.as_str()) {
+            out.push(XMLNode::Element(elem));
+            return;
+        }
+        let Some(XMLNode::Element(span)) = out.last_mut() else {
+            out.push(XMLNode::Element(elem));
+            return;
+        };
+        verify::strip_kobo(&mut elem);
+        span.children.push(XMLNode::Element(elem));
+        self.counter = counter;
+        self.stats.span_count -= 1;
+        if self.options.record_spans {
+            if let Some(joined) = self.stats.spans.pop() {
+                let last = self.stats.spans.last_mut().unwrap();
+                last.text.push_str(&joined.text);
+                last.end = joined.end;
+            }
+        }
+    }
+
+    /// Moves on to the next child of the element being rewritten, returning
+    /// whether the children before and after it are inline elements
+    fn next_child(&mut self) -> (bool, bool) {
+        let Some((elements, next)) = self.siblings.last_mut() else {
+            return (false, false);
+        };
+        let i = *next;
+        *next += 1;
+        let before = i > 0 && elements[i - 1];
+        return (before, elements.get(i + 1).copied().unwrap_or(false));
+    }
+
+    fn start_rewrite(&mut self, elem: &Element) {
+        let elements = elem
+            .children
+            .iter()
+            .map(|c| return matches!(c, XMLNode::Element(e) if element_kind(e) == ElementKind::Inline))
+            .collect();
+        self.siblings.push((elements, 0));
+    }
+
+    fn start_para(&mut self) {
+        self.check_para();
+        self.counter.next_para();
+        self.force_new_para = false;
+        self.para_start = self.offset;
+        self.para_len = 0;
+        self.para_warned = false;
+        self.last_char = None;
+    }
+}
+
+impl Rewriter for KoboSpans<'_> {
+    fn enter(&mut self, elem: &mut Element) -> Walk {
+        self.next_child();
+        self.start_rewrite(elem);
+        let lang = element_lang(elem, self.langs.last().cloned().flatten());
+        self.langs.push(lang);
+        let inherited = self.preserve.last().copied().unwrap_or(false);
+        let preserve = space::preserves_space(elem, inherited);
+        self.preserve.push(preserve);
+        // a region where whitespace is significant is a paragraph of its own
+        if preserve && !inherited {
+            self.force_new_para = true;
+        }
+        match element_kind(elem) {
+            ElementKind::Image | ElementKind::Opaque => return Walk::Skip,
+            ElementKind::Block => self.force_new_para = true,
+            ElementKind::Inline => {}
+        }
+        return Walk::Descend;
+    }
+
+    fn leave(&mut self, elem: Element, out: &mut Vec<XMLNode>) {
+        self.preserve.pop();
+        self.siblings.pop();
+        self.langs.pop();
+        let depth = self.siblings.len();
+        if let Some((_, counter)) = self.leading.take_if(|(d, _)| return *d == depth) {
+            self.join_leading(elem, counter, out);
+            return;
+        }
+        match element_kind(&elem) {
+            // images get wrapped in their own para. One inside a link stays
+            // inside it, so the link keeps working
+            ElementKind::Image => {
+                self.start_para();
+                self.record_span("");
+
+                let mut s = make_span(&self.counter.id(), None);
+                s.children.push(XMLNode::Element(elem));
+                out.push(XMLNode::Element(s));
+            }
+            // opaque elements, like equations and vector illustrations, are
+            // kept as they are
+            _ => out.push(XMLNode::Element(elem)),
+        }
+    }
+
+    fn node(&mut self, parent: &Element, node: XMLNode, out: &mut Vec<XMLNode>) {
+        let (after_element, before_element) = self.next_child();
+        let t = match node {
+            XMLNode::Text(t) => t,
+            // comments, CDATA sections and processing instructions are kept
+            // as they are
+            other => {
+                out.push(other);
+                return;
+            }
+        };
+
+        // wrap each sentence in a span (don't wrap whitespace unless it is
+        // directly under a P tag [TODO: are there any other cases we wrap
+        // whitespace? ... I need to find a kepub like this]) and add it
+        // back to the parent.
+        let (t, changed) = self.normalization.normalize(&t);
+        self.stats.normalized_chars += changed;
+        let (stripped, breaks) = text::strip_word_breaks(&t);
+        self.stats.word_breaks += breaks;
+        let t = match self.options.strip_word_breaks {
+            true => stripped,
+            false => t,
+        };
+
+        // text that continues a word from the previous node ends up in
+        // another span, unless a new paragraph starts here anyway
+        if !self.force_new_para
+            && segment::is_word_char(self.last_char)
+            && segment::is_word_char(t.chars().next())
+        {
+            debug!(
+                "{}: word split across {} and the span after it",
+                self.rel_path,
+                self.counter.id()
+            );
+            self.stats.split_words += 1;
+        }
+
+        // text where whitespace is significant isn't split, so lines stay
+        // together, and whitespace-only text is left as it is
+        let preserve = self.preserve.last().copied().unwrap_or(false);
+        if preserve && t.trim().is_empty() {
+            self.offset += t.chars().count();
+            self.last_char = t.chars().last().or(self.last_char);
+            out.push(XMLNode::Text(t));
+            return;
+        }
+        // punctuation next to inline elements, like a dash between two links
+        // or the quotes around an emphasized phrase, goes into the span of
+        // the sentence beside it instead of getting an odd span of its own
+        if !preserve && segment::is_punctuation_only(&t) {
+            let trailing = after_element && self.counter.sentence() > 0 && !self.force_new_para;
+            if trailing && self.join_trailing(&t, out) {
+                self.last_char = t.chars().last().or(self.last_char);
+                return;
+            }
+            if before_element {
+                if self.force_new_para || self.counter.para() == 0 {
+                    self.start_para();
+                }
+                let id = self.counter.next_sentence();
+                let len = t.chars().count();
+                self.offset += len;
+                self.para_len += len;
+                self.record_span(&t);
+                self.leading = Some((self.siblings.len(), self.counter));
+                self.last_char = t.chars().last().or(self.last_char);
+                out.push(XMLNode::Element(make_span(&id, Some(&t))));
+                return;
+            }
+        }
+        let sentences = match preserve {
+            true => vec![t.clone()],
+            false => {
+                let sentences = self
+                    .options
+                    .segmenter
+                    .segment_in(&t, self.langs.last().and_then(|l| l.as_deref()))
+                    .into_iter()
+                    .flat_map(|s| segment::chunk(&s, self.options.chunk_length))
+                    .collect::<Vec<_>>();
+                segment::join_split_words(sentences)
+            }
+        };
+        for sentence in sentences {
+            let len = sentence.chars().count();
+            let start = self.offset;
+            self.offset += len;
+            if sentence.trim().is_empty() && parent.name != "p" {
+                continue;
+            }
+            // text before any paragraph starts the first one
+            if self.force_new_para || self.counter.para() == 0 {
+                self.start_para();
+            }
+            let id = self.counter.next_sentence();
+            self.para_len += len;
+            self.stats.sentence_lengths.add(len);
+            if self.is_too_long(len) {
+                self.warn_long(format_args!(
+                    "{}: sentence {} is {} characters long (at text offset {})",
+                    self.rel_path, id, len, start
+                ));
+                self.stats.long_texts += 1;
+                self.para_warned = true;
+            }
+            self.record_span(&sentence);
+            out.push(XMLNode::Element(make_span(&id, Some(&sentence))));
+        }
+        self.last_char = t.chars().last().or(self.last_char);
+    }
+}
+
+/// Logs the transforms that changed each file, as returned by
+/// [`Converter::transformed_files`]
+fn log_transforms(files: &BTreeMap<String, Vec<String>>) {
+    for (name, transforms) in files {
+        debug!("{}: {}", name, transforms.join(", "));
+    }
+}
+
+/// Whether a `src` or `href` of `elem`, in the document at `doc_href`,
+/// points to `target`. Both hrefs are normalized
+fn links_to(elem: &Element, doc_href: &str, target: &str) -> bool {
+    return ["src", "href"]
+        .iter()
+        .filter_map(|a| elem.attributes.get(*a))
+        .any(|link| return !link.contains(':') && href::resolve(doc_href, link) == target);
+}
+
+/// Points the links of `root`, a document at `doc_href`, to `old` at the
+/// file `new_name` in the same directory instead. Fragments are kept
+fn rename_links(root: &mut Element, doc_href: &str, old: &str, new_name: &str) {
+    root.walk_mut(|e, _| {
+        if !links_to(e, doc_href, old) {
+            return Walk::Descend;
+        }
+        for attr in ["src", "href"] {
+            let Some(link) = e.attributes.get_mut(attr) else {
+                continue;
+            };
+            let (path, fragment) = href::split_fragment(link);
+            let dir = path.rsplit_once('/').map(|(d, _)| return format!("{}/", d));
+            let mut renamed = format!("{}{}", dir.unwrap_or_default(), new_name);
+            if let Some(f) = fragment {
+                renamed = format!("{}#{}", renamed, f);
+            }
+            *link = renamed;
+        }
+        return Walk::Descend;
+    });
+}
+
+/// Adds a link of the `epub:type` `kind` to `href` to the landmarks of a
+/// navigation document, adding hidden landmarks to its body if it has none
+fn add_landmark(root: &mut Element, kind: &str, title: &str, href: &str) {
+    let li = El::new("li").child(
+        El::new("a")
+            .attr(elem::EPUB_TYPE, kind)
+            .attr("href", href)
+            .text(title),
+    );
+    let mut li = Some(li.build());
+    root.walk_mut(|e, _| {
+        if e.name != "nav" || !elem::has_epub_type(e, "landmarks") {
+            return Walk::Descend;
+        }
+        if let (Some(ol), Some(li)) = (e.find_first_mut("ol"), li.take()) {
+            ol.children.push(XMLNode::Element(li));
+        }
+        return Walk::Skip;
+    });
+    if let (Some(body), Some(li)) = (root.find_first_mut("body"), li) {
+        let nav = El::new("nav")
+            .attr(elem::EPUB_TYPE, "landmarks")
+            .attr("hidden", "hidden")
+            .child(El::new("ol").child(XMLNode::Element(li)));
+        body.children.push(nav.into());
+    }
+    elem::declare_epub_namespace(root);
+}
+
+/// A title page showing `cover`, an href relative to the page, above the
+/// title and the authors. EPUB 2 pages leave out the `epub:type` and use a
+/// `<div>`, as XHTML 1.1 has no `<section>`
+fn title_page_doc(
+    title: Option<&str>,
+    authors: &[String],
+    cover: Option<&str>,
+    language: Option<&str>,
+    epub3: bool,
+) -> String {
+    let mut body = String::new();
+    if let Some(src) = cover {
+        body.push_str(&format!(
+            "    <div class=\"cover\"><img src=\"{}\" alt=\"\"/></div>\n",
+            pack::escape(src)
+        ));
+    }
+    if let Some(t) = title {
+        body.push_str(&format!("    <h1>{}</h1>\n", pack::escape(t)));
+    }
+    if !authors.is_empty() {
+        body.push_str(&format!(
+            "    <p class=\"author\">{}</p>\n",
+            pack::escape(&authors.join(", "))
+        ));
+    }
+    let (doctype, epub_ns, section, section_type) = match epub3 {
+        true => (
+            "<!DOCTYPE html>\n",
+            " xmlns:epub=\"http://www.idpf.org/2007/ops\"",
+            "section",
+            " epub:type=\"titlepage\"",
+        ),
+        false => ("", "", "div", ""),
+    };
+    let lang = language
+        .map(|l| return format!(" xml:lang=\"{}\"", pack::escape(l)))
+        .unwrap_or_default();
+    return format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+{}<html xmlns="http://www.w3.org/1999/xhtml"{}{}>
+<head>
+  <title>{}</title>
+  <style type="text/css">
+    body {{ margin: 0; text-align: center; }}
+    .cover img {{ max-width: 100%; max-height: 60vh; }}
+    h1 {{ margin: 1em 0 0.5em; }}
+  </style>
+</head>
+<body>
+  <{section} class="titlepage"{}>
+{}  </{section}>
+</body>
+</html>
+"#,
+        doctype,
+        epub_ns,
+        lang,
+        pack::escape(title.unwrap_or("Title Page")),
+        section_type,
+        body,
+        section = section
+    );
+}
+
+/// Whether a file with the media type `media_type`, which can be empty, or
+/// the path `href` is a font
+fn is_font(media_type: &str, href: &str) -> bool {
+    let ext = Path::new(href)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    return media_type.starts_with("font/")
+        || media_type.contains("font-")
+        || media_type == "application/vnd.ms-opentype"
+        || ["ttf", "otf", "woff", "woff2"].contains(&ext.as_str());
+}
+
+/// Removes the `EncryptedData` entries of a parsed `encryption.xml` whose
+/// cipher reference, relative to the archive root, fails `exists`. Returns
+/// how many were removed
+fn prune_encryption(root: &mut Element, exists: impl Fn(&str) -> bool) -> usize {
+    let before = root.children.len();
+    root.children.retain(|c| {
+        let Some(data) = c.as_element().filter(|e| e.name == "EncryptedData") else {
+            return true;
+        };
+        return data
+            .find_first("CipherReference")
+            .and_then(|r| r.attributes.get("URI"))
+            .is_some_and(|uri| exists(uri));
+    });
+    return before - root.children.len();
+}
+
+/// Labels of the entries of a nav document or NCX at `toc_href`, with the
+/// normalized hrefs they point to, in document order. Only the `toc` nav of
+/// a nav document is read, not the page list or landmarks
+fn toc_labels(root: &Element, toc_href: &str) -> Vec<(String, String)> {
+    let mut labels = Vec::new();
+    let mut add = |target: Option<&String>, label: String| {
+        let label = label.split_whitespace().collect::<Vec<_>>().join(" ");
+        if let (Some(t), false) = (target, label.is_empty()) {
+            labels.push((href::resolve(toc_href, t), label));
+        }
+    };
+
+    if root.name == "ncx" {
+        for point in root.find_where(|e| e.name == "navPoint") {
+            let label = point
+                .get_child("navLabel")
+                .and_then(|l| l.get_child("text"))
+                .map(verify::text_content)
+                .unwrap_or_default();
+            let src = point
+                .get_child("content")
+                .and_then(|c| c.attributes.get("src"));
+            add(src, label);
+        }
+        return labels;
+    }
+
+    let mut navs = root.find_where(|e| e.name == "nav").collect::<Vec<_>>();
+    let toc = navs
+        .iter()
+        .position(|n| elem::has_epub_type(n, "toc"))
+        .map(|i| navs.swap_remove(i))
+        .or_else(|| navs.into_iter().next());
+    for a in toc
+        .into_iter()
+        .flat_map(|n| n.find_where(|e| e.name == "a"))
+    {
+        add(a.attributes.get("href"), verify::text_content(a));
+    }
+    return labels;
+}
+
+/// Text of the first `<h1>` to `<h6>` in a content document, with its
+/// whitespace collapsed
+pub(crate) fn first_heading(root: &Element) -> Option<String> {
+    const HEADINGS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+    let body = root.get_child("body")?;
+    return body
+        .find_where(|e| HEADINGS.contains(&e.name.to_ascii_lowercase().as_str()))
+        .map(|h| {
+            let text = verify::text_content(h);
+            return text.split_whitespace().collect::<Vec<_>>().join(" ");
+        })
+        .find(|t| !t.is_empty());
+}
+
+/// Source of the first `<img>` or SVG `<image>` in a content document, as
+/// written in it
+fn first_image(root: &Element) -> Option<String> {
+    return root
+        .find_where(|e| e.name == "img" || e.name == "image")
+        .find_map(|e| {
+            let attr = if e.name == "img" { "src" } else { "href" };
+            return e.attributes.get(attr).cloned();
+        });
+}
+
+/// Returns true if the file at `path` is XML with a root element named
+/// `name`
+pub(crate) fn has_root_element(path: &Path, name: &str) -> bool {
+    return File::open(path)
+        .ok()
+        .and_then(|f| Element::parse(f).ok())
+        .is_some_and(|root| root.name == name);
+}
+
+fn is_kobo_span(elem: &Element) -> bool {
+    return elem.name == "span"
+        && elem
+            .attributes
+            .get("class")
+            .is_some_and(|c| c.split_whitespace().any(|c| c == "kobospan"));
+}
+
+/// Id of the kobospan in `elem`, if it has exactly one
+fn only_kobo_span(elem: &Element) -> Option<&str> {
+    let mut spans = elem.find_where(|e| return is_kobo_span(e));
+    let id = spans.next()?.attributes.get("id")?;
+    return match spans.next() {
+        Some(_) => None,
+        None => Some(id),
+    };
+}
+
+fn make_span(id: &str, content: Option<&str>) -> Element {
+    let span = El::new("span").class("kobospan").id(id);
+    return match content {
+        Some(c) => span.text(c).build(),
+        None => span.build(),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use xmltree::{Element, XMLNode};
+
+    use std::{
+        collections::HashSet,
+        fs::File,
+        io::{Read, Write},
+        path::{Path, PathBuf},
+    };
+
+    use super::{
+        convert_chapter, element_kind, first_heading, first_image, is_blank, prune_encryption,
+        prune_toc, replace_text, smarten_punctuation, toc_labels, ChapterOptions, ConverterBuilder,
+        ElementKind, ImageOptions, KoboSpans, OutputFormat, Preset, KOBO_STYLE,
+    };
+    use crate::{
+        codes::WarningCode,
+        config::Config,
+        elem::ElementExt,
+        media::MediaPolicy,
+        report::Report,
+        segment::Segmenter,
+        sink::{DirSink, MemorySink},
+        text::Normalization,
+        verify::text_content,
+        workdir::WorkDir,
+    };
+
+    fn span_body(xml: &str, long_text_warn: usize, chunk_length: usize) -> (Element, usize) {
+        let mut body = Element::parse(xml.as_bytes()).unwrap();
+        let options = ChapterOptions::default()
+            .with_long_text_warning(long_text_warn)
+            .with_chunking(chunk_length);
+        let mut spans = KoboSpans::new("test.xhtml", &options);
+        body.rewrite(&mut spans);
+        spans.check_para();
+        return (body, spans.stats.long_texts);
+    }
+
+    #[test]
+    fn test_long_texts() {
+        let xml = "<body><p>Short one. Another one.</p><p>Tiny</p>\
+            <p>A run of words without any end</p></body>";
+        assert_eq!(span_body(xml, 0, 0).1, 0);
+        assert_eq!(span_body(xml, 100, 0).1, 0);
+        // the first paragraph is too long, the third has a long sentence
+        assert_eq!(span_body(xml, 20, 0).1, 2);
+        assert_eq!(span_body(xml, 3, 0).1, 4);
+    }
+
+    #[test]
+    fn test_chunking() {
+        let xml = "<body><p>A run of words without any end</p></body>";
+        let (body, long_texts) = span_body(xml, 12, 12);
+        let ids = body
+            .find_all("span")
+            .iter()
+            .map(|s| s.attributes["id"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["kobo.1.1", "kobo.1.2", "kobo.1.3", "kobo.1.4"]);
+        // the chunks fit but the paragraph is still reported
+        assert_eq!(long_texts, 1);
+    }
+
+    #[test]
+    fn test_word_breaks() {
+        let mut body = Element::parse(
+            "<body><p>un<em>believ</em>able dic\u{AD}tion</p><p>a</p><p>b</p></body>".as_bytes(),
+        )
+        .unwrap();
+        let options = ChapterOptions::default().with_word_break_removal(true);
+        let mut spans = KoboSpans::new("test.xhtml", &options);
+        body.rewrite(&mut spans);
+        assert_eq!(spans.stats.split_words, 2);
+        assert_eq!(spans.stats.word_breaks, 1);
+        assert!(body
+            .find_all("span")
+            .iter()
+            .any(|s| text_content(s) == "able diction"));
+    }
+
+    #[test]
+    fn test_existing_wrappers() {
+        // a book where only the first chapter was converted before
+        let converted = convert_chapter(
+            r#"<html><body><div id="book-columns"><div id="book-inner"><p>One.</p></div></div></body></html>"#,
+            &ChapterOptions::default(),
+        )
+        .unwrap();
+        let fresh = convert_chapter(
+            "<html><body><p>Two.</p></body></html>",
+            &ChapterOptions::default(),
+        )
+        .unwrap();
+        for xhtml in [converted, fresh] {
+            let root = Element::parse(xhtml.as_bytes()).unwrap();
+            assert_eq!(root.find_with_attr("id", "book-columns").count(), 1);
+            assert_eq!(root.find_with_attr("id", "book-inner").count(), 1);
+            assert_eq!(root.find_all("span").len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_prune_encryption() {
+        let mut root = Element::parse(
+            r#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container"
+                xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+            <enc:EncryptedData>
+            <enc:EncryptionMethod Algorithm="http://www.idpf.org/2008/embedding"/>
+            <enc:CipherData><enc:CipherReference URI="OEBPS/fonts/a.otf"/></enc:CipherData>
+            </enc:EncryptedData>
+            <enc:EncryptedData>
+            <enc:EncryptionMethod Algorithm="http://www.idpf.org/2008/embedding"/>
+            <enc:CipherData><enc:CipherReference URI="OEBPS/fonts/b%20c.otf"/></enc:CipherData>
+            </enc:EncryptedData>
+            </encryption>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(prune_encryption(&mut root, |uri| uri.contains("a.otf")), 1);
+        let left = root
+            .find_all("CipherReference")
+            .iter()
+            .map(|r| r.attributes["URI"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(left, ["OEBPS/fonts/a.otf"]);
+    }
+
+    #[test]
+    fn test_chapter_titles() {
+        // parsed like content documents, which keep the prefix of epub:type
+        let nav = crate::space::parse(
+            r#"<html xmlns:epub="http://www.idpf.org/2007/ops"><body>
+            <nav epub:type="landmarks"><ol><li><a href="../text/ch1.xhtml">Start</a></li></ol></nav>
+            <nav epub:type="toc"><ol><li><a href="../text/ch1.xhtml#top">Chapter
+              <em>One</em></a></li><li><a href="../text/ch2.xhtml"> </a></li></ol></nav>
+            </body></html>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            toc_labels(&nav, "nav/nav.xhtml"),
+            [("text/ch1.xhtml".to_string(), "Chapter One".to_string())]
+        );
+
+        let ncx = Element::parse(
+            r#"<ncx><navMap><navPoint><navLabel><text>Preface</text></navLabel>
+            <content src="pre%20face.xhtml"/></navPoint></navMap></ncx>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            toc_labels(&ncx, "toc.ncx"),
+            [("pre face.xhtml".to_string(), "Preface".to_string())]
+        );
+
+        let doc = |xml: &str| first_heading(&Element::parse(xml.as_bytes()).unwrap());
+        assert_eq!(
+            doc("<html><body><h2> </h2><div><H3>The\n End</H3></div></body></html>").as_deref(),
+            Some("The End")
+        );
+        assert_eq!(doc("<html><body><p>x</p></body></html>"), None);
+    }
+
+    #[test]
+    fn test_first_image() {
+        let doc = |xml: &str| first_image(&Element::parse(xml.as_bytes()).unwrap());
+        assert_eq!(
+            doc(
+                r#"<html><body><div><svg><image href="../images/c.jpg"/></svg></div>
+            <img src="b.png"/></body></html>"#
+            )
+            .as_deref(),
+            Some("../images/c.jpg")
+        );
+        assert_eq!(doc("<html><body><img/><p>x</p></body></html>"), None);
+    }
+
+    #[test]
+    fn test_blank_pages() {
+        let blank = |xml: &str| is_blank(&Element::parse(xml.as_bytes()).unwrap());
+        assert!(blank("<html><body><div><p> </p><br/></div></body></html>"));
+        assert!(!blank("<html><body><p>x</p></body></html>"));
+        assert!(!blank(
+            r#"<html><body><div><img src="a.png"/></div></body></html>"#
+        ));
+
+        let mut nav = Element::parse(
+            r#"<nav><ol><li><a href="../text/blank.xhtml">Blank</a></li>
+            <li><a href="../text/part.xhtml#x">Part</a><ol>
+            <li><a href="../text/blank.xhtml">Nested</a></li></ol></li>
+            <li><a href="../text/blank.xhtml#a">Parent</a><ol><li><a href="ch1.xhtml">1</a></li></ol></li>
+            </ol></nav>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        let hrefs = HashSet::from(["text/blank.xhtml".to_string()]);
+        assert_eq!(prune_toc(&mut nav, "nav/nav.xhtml", &hrefs), 2);
+        let left = nav
+            .find_all("a")
+            .iter()
+            .map(|a| text_content(a))
+            .collect::<Vec<_>>();
+        assert_eq!(left, ["Part", "Parent", "1"]);
+    }
+
+    #[test]
+    fn test_record_spans() {
+        let mut body =
+            Element::parse(r#"<body><p>One. Two.</p><p><img src="a.png"/></p></body>"#.as_bytes())
+                .unwrap();
+        let options = ChapterOptions {
+            record_spans: true,
+            ..Default::default()
+        };
+        let mut spans = KoboSpans::new("test.xhtml", &options);
+        body.rewrite(&mut spans);
+        let records = spans
+            .stats
+            .spans
+            .iter()
+            .map(|r| (r.id.as_str(), r.start, r.end, r.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            [
+                ("kobo.1.1", 0, 5, "One. "),
+                ("kobo.1.2", 5, 9, "Two."),
+                ("kobo.2.0", 9, 9, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_punctuation_nodes() {
+        let mut body = Element::parse(
+            "<body><p>\u{201C}<em>Stop</em>,\u{201D} she said. <a>One</a> \u{2014} <a>two</a>.</p>\
+            <p>* * *</p><p>(<em>One. Two.</em>)</p></body>"
+                .as_bytes(),
+        )
+        .unwrap();
+        let options = ChapterOptions {
+            record_spans: true,
+            ..Default::default()
+        };
+        let mut spans = KoboSpans::new("test.xhtml", &options);
+        body.rewrite(&mut spans);
+        let records = spans
+            .stats
+            .spans
+            .iter()
+            .map(|r| (r.id.as_str(), r.start, r.end, r.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            [
+                ("kobo.1.1", 0, 5, "\u{201C}Stop"),
+                ("kobo.1.2", 5, 18, ",\u{201D} she said. "),
+                ("kobo.1.3", 18, 24, "One \u{2014} "),
+                ("kobo.1.4", 24, 28, "two."),
+                ("kobo.2.1", 28, 33, "* * *"),
+                // punctuation beside an element with more sentences keeps
+                // a span of its own
+                ("kobo.3.1", 33, 34, "("),
+                ("kobo.3.2", 34, 39, "One. "),
+                ("kobo.3.3", 39, 43, "Two."),
+                ("kobo.3.4", 43, 44, ")"),
+            ]
+        );
+        let span_texts = body
+            .find_all("span")
+            .iter()
+            .map(|s| return (s.attributes["id"].as_str(), text_content(s)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            span_texts,
+            records
+                .iter()
+                .map(|(id, _, _, text)| return (*id, text.to_string()))
+                .collect::<Vec<_>>()
+        );
+        let quoted = body.find_with_attr("id", "kobo.1.1").next().unwrap();
+        assert_eq!(quoted.find_first("em").unwrap().get_text().unwrap(), "Stop");
+        assert_eq!(
+            text_content(&body),
+            "\u{201C}Stop,\u{201D} she said. One \u{2014} two.* * *(One. Two.)"
+        );
+    }
+
+    #[test]
+    fn test_element_kind() {
+        let kind = |xml: &str| element_kind(&Element::parse(xml.as_bytes()).unwrap());
+        assert_eq!(kind("<h3/>"), ElementKind::Block);
+        assert_eq!(kind("<H1/>"), ElementKind::Block);
+        assert_eq!(
+            kind(r#"<x:h2 xmlns:x="http://www.w3.org/1999/xhtml"/>"#),
+            ElementKind::Block
+        );
+        assert_eq!(kind("<blockquote/>"), ElementKind::Block);
+        assert_eq!(kind("<hr/>"), ElementKind::Inline);
+        assert_eq!(kind("<em/>"), ElementKind::Inline);
+        assert_eq!(kind("<img/>"), ElementKind::Image);
+        assert_eq!(kind("<svg/>"), ElementKind::Opaque);
+    }
+
+    #[test]
+    fn test_paragraphs() {
+        let xml = "<body><h1>Title</h1><hr/><section>One. <em>Two.</em></section>\
+            <blockquote>Three.</blockquote></body>";
+        let (body, _) = span_body(xml, 0, 0);
+        let ids = body
+            .find_all("span")
+            .iter()
+            .map(|s| s.attributes["id"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["kobo.1.1", "kobo.2.1", "kobo.2.2", "kobo.3.1"]);
+    }
+
+    #[test]
+    fn test_span_histograms() {
+        let mut body = Element::parse(
+            "<body><p>One. Two two.</p><img src=\"a.jpg\"/><p>Three three three.</p></body>"
+                .as_bytes(),
+        )
+        .unwrap();
+        let options = ChapterOptions::default();
+        let mut spans = KoboSpans::new("test.xhtml", &options);
+        body.rewrite(&mut spans);
+        spans.check_para();
+        // "One. ", "Two two." and "Three three three."
+        assert_eq!(
+            spans
+                .stats
+                .sentence_lengths
+                .0
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [(4, 1), (8, 1), (16, 1)]
+        );
+        // the image paragraph has no sentences
+        assert_eq!(
+            spans
+                .stats
+                .spans_per_paragraph
+                .0
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [(1, 1), (2, 1)]
+        );
+    }
+
+    #[test]
+    fn test_span_numbering() {
+        // numbered like kepubify, from kobo.1.1 even when text comes before
+        // the first paragraph
+        let xml = "<body><div>Loose text. More.</div><p>One. <em>Two.</em></p>\
+            <h2>Title</h2><div><p>Three.</p>Tail.</div></body>";
+        let (body, _) = span_body(xml, 0, 0);
+        let ids = body
+            .find_all("span")
+            .iter()
+            .map(|s| s.attributes["id"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            ["kobo.1.1", "kobo.1.2", "kobo.2.1", "kobo.2.2", "kobo.3.1", "kobo.4.1", "kobo.4.2"]
+        );
+    }
+
+    #[test]
+    fn test_images() {
+        let xml = r#"<body><figure><a href="big.jpg"><img src="a.jpg"/></a>
+            <figcaption>A caption.</figcaption></figure>
+            <picture><source srcset="b.webp"/><img src="b.jpg"/></picture></body>"#;
+        let (body, _) = span_body(xml, 0, 0);
+
+        let spans = body.find_all("span");
+        let ids = spans
+            .iter()
+            .map(|s| s.attributes["id"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["kobo.1.0", "kobo.2.1", "kobo.3.0"]);
+
+        let link = body.find_first("a").unwrap();
+        assert!(link.find_first("span").unwrap().get_child("img").is_some());
+        let caption = body.find_first("figcaption").unwrap();
+        assert_eq!(caption.find_all("span").len(), 1);
+        // the picture is wrapped whole, with nothing wrapped inside it
+        assert_eq!(spans[2].children.len(), 1);
+        assert!(spans[2].find_first("span").is_none());
+        assert_eq!(spans[2].find_all("img").len(), 1);
+    }
+
+    #[test]
+    fn test_other_nodes() {
+        let xml = "<body><!-- note --><p>One.<![CDATA[a < b]]><?page 12?> Two.</p></body>";
+        let (body, _) = span_body(xml, 0, 0);
+        assert!(matches!(&body.children[0], XMLNode::Comment(c) if c == " note "));
+        let p = body.get_child("p").unwrap();
+        assert!(p
+            .children
+            .iter()
+            .any(|c| matches!(c, XMLNode::CData(t) if t == "a < b")));
+        assert!(p.children.iter().any(
+            |c| matches!(c, XMLNode::ProcessingInstruction(n, Some(d)) if n == "page" && d == "12")
+        ));
+        assert_eq!(p.find_all("span").len(), 2);
+    }
+
+    #[test]
+    fn test_opaque() {
+        let xml = r#"<body><p>Where <math><mi>x</mi><mo>=</mo><mn>2</mn></math> holds.</p>
+            <svg viewBox="0 0 10 10"><text>Label</text></svg></body>"#;
+        let (body, _) = span_body(xml, 0, 0);
+
+        let math = body.find_first("math").unwrap();
+        assert_eq!(math.children.len(), 3);
+        assert!(math.find_first("span").is_none());
+        let svg = body.find_first("svg").unwrap();
+        assert_eq!(svg.attributes["viewBox"], "0 0 10 10");
+        assert_eq!(svg.find_first("text").unwrap().get_text().unwrap(), "Label");
+        assert!(svg.find_first("span").is_none());
+
+        let xml = "<body><p>Run <code>a.b()</code> first.</p><pre>x = 1\n  y = 2</pre>\
+            <script>if (a &lt; b) { c(); }</script><style>p { color: red }</style></body>";
+        let (body, _) = span_body(xml, 0, 0);
+        for name in ["code", "pre", "script", "style"] {
+            let e = body.find_first(name).unwrap();
+            assert!(e.find_first("span").is_none(), "{}", name);
+        }
+        assert_eq!(
+            body.find_first("pre").unwrap().get_text().unwrap(),
+            "x = 1\n  y = 2"
+        );
+        let ids = body
+            .find_all("span")
+            .iter()
+            .map(|s| s.attributes["id"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["kobo.1.1", "kobo.1.2"]);
+    }
+
+    #[test]
+    fn test_convert_chapter() {
+        let xhtml = r#"<html xmlns="http://www.w3.org/1999/xhtml"><head><title>T</title></head>
+            <body><p>One. Two.</p></body></html>"#;
+        let out = convert_chapter(xhtml, &ChapterOptions::default()).unwrap();
+        let root = Element::parse(out.as_bytes()).unwrap();
+
+        let inner = root.find_first_with_attrs("div", &[("id", "book-inner")]);
+        assert!(root
+            .find_first_with_attrs("div", &[("id", "book-columns")])
+            .is_some());
+        assert_eq!(inner.unwrap().find_all("span").len(), 2);
+        assert!(!out.contains("\r"));
+
+        let no_spans = ChapterOptions::default().with_spans(false);
+        let root = Element::parse(convert_chapter(xhtml, &no_spans).unwrap().as_bytes()).unwrap();
+        assert!(root.find_first("span").is_none());
+        assert!(root.find_first("div").is_some());
+        let no_wrapper = ChapterOptions::default().with_wrapper(false);
+        let root = Element::parse(convert_chapter(xhtml, &no_wrapper).unwrap().as_bytes()).unwrap();
+        assert!(root.find_first("div").is_none());
+        assert_eq!(root.find_all("span").len(), 2);
+
+        let styled = ChapterOptions::default().with_style(Some(KOBO_STYLE.to_string()));
+        let out = convert_chapter(xhtml, &styled).unwrap();
+        let out = convert_chapter(&out, &styled).unwrap();
+        let root = Element::parse(out.as_bytes()).unwrap();
+        let head = root.get_child("head").unwrap();
+        assert_eq!(head.find_all("style").len(), 1);
+        assert_eq!(
+            head.find_first("style").unwrap().get_text().unwrap(),
+            KOBO_STYLE
+        );
+        let root = Element::parse(
+            convert_chapter("<html><body/></html>", &styled)
+                .unwrap()
+                .as_bytes(),
+        )
+        .unwrap();
+        assert!(root
+            .get_child("head")
+            .unwrap()
+            .find_first("style")
+            .is_some());
+
+        assert!(convert_chapter("<html/>", &ChapterOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_replace_text() {
+        let mut body = Element::parse(
+            r#"<body><p>Teh end<em> [WM-1234]</em></p><style>p.teh {}</style></body>"#.as_bytes(),
+        )
+        .unwrap();
+        let replacements = ["\\bTeh\\b::The", r" ?\[WM-\d+\]::", "The::A"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(replace_text(&mut body, &replacements), 3);
+        let p = body.find_first("p").unwrap();
+        assert_eq!(text_content(p), "A end");
+        assert!(p.find_first("em").unwrap().children.is_empty());
+        assert_eq!(replace_text(&mut body, &[]), 0);
+    }
+
+    #[test]
+    fn test_smarten_punctuation() {
+        let mut body = Element::parse(
+            r#"<body><p title="a 'b'">"<em>Yes</em>," he said -- 'I <b>don</b>'t know...'</p>
+            <p><code>x = "a"</code> "b" <pre>'c'</pre></p><p>"d<br/>"e"</p></body>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(smarten_punctuation(&mut body, &mut None), 12);
+        let ps = body.find_all("p");
+        assert_eq!(
+            text_content(ps[0]),
+            "\u{201C}Yes,\u{201D} he said \u{2014} \u{2018}I don\u{2019}t know\u{2026}\u{2019}"
+        );
+        assert_eq!(ps[0].attributes["title"], "a 'b'");
+        assert_eq!(text_content(ps[1]), "x = \"a\" \u{201C}b\u{201D} 'c'");
+        assert_eq!(text_content(ps[2]), "\u{201C}d\u{201C}e\u{201D}");
+    }
+
+    #[test]
+    fn test_preset() {
+        assert_eq!("Archival".parse(), Ok(Preset::Archival));
+        assert!("slow".parse::<Preset>().is_err());
+
+        let b = ConverterBuilder::default()
+            .with_preset(Preset::Fast)
+            .with_long_text_warning(500);
+        assert_eq!(b.compression_level, Some(0));
+        assert_eq!(b.chapter.long_text_warn, 500);
+        let b = ConverterBuilder::default()
+            .with_preset(Preset::Device)
+            .with_layout_fix(false);
+        assert!(b.chapter.style.is_some() && !b.fix_layout);
+        assert_eq!(b.images, Some(ImageOptions::default()));
+        // archival makes no lossy change, even after device
+        let b = ConverterBuilder::default()
+            .with_grayscale_images(true)
+            .with_preset(Preset::Device)
+            .with_preset(Preset::Archival);
+        assert_eq!(b.images, None);
+        assert!(!b.grayscale_images);
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!("EPUB".parse(), Ok(OutputFormat::Epub));
+        assert!("mobi".parse::<OutputFormat>().is_err());
+
+        // the kobo transforms are off whatever the order of the options
+        let c = ConverterBuilder::default()
+            .with_format(OutputFormat::Epub)
+            .with_preset(Preset::Device)
+            .with_spans(true)
+            .with_calibre_removal(true)
+            .build()
+            .unwrap();
+        assert!(!c.chapter.spans && !c.chapter.wrapper && c.chapter.style.is_none());
+        assert!(c.skip_cover_fix && c.strip_calibre);
+    }
+
+    #[test]
+    fn test_bilingual() {
+        let xhtml = r#"<html xmlns="http://www.w3.org/1999/xhtml" xml:lang="el"><body>
+            <p>Τι κάνεις; Καλά.</p>
+            <p xml:lang="en">What; not split. <em lang="el">Ναι; Όχι.</em></p>
+            <p lang="">Unknown; language.</p></body></html>"#;
+        let no_wrapper = ChapterOptions::default().with_wrapper(false);
+        let out = convert_chapter(xhtml, &no_wrapper).unwrap();
+        let root = Element::parse(out.as_bytes()).unwrap();
+        let texts = root
+            .find_all("span")
+            .iter()
+            .map(|s| s.get_text().unwrap().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            [
+                "Τι κάνεις; ",
+                "Καλά.",
+                "What; not split. ",
+                "Ναι; ",
+                "Όχι.",
+                "Unknown; language."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_convert_preserved() {
+        let xhtml = "<html><body><p>Intro. More.</p>\
+            <div xml:space=\"preserve\">First line. Still\n  <em>second.</em> Third.\n<br/>\n<br/>Last.</div>\
+            </body></html>";
+        let no_wrapper = ChapterOptions::default().with_wrapper(false);
+        let out = convert_chapter(xhtml, &no_wrapper).unwrap();
+        let root = Element::parse(out.as_bytes()).unwrap();
+        let div = root.find_first("div").unwrap();
+        let spans = div
+            .find_all("span")
+            .iter()
+            .map(|s| return (s.attributes["id"].as_str(), s.get_text().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                ("kobo.2.1", "First line. Still\n  ".into()),
+                ("kobo.2.2", "second.".into()),
+                ("kobo.2.3", " Third.\n".into()),
+                ("kobo.2.4", "Last.".into()),
+            ]
+        );
+        assert!(out.contains("<br />\n<br />"));
+    }
+
+    const CONTAINER: &[u8] =
+        br#"<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+        <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+        </rootfiles></container>"#;
+
+    /// Writes a book to a new working dir, with a package document at
+    /// OEBPS/content.opf of `version` around `opf`, and `files` by archive
+    /// name and content. The `dc` prefix is declared and the unique
+    /// identifier is the one with the id "uid"
+    fn write_book(version: &str, opf: &str, files: &[(&str, &[u8])]) -> (WorkDir, PathBuf) {
+        let dir = WorkDir::create_in(&std::env::temp_dir(), "kepub-rs-test").unwrap();
+        let package = format!(
+            r#"<package xmlns="http://www.idpf.org/2007/opf" xmlns:dc="http://purl.org/dc/elements/1.1/"
+            version="{}" unique-identifier="uid">{}</package>"#,
+            version, opf
+        );
+        let required: [(&str, &[u8]); 3] = [
+            ("mimetype", b"application/epub+zip"),
+            ("META-INF/container.xml", CONTAINER),
+            ("OEBPS/content.opf", package.as_bytes()),
+        ];
+        let epub = dir.join("book.epub");
+        let mut zip = zip::ZipWriter::new(File::create(&epub).unwrap());
+        for (name, content) in required.iter().chain(files) {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap();
+        return (dir, epub);
+    }
+
+    fn open(path: &Path) -> zip::ZipArchive<File> {
+        return zip::ZipArchive::new(File::open(path).unwrap()).unwrap();
+    }
+
+    /// Content of the entry `name` of the book at `path`
+    fn read_bytes(path: &Path, name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        open(path)
+            .by_name(name)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        return data;
+    }
+
+    fn read(path: &Path, name: &str) -> String {
+        return String::from_utf8(read_bytes(path, name)).unwrap();
+    }
+
+    fn read_xml(path: &Path, name: &str) -> Element {
+        return crate::space::parse(read_bytes(path, name).as_slice()).unwrap();
+    }
+
+    #[test]
+    fn test_convert_to() {
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title></metadata>
+            <manifest><item id="a" href="a.xhtml" media-type="application/xhtml+xml"/></manifest>
+            <spine><itemref idref="a"/></spine>"#,
+            &[("OEBPS/a.xhtml", b"<html><body><p>One.</p></body></html>")],
+        );
+        let data = ConverterBuilder::default()
+            .build()
+            .unwrap()
+            .convert_to(&mut open(&epub), MemorySink::new(None))
+            .unwrap();
+        let mut kepub = zip::ZipArchive::new(std::io::Cursor::new(data)).unwrap();
+        assert_eq!(kepub.file_names().count(), 4);
+        let mut a = String::new();
+        kepub
+            .by_name("OEBPS/a.xhtml")
+            .unwrap()
+            .read_to_string(&mut a)
+            .unwrap();
+        assert!(a.contains("kobo.1.1"));
+
+        let root = ConverterBuilder::default()
+            .build()
+            .unwrap()
+            .convert_to(
+                &mut open(&epub),
+                DirSink::new(&dir.join("expanded")).unwrap(),
+            )
+            .unwrap();
+        let expanded_a = std::fs::read_to_string(root.join("OEBPS/a.xhtml")).unwrap();
+        assert!(expanded_a.contains("kobo.1.1"));
+        assert_eq!(
+            std::fs::read_to_string(root.join("mimetype")).unwrap(),
+            "application/epub+zip"
+        );
+        assert!(root.join("META-INF/container.xml").is_file());
+    }
+
+    #[test]
+    fn test_not_well_formed() {
+        // only content documents are parsed as HTML, the NCX is copied
+        let ncx = br#"<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1"><navMap>
+            <navPoint id="p1"><navLabel><text>Tom & Jerry</text></navLabel><content src="a.xhtml"/></navPoint>
+            </navMap></ncx>"#;
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title></metadata>
+            <manifest><item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+            <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/></manifest>
+            <spine toc="ncx"><itemref idref="a"/></spine>"#,
+            &[
+                (
+                    "OEBPS/a.xhtml",
+                    b"<html><body><p>One.<br></p></body></html>",
+                ),
+                ("OEBPS/toc.ncx", ncx),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        let report = dir.join("report.json");
+        ConverterBuilder::default()
+            .with_blank_page_removal(true)
+            .with_report(&report)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+        assert_eq!(Report::open(&report).unwrap().codes.get("W001"), Some(&1));
+        assert!(read(&out, "OEBPS/a.xhtml").contains("kobo.1.1"));
+        assert_eq!(read_bytes(&out, "OEBPS/toc.ncx"), ncx);
+    }
+
+    /// Segmenter with a bug triggered by the word "boom"
+    struct PanickingSegmenter;
+
+    impl Segmenter for PanickingSegmenter {
+        fn segment(&self, text: &str) -> Vec<String> {
+            assert!(!text.contains("boom"), "cannot segment {:?}", text);
+            return vec![text.to_string()];
+        }
+    }
+
+    #[test]
+    fn test_panicking_chapter() {
+        let bad = "<html><body><p>It goes boom.</p></body></html>";
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title></metadata>
+            <manifest><item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+            <item id="b" href="b.xhtml" media-type="application/xhtml+xml"/></manifest>
+            <spine><itemref idref="a"/><itemref idref="b"/></spine>"#,
+            &[
+                ("OEBPS/a.xhtml", bad.as_bytes()),
+                ("OEBPS/b.xhtml", b"<html><body><p>Fine.</p></body></html>"),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        let report = dir.join("report.json");
+        let unconverted = ConverterBuilder::default()
+            .with_segmenter(PanickingSegmenter)
+            .with_report(&report)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+        assert_eq!(unconverted.len(), 1);
+        assert_eq!(unconverted[0].file, "OEBPS/a.xhtml");
+        assert!(unconverted[0].error.to_string().contains("cannot segment"));
+        assert_eq!(unconverted[0].error.category(), "panic");
+
+        assert_eq!(read(&out, "OEBPS/a.xhtml"), bad);
+        assert!(read(&out, "OEBPS/b.xhtml").contains("kobo.1.1"));
+        let codes = Report::open(&report).unwrap().codes;
+        assert_eq!(codes.get("W012"), Some(&1));
+        assert_eq!(codes.get("W003"), Some(&1));
+
+        // allowed warnings are left out of the report
+        ConverterBuilder::default()
+            .with_segmenter(PanickingSegmenter)
+            .with_report(&report)
+            .with_allowed_warnings([WarningCode::ChapterSkipped])
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+        let report = Report::open(&report).unwrap();
+        assert!(!report.codes.contains_key("W012"));
+        assert!(report.warnings.iter().all(|w| w.starts_with("W003: ")));
+    }
+
+    #[test]
+    fn test_book_rules() {
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title>
+            <dc:identifier id="uid">urn:isbn:9780000000001</dc:identifier></metadata>
+            <manifest><item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+            <item id="pic" href="pic.jpg" media-type="image/png"/></manifest>
+            <spine><itemref idref="a"/></spine>"#,
+            &[
+                ("OEBPS/a.xhtml", b"<html><body><p>Text.</p></body></html>"),
+                ("OEBPS/pic.jpg", b"not really a jpeg"),
+            ],
+        );
+
+        let config: Config = serde_json::from_str(
+            r#"{"books": [
+                {"identifier": "urn:isbn:978000*", "allow": ["W003"], "fix": ["W021"]},
+                {"identifier": "urn:isbn:979*", "allow": ["W012"]}]}"#,
+        )
+        .unwrap();
+        let out = dir.join("book.kepub.epub");
+        let report = dir.join("report.json");
+        ConverterBuilder::default()
+            .with_report(&report)
+            .with_config(&config)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+        let report = Report::open(&report).unwrap();
+        assert!(report.codes.is_empty(), "{:?}", report.codes);
+        assert!(report.files["OEBPS/content.opf"].contains(&"repair".to_string()));
+        assert!(read(&out, "OEBPS/content.opf").contains("image/jpeg"));
+    }
+
+    #[test]
+    fn test_media_overlays() {
+        let spanned = r#"<html xmlns="http://www.w3.org/1999/xhtml"><body><p><span class="kobospan" id="kobo.1.1">Read to me.</span></p>
+            <audio src="narration.mp3"><p>No audio.</p></audio></body></html>"#;
+        let (dir, epub) = write_book(
+            "3.0",
+            r#"<metadata><dc:title>T</dc:title>
+            <meta property="media:duration">0:00:02.500</meta></metadata>
+            <manifest><item id="a" href="a.xhtml" media-type="application/xhtml+xml" media-overlay="a-smil"/>
+            <item id="a-smil" href="a.smil" media-type="application/smil+xml"/>
+            <item id="mp3" href="narration.mp3" media-type="audio/mpeg"/></manifest>
+            <spine><itemref idref="a"/></spine>"#,
+            &[
+                ("OEBPS/a.xhtml", spanned.as_bytes()),
+                (
+                    "OEBPS/a.smil",
+                    br#"<smil xmlns="http://www.w3.org/ns/SMIL" version="3.0"><body>
+                    <par><text src="a.xhtml#kobo.1.1"/><audio src="narration.mp3" clipEnd="2.5s"/></par>
+                    </body></smil>"#,
+                ),
+                ("OEBPS/narration.mp3", b"ID3"),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        let report = dir.join("report.json");
+        ConverterBuilder::default()
+            .with_respan(true)
+            .with_media_policy(MediaPolicy::Strip)
+            .with_report(&report)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+        assert!(!Report::open(&report).unwrap().codes.contains_key("W027"));
+
+        // the overlay still plays its audio and finds its span
+        let doc = read(&out, "OEBPS/a.xhtml");
+        assert!(doc.contains(r#"id="kobo.1.1""#));
+        assert_eq!(doc.matches("kobospan").count(), 1);
+        assert!(!doc.contains("<audio"));
+        assert_eq!(read(&out, "OEBPS/narration.mp3"), "ID3");
+        let opf = read(&out, "OEBPS/content.opf");
+        assert!(opf.contains(r#"media-overlay="a-smil""#));
+        assert!(opf.contains("media:duration"));
+    }
+
+    #[test]
+    fn test_link_targets() {
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title></metadata>
+            <manifest><item id="a" href="text/a.xhtml" media-type="application/xhtml+xml"/>
+            <item id="b" href="text/b.xhtml" media-type="application/xhtml+xml"/>
+            <item id="clip" href="clip.mp3" media-type="audio/mpeg"/></manifest>
+            <spine><itemref idref="a"/><itemref idref="b"/></spine>
+            <guide><reference type="text" href="text/a.xhtml#start"/></guide>"#,
+            &[
+                (
+                    "OEBPS/text/a.xhtml",
+                    br#"<html><body><h1 id="start">One</h1><p>Hear <a href="b.xhtml#clip">the clip</a>
+                    and read <a href="b.xhtml#fn1">the note</a>.</p></body></html>"#,
+                ),
+                (
+                    "OEBPS/text/b.xhtml",
+                    br#"<html><body><audio id="clip" src="../clip.mp3">Audio.</audio>
+                    <p id="fn1">A note.</p></body></html>"#,
+                ),
+                ("OEBPS/clip.mp3", b"ID3"),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        let report = dir.join("report.json");
+        ConverterBuilder::default()
+            .with_media_policy(MediaPolicy::Strip)
+            .with_report(&report)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+        assert!(!Report::open(&report).unwrap().codes.contains_key("W028"));
+
+        // the link to the audio now leads to its fallback
+        let b = read(&out, "OEBPS/text/b.xhtml");
+        assert!(!b.contains("<audio"));
+        assert!(b.contains(r#"id="clip""#));
+        assert!(b.contains(r#"id="fn1""#));
+    }
+
+    #[test]
+    fn test_footnote_popups() {
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title></metadata>
+            <manifest><item id="a" href="text/a.xhtml" media-type="application/xhtml+xml"/>
+            <item id="n" href="text/notes.xhtml" media-type="application/xhtml+xml"/></manifest>
+            <spine><itemref idref="a"/><itemref idref="n"/></spine>"#,
+            &[
+                (
+                    "OEBPS/text/a.xhtml",
+                    br##"<html xmlns="http://www.w3.org/1999/xhtml"><body>
+                    <p>Text<a id="r1" href="notes.xhtml#n1">1</a>.</p></body></html>"##,
+                ),
+                (
+                    "OEBPS/text/notes.xhtml",
+                    br##"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+                    <body><div epub:type="footnote"><a id="n1" href="a.xhtml#r1">1.</a> A note.</div>
+                    </body></html>"##,
+                ),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        let report = dir.join("report.json");
+        ConverterBuilder::default()
+            .with_footnote_popups(true)
+            .with_report(&report)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+        assert!(!Report::open(&report).unwrap().codes.contains_key("W028"));
+
+        let a = read_xml(&out, "OEBPS/text/a.xhtml");
+        let link = a.find_with_attr("href", "notes.xhtml#n1").next().unwrap();
+        assert_eq!(link.attributes["epub:type"], "noteref");
+        let notes = read_xml(&out, "OEBPS/text/notes.xhtml");
+        let note = notes.find_first("aside").unwrap();
+        assert_eq!(note.attributes["id"], "n1");
+        assert_eq!(note.attributes["epub:type"], "footnote");
+    }
+
+    #[test]
+    fn test_restore_fonts() {
+        let uid = "urn:uuid:0f6e2b4a-1c3d-4e5f-8a9b-0c1d2e3f4a5b";
+        let font = [b"OTTO".as_slice(), &[7; 2000]].concat();
+        let obfuscate = |key: &[u8]| {
+            let mut data = font.clone();
+            for (i, b) in data[..1040].iter_mut().enumerate() {
+                *b ^= key[i % key.len()];
+            }
+            return data;
+        };
+        let good = obfuscate(
+            &crate::obfuscation::Algorithm::Idpf
+                .key(&[uid.to_string()])
+                .unwrap(),
+        );
+        let bad = obfuscate(b"not the key");
+        let entry = |uri: &str| {
+            return format!(
+                r#"<enc:EncryptedData><enc:EncryptionMethod Algorithm="http://www.idpf.org/2008/embedding"/>
+                <enc:CipherData><enc:CipherReference URI="{}"/></enc:CipherData></enc:EncryptedData>"#,
+                uri
+            );
+        };
+        let encryption = format!(
+            r#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container"
+            xmlns:enc="http://www.w3.org/2001/04/xmlenc#">{}{}</encryption>"#,
+            entry("OEBPS/fonts/good.otf"),
+            entry("OEBPS/fonts/bad.otf")
+        );
+        let (dir, epub) = write_book(
+            "3.0",
+            &format!(
+                r#"<metadata><dc:title>T</dc:title><dc:identifier id="uid">{}</dc:identifier></metadata>
+                <manifest><item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+                <item id="f1" href="fonts/good.otf" media-type="font/otf"/>
+                <item id="f2" href="fonts/bad.otf" media-type="font/otf"/></manifest>
+                <spine><itemref idref="a"/></spine>"#,
+                uid
+            ),
+            &[
+                ("META-INF/encryption.xml", encryption.as_bytes()),
+                ("OEBPS/a.xhtml", b"<html><body><p>Text.</p></body></html>"),
+                ("OEBPS/fonts/good.otf", &good),
+                ("OEBPS/fonts/bad.otf", &bad),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        let report = dir.join("report.json");
+        ConverterBuilder::default()
+            .with_report(&report)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+        assert_eq!(Report::open(&report).unwrap().codes["W029"], 1);
+
+        assert_eq!(read_bytes(&out, "OEBPS/fonts/good.otf"), font);
+        let mut archive = open(&out);
+        assert!(archive.by_name("OEBPS/fonts/bad.otf").is_err());
+        assert!(archive.by_name("META-INF/encryption.xml").is_err());
+        assert!(!read(&out, "OEBPS/content.opf").contains("bad.otf"));
+    }
+
+    #[test]
+    fn test_remove_fonts() {
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title></metadata>
+            <manifest><item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+            <item id="css" href="style.css" media-type="text/css"/>
+            <item id="f" href="fonts/a.ttf" media-type="application/x-font-ttf"/></manifest>
+            <spine><itemref idref="a"/></spine>"#,
+            &[
+                (
+                    "OEBPS/a.xhtml",
+                    br#"<html><head><style>@font-face { font-family: B; src: url(fonts/b.otf) }</style>
+                    </head><body><p>Text.</p></body></html>"#,
+                ),
+                (
+                    "OEBPS/style.css",
+                    b"@font-face { font-family: A; src: url(fonts/a.ttf) }\np { font-family: A }",
+                ),
+                ("OEBPS/fonts/a.ttf", b"font"),
+                ("OEBPS/fonts/b.otf", b"font"),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        ConverterBuilder::default()
+            .with_font_removal(true)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+
+        assert!(open(&out)
+            .file_names()
+            .all(|n| return !n.contains("fonts/")));
+        assert!(!read(&out, "OEBPS/content.opf").contains("a.ttf"));
+        assert_eq!(read(&out, "OEBPS/style.css").trim(), "p { font-family: A }");
+        assert!(!read(&out, "OEBPS/a.xhtml").contains("@font-face"));
+    }
+
+    #[test]
+    fn test_optimize_images() {
+        let mut seed = 1u32;
+        let noise = image::RgbImage::from_fn(200, 100, |_, _| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let [_, r, g, b] = seed.to_le_bytes();
+            return image::Rgb([r, g, b]);
+        });
+        let mut png = std::io::Cursor::new(Vec::new());
+        noise.write_to(&mut png, image::ImageFormat::Png).unwrap();
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title></metadata>
+            <manifest><item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+            <item id="img" href="wide.png" media-type="image/png"/></manifest>
+            <spine><itemref idref="a"/></spine>"#,
+            &[
+                (
+                    "OEBPS/a.xhtml",
+                    br#"<html><body><p><img src="wide.png" alt=""/></p></body></html>"#,
+                ),
+                ("OEBPS/wide.png", png.get_ref()),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        ConverterBuilder::default()
+            .with_image_optimization(Some(ImageOptions {
+                max_width: 50,
+                max_height: 50,
+                quality: 80,
+            }))
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+
+        let img = image::load_from_memory(&read_bytes(&out, "OEBPS/wide.png")).unwrap();
+        assert_eq!((img.width(), img.height()), (50, 25));
+    }
+
+    #[test]
+    fn test_normalize_cover() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(100, 150)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let (dir, epub) = write_book(
+            "3.0",
+            r#"<metadata><dc:title>T</dc:title><dc:identifier id="uid">x</dc:identifier></metadata>
+            <manifest><item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+            <item id="c" href="text/cover.xhtml" media-type="application/xhtml+xml"/>
+            <item id="img" href="images/cover.png" media-type="image/png" properties="cover-image"/></manifest>
+            <spine><itemref idref="c"/></spine>"#,
+            &[
+                (
+                    "OEBPS/nav.xhtml",
+                    br#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops"><body>
+                    <nav epub:type="toc"><ol><li><a href="text/cover.xhtml">Cover</a></li></ol></nav></body></html>"#,
+                ),
+                (
+                    "OEBPS/text/cover.xhtml",
+                    br#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:xlink="http://www.w3.org/1999/xlink"><body>
+                    <svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 150">
+                    <image width="100" height="150" xlink:href="../images/cover.png"/></svg></body></html>"#,
+                ),
+                ("OEBPS/images/cover.png", png.get_ref()),
+                // taken, so the JPEG gets another name
+                ("OEBPS/images/cover.jpg", b"not the cover"),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        ConverterBuilder::default()
+            .with_cover_normalization(true)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+
+        assert!(open(&out).by_name("OEBPS/images/cover.png").is_err());
+        let img = image::load_from_memory_with_format(
+            &read_bytes(&out, "OEBPS/images/cover-1.jpg"),
+            image::ImageFormat::Jpeg,
+        )
+        .unwrap();
+        assert_eq!(
+            (img.width(), img.height()),
+            (965, crate::images::MIN_COVER_SIZE.1)
+        );
+        let opf = read_xml(&out, "OEBPS/content.opf");
+        let item = opf.find_with_attr("id", "img").next().unwrap();
+        assert_eq!(item.attributes["href"], "images/cover-1.jpg");
+        assert_eq!(item.attributes["media-type"], "image/jpeg");
+        let reference = opf.find_first("reference").unwrap();
+        assert_eq!(reference.attributes["type"], "cover");
+        assert_eq!(reference.attributes["href"], "text/cover.xhtml");
+        let page = read_xml(&out, "OEBPS/text/cover.xhtml");
+        assert_eq!(
+            page.find_first("image").unwrap().attributes["href"],
+            "../images/cover-1.jpg"
+        );
+        let nav = read_xml(&out, "OEBPS/nav.xhtml");
+        let landmark = nav.find_with_attr("epub:type", "cover").next().unwrap();
+        assert_eq!(landmark.attributes["href"], "text/cover.xhtml");
+    }
+
+    #[test]
+    fn test_title_page() {
+        let (dir, epub) = write_book(
+            "3.0",
+            r#"<metadata><dc:title>Tom &amp; Jerry</dc:title>
+            <dc:creator>A. Author</dc:creator><dc:language>en</dc:language>
+            <dc:identifier id="uid">x</dc:identifier></metadata>
+            <manifest><item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+            <item id="titlepage" href="images/titlepage.jpg" media-type="image/jpeg" properties="cover-image"/>
+            <item id="ch1" href="text/ch1.xhtml" media-type="application/xhtml+xml"/></manifest>
+            <spine><itemref idref="ch1"/></spine>"#,
+            &[
+                (
+                    "OEBPS/nav.xhtml",
+                    br#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops"><body>
+                    <nav epub:type="toc"><ol><li><a href="text/ch1.xhtml">One</a></li></ol></nav></body></html>"#,
+                ),
+                (
+                    "OEBPS/text/ch1.xhtml",
+                    br#"<html xmlns="http://www.w3.org/1999/xhtml"><body><h1>One</h1><p>Text.</p></body></html>"#,
+                ),
+                ("OEBPS/images/titlepage.jpg", b"jpeg"),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        let convert = |path: &Path| {
+            ConverterBuilder::default()
+                .with_title_page(true)
+                .with_respan(true)
+                .build()
+                .unwrap()
+                .convert(&mut open(path), out.to_str().unwrap())
+                .unwrap();
+        };
+        convert(&epub);
+
+        let opf = read_xml(&out, "OEBPS/content.opf");
+        // the cover image has the id the page would get
+        let item = opf.find_with_attr("id", "titlepage-2").next().unwrap();
+        assert_eq!(item.attributes["href"], "text/titlepage.xhtml");
+        let spine = opf.find_first("spine").unwrap();
+        assert_eq!(
+            spine.find_first("itemref").unwrap().attributes["idref"],
+            "titlepage-2"
+        );
+        let reference = opf.find_first("reference").unwrap();
+        assert_eq!(reference.attributes["type"], "title-page");
+        assert_eq!(reference.attributes["href"], "text/titlepage.xhtml");
+        let page = read_xml(&out, "OEBPS/text/titlepage.xhtml");
+        assert_eq!(
+            page.find_first("img").unwrap().attributes["src"],
+            "../images/titlepage.jpg"
+        );
+        assert!(page
+            .find_with_attr("epub:type", "titlepage")
+            .next()
+            .is_some());
+        let heading = page.find_first("h1").unwrap();
+        assert_eq!(text_content(heading).trim(), "Tom & Jerry");
+        assert!(text_content(&page).contains("A. Author"));
+        let nav = read_xml(&out, "OEBPS/nav.xhtml");
+        let landmark = nav.find_with_attr("epub:type", "titlepage").next().unwrap();
+        assert_eq!(landmark.attributes["href"], "text/titlepage.xhtml");
+
+        // the converted book opens on its title page, converting it again
+        // adds none
+        let again = dir.join("again.epub");
+        std::fs::rename(&out, &again).unwrap();
+        convert(&again);
+        assert!(open(&out).by_name("OEBPS/text/titlepage-2.xhtml").is_err());
+    }
+
+    #[test]
+    fn test_content_extensions() {
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title></metadata>
+            <manifest><item id="a" href="a.htm" media-type="text/html"/>
+            <item id="b" href="text/b.xml" media-type="application/xml"/>
+            <item id="data" href="data.xml" media-type="application/xml"/></manifest>
+            <spine><itemref idref="a"/><itemref idref="b"/></spine>"#,
+            &[
+                (
+                    "OEBPS/a.htm",
+                    br#"<html><body><p>One. <a href="text/b.xml#x">Two.</a></p></body></html>"#,
+                ),
+                (
+                    "OEBPS/text/b.xml",
+                    br#"<html><body><p id="x">Three.</p></body></html>"#,
+                ),
+                (
+                    "OEBPS/data.xml",
+                    b"<data><body>Not a chapter.</body></data>",
+                ),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        ConverterBuilder::default()
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+
+        let a = read(&out, "OEBPS/a.htm");
+        assert!(a.contains("kobo.1.2") && a.contains(r#"href="text/b.xml#x""#));
+        assert!(read(&out, "OEBPS/text/b.xml").contains("kobo.1.1"));
+        assert!(!read(&out, "OEBPS/data.xml").contains("kobospan"));
+        let opf = read(&out, "OEBPS/content.opf");
+        assert_eq!(opf.matches("application/xhtml+xml").count(), 2);
+    }
+
+    #[test]
+    fn test_vertical() {
+        let css = "body { line-height: 1.8; -epub-writing-mode: vertical-rl; }";
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title></metadata>
+            <manifest><item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+            <item id="css" href="style.css" media-type="text/css"/></manifest>
+            <spine page-progression-direction="rtl"><itemref idref="a"/></spine>"#,
+            &[
+                (
+                    "OEBPS/a.xhtml",
+                    r#"<html><body><p>第１２章。"ＡＢ"と言った。</p></body></html>"#.as_bytes(),
+                ),
+                ("OEBPS/style.css", css.as_bytes()),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        ConverterBuilder::default()
+            .with_normalization(Normalization::Nfkc)
+            .with_punctuation_smartening(true)
+            .with_layout_fix(true)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+
+        let a = read(&out, "OEBPS/a.xhtml");
+        assert!(a.contains("第１２章。"), "{}", a);
+        assert!(a.contains("ＡＢ") && !a.contains('“'), "{}", a);
+        assert!(a.contains("kobo.1.2"));
+        assert_eq!(read(&out, "OEBPS/style.css"), css);
+        assert!(read(&out, "OEBPS/content.opf").contains(r#"page-progression-direction="rtl""#));
+    }
+
+    #[test]
+    fn test_fixed_layout() {
+        let page = |viewport: &str| {
+            return format!(
+                r#"<html><head>{}</head><body><img src="p.jpg"/><p>Boom.</p></body></html>"#,
+                viewport
+            );
+        };
+        let with_viewport = page(r#"<meta name="viewport" content="width=600, height=800"/>"#);
+        let blank = page("").replace(r#"<img src="p.jpg"/><p>Boom.</p>"#, "");
+        let (dir, epub) = write_book(
+            "2.0",
+            r#"<metadata><dc:title>T</dc:title><meta name="fixed-layout" content="true"/></metadata>
+            <manifest><item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+            <item id="b" href="b.xhtml" media-type="application/xhtml+xml"/></manifest>
+            <spine><itemref idref="a"/><itemref idref="b"/></spine>"#,
+            &[
+                ("OEBPS/a.xhtml", with_viewport.as_bytes()),
+                ("OEBPS/b.xhtml", blank.as_bytes()),
+            ],
+        );
+
+        let out = dir.join("book.kepub.epub");
+        let report = dir.join("report.json");
+        ConverterBuilder::default()
+            .with_style(Some(KOBO_STYLE.to_string()))
+            .with_blank_page_removal(true)
+            .with_report(&report)
+            .build()
+            .unwrap()
+            .convert(&mut open(&epub), out.to_str().unwrap())
+            .unwrap();
+
+        let a = read(&out, "OEBPS/a.xhtml");
+        assert!(a.contains(r#"name="viewport""#) && a.contains("kobospan"));
+        assert!(!a.contains("book-columns") && !a.contains(KOBO_STYLE));
+        let opf = read(&out, "OEBPS/content.opf");
+        assert!(opf.contains(r#"property="rendition:layout""#));
+        assert!(opf.contains(r#"idref="b""#));
+        let report = Report::open(&report).unwrap();
+        assert_eq!(report.codes.get("W026"), Some(&1));
+        assert!(report.warnings.iter().any(|w| w.ends_with(": b.xhtml")));
+    }
+}
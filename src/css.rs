@@ -0,0 +1,512 @@
+//! Minimal stylesheet rewriting. There is no full CSS parser here, only
+//! enough scanning to find style rules and their declarations, skipping
+//! comments and strings, and descending into conditional at-rules.
+
+/// Class that publisher layout declarations are moved behind by
+/// [`neutralize_layout`]. Nothing in the book uses it
+pub const PUBLISHER_LAYOUT_CLASS: &str = "kepub-publisher-layout";
+
+/// At-rules whose block holds style rules
+const NESTED_AT_RULES: [&str; 4] = ["media", "supports", "document", "layer"];
+
+/// Moves the declarations that keep the device's line spacing, margin and
+/// font size settings from working out of the rules styling body text:
+///
+/// - `line-height` on `html`, `body`, `p`, `div` and class-only selectors
+/// - `font-size` on `html`, `body`, `p` and `div`
+/// - `margin` on `html` and `body`
+///
+/// Each rule that loses declarations is followed by a copy of them scoped
+/// to [`PUBLISHER_LAYOUT_CLASS`], so they are kept but don't apply. Returns
+/// the stylesheet and how many declarations were moved
+pub fn neutralize_layout(css: &str) -> (String, usize) {
+    let mut out = String::with_capacity(css.len());
+    let mut moved = 0;
+    rewrite_rules(css, &mut out, &mut |selectors, body| {
+        let (kept, removed) = split_layout(selectors, body);
+        if removed.is_empty() {
+            return None;
+        }
+        moved += removed.len();
+        let scoped = selectors
+            .split(',')
+            .map(|s| scope(s.trim()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Some(format!(
+            "{} {{{}}}\n{} {{{}}}",
+            selectors.trim(),
+            format_declarations(&kept),
+            scoped,
+            format_declarations(&removed)
+        ));
+    });
+    return (out, moved);
+}
+
+/// Whether a rule of the stylesheet sets a vertical `writing-mode`, with or
+/// without the `-epub-` and `-webkit-` prefixes
+pub fn has_vertical_writing_mode(css: &str) -> bool {
+    let mut vertical = false;
+    rewrite_rules(css, &mut String::new(), &mut |_, body| {
+        vertical |= split_top_level(body, ';')
+            .iter()
+            .any(|d| return is_vertical_writing_mode(d));
+        return None;
+    });
+    return vertical;
+}
+
+/// Whether a declaration sets a vertical writing mode, `vertical-rl`,
+/// `vertical-lr` or their older names `tb-rl` and `tb-lr`
+pub fn is_vertical_writing_mode(decl: &str) -> bool {
+    let Some((prop, value)) = decl.split_once(':') else {
+        return false;
+    };
+    let prop = prop.trim().to_ascii_lowercase();
+    let prop = prop
+        .strip_prefix("-epub-")
+        .or_else(|| prop.strip_prefix("-webkit-"))
+        .unwrap_or(&prop);
+    let value = value.trim().to_ascii_lowercase();
+    return prop == "writing-mode" && (value.starts_with("vertical") || value.starts_with("tb"));
+}
+
+/// Removes the declarations that box the text of `html` and `body` into a
+/// column, like `margin: 0 auto` or `max-width`, which leave wide empty
+/// margins in Kobo's fullscreen reading mode. Rules left empty are removed.
+/// Returns the stylesheet and how many declarations were removed
+pub fn unconstrain_page(css: &str) -> (String, usize) {
+    let mut out = String::with_capacity(css.len());
+    let mut removed = 0;
+    rewrite_rules(css, &mut out, &mut |selectors, body| {
+        let root = selectors.split(',').all(|s| {
+            return subject_element(s).is_some_and(|t| t == "html" || t == "body");
+        });
+        if !root {
+            return None;
+        }
+        let decls = split_top_level(body, ';');
+        let kept = decls
+            .iter()
+            .copied()
+            .filter(|d| !d.trim().is_empty() && !constrains_page(d))
+            .collect::<Vec<_>>();
+        let n = decls.iter().filter(|d| constrains_page(d)).count();
+        if n == 0 {
+            return None;
+        }
+        removed += n;
+        if kept.is_empty() {
+            return Some(String::new());
+        }
+        return Some(format!(
+            "{} {{{}}}",
+            selectors.trim(),
+            format_declarations(&kept)
+        ));
+    });
+    return (out, removed);
+}
+
+/// [`unconstrain_page`] for the declarations of a `style` attribute of
+/// `html` or `body`
+pub fn unconstrain_declarations(decls: &str) -> (String, usize) {
+    let decls = split_top_level(decls, ';');
+    let kept = decls
+        .iter()
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty() && !constrains_page(d))
+        .collect::<Vec<_>>();
+    let removed = decls.iter().filter(|d| constrains_page(d)).count();
+    return (kept.join("; "), removed);
+}
+
+/// Whether a declaration limits the width of the page or centers it
+fn constrains_page(decl: &str) -> bool {
+    let Some((prop, value)) = decl.split_once(':') else {
+        return false;
+    };
+    let value = value.trim().to_ascii_lowercase();
+    return match prop.trim().to_ascii_lowercase().as_str() {
+        "max-width" => true,
+        "width" => !value.starts_with("auto") && !value.starts_with("100%"),
+        "margin" | "margin-left" | "margin-right" => value.contains("auto"),
+        _ => false,
+    };
+}
+
+/// Splits the declarations of a rule into those kept and those that
+/// override the reading settings for `selectors`
+fn split_layout<'a>(selectors: &str, body: &'a str) -> (Vec<&'a str>, Vec<&'a str>) {
+    let targets = selectors
+        .split(',')
+        .map(subject_element)
+        .collect::<Vec<_>>();
+    let is = |names: &[&str]| {
+        return targets
+            .iter()
+            .all(|t| t.as_deref().is_some_and(|t| names.contains(&t)));
+    };
+    let root = is(&["html", "body"]);
+    let text = is(&["html", "body", "p", "div"]);
+    let class_only = targets.iter().all(|t| t.as_deref() == Some(""));
+
+    let mut kept = Vec::new();
+    let mut removed = Vec::new();
+    for decl in split_top_level(body, ';') {
+        if decl.trim().is_empty() {
+            continue;
+        }
+        let prop = decl
+            .split(':')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let layout = match prop.as_str() {
+            "line-height" => text || class_only,
+            "font-size" => text,
+            p if p == "margin" || p.starts_with("margin-") => root,
+            _ => false,
+        };
+        match layout {
+            true => removed.push(decl),
+            false => kept.push(decl),
+        }
+    }
+    return (kept, removed);
+}
+
+/// Element name of the last compound of `selector`, lowercased. Empty for a
+/// compound of only classes, None for anything else (ids, attributes,
+/// universal selectors...)
+fn subject_element(selector: &str) -> Option<String> {
+    let last = selector
+        .rsplit(|c: char| c.is_whitespace() || c == '>' || c == '+' || c == '~')
+        .find(|s| !s.is_empty())?;
+    let last = last.split(':').next().unwrap_or_default();
+    let name_end = last.find(['.', '#', '[']).unwrap_or(last.len());
+    let (name, rest) = last.split_at(name_end);
+    if rest.contains(['#', '[']) {
+        return None;
+    }
+    if name.is_empty() && rest.is_empty() {
+        return None;
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    return Some(name.to_ascii_lowercase());
+}
+
+fn scope(selector: &str) -> String {
+    if let Some(rest) = selector.strip_prefix("html") {
+        if rest.is_empty() || !rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '-') {
+            return format!("html.{}{}", PUBLISHER_LAYOUT_CLASS, rest);
+        }
+    }
+    return format!(".{} {}", PUBLISHER_LAYOUT_CLASS, selector);
+}
+
+fn format_declarations(decls: &[&str]) -> String {
+    if decls.is_empty() {
+        return String::new();
+    }
+    let body = decls
+        .iter()
+        .map(|d| format!("\n    {};", d.trim()))
+        .collect::<String>();
+    return format!("{}\n", body);
+}
+
+/// Copies `css` to `out`, passing every style rule, including those nested
+/// in conditional at-rules, to `f` as its selectors and declaration block.
+/// A rule is replaced by whatever `f` returns, or kept if it returns None
+fn rewrite_rules(css: &str, out: &mut String, f: &mut dyn FnMut(&str, &str) -> Option<String>) {
+    let mut i = 0;
+    while i < css.len() {
+        let rest = &css[i..];
+        let skip = skip_trivia(rest);
+        if skip > 0 {
+            out.push_str(&rest[..skip]);
+            i += skip;
+            continue;
+        }
+
+        // prelude up to the block, or to the end of a statement at-rule
+        let Some(open) = find_top_level(rest, &['{', ';']) else {
+            out.push_str(rest);
+            return;
+        };
+        if rest.as_bytes()[open] == b';' {
+            out.push_str(&rest[..=open]);
+            i += open + 1;
+            continue;
+        }
+        let Some(close) = find_block_end(rest, open) else {
+            out.push_str(rest);
+            return;
+        };
+        let prelude = &rest[..open];
+        let body = &rest[open + 1..close];
+
+        if let Some(name) = at_rule_name(prelude) {
+            if NESTED_AT_RULES.contains(&name.as_str()) {
+                out.push_str(&rest[..=open]);
+                rewrite_rules(body, out, f);
+                out.push('}');
+            } else {
+                out.push_str(&rest[..=close]);
+            }
+        } else {
+            match f(prelude, body) {
+                Some(rule) => out.push_str(&rule),
+                None => out.push_str(&rest[..=close]),
+            }
+        }
+        i += close + 1;
+    }
+}
+
+/// Removes the `@font-face` rules of the stylesheet, including those nested
+/// in conditional at-rules. Returns the stylesheet and how many were removed
+pub fn remove_font_faces(css: &str) -> (String, usize) {
+    let mut out = String::with_capacity(css.len());
+    let removed = remove_at_rules(css, "font-face", &mut out);
+    return (out, removed);
+}
+
+/// Copies `css` to `out` without the at-rules called `name`, descending
+/// into conditional at-rules. Returns how many were left out
+fn remove_at_rules(css: &str, name: &str, out: &mut String) -> usize {
+    let mut removed = 0;
+    let mut i = 0;
+    while i < css.len() {
+        let rest = &css[i..];
+        let skip = skip_trivia(rest);
+        if skip > 0 {
+            out.push_str(&rest[..skip]);
+            i += skip;
+            continue;
+        }
+        let Some(open) = find_top_level(rest, &['{', ';']) else {
+            out.push_str(rest);
+            break;
+        };
+        if rest.as_bytes()[open] == b';' {
+            out.push_str(&rest[..=open]);
+            i += open + 1;
+            continue;
+        }
+        let Some(close) = find_block_end(rest, open) else {
+            out.push_str(rest);
+            break;
+        };
+        match at_rule_name(&rest[..open]) {
+            Some(n) if n == name => removed += 1,
+            Some(n) if NESTED_AT_RULES.contains(&n.as_str()) => {
+                out.push_str(&rest[..=open]);
+                removed += remove_at_rules(&rest[open + 1..close], name, out);
+                out.push('}');
+            }
+            _ => out.push_str(&rest[..=close]),
+        }
+        i += close + 1;
+    }
+    return removed;
+}
+
+/// Lowercased name of the at-rule with the prelude `prelude`, like `media`,
+/// or None for a style rule
+fn at_rule_name(prelude: &str) -> Option<String> {
+    let at = prelude.strip_prefix('@')?;
+    return Some(
+        at.split(|c: char| !c.is_ascii_alphanumeric() && c != '-')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase(),
+    );
+}
+
+/// Length of the whitespace and comments at the start of `s`
+fn skip_trivia(s: &str) -> usize {
+    let mut i = 0;
+    loop {
+        let rest = &s[i..];
+        let trimmed = rest.trim_start();
+        i += rest.len() - trimmed.len();
+        if !trimmed.starts_with("/*") {
+            return i;
+        }
+        match trimmed[2..].find("*/") {
+            Some(end) => i += end + 4,
+            None => return s.len(),
+        }
+    }
+}
+
+/// Byte index of the first of `chars` outside of strings, comments,
+/// parentheses and brackets
+fn find_top_level(s: &str, chars: &[char]) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut iter = s.char_indices().peekable();
+    while let Some((i, c)) = iter.next() {
+        match c {
+            '"' | '\'' => skip_string(&mut iter, c),
+            '/' if iter.peek().is_some_and(|(_, n)| *n == '*') => skip_comment(&mut iter),
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            _ if depth == 0 && chars.contains(&c) => return Some(i),
+            _ => {}
+        }
+    }
+    return None;
+}
+
+/// Byte index of the `}` closing the block opened at `open`
+fn find_block_end(s: &str, open: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut iter = s.char_indices().skip_while(|(i, _)| *i < open).peekable();
+    while let Some((i, c)) = iter.next() {
+        match c {
+            '"' | '\'' => skip_string(&mut iter, c),
+            '/' if iter.peek().is_some_and(|(_, n)| *n == '*') => skip_comment(&mut iter),
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    return None;
+}
+
+/// Splits `s` on `sep` where it isn't inside a string, comment or
+/// parentheses, like the `;` in a data URL
+fn split_top_level(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while let Some(i) = find_top_level(rest, &[sep]) {
+        parts.push(&rest[..i]);
+        rest = &rest[i + 1..];
+    }
+    parts.push(rest);
+    return parts;
+}
+
+fn skip_string(iter: &mut impl Iterator<Item = (usize, char)>, quote: char) {
+    while let Some((_, c)) = iter.next() {
+        match c {
+            '\\' => {
+                iter.next();
+            }
+            _ if c == quote => return,
+            _ => {}
+        }
+    }
+}
+
+fn skip_comment(iter: &mut impl Iterator<Item = (usize, char)>) {
+    iter.next();
+    let mut star = false;
+    for (_, c) in iter {
+        if star && c == '/' {
+            return;
+        }
+        star = c == '*';
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        has_vertical_writing_mode, is_vertical_writing_mode, neutralize_layout, remove_font_faces,
+        subject_element, unconstrain_declarations, unconstrain_page,
+    };
+
+    #[test]
+    fn test_subject_element() {
+        assert_eq!(subject_element("body").as_deref(), Some("body"));
+        assert_eq!(subject_element("div.chapter > P").as_deref(), Some("p"));
+        assert_eq!(subject_element(".calibre1").as_deref(), Some(""));
+        assert_eq!(subject_element("p:first-child").as_deref(), Some("p"));
+        assert_eq!(subject_element("p#intro"), None);
+        assert_eq!(subject_element("*"), None);
+    }
+
+    #[test]
+    fn test_neutralize_layout() {
+        let css = "/* { p } */\n@charset \"utf-8\";\n\
+            body { margin: 5%; color: black; font-size: 1.2em }\n\
+            h1 { font-size: 2em; line-height: 1 }\n\
+            @media amzn-kf8 { p, div { line-height: 1.5 !important; text-indent: 1em } }\n\
+            .note { line-height: 1.1; background: url(\"a;b.png\") }\n\
+            @font-face { font-family: x; font-size: 1em }";
+        let (out, moved) = neutralize_layout(css);
+        assert_eq!(moved, 4);
+        assert!(out.starts_with("/* { p } */\n@charset \"utf-8\";\n"));
+        assert!(out.contains("body {\n    color: black;\n}"));
+        assert!(out
+            .contains(".kepub-publisher-layout body {\n    margin: 5%;\n    font-size: 1.2em;\n}"));
+        assert!(out.contains("h1 { font-size: 2em; line-height: 1 }"));
+        assert!(out.contains(".kepub-publisher-layout p, .kepub-publisher-layout div {\n    line-height: 1.5 !important;\n}"));
+        assert!(out.contains("background: url(\"a;b.png\");"));
+        assert!(out.ends_with("@font-face { font-family: x; font-size: 1em }"));
+
+        let plain = "p { text-indent: 1em }";
+        assert_eq!(neutralize_layout(plain), (plain.to_string(), 0));
+    }
+
+    #[test]
+    fn test_vertical_writing_mode() {
+        assert!(has_vertical_writing_mode(
+            "/* writing-mode: vertical-rl */ p { margin: 0 }\n\
+             html { -epub-writing-mode: vertical-rl; }"
+        ));
+        assert!(has_vertical_writing_mode(
+            "@media all { body { writing-mode:TB-RL } }"
+        ));
+        assert!(!has_vertical_writing_mode(
+            "body { writing-mode: horizontal-tb; content: 'writing-mode: vertical-rl' }"
+        ));
+        assert!(is_vertical_writing_mode(
+            "-webkit-writing-mode: vertical-lr"
+        ));
+        assert!(!is_vertical_writing_mode("text-orientation: upright"));
+    }
+
+    #[test]
+    fn test_unconstrain_page() {
+        let css = "body { margin: 0 auto; max-width: 35em; color: black }\n\
+            body { max-width: 40em }\n\
+            html, body { width: 100%; margin-left: auto }\n\
+            div.page { margin: 0 auto; max-width: 35em }";
+        let (out, removed) = unconstrain_page(css);
+        assert_eq!(removed, 4);
+        assert!(out.starts_with("body {\n    color: black;\n}\n\nhtml, body"));
+        assert!(out.contains("html, body {\n    width: 100%;\n}"));
+        assert!(out.ends_with("div.page { margin: 0 auto; max-width: 35em }"));
+
+        assert_eq!(
+            unconstrain_declarations("margin: 0 auto; width: 600px; color: red;"),
+            ("color: red".to_string(), 2)
+        );
+    }
+    #[test]
+    fn test_remove_font_faces() {
+        let css = "@import url(\"fonts.css\");\n\
+            @font-face { font-family: \"A\"; src: url(\"a.otf\") }\n\
+            p { font-family: \"A\", serif }\n\
+            @media screen { @FONT-FACE { font-family: B; src: url(b.woff) } h1 { color: red } }";
+        let (out, removed) = remove_font_faces(css);
+        assert_eq!(removed, 2);
+        assert!(!out.contains("src:"));
+        assert!(out.starts_with("@import url(\"fonts.css\");\n"));
+        assert!(out.contains("p { font-family: \"A\", serif }"));
+        assert!(out.contains("@media screen {  h1 { color: red } }"));
+    }
+}
@@ -0,0 +1,356 @@
+//! A long-running conversion server on a unix socket, so that tools
+//! converting books one at a time don't pay for starting a process and
+//! loading the options every time.
+//!
+//! The protocol is line based. A client sends one `INPUT<TAB>OUT_DIR` line
+//! per book, then shuts down its side of the connection. The server answers
+//! every request, in order, with `ok<TAB>OUTPUT` or `error<TAB>MESSAGE`.
+//! Paths are used as they are, so clients should send absolute ones.
+
+use std::{
+    fs::{DirBuilder, Permissions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+};
+
+use crate::{
+    errors::{io_err, ConverterError},
+    logger::{debug, info, warning},
+};
+
+/// A book to convert
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub input: String,
+    /// Empty to write the kepub next to the input
+    pub out_dir: String,
+}
+
+/// Path of the written kepub, or why the book failed to convert
+pub type Response = Result<String, String>;
+
+impl Request {
+    fn parse(line: &str) -> Result<Self, String> {
+        return match line.split_once('\t') {
+            Some((input, out_dir)) if !input.is_empty() && !out_dir.contains('\t') => Ok(Self {
+                input: input.to_string(),
+                out_dir: out_dir.to_string(),
+            }),
+            _ => Err(format!(
+                "invalid request '{}', expected INPUT<TAB>OUT_DIR",
+                line
+            )),
+        };
+    }
+
+    fn to_line(&self) -> Result<String, ConverterError> {
+        if [&self.input, &self.out_dir]
+            .iter()
+            .any(|p| p.contains(['\t', '\n']))
+        {
+            return Err(io_err!(
+                ErrorKind::InvalidInput,
+                "Cannot send paths with tabs or line breaks: {}",
+                self.input
+            ));
+        }
+        return Ok(format!("{}\t{}\n", self.input, self.out_dir));
+    }
+}
+
+fn format_response(response: &Response) -> String {
+    let (status, text) = match response {
+        Ok(path) => ("ok", path),
+        Err(msg) => ("error", msg),
+    };
+    return format!("{}\t{}\n", status, text.replace(['\t', '\n'], " "));
+}
+
+fn parse_response(line: &str) -> Result<Response, ConverterError> {
+    return match line.split_once('\t') {
+        Some(("ok", path)) => Ok(Ok(path.to_string())),
+        Some(("error", msg)) => Ok(Err(msg.to_string())),
+        _ => Err(io_err!(
+            ErrorKind::InvalidData,
+            "Invalid response from the daemon: {}",
+            line
+        )),
+    };
+}
+
+/// The socket used when none is given: `kepub-rs.sock` in
+/// `$XDG_RUNTIME_DIR`, or else in a `kepub-rs-UID` directory of the
+/// temporary directory that only the current user can access
+pub fn default_socket() -> Result<PathBuf, ConverterError> {
+    let runtime = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute() && p.is_dir());
+    if let Some(dir) = runtime {
+        return Ok(dir.join("kepub-rs.sock"));
+    }
+    // SAFETY: getuid has no preconditions and can't fail
+    let uid = unsafe { libc::getuid() };
+    let dir = std::env::temp_dir().join(format!("kepub-rs-{}", uid));
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e.into()),
+    }
+    // anyone can create it first in a shared temporary directory
+    let meta = std::fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        return Err(io_err!(
+            ErrorKind::PermissionDenied,
+            "{:?} is not a private directory of the current user, give a socket with --socket",
+            dir
+        ));
+    }
+    return Ok(dir.join("kepub-rs.sock"));
+}
+
+/// A book waiting for a worker, with where to send its response
+type Job = (Request, mpsc::Sender<Response>);
+
+/// Listens on `socket` and converts the requested books with `convert` on
+/// `jobs` threads, until the process is stopped. A socket file left behind
+/// by a daemon that is gone is replaced, any other file is left alone
+pub fn serve<F>(socket: &Path, jobs: usize, convert: F) -> Result<(), ConverterError>
+where
+    F: Fn(&Request) -> Result<String, ConverterError> + Sync,
+{
+    if let Ok(meta) = std::fs::symlink_metadata(socket) {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(io_err!(
+                ErrorKind::AddrInUse,
+                "A daemon is already listening on {:?}",
+                socket
+            ));
+        }
+        if !meta.file_type().is_socket() {
+            return Err(io_err!(
+                ErrorKind::AlreadyExists,
+                "{:?} exists and is not a socket",
+                socket
+            ));
+        }
+        debug!("Removing stale socket {:?}", socket);
+        std::fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)?;
+    // only the owner may send books and read the replies
+    std::fs::set_permissions(socket, Permissions::from_mode(0o600))?;
+    info!("Listening on {:?} with {} workers", socket, jobs);
+
+    let (queue, jobs_rx) = mpsc::channel::<Job>();
+    let jobs_rx = Mutex::new(jobs_rx);
+    let convert = &convert;
+    let worker = || loop {
+        let job = jobs_rx.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        let Ok((request, reply)) = job else {
+            return;
+        };
+        let res = convert(&request);
+        match &res {
+            Ok(out_path) => info!("Converted {} to {}", request.input, out_path),
+            Err(e) => warning!("{}: {}", request.input, e),
+        }
+        // the client may have hung up
+        let _ = reply.send(res.map_err(|e| e.to_string()));
+    };
+    std::thread::scope(|s| {
+        for _ in 0..jobs.max(1) {
+            s.spawn(worker);
+        }
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let queue = queue.clone();
+                    s.spawn(move || {
+                        if let Err(e) = handle_client(stream, &queue) {
+                            debug!("Client connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warning!("Cannot accept connection: {}", e),
+            }
+        }
+    });
+    return Ok(());
+}
+
+/// Queues the requests of a client and writes back the responses in
+/// request order, each as soon as it and those before it are done
+fn handle_client(stream: UnixStream, queue: &mpsc::Sender<Job>) -> Result<(), ConverterError> {
+    let mut writer = stream.try_clone()?;
+    let (pending_tx, pending_rx) = mpsc::channel::<mpsc::Receiver<Response>>();
+    return std::thread::scope(|s| {
+        let responder = s.spawn(move || -> std::io::Result<()> {
+            for pending in pending_rx {
+                let response = pending
+                    .recv()
+                    .unwrap_or_else(|_| Err("conversion was aborted".to_string()));
+                writer.write_all(format_response(&response).as_bytes())?;
+            }
+            return Ok(());
+        });
+
+        for line in BufReader::new(&stream).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let (reply, pending) = mpsc::channel();
+            match Request::parse(&line) {
+                Ok(request) => {
+                    debug!("Queued {}", request.input);
+                    let _ = queue.send((request, reply));
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                }
+            }
+            if pending_tx.send(pending).is_err() {
+                break;
+            }
+        }
+        drop(pending_tx);
+        let res = responder.join().unwrap_or(Ok(()));
+        let _ = stream.shutdown(Shutdown::Both);
+        return Ok(res?);
+    });
+}
+
+/// Sends `requests` to the daemon listening on `socket` and calls
+/// `on_response` with the index of each request and its response, in
+/// request order
+pub fn submit<F>(
+    socket: &Path,
+    requests: &[Request],
+    mut on_response: F,
+) -> Result<(), ConverterError>
+where
+    F: FnMut(usize, Response),
+{
+    let stream = UnixStream::connect(socket).map_err(|e| {
+        return io_err!(
+            e.kind(),
+            "Cannot connect to the daemon at {:?}: {}",
+            socket,
+            e
+        );
+    })?;
+    let mut writer = &stream;
+    for request in requests {
+        writer.write_all(request.to_line()?.as_bytes())?;
+    }
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut answered = 0;
+    for line in BufReader::new(&stream).lines() {
+        if answered == requests.len() {
+            break;
+        }
+        let response = parse_response(&line?)?;
+        on_response(answered, response);
+        answered += 1;
+    }
+    if answered < requests.len() {
+        return Err(io_err!(
+            ErrorKind::UnexpectedEof,
+            "The daemon answered {} of {} requests",
+            answered,
+            requests.len()
+        ));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod test {
+    use std::{os::unix::fs::PermissionsExt, path::Path};
+
+    use super::{format_response, parse_response, serve, submit, Request};
+    use crate::{errors::ConverterError, workdir::WorkDir};
+
+    #[test]
+    fn test_protocol() {
+        let request = Request {
+            input: "/books/a b.epub".to_string(),
+            out_dir: String::new(),
+        };
+        let line = request.to_line().unwrap();
+        assert_eq!(Request::parse(line.trim_end_matches('\n')), Ok(request));
+        assert!(Request::parse("a.epub").is_err());
+        assert!(Request {
+            input: "a\nb".to_string(),
+            out_dir: String::new(),
+        }
+        .to_line()
+        .is_err());
+
+        let err = Err("bad\tzip\nfile".to_string());
+        let line = format_response(&err);
+        assert_eq!(line, "error\tbad zip file\n");
+        assert_eq!(
+            parse_response(line.trim_end()).unwrap(),
+            Err("bad zip file".to_string())
+        );
+        assert!(parse_response("maybe").is_err());
+    }
+
+    #[test]
+    fn test_serve() {
+        let socket =
+            std::env::temp_dir().join(format!("kepub-rs-test-{}.sock", std::process::id()));
+        let server = socket.clone();
+        std::thread::spawn(move || {
+            let _ = serve(&server, 2, |r| {
+                return match r.input.strip_suffix(".epub") {
+                    Some(stem) => Ok(format!("{}/{}.kepub.epub", r.out_dir, stem)),
+                    None => Err(ConverterError::Other("not an epub".to_string())),
+                };
+            });
+        });
+        for _ in 0..100 {
+            if socket.exists() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let requests = ["a.epub", "b.pdf", "c.epub"].map(|input| Request {
+            input: input.to_string(),
+            out_dir: "out".to_string(),
+        });
+        let mut responses = Vec::new();
+        submit(&socket, &requests, |i, r| responses.push((i, r))).unwrap();
+        assert_eq!(
+            responses,
+            [
+                (0, Ok("out/a.kepub.epub".to_string())),
+                (1, Err("not an epub".to_string())),
+                (2, Ok("out/c.kepub.epub".to_string())),
+            ]
+        );
+        assert!(submit(Path::new("/nonexistent/kepub.sock"), &requests, |_, _| {}).is_err());
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[test]
+    fn test_serve_not_a_socket() {
+        let dir = WorkDir::create_in(&std::env::temp_dir(), "kepub-rs-test").unwrap();
+        let notes = dir.join("notes.txt");
+        std::fs::write(&notes, "keep me").unwrap();
+        assert!(serve(&notes, 1, |_| return Ok(String::new())).is_err());
+        assert_eq!(std::fs::read_to_string(&notes).unwrap(), "keep me");
+    }
+}
@@ -0,0 +1,228 @@
+//! Content-level comparison of two books, epubs or kepubs, to tell whether
+//! a new edition changed anything worth converting again.
+//!
+//! Only what a reader sees is compared: the main metadata, the documents of
+//! the spine and the text of each. Kobo markup, file layout, styles and
+//! whitespace are ignored.
+
+use std::{collections::BTreeMap, fs::File, io::Read, path::Path};
+
+use xmltree::Element;
+use zip::ZipArchive;
+
+use crate::{
+    errors::{xml_err, ConverterError},
+    href,
+    logger::debug,
+    opf::{self, Package},
+    text::{self, Normalization},
+    verify::{self, TextDiff},
+};
+
+/// Metadata elements compared, by local name
+const METADATA: [&str; 6] = [
+    "title",
+    "creator",
+    "publisher",
+    "language",
+    "identifier",
+    "date",
+];
+
+/// A way in which the second book differs from the first
+#[derive(Debug, Clone, PartialEq)]
+pub enum Difference {
+    Metadata {
+        name: String,
+        old: Vec<String>,
+        new: Vec<String>,
+    },
+    /// A spine document, by href relative to the package document, that
+    /// only the old book has
+    Removed(String),
+    /// A spine document only the new book has
+    Added(String),
+    /// Both books have the same spine documents, in a different order
+    Reordered,
+    /// The first differing stretch of text of a document both books have
+    Text(TextDiff),
+}
+
+/// What is compared of one book
+struct BookContent {
+    metadata: BTreeMap<&'static str, Vec<String>>,
+    /// Spine documents, by href relative to the package document
+    spine: Vec<String>,
+    /// Text of each spine document
+    text: BTreeMap<String, String>,
+}
+
+impl BookContent {
+    fn read(path: &Path) -> Result<Self, ConverterError> {
+        let mut zip = ZipArchive::new(File::open(path)?)?;
+        let opf_name = find_opf(&mut zip)?;
+        let mut buf = Vec::new();
+        zip.by_name(&opf_name)?.read_to_end(&mut buf)?;
+        let pkg = Package::parse(buf.as_slice())?;
+
+        let metadata = METADATA
+            .iter()
+            .map(|&name| return (name, pkg.metadata(name)))
+            .collect();
+
+        let mut spine = Vec::new();
+        let mut text = BTreeMap::new();
+        for item in pkg.spine() {
+            let Some(doc) = pkg.item(&item.idref) else {
+                continue;
+            };
+            let rel = href::normalize(&doc.href);
+            if text.contains_key(&rel) {
+                continue;
+            }
+            let body = match verify::read_body(&mut zip, &href::resolve(&opf_name, &doc.href)) {
+                Ok(Some(mut body)) => {
+                    verify::strip_kobo(&mut body);
+                    verify::text_content(&body)
+                }
+                Ok(None) => String::new(),
+                Err(e) => {
+                    debug!("{}: cannot read {}: {}", path.display(), rel, e);
+                    String::new()
+                }
+            };
+            // undo what a conversion may have changed
+            let body = text::strip_word_breaks(&Normalization::Nfc.normalize(&body).0).0;
+            spine.push(rel.clone());
+            text.insert(rel, body);
+        }
+        return Ok(Self {
+            metadata,
+            spine,
+            text,
+        });
+    }
+}
+
+/// Name of the package document in `zip`, from `META-INF/container.xml` or
+/// else the first `.opf` file
+fn find_opf(zip: &mut ZipArchive<File>) -> Result<String, ConverterError> {
+    let from_container = zip
+        .by_name("META-INF/container.xml")
+        .ok()
+        .and_then(|f| Element::parse(f).ok())
+        .and_then(|root| opf::rootfile_path(&root))
+        .map(|p| href::normalize(&p));
+    if let Some(name) = from_container.filter(|n| zip.index_for_name(n).is_some()) {
+        return Ok(name);
+    }
+    return zip
+        .file_names()
+        .find(|n| n.to_ascii_lowercase().ends_with(".opf"))
+        .map(String::from)
+        .ok_or_else(|| return xml_err!("Could not find a .opf package document in epub archive"));
+}
+
+/// Compares the books at `old` and `new`, returning every difference found
+pub fn diff_books(old: &Path, new: &Path) -> Result<Vec<Difference>, ConverterError> {
+    let old = BookContent::read(old)?;
+    let new = BookContent::read(new)?;
+    return Ok(diff_content(&old, &new));
+}
+
+fn diff_content(old: &BookContent, new: &BookContent) -> Vec<Difference> {
+    let mut diffs = Vec::new();
+    for (name, values) in &old.metadata {
+        let new_values = new.metadata.get(name).cloned().unwrap_or_default();
+        if *values != new_values {
+            diffs.push(Difference::Metadata {
+                name: name.to_string(),
+                old: values.clone(),
+                new: new_values,
+            });
+        }
+    }
+
+    for h in old.spine.iter().filter(|h| !new.text.contains_key(*h)) {
+        diffs.push(Difference::Removed(h.clone()));
+    }
+    for h in new.spine.iter().filter(|h| !old.text.contains_key(*h)) {
+        diffs.push(Difference::Added(h.clone()));
+    }
+    let common = |spine: &[String], other: &BTreeMap<String, String>| {
+        return spine
+            .iter()
+            .filter(|h| other.contains_key(*h))
+            .cloned()
+            .collect::<Vec<_>>();
+    };
+    if common(&old.spine, &new.text) != common(&new.spine, &old.text) {
+        diffs.push(Difference::Reordered);
+    }
+
+    for h in &old.spine {
+        let (Some(a), Some(b)) = (old.text.get(h), new.text.get(h)) else {
+            continue;
+        };
+        if let Some((kind, word, o, c)) = verify::diff_words(a, b) {
+            diffs.push(Difference::Text(TextDiff {
+                file: h.clone(),
+                kind,
+                word,
+                original: o,
+                converted: c,
+            }));
+        }
+    }
+    return diffs;
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{diff_content, BookContent, Difference};
+    use crate::verify::DiffKind;
+
+    fn book(title: &str, chapters: &[(&str, &str)]) -> BookContent {
+        return BookContent {
+            metadata: BTreeMap::from([("title", vec![title.to_string()])]),
+            spine: chapters.iter().map(|(h, _)| h.to_string()).collect(),
+            text: chapters
+                .iter()
+                .map(|(h, t)| (h.to_string(), t.to_string()))
+                .collect(),
+        };
+    }
+
+    #[test]
+    fn test_diff_content() {
+        let old = book("Book", &[("a.xhtml", "One two."), ("b.xhtml", "Three.")]);
+        assert!(diff_content(&old, &old).is_empty());
+
+        let new = book(
+            "Book, 2nd edition",
+            &[
+                ("b.xhtml", "Three."),
+                ("a.xhtml", "One  two!"),
+                ("c.xhtml", ""),
+            ],
+        );
+        let diffs = diff_content(&old, &new);
+        assert_eq!(diffs.len(), 4);
+        assert!(matches!(&diffs[0], Difference::Metadata { name, .. } if name == "title"));
+        assert_eq!(diffs[1], Difference::Added("c.xhtml".to_string()));
+        assert_eq!(diffs[2], Difference::Reordered);
+        match &diffs[3] {
+            Difference::Text(d) => {
+                assert_eq!(d.file, "a.xhtml");
+                assert_eq!(d.kind, DiffKind::Changed);
+                assert_eq!(
+                    (d.original.as_str(), d.converted.as_str()),
+                    ("two.", "two!")
+                );
+            }
+            d => panic!("unexpected {:?}", d),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Checks run before a book is extracted or written, so that a full disk or
+//! a read-only output directory fails the conversion right away, with an
+//! error saying so, instead of halfway through with a bare I/O error.
+
+use std::{fs::OpenOptions, path::Path};
+
+use zip::ZipArchive;
+
+use crate::{errors::ConverterError, logger::debug};
+
+/// Bytes free for the current user on the file system holding `path`, or
+/// None when it cannot be told
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c_path is a valid C string and stat is only read when
+    // statvfs succeeded and filled it in
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    // some network and virtual file systems report no blocks at all
+    if stat.f_blocks == 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    return Some(stat.f_bavail as u64 * stat.f_frsize as u64);
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    return None;
+}
+
+/// Fails unless files can be created in the directory `dir`, by creating
+/// and removing one
+pub fn check_writable(dir: &Path) -> Result<(), ConverterError> {
+    let probe = dir.join(format!(".kepub-rs-probe-{}", std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|e| return ConverterError::NotWritable(dir.to_path_buf(), e.to_string()))?;
+    let _ = std::fs::remove_file(&probe);
+    return Ok(());
+}
+
+/// Fails if the file system holding `dir` has less than `needed` bytes
+/// free. Passes when the free space is unknown
+pub fn check_space(dir: &Path, needed: u64) -> Result<(), ConverterError> {
+    let Some(available) = free_space(dir) else {
+        debug!("Cannot tell the free space in {:?}", dir);
+        return Ok(());
+    };
+    debug!("{:?} has {} bytes free, {} needed", dir, available, needed);
+    if available < needed {
+        return Err(ConverterError::NoSpace {
+            path: dir.to_path_buf(),
+            needed,
+            available,
+        });
+    }
+    return Ok(());
+}
+
+/// Bytes taken by the files of `epub` once extracted
+pub fn extracted_size<R: std::io::Read + std::io::Seek>(
+    epub: &mut ZipArchive<R>,
+) -> Result<u64, ConverterError> {
+    let mut size = 0;
+    for i in 0..epub.len() {
+        size += epub.by_index_raw(i)?.size();
+    }
+    return Ok(size);
+}
+
+/// Bytes the kepub of `epub` is expected to take: its compressed files,
+/// with a quarter more for the kobospans added to the markup
+pub fn output_size<R: std::io::Read + std::io::Seek>(
+    epub: &mut ZipArchive<R>,
+) -> Result<u64, ConverterError> {
+    let mut size = 0;
+    for i in 0..epub.len() {
+        size += epub.by_index_raw(i)?.compressed_size();
+    }
+    return Ok(size + size / 4);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_space, check_writable, free_space};
+    use crate::errors::ConverterError;
+
+    #[test]
+    fn test_checks() {
+        let dir = std::env::temp_dir();
+        assert!(check_writable(&dir).is_ok());
+        let probe = dir.join(format!(".kepub-rs-probe-{}", std::process::id()));
+        assert!(!probe.exists());
+
+        // a file where the directory should be
+        let file = dir.join(format!("kepub-rs-not-a-dir-{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let res = check_writable(&file);
+        let _ = std::fs::remove_file(&file);
+        assert!(matches!(res, Err(ConverterError::NotWritable(..))));
+
+        assert!(check_space(&dir, 0).is_ok());
+        if cfg!(unix) {
+            assert!(free_space(&dir).is_some());
+            assert!(matches!(
+                check_space(&dir, u64::MAX),
+                Err(ConverterError::NoSpace { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! Search, traversal and construction helpers for [`xmltree::Element`] trees.
+//!
+//! All `find_*` methods search the descendants of an element depth-first,
+//! in document order, and never match the element they are called on.
+
+mod builder;
+mod selector;
+
+pub use builder::El;
+pub use selector::Selector;
+use xmltree::{Element, Namespace, XMLNode};
+
+/// Namespace of the `epub:` attributes of content documents
+pub const OPS_NAMESPACE: &str = "http://www.idpf.org/2007/ops";
+
+/// Key of the `epub:type` attribute, which parsing keeps with its prefix
+pub const EPUB_TYPE: &str = "epub:type";
+
+/// Whether `value` is one of the words of the `epub:type` of `elem`
+pub fn has_epub_type(elem: &Element, value: &str) -> bool {
+    return elem
+        .attributes
+        .get(EPUB_TYPE)
+        .is_some_and(|t| return t.split_whitespace().any(|w| w == value));
+}
+
+/// Declares the `epub` prefix on `root`, for `epub:` attributes added to
+/// the document
+pub fn declare_epub_namespace(root: &mut Element) {
+    let mut namespaces = root.namespaces.take().unwrap_or_else(Namespace::empty);
+    namespaces.put("epub", OPS_NAMESPACE);
+    root.namespaces = Some(namespaces);
+}
+
+pub trait ElementExt {
+    /// Finds the first descendant element with a matching tag name
+    fn find_first(&self, tag: &str) -> Option<&Element>;
+    fn find_first_mut(&mut self, tag: &str) -> Option<&mut Element>;
+
+    /// Finds the first descendant element with a matching tag name that also
+    /// contains the provided attribute (key, value) pairs
+    fn find_first_with_attrs(&self, tag: &str, attrs: &[(&str, &str)]) -> Option<&Element>;
+    fn find_first_with_attrs_mut(
+        &mut self,
+        tag: &str,
+        attrs: &[(&str, &str)],
+    ) -> Option<&mut Element>;
+
+    /// Collects every descendant element with a matching tag name
+    fn find_all(&self, tag: &str) -> Vec<&Element>;
+
+    /// Collects every descendant element with a matching tag name that also
+    /// contains the provided attribute (key, value) pairs
+    fn find_all_with_attrs(&self, tag: &str, attrs: &[(&str, &str)]) -> Vec<&Element>;
+
+    /// Iterates over the direct child elements with a matching tag name
+    fn find_children<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a Element>;
+
+    /// Iterates over the descendant elements that have attribute `key` set to
+    /// `value`, whatever their tag name
+    fn find_with_attr<'a>(
+        &'a self,
+        key: &'a str,
+        value: &'a str,
+    ) -> impl Iterator<Item = &'a Element>;
+
+    /// Iterates over the descendant elements for which `pred` returns true
+    fn find_where<'a, P>(&'a self, pred: P) -> impl Iterator<Item = &'a Element>
+    where
+        P: FnMut(&&'a Element) -> bool;
+
+    /// Collects every descendant element matching `selector`
+    fn select(&self, selector: &Selector) -> Vec<&Element>;
+
+    /// Finds the first descendant element matching `selector`
+    fn select_first(&self, selector: &Selector) -> Option<&Element>;
+    fn select_first_mut(&mut self, selector: &Selector) -> Option<&mut Element>;
+
+    /// Creates an iterator that returns this element followed by all of its
+    /// descendant Elements, searching depth-first
+    ///
+    /// Example:
+    ///
+    /// ```xml
+    /// <root id='root'>
+    ///   <child id='c1'>
+    ///     <grandchild id='c1-gc1'></grandchild>
+    ///     <grandchild id='c1-gc2'></grandchild>
+    ///   </child>
+    ///   <child id='c2'></child>
+    /// </root>
+    /// ```
+    ///
+    /// Elements will be returned in order:
+    /// root, c1, c1-gc1, c1-gc2, c2
+    fn descendants(&self) -> Descendants<'_>;
+
+    /// Visits this element and then every descendant element depth-first,
+    /// in document order, with mutable access. `f` receives the element and
+    /// its depth below this one. Changes `f` makes to an element's children
+    /// are seen when those children are visited
+    fn walk_mut<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut Element, usize) -> Walk;
+
+    /// Rebuilds the descendants of this element in document order, letting
+    /// `rewriter` replace, drop or insert nodes along the way.
+    ///
+    /// Uses an explicit stack rather than recursion, so deeply nested
+    /// documents can't overflow the call stack
+    fn rewrite<R: Rewriter>(&mut self, rewriter: &mut R);
+}
+
+/// Whether to visit the children of an element during a traversal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Walk {
+    Descend,
+    Skip,
+}
+
+/// Callbacks used by [`ElementExt::rewrite`]. Default implementations keep
+/// the tree unchanged
+pub trait Rewriter {
+    /// Called on entering an element, before any of its children. Returning
+    /// [`Walk::Skip`] keeps the children as they are
+    fn enter(&mut self, _elem: &mut Element) -> Walk {
+        return Walk::Descend;
+    }
+
+    /// Called once the children of `elem` have been rewritten (or skipped).
+    /// Whatever is pushed to `out` replaces the element in its parent
+    fn leave(&mut self, elem: Element, out: &mut Vec<XMLNode>) {
+        out.push(XMLNode::Element(elem));
+    }
+
+    /// Called for every text, comment, CDATA or processing instruction child
+    /// of `parent`. Whatever is pushed to `out` replaces the node. `parent`'s
+    /// children are detached while it is being rewritten
+    fn node(&mut self, _parent: &Element, node: XMLNode, out: &mut Vec<XMLNode>) {
+        out.push(node);
+    }
+}
+
+/// Returns true if `elem` has tag name `tag` and every attribute in `attrs`
+pub fn matches(elem: &Element, tag: &str, attrs: &[(&str, &str)]) -> bool {
+    return elem.name == tag
+        && attrs
+            .iter()
+            .all(|(k, v)| elem.attributes.get(*k).is_some_and(|val| val == v));
+}
+
+impl ElementExt for Element {
+    fn find_first(&self, tag: &str) -> Option<&Element> {
+        return self.find_first_with_attrs(tag, &[]);
+    }
+
+    fn find_first_mut(&mut self, tag: &str) -> Option<&mut Element> {
+        return self.find_first_with_attrs_mut(tag, &[]);
+    }
+
+    fn find_first_with_attrs(&self, tag: &str, attrs: &[(&str, &str)]) -> Option<&Element> {
+        return self.descendants().skip(1).find(|e| matches(e, tag, attrs));
+    }
+
+    fn find_first_with_attrs_mut(
+        &mut self,
+        tag: &str,
+        attrs: &[(&str, &str)],
+    ) -> Option<&mut Element> {
+        for c in self.children.iter_mut() {
+            if let XMLNode::Element(element) = c {
+                if matches(element, tag, attrs) {
+                    return Some(element);
+                }
+                if let Some(e) = element.find_first_with_attrs_mut(tag, attrs) {
+                    return Some(e);
+                }
+            }
+        }
+
+        return None;
+    }
+
+    fn find_all(&self, tag: &str) -> Vec<&Element> {
+        return self.find_all_with_attrs(tag, &[]);
+    }
+
+    fn find_all_with_attrs(&self, tag: &str, attrs: &[(&str, &str)]) -> Vec<&Element> {
+        return self.find_where(|e| matches(e, tag, attrs)).collect();
+    }
+
+    fn find_children<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a Element> {
+        return self
+            .children
+            .iter()
+            .filter_map(|c| c.as_element())
+            .filter(move |e| e.name == tag);
+    }
+
+    fn find_with_attr<'a>(
+        &'a self,
+        key: &'a str,
+        value: &'a str,
+    ) -> impl Iterator<Item = &'a Element> {
+        return self.find_where(move |e| e.attributes.get(key).is_some_and(|v| v == value));
+    }
+
+    fn find_where<'a, P>(&'a self, pred: P) -> impl Iterator<Item = &'a Element>
+    where
+        P: FnMut(&&'a Element) -> bool,
+    {
+        return self.descendants().skip(1).filter(pred);
+    }
+
+    fn select(&self, selector: &Selector) -> Vec<&Element> {
+        return selector
+            .match_paths(self, false)
+            .iter()
+            .filter_map(|p| follow_path(self, p))
+            .collect();
+    }
+
+    fn select_first(&self, selector: &Selector) -> Option<&Element> {
+        let path = selector.match_paths(self, true).into_iter().next()?;
+        return follow_path(self, &path);
+    }
+
+    fn select_first_mut(&mut self, selector: &Selector) -> Option<&mut Element> {
+        let path = selector.match_paths(self, true).into_iter().next()?;
+        let mut e = self;
+        for idx in path {
+            e = match e.children.get_mut(idx) {
+                Some(XMLNode::Element(c)) => c,
+                _ => return None,
+            };
+        }
+        return Some(e);
+    }
+
+    fn descendants(&self) -> Descendants<'_> {
+        return Descendants::new(self);
+    }
+
+    fn walk_mut<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Element, usize) -> Walk,
+    {
+        if f(self, 0) == Walk::Skip {
+            return;
+        }
+
+        let mut stack = vec![self.children.iter_mut()];
+        while let Some(top) = stack.last_mut() {
+            match top.next() {
+                Some(XMLNode::Element(e)) => {
+                    if f(e, stack.len()) == Walk::Descend {
+                        stack.push(e.children.iter_mut());
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    stack.pop();
+                }
+            }
+        }
+    }
+
+    fn rewrite<R: Rewriter>(&mut self, rewriter: &mut R) {
+        // Each frame owns an element whose children are being rebuilt, the
+        // children still to process and the rebuilt children so far
+        struct Frame {
+            elem: Element,
+            pending: std::vec::IntoIter<XMLNode>,
+            done: Vec<XMLNode>,
+        }
+
+        let children = std::mem::take(&mut self.children);
+        let root = std::mem::replace(self, Element::new(""));
+        let mut stack = vec![Frame {
+            elem: root,
+            pending: children.into_iter(),
+            done: Vec::new(),
+        }];
+
+        loop {
+            let top = stack.last_mut().unwrap();
+            match top.pending.next() {
+                Some(XMLNode::Element(mut e)) => {
+                    if rewriter.enter(&mut e) == Walk::Skip {
+                        rewriter.leave(e, &mut top.done);
+                        continue;
+                    }
+                    let children = std::mem::take(&mut e.children);
+                    stack.push(Frame {
+                        elem: e,
+                        pending: children.into_iter(),
+                        done: Vec::new(),
+                    });
+                }
+                Some(node) => rewriter.node(&top.elem, node, &mut top.done),
+                None => {
+                    let mut finished = stack.pop().unwrap();
+                    finished.elem.children = finished.done;
+                    match stack.last_mut() {
+                        Some(parent) => rewriter.leave(finished.elem, &mut parent.done),
+                        None => {
+                            *self = finished.elem;
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Walks a path of child indexes down from `root`
+fn follow_path<'a>(root: &'a Element, path: &[usize]) -> Option<&'a Element> {
+    let mut e = root;
+    for idx in path {
+        e = e.children.get(*idx)?.as_element()?;
+    }
+    return Some(e);
+}
+
+pub struct Descendants<'a> {
+    stack: Vec<&'a Element>,
+}
+
+impl<'a> Descendants<'a> {
+    fn new(root: &'a Element) -> Self {
+        Self { stack: vec![root] }
+    }
+}
+
+impl<'a> Iterator for Descendants<'a> {
+    type Item = &'a Element;
+    fn next(&mut self) -> Option<Self::Item> {
+        let c = self.stack.pop()?;
+
+        for child in c.children.iter().filter_map(|x| x.as_element()).rev() {
+            self.stack.push(child);
+        }
+
+        return Some(c);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use xmltree::Element;
+
+    use xmltree::XMLNode;
+
+    use super::{ElementExt, Rewriter, Walk};
+
+    const TEST_XML: &str = r"<root id='root'>
+	<child id='c1'>
+		<grandchild id='c1-gc1'></grandchild>
+		<grandchild id='c1-gc2' class='x'></grandchild>
+	</child>
+	<child id='c2'>Hi</child>
+	<child id='c3'>
+		<grandchild id='c3-gc1' class='x'></grandchild>
+		<grandchild id='c3-gc2'>
+			<greatgrandchild id='c3-gc2-ggc1'></greatgrandchild>
+		</grandchild>
+	</child>
+</root>";
+
+    #[test]
+    fn test_iter() {
+        const ORDER: [&str; 9] = [
+            "root",
+            "c1",
+            "c1-gc1",
+            "c1-gc2",
+            "c2",
+            "c3",
+            "c3-gc1",
+            "c3-gc2",
+            "c3-gc2-ggc1",
+        ];
+
+        let root = Element::parse(TEST_XML.as_bytes()).unwrap();
+        for (i, d) in root.descendants().enumerate() {
+            let id = &d.attributes["id"];
+            assert_eq!(id, ORDER[i])
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let mut root = Element::parse(TEST_XML.as_bytes()).unwrap();
+
+        assert_eq!(
+            root.find_first("grandchild").unwrap().attributes["id"],
+            "c1-gc1"
+        );
+        assert_eq!(
+            root.find_first_with_attrs("grandchild", &[("class", "x")])
+                .unwrap()
+                .attributes["id"],
+            "c1-gc2"
+        );
+        assert!(root.find_first("root").is_none());
+
+        let ids = root
+            .find_all_with_attrs("grandchild", &[("class", "x")])
+            .iter()
+            .map(|e| e.attributes["id"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["c1-gc2", "c3-gc1"]);
+        assert_eq!(root.find_all("child").len(), 3);
+
+        root.find_first_mut("greatgrandchild")
+            .unwrap()
+            .attributes
+            .insert("class".to_string(), "x".to_string());
+        assert_eq!(
+            root.find_all_with_attrs("greatgrandchild", &[("class", "x")])
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_find_iters() {
+        let root = Element::parse(TEST_XML.as_bytes()).unwrap();
+        let c3 = root.find_with_attr("id", "c3").next().unwrap();
+
+        let ids = c3
+            .find_children("grandchild")
+            .map(|e| e.attributes["id"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["c3-gc1", "c3-gc2"]);
+        assert_eq!(root.find_children("grandchild").count(), 0);
+
+        let ids = root
+            .find_where(|e| e.attributes["id"].ends_with("gc1"))
+            .map(|e| e.attributes["id"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["c1-gc1", "c3-gc1", "c3-gc2-ggc1"]);
+        assert_eq!(root.find_with_attr("id", "root").count(), 0);
+    }
+
+    #[test]
+    fn test_walk_mut() {
+        let mut root = Element::parse(TEST_XML.as_bytes()).unwrap();
+        let mut visited = Vec::new();
+        root.walk_mut(|e, depth| {
+            visited.push((e.attributes["id"].clone(), depth));
+            if e.attributes["id"] == "c3" {
+                return Walk::Skip;
+            }
+            e.attributes.insert("seen".to_string(), "1".to_string());
+            return Walk::Descend;
+        });
+
+        assert_eq!(visited.len(), 6);
+        assert_eq!(visited[2], ("c1-gc1".to_string(), 2));
+        assert_eq!(
+            root.find_all_with_attrs("grandchild", &[("seen", "1")])
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_rewrite() {
+        // Unwraps grandchildren and uppercases text
+        struct R;
+        impl Rewriter for R {
+            fn leave(&mut self, elem: Element, out: &mut Vec<XMLNode>) {
+                match elem.name.as_str() {
+                    "grandchild" => out.extend(elem.children),
+                    _ => out.push(XMLNode::Element(elem)),
+                }
+            }
+            fn node(&mut self, _parent: &Element, node: XMLNode, out: &mut Vec<XMLNode>) {
+                if let XMLNode::Text(t) = node {
+                    out.push(XMLNode::Text(t.to_uppercase()));
+                }
+            }
+        }
+
+        let mut root = Element::parse(TEST_XML.as_bytes()).unwrap();
+        root.rewrite(&mut R);
+
+        assert_eq!(root.name, "root");
+        assert_eq!(root.find_all("grandchild").len(), 0);
+        let ids = root
+            .descendants()
+            .map(|e| e.attributes["id"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["root", "c1", "c2", "c3", "c3-gc2-ggc1"]);
+        let c2 = root
+            .find_first_with_attrs("child", &[("id", "c2")])
+            .unwrap();
+        assert_eq!(c2.get_text().unwrap(), "HI");
+    }
+
+    #[test]
+    fn test_rewrite_deep() {
+        let depth = 100_000;
+        let mut root = Element::new("root");
+        let mut e = Element::new("a");
+        for _ in 1..depth {
+            let mut p = Element::new("a");
+            p.children.push(XMLNode::Element(e));
+            e = p;
+        }
+        root.children.push(XMLNode::Element(e));
+
+        struct Count(usize);
+        impl Rewriter for Count {
+            fn enter(&mut self, _elem: &mut Element) -> Walk {
+                self.0 += 1;
+                return Walk::Descend;
+            }
+        }
+        let mut c = Count(0);
+        root.rewrite(&mut c);
+        assert_eq!(c.0, depth);
+
+        // Dropping deeply nested xmltree elements recurses, so leak instead
+        std::mem::forget(root);
+    }
+}
@@ -0,0 +1,83 @@
+use xmltree::{Element, XMLNode};
+
+/// Fluent builder for generated markup, e.g.
+/// `El::new("span").class("kobospan").id("kobo.1.1").text("Hi").build()`
+#[derive(Debug, Clone)]
+pub struct El(Element);
+
+impl El {
+    pub fn new(name: &str) -> Self {
+        return Self(Element::new(name));
+    }
+
+    /// Sets an attribute, replacing any previous value
+    pub fn attr(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.0.attributes.insert(key.to_string(), value.into());
+        return self;
+    }
+
+    pub fn id(self, id: impl Into<String>) -> Self {
+        return self.attr("id", id);
+    }
+
+    pub fn class(self, class: impl Into<String>) -> Self {
+        return self.attr("class", class);
+    }
+
+    /// Appends a text node
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        self.0.children.push(XMLNode::Text(text.into()));
+        return self;
+    }
+
+    /// Appends a child node or element
+    pub fn child(mut self, child: impl Into<XMLNode>) -> Self {
+        self.0.children.push(child.into());
+        return self;
+    }
+
+    /// Appends several child nodes
+    pub fn children(mut self, children: impl IntoIterator<Item = XMLNode>) -> Self {
+        self.0.children.extend(children);
+        return self;
+    }
+
+    pub fn build(self) -> Element {
+        return self.0;
+    }
+}
+
+impl From<El> for Element {
+    fn from(value: El) -> Self {
+        return value.0;
+    }
+}
+
+impl From<El> for XMLNode {
+    fn from(value: El) -> Self {
+        return XMLNode::Element(value.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use xmltree::XMLNode;
+
+    use super::El;
+
+    #[test]
+    fn test_build() {
+        let e = El::new("div")
+            .id("book-columns")
+            .child(El::new("span").class("kobospan").text("Hi"))
+            .children(vec![XMLNode::Text("!".to_string())])
+            .build();
+
+        assert_eq!(e.name, "div");
+        assert_eq!(e.attributes["id"], "book-columns");
+        assert_eq!(e.children.len(), 2);
+        let span = e.get_child("span").unwrap();
+        assert_eq!(span.attributes["class"], "kobospan");
+        assert_eq!(span.get_text().unwrap(), "Hi");
+    }
+}
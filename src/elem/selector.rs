@@ -0,0 +1,409 @@
+use xmltree::Element;
+
+use crate::errors::ConverterError;
+
+/// A parsed element query, using a small subset of CSS selector syntax:
+///
+/// - `tag` or `*`, where `tag` matches the local name, or `prefix:tag`
+///   to also require a namespace prefix
+/// - `#id` and `.class`
+/// - `[attr]`, `[attr=value]`, `[attr~=word]`, `[attr^=start]`,
+///   `[attr$=end]` and `[attr*=part]`, where the value may be quoted
+/// - descendant (`a b`) and child (`a > b`) combinators
+///
+/// e.g. `manifest > item[media-type=application/xhtml+xml]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    /// Compound selectors from left to right. The combinator stored with
+    /// each compound relates it to the compound before it
+    parts: Vec<(Combinator, Compound)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Compound {
+    prefix: Option<String>,
+    tag: Option<String>,
+    attrs: Vec<AttrMatch>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct AttrMatch {
+    key: String,
+    op: AttrOp,
+    value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AttrOp {
+    Exists,
+    Equals,
+    Word,
+    Prefix,
+    Suffix,
+    Contains,
+}
+
+impl Selector {
+    pub fn parse(query: &str) -> Result<Self, ConverterError> {
+        let err = |msg: &str| {
+            return ConverterError::Other(format!("Invalid selector '{}': {}", query, msg));
+        };
+
+        let chars = query.chars().collect::<Vec<_>>();
+        let mut parts = Vec::new();
+        let mut i = 0;
+        let mut combinator = Combinator::Descendant;
+
+        skip_ws(&chars, &mut i);
+        while i < chars.len() {
+            let mut compound = Compound::default();
+            let universal = chars[i] == '*';
+
+            if universal {
+                i += 1;
+            } else if is_ident_char(chars[i]) {
+                let name = take_ident(&chars, &mut i, true);
+                match name.split_once(':') {
+                    Some((p, n)) => {
+                        compound.prefix = Some(p.to_string());
+                        compound.tag = Some(n.to_string());
+                    }
+                    None => compound.tag = Some(name),
+                }
+            }
+
+            while i < chars.len() && ['#', '.', '['].contains(&chars[i]) {
+                let c = chars[i];
+                i += 1;
+                match c {
+                    '#' | '.' => {
+                        let v = take_ident(&chars, &mut i, false);
+                        if v.is_empty() {
+                            return Err(err("expected name after '#' or '.'"));
+                        }
+                        compound.attrs.push(match c {
+                            '#' => AttrMatch::new("id", AttrOp::Equals, v),
+                            _ => AttrMatch::new("class", AttrOp::Word, v),
+                        });
+                    }
+                    _ => compound
+                        .attrs
+                        .push(parse_attr(&chars, &mut i).map_err(err)?),
+                }
+            }
+
+            if compound == Compound::default() && !universal {
+                return Err(err("expected element name, '*', '#', '.' or '['"));
+            }
+            parts.push((combinator, compound));
+
+            let had_ws = skip_ws(&chars, &mut i);
+            combinator = Combinator::Descendant;
+            if i < chars.len() && chars[i] == '>' {
+                i += 1;
+                skip_ws(&chars, &mut i);
+                combinator = Combinator::Child;
+                if i >= chars.len() {
+                    return Err(err("selector ends with '>'"));
+                }
+            } else if i < chars.len() && !had_ws {
+                return Err(err(&format!("unexpected character '{}'", chars[i])));
+            }
+        }
+
+        if parts.is_empty() {
+            return Err(err("selector is empty"));
+        }
+        return Ok(Self { parts });
+    }
+
+    /// Returns true if `elem` matches the selector, given its ancestors
+    /// ordered from the outermost to the direct parent
+    pub fn matches(&self, elem: &Element, ancestors: &[&Element]) -> bool {
+        let (last, rest) = match self.parts.split_last() {
+            Some(p) => p,
+            None => return false,
+        };
+        if !last.1.matches(elem) {
+            return false;
+        }
+        return match_ancestors(rest, last.0, ancestors);
+    }
+
+    /// Child index paths, relative to `root`, of every matching descendant of
+    /// `root` in document order. `root` itself is never matched but may
+    /// satisfy the left-hand side of a combinator
+    pub(crate) fn match_paths(&self, root: &Element, first_only: bool) -> Vec<Vec<usize>> {
+        let mut found = Vec::new();
+        let mut ancestors = vec![root];
+        let mut path = Vec::new();
+        self.collect_paths(root, &mut ancestors, &mut path, &mut found, first_only);
+        return found;
+    }
+
+    fn collect_paths<'a>(
+        &self,
+        elem: &'a Element,
+        ancestors: &mut Vec<&'a Element>,
+        path: &mut Vec<usize>,
+        found: &mut Vec<Vec<usize>>,
+        first_only: bool,
+    ) {
+        for (idx, child) in elem.children.iter().enumerate() {
+            if first_only && !found.is_empty() {
+                return;
+            }
+            let child = match child.as_element() {
+                Some(c) => c,
+                None => continue,
+            };
+
+            path.push(idx);
+            if self.matches(child, ancestors) {
+                found.push(path.clone());
+            }
+            ancestors.push(child);
+            self.collect_paths(child, ancestors, path, found, first_only);
+            ancestors.pop();
+            path.pop();
+        }
+    }
+}
+
+/// Checks the remaining compounds (`parts`) against `ancestors`, where
+/// `combinator` relates the last of `parts` to the element already matched
+fn match_ancestors(
+    parts: &[(Combinator, Compound)],
+    combinator: Combinator,
+    ancestors: &[&Element],
+) -> bool {
+    let (last, rest) = match parts.split_last() {
+        Some(p) => p,
+        None => return true,
+    };
+
+    match combinator {
+        Combinator::Child => {
+            let (parent, above) = match ancestors.split_last() {
+                Some(a) => a,
+                None => return false,
+            };
+            return last.1.matches(parent) && match_ancestors(rest, last.0, above);
+        }
+        Combinator::Descendant => {
+            for i in (0..ancestors.len()).rev() {
+                if last.1.matches(ancestors[i]) && match_ancestors(rest, last.0, &ancestors[..i]) {
+                    return true;
+                }
+            }
+            return false;
+        }
+    }
+}
+
+impl Compound {
+    fn matches(&self, elem: &Element) -> bool {
+        if self.tag.as_ref().is_some_and(|t| *t != elem.name) {
+            return false;
+        }
+        if self.prefix.is_some() && self.prefix != elem.prefix {
+            return false;
+        }
+        return self.attrs.iter().all(|a| a.matches(elem));
+    }
+}
+
+impl AttrMatch {
+    fn new(key: &str, op: AttrOp, value: String) -> Self {
+        return Self {
+            key: key.to_string(),
+            op,
+            value,
+        };
+    }
+
+    fn matches(&self, elem: &Element) -> bool {
+        let v = match elem.attributes.get(&self.key) {
+            Some(v) => v,
+            None => return false,
+        };
+        return match self.op {
+            AttrOp::Exists => true,
+            AttrOp::Equals => *v == self.value,
+            AttrOp::Word => v.split_whitespace().any(|w| w == self.value),
+            AttrOp::Prefix => v.starts_with(&self.value),
+            AttrOp::Suffix => v.ends_with(&self.value),
+            AttrOp::Contains => v.contains(&self.value),
+        };
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    return c.is_alphanumeric() || c == '-' || c == '_';
+}
+
+fn take_ident(chars: &[char], i: &mut usize, allow_colon: bool) -> String {
+    let start = *i;
+    while *i < chars.len() && (is_ident_char(chars[*i]) || (allow_colon && chars[*i] == ':')) {
+        *i += 1;
+    }
+    return chars[start..*i].iter().collect();
+}
+
+/// Skips whitespace, returning true if any was found
+fn skip_ws(chars: &[char], i: &mut usize) -> bool {
+    let start = *i;
+    while *i < chars.len() && chars[*i].is_whitespace() {
+        *i += 1;
+    }
+    return *i > start;
+}
+
+/// Parses the inside of an attribute matcher, after the opening `[`
+fn parse_attr(chars: &[char], i: &mut usize) -> Result<AttrMatch, &'static str> {
+    skip_ws(chars, i);
+    let key = take_ident(chars, i, false);
+    if key.is_empty() {
+        return Err("expected attribute name after '['");
+    }
+    skip_ws(chars, i);
+
+    let op = match chars.get(*i) {
+        Some(']') => {
+            *i += 1;
+            return Ok(AttrMatch::new(&key, AttrOp::Exists, String::new()));
+        }
+        Some('=') => AttrOp::Equals,
+        Some('~') => AttrOp::Word,
+        Some('^') => AttrOp::Prefix,
+        Some('$') => AttrOp::Suffix,
+        Some('*') => AttrOp::Contains,
+        _ => return Err("expected ']' or an attribute operator"),
+    };
+    *i += 1;
+    if op != AttrOp::Equals {
+        if chars.get(*i) != Some(&'=') {
+            return Err("expected '=' in attribute operator");
+        }
+        *i += 1;
+    }
+    skip_ws(chars, i);
+
+    let value = match chars.get(*i) {
+        Some(q) if *q == '"' || *q == '\'' => {
+            let q = *q;
+            *i += 1;
+            let start = *i;
+            while *i < chars.len() && chars[*i] != q {
+                *i += 1;
+            }
+            if *i >= chars.len() {
+                return Err("unterminated quoted value");
+            }
+            *i += 1;
+            chars[start..*i - 1].iter().collect::<String>()
+        }
+        _ => {
+            let start = *i;
+            while *i < chars.len() && chars[*i] != ']' {
+                *i += 1;
+            }
+            chars[start..*i]
+                .iter()
+                .collect::<String>()
+                .trim_end()
+                .to_string()
+        }
+    };
+    skip_ws(chars, i);
+
+    if chars.get(*i) != Some(&']') {
+        return Err("expected ']'");
+    }
+    *i += 1;
+    return Ok(AttrMatch::new(&key, op, value));
+}
+
+#[cfg(test)]
+mod test {
+    use xmltree::Element;
+
+    use super::Selector;
+    use crate::elem::ElementExt;
+
+    const TEST_OPF: &str = r#"<package xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <metadata>
+        <dc:title>Title</dc:title>
+        <meta name="cover" content="img"/>
+    </metadata>
+    <manifest>
+        <item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/>
+        <item id="img" href="img.jpg" media-type="image/jpeg" properties="cover-image svg"/>
+        <item id="c2" href="c2.xhtml" media-type="application/xhtml+xml"/>
+    </manifest>
+    <spine><itemref idref="c1"/></spine>
+</package>"#;
+
+    fn ids<'a>(elems: &[&'a Element]) -> Vec<&'a str> {
+        return elems
+            .iter()
+            .map(|e| e.attributes.get("id").map(|s| s.as_str()).unwrap_or(""))
+            .collect();
+    }
+
+    #[test]
+    fn test_select() {
+        let root = Element::parse(TEST_OPF.as_bytes()).unwrap();
+        let sel = |q: &str| Selector::parse(q).unwrap();
+
+        let found = root.select(&sel("manifest > item[media-type=application/xhtml+xml]"));
+        assert_eq!(ids(&found), ["c1", "c2"]);
+
+        let found = root.select(&sel("package item[properties~=cover-image]"));
+        assert_eq!(ids(&found), ["img"]);
+
+        assert_eq!(ids(&root.select(&sel("#c2"))), ["c2"]);
+        assert_eq!(ids(&root.select(&sel("item[href$='.jpg']"))), ["img"]);
+        assert_eq!(root.select(&sel("dc:title")).len(), 1);
+        assert_eq!(root.select(&sel("metadata > title")).len(), 1);
+        assert_eq!(root.select(&sel("metadata > item")).len(), 0);
+        assert_eq!(root.select(&sel("package > *")).len(), 3);
+        assert!(root.select_first(&sel("spine > itemref[idref]")).is_some());
+    }
+
+    #[test]
+    fn test_select_mut() {
+        let mut root = Element::parse(TEST_OPF.as_bytes()).unwrap();
+        let sel = Selector::parse(r#"manifest > item[id="c2"]"#).unwrap();
+        root.select_first_mut(&sel)
+            .unwrap()
+            .attributes
+            .insert("linear".to_string(), "no".to_string());
+        assert_eq!(
+            root.select(&Selector::parse("[linear=no]").unwrap()).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for q in [
+            "",
+            "!",
+            "item[",
+            "item[id=",
+            "a >",
+            "a!b",
+            "[=x]",
+            "item[id|=x]",
+        ] {
+            assert!(Selector::parse(q).is_err(), "{}", q);
+        }
+    }
+}
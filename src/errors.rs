@@ -1,63 +1,187 @@
-#![allow(unused)]
-
-use std::fmt::Display;
-use thiserror::Error;
-use zip::result::ZipError;
-
-#[derive(Debug, Error)]
-pub enum ConverterError {
-    IOErr(#[from] std::io::Error),
-    XMLError(String),
-    Other(String),
-}
-
-impl Display for ConverterError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
-    }
-}
-
-impl From<ZipError> for ConverterError {
-    fn from(value: ZipError) -> Self {
-        io_err!(std::io::ErrorKind::InvalidData, "{}", value.to_string())
-    }
-}
-
-impl From<xmltree::ParseError> for ConverterError {
-    fn from(value: xmltree::ParseError) -> Self {
-        match value {
-            xmltree::ParseError::CannotParse => xml_err!("Cannot parse xml file"),
-            xmltree::ParseError::MalformedXml(e) => ConverterError::XMLError(e.to_string()),
-        }
-    }
-}
-
-impl From<xmltree::Error> for ConverterError {
-    fn from(value: xmltree::Error) -> Self {
-        match value {
-            xmltree::Error::Io(error) => ConverterError::IOErr(error),
-            xmltree::Error::DocumentStartAlreadyEmitted => xml_err!("Document start already written"),
-            xmltree::Error::LastElementNameNotAvailable => xml_err!("Last element name not available"),
-            xmltree::Error::EndElementNameIsNotEqualToLastStartElementName => {
-                xml_err!("End element name is not equal to last start element name")
-            }
-            xmltree::Error::EndElementNameIsNotSpecified => xml_err!("End element name is not specified"),
-        }
-    }
-}
-
-impl ConverterError {}
-
-macro_rules! io_err {
-    ($kind:expr, $($arg:tt)*) => {
-       $crate::errors::ConverterError::IOErr(std::io::Error::new($kind, format!($($arg)*)))
-    };
-}
-pub(crate) use io_err;
-
-macro_rules! xml_err {
-    ($($arg:tt)*) => {
-        $crate::errors::ConverterError::XMLError(format!($($arg)*))
-    };
-}
-pub(crate) use xml_err;
+#![allow(unused)]
+
+use std::{fmt::Display, path::PathBuf};
+use thiserror::Error;
+use zip::result::ZipError;
+
+#[derive(Debug, Error)]
+pub enum ConverterError {
+    IOErr(#[from] std::io::Error),
+    XMLError(String),
+    Other(String),
+    /// The book has no content documents to convert
+    NoContentDocuments,
+    /// Converting panicked, with the panic message. It is a bug, caught so
+    /// that one odd file doesn't stop a batch
+    Panic(String),
+    /// The file system holding a directory has fewer bytes free than the
+    /// conversion needs
+    NoSpace {
+        path: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+    /// Files cannot be created in a directory, with the reason
+    NotWritable(PathBuf, String),
+    /// Another error, tagged with the conversion stage it occurred in
+    InStage(Stage, Box<ConverterError>),
+}
+
+/// Steps of converting a single book, used to report where a failure happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Pack,
+    Setup,
+    Input,
+    Extract,
+    Opf,
+    Html,
+    Css,
+    Write,
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Stage::Pack => "pack",
+            Stage::Setup => "setup",
+            Stage::Input => "input",
+            Stage::Extract => "extract",
+            Stage::Opf => "opf",
+            Stage::Html => "html",
+            Stage::Css => "css",
+            Stage::Write => "write",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Display for ConverterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConverterError::IOErr(e) => write!(f, "{}", e),
+            ConverterError::XMLError(e) => write!(f, "XML error: {}", e),
+            ConverterError::Other(e) => write!(f, "{}", e),
+            ConverterError::NoContentDocuments => {
+                write!(f, "The book has no XHTML content documents")
+            }
+            ConverterError::Panic(msg) => write!(f, "Conversion panicked: {}", msg),
+            ConverterError::NoSpace {
+                path,
+                needed,
+                available,
+            } => write!(
+                f,
+                "Not enough free space in {:?}: {} bytes needed, {} available",
+                path, needed, available
+            ),
+            ConverterError::NotWritable(path, e) => {
+                write!(f, "Cannot write to {:?}: {}", path, e)
+            }
+            ConverterError::InStage(_, e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ZipError> for ConverterError {
+    fn from(value: ZipError) -> Self {
+        io_err!(std::io::ErrorKind::InvalidData, "{}", value.to_string())
+    }
+}
+
+impl From<xmltree::ParseError> for ConverterError {
+    fn from(value: xmltree::ParseError) -> Self {
+        match value {
+            xmltree::ParseError::CannotParse => xml_err!("Cannot parse xml file"),
+            xmltree::ParseError::MalformedXml(e) => ConverterError::XMLError(e.to_string()),
+        }
+    }
+}
+
+impl From<xmltree::Error> for ConverterError {
+    fn from(value: xmltree::Error) -> Self {
+        match value {
+            xmltree::Error::Io(error) => ConverterError::IOErr(error),
+            xmltree::Error::DocumentStartAlreadyEmitted => {
+                xml_err!("Document start already written")
+            }
+            xmltree::Error::LastElementNameNotAvailable => {
+                xml_err!("Last element name not available")
+            }
+            xmltree::Error::EndElementNameIsNotEqualToLastStartElementName => {
+                xml_err!("End element name is not equal to last start element name")
+            }
+            xmltree::Error::EndElementNameIsNotSpecified => {
+                xml_err!("End element name is not specified")
+            }
+        }
+    }
+}
+
+impl ConverterError {
+    /// Tags the error with the stage it occurred in. An error that already
+    /// carries a stage keeps the innermost one
+    pub fn in_stage(self, stage: Stage) -> Self {
+        return match self {
+            ConverterError::InStage(..) => self,
+            _ => ConverterError::InStage(stage, Box::new(self)),
+        };
+    }
+
+    pub fn stage(&self) -> Option<Stage> {
+        return match self {
+            ConverterError::InStage(s, _) => Some(*s),
+            _ => None,
+        };
+    }
+
+    /// Short, stable name of the kind of error, for summaries
+    pub fn category(&self) -> &'static str {
+        return match self {
+            ConverterError::IOErr(_) => "io",
+            ConverterError::XMLError(_) => "xml",
+            ConverterError::Other(_) => "other",
+            ConverterError::NoContentDocuments => "no-content",
+            ConverterError::Panic(_) => "panic",
+            ConverterError::NoSpace { .. } => "no-space",
+            ConverterError::NotWritable(..) => "not-writable",
+            ConverterError::InStage(_, e) => e.category(),
+        };
+    }
+}
+
+/// Runs `f`, turning a panic into [`ConverterError::Panic`]. Whatever `f`
+/// changed before panicking must be discarded or be valid as it is
+pub fn catch_panic<T, F>(f: F) -> Result<T, ConverterError>
+where
+    F: FnOnce() -> Result<T, ConverterError>,
+{
+    return std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let msg = match payload.downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => payload
+                .downcast_ref::<String>()
+                .cloned()
+                .unwrap_or_else(|| "unknown cause".to_string()),
+        };
+        return Err(ConverterError::Panic(msg));
+    });
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __io_err {
+    ($kind:expr, $($arg:tt)*) => {
+       $crate::errors::ConverterError::IOErr(std::io::Error::new($kind, format!($($arg)*)))
+    };
+}
+pub use __io_err as io_err;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __xml_err {
+    ($($arg:tt)*) => {
+        $crate::errors::ConverterError::XMLError(format!($($arg)*))
+    };
+}
+pub use __xml_err as xml_err;
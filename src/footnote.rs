@@ -0,0 +1,286 @@
+//! Pop-up footnotes. Kobo devices show a note in a pop-up, instead of
+//! jumping to it, when the link has an `epub:type` of `noteref` and the note
+//! is an `<aside>` with an `epub:type` of `footnote`, `endnote` or
+//! `rearnote`. Books often mark only one side, or put the id of the note on
+//! its back link, so both are rewritten to that shape.
+
+use std::collections::BTreeSet;
+
+use xmltree::{Element, XMLNode};
+
+use crate::{
+    anchor::Targets,
+    elem::{declare_epub_namespace, has_epub_type, El, ElementExt, Rewriter, Walk, EPUB_TYPE},
+    href,
+};
+
+/// The `epub:type`s of notes Kobo shows in pop-ups
+const NOTE_TYPES: [&str; 3] = ["footnote", "endnote", "rearnote"];
+
+/// Elements that can hold a whole note. Inline elements with the id of a
+/// note, like a back link at its start, stand for the closest one of these
+const CONTAINERS: [&str; 8] = [
+    "aside",
+    "p",
+    "div",
+    "li",
+    "dd",
+    "td",
+    "section",
+    "blockquote",
+];
+
+/// Elements whose content is wrapped in the `<aside>`, where replacing the
+/// element itself would break the list or table around it
+const WRAPPED: [&str; 3] = ["li", "dd", "td"];
+
+/// The note type of `elem`, if it has one Kobo knows
+pub fn note_type(elem: &Element) -> Option<&'static str> {
+    return NOTE_TYPES.into_iter().find(|t| has_epub_type(elem, t));
+}
+
+/// The notes the `noteref` links of `root`, a document at `doc_href`, point
+/// to. Hrefs are relative to the same directory as `doc_href`
+pub fn noterefs(root: &Element, doc_href: &str) -> Targets {
+    let mut targets = Targets::new();
+    for a in root.find_where(|e| e.name == "a" && has_epub_type(e, "noteref")) {
+        let Some(link) = a.attributes.get("href") else {
+            continue;
+        };
+        let (path, Some(id)) = href::split_fragment(link) else {
+            continue;
+        };
+        let doc = match path {
+            "" => href::normalize(doc_href),
+            p => href::resolve(doc_href, p),
+        };
+        targets
+            .entry(doc)
+            .or_default()
+            .insert(href::percent_decode(id));
+    }
+    return targets;
+}
+
+/// The ids of `ids` on elements of `root` that are notes already, or on
+/// elements inside them like a back link
+pub fn typed_notes(root: &Element, ids: &BTreeSet<String>) -> BTreeSet<String> {
+    return root
+        .find_where(|e| return note_type(e).is_some())
+        .flat_map(|e| e.descendants())
+        .filter_map(|e| e.attributes.get("id"))
+        .filter(|id| ids.contains(*id))
+        .cloned()
+        .collect();
+}
+
+/// Whether `root` has elements with the ids in `ids` that [`make_notes`]
+/// may change, which is any but pop-up notes already
+pub fn has_notes_to_make(root: &Element, ids: &BTreeSet<String>) -> bool {
+    return root.descendants().any(|e| {
+        return e.attributes.get("id").is_some_and(|id| ids.contains(id))
+            && !(e.name == "aside" && note_type(e).is_some());
+    });
+}
+
+/// Turns the elements of `root` with the ids in `ids` into pop-up notes.
+/// Returns how many were changed
+pub fn make_notes(root: &mut Element, ids: &BTreeSet<String>) -> usize {
+    struct Notes<'a> {
+        ids: &'a BTreeSet<String>,
+        done: BTreeSet<String>,
+        changed: usize,
+    }
+
+    impl Notes<'_> {
+        /// The id of the note `elem` holds, with the note type of the
+        /// element that has it, and whether that is `elem` itself
+        fn find(&self, elem: &Element) -> Option<(String, Option<&'static str>, bool)> {
+            let pending = |e: &Element| {
+                return e
+                    .attributes
+                    .get("id")
+                    .filter(|id| self.ids.contains(*id) && !self.done.contains(*id))
+                    .cloned();
+            };
+            if let Some(id) = pending(elem) {
+                return Some((id, note_type(elem), true));
+            }
+            return elem
+                .find_where(|e| return !CONTAINERS.contains(&e.name.as_str()))
+                .find_map(|e| return pending(e).map(|id| (id, note_type(e), false)));
+        }
+    }
+
+    impl Rewriter for Notes<'_> {
+        fn leave(&mut self, mut elem: Element, out: &mut Vec<XMLNode>) {
+            if !CONTAINERS.contains(&elem.name.as_str()) {
+                out.push(XMLNode::Element(elem));
+                return;
+            }
+            let Some((id, kind, on_elem)) = self.find(&elem) else {
+                out.push(XMLNode::Element(elem));
+                return;
+            };
+            if on_elem && elem.name == "aside" && kind.is_some() {
+                self.done.insert(id);
+                out.push(XMLNode::Element(elem));
+                return;
+            }
+            // the id moves from the back link to the note, unless the note
+            // has one of its own
+            if !on_elem && elem.attributes.contains_key("id") {
+                out.push(XMLNode::Element(elem));
+                return;
+            }
+            let kind = kind.or(note_type(&elem)).unwrap_or("footnote");
+            if !on_elem {
+                elem.walk_mut(|e, _| {
+                    if e.attributes.get("id") == Some(&id) {
+                        e.attributes.remove("id");
+                    }
+                    return Walk::Descend;
+                });
+            }
+            self.done.insert(id.clone());
+            self.changed += 1;
+
+            if WRAPPED.contains(&elem.name.as_str()) {
+                elem.attributes.remove("id");
+                let aside = El::new("aside")
+                    .id(&id)
+                    .attr(EPUB_TYPE, kind)
+                    .children(elem.children.drain(..));
+                elem.children.push(aside.into());
+                out.push(XMLNode::Element(elem));
+                return;
+            }
+            elem.name = "aside".to_string();
+            elem.attributes.insert("id".to_string(), id);
+            add_epub_type(&mut elem, kind);
+            out.push(XMLNode::Element(elem));
+        }
+    }
+
+    let mut notes = Notes {
+        ids,
+        done: BTreeSet::new(),
+        changed: 0,
+    };
+    root.rewrite(&mut notes);
+    if notes.changed > 0 {
+        declare_epub_namespace(root);
+    }
+    return notes.changed;
+}
+
+/// Whether `elem`, in the document at `doc_href`, is a link to a note of
+/// `notes` that isn't marked as a `noteref`
+fn is_unmarked_noteref(elem: &Element, doc_href: &str, notes: &Targets) -> bool {
+    if elem.name != "a" || has_epub_type(elem, "noteref") {
+        return false;
+    }
+    let Some((path, Some(id))) = elem.attributes.get("href").map(|h| href::split_fragment(h))
+    else {
+        return false;
+    };
+    let doc = match path {
+        "" => href::normalize(doc_href),
+        p => href::resolve(doc_href, p),
+    };
+    let id = href::percent_decode(id);
+    return notes.get(&doc).is_some_and(|ids| ids.contains(&id));
+}
+
+/// Whether [`mark_noterefs`] would change any link of `root`
+pub fn has_noterefs_to_mark(root: &Element, doc_href: &str, notes: &Targets) -> bool {
+    return root
+        .descendants()
+        .any(|e| return is_unmarked_noteref(e, doc_href, notes));
+}
+
+/// Adds `noteref` to the `epub:type` of the links of `root`, a document at
+/// `doc_href`, that point to a note of `notes`. Returns how many were changed
+pub fn mark_noterefs(root: &mut Element, doc_href: &str, notes: &Targets) -> usize {
+    let mut changed = 0;
+    root.walk_mut(|e, _| {
+        if is_unmarked_noteref(e, doc_href, notes) {
+            add_epub_type(e, "noteref");
+            changed += 1;
+        }
+        return Walk::Descend;
+    });
+    if changed > 0 {
+        declare_epub_namespace(root);
+    }
+    return changed;
+}
+
+/// Adds `value` to the words of the `epub:type` of `elem`
+fn add_epub_type(elem: &mut Element, value: &str) {
+    if has_epub_type(elem, value) {
+        return;
+    }
+    let types = match elem.attributes.get(EPUB_TYPE) {
+        Some(t) if !t.trim().is_empty() => format!("{} {}", t.trim(), value),
+        _ => value.to_string(),
+    };
+    elem.attributes.insert(EPUB_TYPE.to_string(), types);
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::{
+        has_noterefs_to_mark, has_notes_to_make, make_notes, mark_noterefs, noterefs, typed_notes,
+    };
+    use crate::{converter::serialize_xml, elem::ElementExt, space};
+
+    #[test]
+    fn test_footnotes() {
+        let mut doc = space::parse(
+            r##"<html xmlns="http://www.w3.org/1999/xhtml"><body>
+            <p>Text<a epub:type="noteref" xmlns:epub="http://www.idpf.org/2007/ops" href="#n1">1</a>
+            and <a href="notes.xhtml#n2">2</a>.</p>
+            <p class="note"><a id="n1" href="#r1">1.</a> First note.</p>
+            <ol><li id="n2">Second note.</li></ol></body></html>"##
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            noterefs(&doc, "text/ch1.xhtml")["text/ch1.xhtml"],
+            BTreeSet::from(["n1".to_string()])
+        );
+        let ids = BTreeSet::from(["n1".to_string(), "n2".to_string()]);
+        assert!(typed_notes(&doc, &ids).is_empty());
+        assert!(has_notes_to_make(&doc, &ids));
+        assert_eq!(make_notes(&mut doc, &ids), 2);
+        assert_eq!(typed_notes(&doc, &ids), ids);
+        assert!(!has_notes_to_make(&doc, &ids));
+
+        // the back link gives its id to the paragraph it is in
+        let note = doc.find_first("aside").unwrap();
+        assert_eq!(note.attributes["id"], "n1");
+        assert_eq!(note.attributes["class"], "note");
+        assert_eq!(note.attributes["epub:type"], "footnote");
+        assert!(!note.find_first("a").unwrap().attributes.contains_key("id"));
+        // list items keep their place in the list
+        let li = doc.find_first("li").unwrap();
+        assert!(!li.attributes.contains_key("id"));
+        assert_eq!(li.find_first("aside").unwrap().attributes["id"], "n2");
+
+        let notes = [(
+            "text/notes.xhtml".to_string(),
+            BTreeSet::from(["n2".to_string()]),
+        )]
+        .into();
+        assert!(has_noterefs_to_mark(&doc, "text/ch1.xhtml", &notes));
+        assert_eq!(mark_noterefs(&mut doc, "text/ch1.xhtml", &notes), 1);
+        assert!(!has_noterefs_to_mark(&doc, "text/ch1.xhtml", &notes));
+        let link = doc.find_with_attr("href", "notes.xhtml#n2").next().unwrap();
+        assert_eq!(link.attributes["epub:type"], "noteref");
+        let out = serialize_xml(&doc).unwrap();
+        assert!(out.contains(r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">"#));
+    }
+}
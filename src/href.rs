@@ -0,0 +1,151 @@
+//! Helpers for the relative URLs used in manifest hrefs and links.
+
+/// Decodes `%XX` escapes. Invalid escapes are kept as they are
+pub fn percent_decode(href: &str) -> String {
+    let bytes = href.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    return String::from_utf8_lossy(&out).into_owned();
+}
+
+/// Splits off the `#fragment` part of an href, if any
+pub fn split_fragment(href: &str) -> (&str, Option<&str>) {
+    return match href.split_once('#') {
+        Some((p, f)) => (p, Some(f)),
+        None => (href, None),
+    };
+}
+
+/// Extensions of the files that can be content documents. Books use `.htm`
+/// and `.xml` as well as `.xhtml` and `.html`
+pub const CONTENT_EXTENSIONS: [&str; 4] = ["xhtml", "html", "htm", "xml"];
+
+/// Whether the file `href` points to has one of the
+/// [`CONTENT_EXTENSIONS`], in any case
+pub fn has_content_extension(href: &str) -> bool {
+    let (path, _) = split_fragment(href);
+    let name = path.rsplit('/').next().unwrap_or_default();
+    return name.rsplit_once('.').is_some_and(|(_, ext)| {
+        return CONTENT_EXTENSIONS
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(ext));
+    });
+}
+
+/// Normalizes a relative href so that different spellings of the same path
+/// compare equal: drops the fragment, decodes escapes and resolves `.` and
+/// `..` segments
+pub fn normalize(href: &str) -> String {
+    let (path, _) = split_fragment(href);
+    let decoded = percent_decode(path);
+
+    let mut segments: Vec<&str> = Vec::new();
+    for seg in decoded.split('/') {
+        match seg {
+            "" | "." => {}
+            ".." => {
+                if segments.last().is_some_and(|s| *s != "..") {
+                    segments.pop();
+                } else {
+                    segments.push(seg);
+                }
+            }
+            _ => segments.push(seg),
+        }
+    }
+    return segments.join("/");
+}
+
+/// Resolves `href`, found in the document at `base`, to a normalized path
+/// relative to the same directory `base` is relative to
+pub fn resolve(base: &str, href: &str) -> String {
+    let dir = base.rsplit_once('/').map(|(d, _)| d).unwrap_or_default();
+    return normalize(&format!("{}/{}", dir, href));
+}
+
+/// The href that points from the document at `base` to `target`, both
+/// normalized and relative to the same directory
+pub fn relative(base: &str, target: &str) -> String {
+    let dirs = base.split('/').collect::<Vec<_>>();
+    let dirs = &dirs[..dirs.len() - 1];
+    let parts = target.split('/').collect::<Vec<_>>();
+    let common = dirs
+        .iter()
+        .zip(&parts[..parts.len() - 1])
+        .take_while(|(a, b)| return a == b)
+        .count();
+    let mut out = vec![".."; dirs.len() - common];
+    out.extend(&parts[common..]);
+    return out
+        .join("/")
+        .replace('%', "%25")
+        .replace(' ', "%20")
+        .replace('#', "%23");
+}
+
+#[cfg(test)]
+mod test {
+    use super::{has_content_extension, normalize, percent_decode, relative, resolve};
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b.xhtml"), "a b.xhtml");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("text/ch1.xhtml"), "text/ch1.xhtml");
+        assert_eq!(normalize("./text//ch1.xhtml#p3"), "text/ch1.xhtml");
+        assert_eq!(normalize("text/../text/ch%31.xhtml"), "text/ch1.xhtml");
+        assert_eq!(normalize("../images/a.jpg"), "../images/a.jpg");
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("nav.xhtml", "ch1.xhtml#a"), "ch1.xhtml");
+        assert_eq!(resolve("text/nav.xhtml", "ch1.xhtml"), "text/ch1.xhtml");
+        assert_eq!(
+            resolve("toc/toc.ncx", "../text/ch1.xhtml"),
+            "text/ch1.xhtml"
+        );
+    }
+
+    #[test]
+    fn test_relative() {
+        assert_eq!(relative("nav.xhtml", "text/ch1.xhtml"), "text/ch1.xhtml");
+        assert_eq!(relative("text/nav.xhtml", "text/ch1.xhtml"), "ch1.xhtml");
+        assert_eq!(
+            relative("toc/nav.xhtml", "text/cover page.xhtml"),
+            "../text/cover%20page.xhtml"
+        );
+        assert_eq!(
+            resolve("toc/nav.xhtml", "../text/ch1.xhtml"),
+            "text/ch1.xhtml"
+        );
+    }
+
+    #[test]
+    fn test_has_content_extension() {
+        for href in ["ch1.xhtml", "text/CH1.HTM", "a.html#p", "part.1/ch.xml"] {
+            assert!(has_content_extension(href), "{}", href);
+        }
+        for href in ["style.css", "htm", "text.htm/cover.jpg", "a.xml.bak"] {
+            assert!(!has_content_extension(href), "{}", href);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Parsing of content documents that are not well-formed XML, like ones
+//! with unescaped ampersands or unclosed `<br>` tags, the way a browser
+//! would. The result is the same tree [`space::parse`] gives for XHTML, so
+//! it is written back as well-formed XHTML.
+
+use std::io::Read;
+
+use html5ever::{parse_document, tendril::TendrilSink, ParseOpts};
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+use xmltree::{Element, Namespace, XMLNode};
+
+use crate::{errors::ConverterError, space};
+
+/// Parses an HTML document. Only fails if it cannot be read: anything is
+/// HTML, though a document without an `<html>` root gets one
+pub fn parse<R: Read>(mut r: R) -> Result<Element, ConverterError> {
+    let dom = parse_document(RcDom::default(), ParseOpts::default())
+        .from_utf8()
+        .read_from(&mut r)?;
+    let mut root = dom
+        .document
+        .children
+        .borrow()
+        .iter()
+        .find_map(|c| return convert_element(c, None))
+        .ok_or_else(|| return ConverterError::Other("No <html> element".to_string()))?;
+    space::strip_insignificant(&mut root);
+    return Ok(root);
+}
+
+/// The element at `node` and its content, or None if it is not an element.
+/// `parent_ns` is the namespace of the parent element, so that a namespace
+/// is only declared where it changes
+fn convert_element(node: &Handle, parent_ns: Option<&str>) -> Option<Element> {
+    let NodeData::Element { name, attrs, .. } = &node.data else {
+        return None;
+    };
+    let ns = name.ns.to_string();
+    let mut elem = Element::new(&name.local);
+    if !ns.is_empty() {
+        if parent_ns != Some(ns.as_str()) {
+            let mut namespaces = Namespace::empty();
+            namespaces.force_put("", ns.clone());
+            elem.namespaces = Some(namespaces);
+        }
+        elem.namespace = Some(ns.clone());
+    }
+    // like space::parse, which keeps attributes by their local name, but
+    // epub:type, and namespace declarations apart from them
+    for attr in attrs.borrow().iter() {
+        let name = attr.name.local.to_string();
+        let is_declaration = name == "xmlns"
+            || name.starts_with("xmlns:")
+            || attr.name.prefix.as_deref() == Some("xmlns");
+        if is_declaration {
+            continue;
+        }
+        let key = match name.starts_with("epub:") {
+            true => name,
+            false => name.rsplit(':').next().unwrap_or_default().to_string(),
+        };
+        elem.attributes.insert(key, attr.value.to_string());
+    }
+
+    for child in node.children.borrow().iter() {
+        let converted = match &child.data {
+            NodeData::Element { .. } => convert_element(child, Some(&ns)).map(XMLNode::Element),
+            NodeData::Text { contents } => Some(XMLNode::Text(contents.borrow().to_string())),
+            NodeData::Comment { contents } => Some(XMLNode::Comment(contents.to_string())),
+            NodeData::ProcessingInstruction { target, contents } => Some(
+                XMLNode::ProcessingInstruction(target.to_string(), Some(contents.to_string())),
+            ),
+            NodeData::Document | NodeData::Doctype { .. } => None,
+        };
+        // adjacent text ends up in one node, as with the XML parser
+        match (converted, elem.children.last_mut()) {
+            (Some(XMLNode::Text(t)), Some(XMLNode::Text(prev))) => prev.push_str(&t),
+            (Some(c), _) => elem.children.push(c),
+            (None, _) => {}
+        }
+    }
+    return Some(elem);
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+    use crate::{
+        converter::serialize_xml,
+        elem::{ElementExt, Walk},
+        space,
+    };
+
+    #[test]
+    fn test_parse() {
+        let html = r#"<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>A & B</title></head>
+<body><p class=x>Fish & chips<br>
+<img src="a.png" alt=""></p><pre>a
+  b</pre>
+<svg viewBox="0 0 10 10"><image xlink:href="c.jpg"/></svg></body></html>"#;
+        assert!(space::parse(html.as_bytes()).is_err());
+
+        let mut root = parse(html.as_bytes()).unwrap();
+        assert_eq!(root.name, "html");
+        let p = root.find_first("p").unwrap();
+        assert_eq!(p.attributes["class"], "x");
+        assert_eq!(p.children.len(), 3);
+        assert_eq!(
+            root.find_first("image").unwrap().attributes["href"],
+            "c.jpg"
+        );
+
+        // written back as XHTML the XML parser reads to the same tree
+        let xhtml = serialize_xml(&root).unwrap();
+        let mut reparsed = space::parse(xhtml.as_bytes()).unwrap();
+        // the XML parser repeats the namespaces in scope on every element
+        for e in [&mut reparsed, &mut root] {
+            e.walk_mut(|e, _| {
+                e.namespaces = None;
+                return Walk::Descend;
+            });
+        }
+        assert_eq!(reparsed, root);
+        assert!(xhtml.contains("Fish &amp; chips<br />"));
+        assert!(xhtml.contains("<pre>a\n  b</pre>"));
+        assert!(xhtml.contains(r#"xmlns="http://www.w3.org/2000/svg""#));
+    }
+}
@@ -0,0 +1,232 @@
+//! Re-encoding of raster images for e-ink screens. Images larger than the
+//! screen, or in color on a grayscale screen, only make the book bigger,
+//! the device scales them down and drops the color anyway.
+
+use std::io::Cursor;
+
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType as PngFilter, PngEncoder},
+    },
+    imageops::FilterType,
+    ColorType, DynamicImage, GrayImage, ImageFormat, RgbImage,
+};
+
+/// How images are re-encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageOptions {
+    /// Largest width, in pixels, images are scaled down to
+    pub max_width: u32,
+    /// Largest height, in pixels, images are scaled down to
+    pub max_height: u32,
+    /// JPEG quality, from 1 to 100
+    pub quality: u8,
+}
+
+impl Default for ImageOptions {
+    /// The screen of the Kobo Sage and Elipsa, the largest ones, at a
+    /// quality that shows no loss on e-ink
+    fn default() -> Self {
+        return Self {
+            max_width: 1440,
+            max_height: 1920,
+            quality: 85,
+        };
+    }
+}
+
+/// Smallest cover Kobo recommends, the screen of the Clara. Smaller covers
+/// look blurry in the library grid and on the sleep screen
+pub const MIN_COVER_SIZE: (u32, u32) = (1072, 1448);
+
+/// Parses a screen size like `1440x1920`
+pub fn parse_dimensions(s: &str) -> Result<(u32, u32), String> {
+    let parse = |n: &str| return n.trim().parse::<u32>().ok().filter(|n| *n > 0);
+    return s
+        .split_once(['x', 'X', '×'])
+        .and_then(|(w, h)| return Some((parse(w)?, parse(h)?)))
+        .ok_or_else(|| return format!("invalid size '{}', expected WIDTHxHEIGHT", s));
+}
+
+/// The format of an image with the media type `media_type`, if it is one
+/// that is re-encoded
+pub fn format(media_type: &str) -> Option<ImageFormat> {
+    return match media_type {
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/png" => Some(ImageFormat::Png),
+        _ => None,
+    };
+}
+
+/// Scales `data`, an image in `format`, down to fit the size of `resize`,
+/// makes it 8-bit grayscale with `grayscale`, and encodes it again in the
+/// same format. Transparency is kept. None when there is nothing to change
+/// or it would not get any smaller. Errors when the image can't be decoded
+pub fn optimize(
+    data: &[u8],
+    format: ImageFormat,
+    resize: Option<&ImageOptions>,
+    grayscale: bool,
+) -> Result<Option<Vec<u8>>, String> {
+    let mut img = image::load_from_memory_with_format(data, format).map_err(|e| e.to_string())?;
+    let too_large =
+        resize.is_some_and(|o| return img.width() > o.max_width || img.height() > o.max_height);
+    let in_color = grayscale && !matches!(img.color(), ColorType::L8 | ColorType::La8);
+    if !too_large && !in_color {
+        return Ok(None);
+    }
+    if let Some(o) = resize.filter(|_| too_large) {
+        img = img.resize(o.max_width, o.max_height, FilterType::Lanczos3);
+    }
+    if in_color {
+        img = match img.color().has_alpha() {
+            true => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+            false => DynamicImage::ImageLuma8(img.to_luma8()),
+        };
+    }
+    let quality = resize.copied().unwrap_or_default().quality;
+    let out = encode(&img, format, quality).map_err(|e| e.to_string())?;
+    return Ok(Some(out).filter(|out| out.len() < data.len()));
+}
+
+/// Makes `data`, a cover image in `format`, a JPEG, scaled up to fit `min`
+/// if it is smaller both ways. Transparent parts become white. None when it
+/// is a large enough JPEG already. Errors when the image can't be decoded
+pub fn normalize_cover(
+    data: &[u8],
+    format: ImageFormat,
+    min: (u32, u32),
+    quality: u8,
+) -> Result<Option<Vec<u8>>, String> {
+    let mut img = image::load_from_memory_with_format(data, format).map_err(|e| e.to_string())?;
+    let small = img.width() < min.0 && img.height() < min.1;
+    if format == ImageFormat::Jpeg && !small {
+        return Ok(None);
+    }
+    if small {
+        img = img.resize(min.0, min.1, FilterType::Lanczos3);
+    }
+    let out = encode(&img, ImageFormat::Jpeg, quality).map_err(|e| e.to_string())?;
+    return Ok(Some(out));
+}
+
+/// `img` drawn on white, without transparency
+fn flatten(img: &DynamicImage) -> DynamicImage {
+    let blend = |c: u8, a: u8| {
+        let (c, a) = (c as u32, a as u32);
+        return ((c * a + 255 * (255 - a)) / 255) as u8;
+    };
+    return match img.color() {
+        ColorType::La8 | ColorType::La16 => {
+            let gray = img.to_luma_alpha8();
+            DynamicImage::ImageLuma8(GrayImage::from_fn(img.width(), img.height(), |x, y| {
+                let [l, a] = gray.get_pixel(x, y).0;
+                return image::Luma([blend(l, a)]);
+            }))
+        }
+        c if c.has_alpha() => {
+            let rgba = img.to_rgba8();
+            DynamicImage::ImageRgb8(RgbImage::from_fn(img.width(), img.height(), |x, y| {
+                let [r, g, b, a] = rgba.get_pixel(x, y).0;
+                return image::Rgb([blend(r, a), blend(g, a), blend(b, a)]);
+            }))
+        }
+        _ => img.clone(),
+    };
+}
+
+fn encode(img: &DynamicImage, format: ImageFormat, quality: u8) -> image::ImageResult<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => {
+            // JPEG has no transparency
+            flatten(img).write_with_encoder(JpegEncoder::new_with_quality(
+                &mut out,
+                quality.clamp(1, 100),
+            ))?;
+        }
+        _ => img.write_with_encoder(PngEncoder::new_with_quality(
+            &mut out,
+            CompressionType::Best,
+            PngFilter::Adaptive,
+        ))?,
+    }
+    return Ok(out.into_inner());
+}
+
+#[cfg(test)]
+mod test {
+    use image::{ColorType, DynamicImage, ImageFormat, RgbImage};
+
+    use super::{encode, normalize_cover, optimize, parse_dimensions, ImageOptions};
+
+    #[test]
+    fn test_optimize() {
+        assert_eq!(parse_dimensions("1072x1448"), Ok((1072, 1448)));
+        assert!(parse_dimensions("1072").is_err());
+        assert!(parse_dimensions("0x10").is_err());
+
+        let options = ImageOptions {
+            max_width: 40,
+            max_height: 40,
+            quality: 80,
+        };
+        // noise, which compresses badly at any size
+        let mut seed = 1u32;
+        let noise = RgbImage::from_fn(120, 60, |_, _| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let [_, a, b, c] = seed.to_le_bytes();
+            return image::Rgb([a, b, c]);
+        });
+        for format in [ImageFormat::Jpeg, ImageFormat::Png] {
+            let data = encode(&DynamicImage::ImageRgb8(noise.clone()), format, 95).unwrap();
+            let out = optimize(&data, format, Some(&options), false)
+                .unwrap()
+                .unwrap();
+            let img = image::load_from_memory_with_format(&out, format).unwrap();
+            // the aspect ratio is kept
+            assert_eq!((img.width(), img.height()), (40, 20));
+            assert_eq!(img.color(), ColorType::Rgb8);
+            assert_eq!(optimize(&out, format, Some(&options), false), Ok(None));
+
+            let gray = optimize(&data, format, None, true).unwrap().unwrap();
+            let img = image::load_from_memory_with_format(&gray, format).unwrap();
+            assert_eq!((img.width(), img.color()), (120, ColorType::L8));
+            assert_eq!(optimize(&gray, format, None, true), Ok(None));
+        }
+        // transparency is kept
+        let icon = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 64, |_, _| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let [_, r, g, a] = seed.to_le_bytes();
+            return image::Rgba([r, g, 10, a]);
+        }));
+        let data = encode(&icon, ImageFormat::Png, 0).unwrap();
+        let gray = optimize(&data, ImageFormat::Png, None, true)
+            .unwrap()
+            .unwrap();
+        let img = image::load_from_memory(&gray).unwrap();
+        assert_eq!(img.color(), ColorType::La8);
+        assert!(optimize(b"not an image", ImageFormat::Png, None, true).is_err());
+    }
+
+    #[test]
+    fn test_normalize_cover() {
+        let transparent = DynamicImage::ImageRgba8(image::RgbaImage::new(30, 40));
+        let png = encode(&transparent, ImageFormat::Png, 0).unwrap();
+        let jpeg = normalize_cover(&png, ImageFormat::Png, (60, 100), 90)
+            .unwrap()
+            .unwrap();
+        let img = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg).unwrap();
+        // scaled up to fit, on white
+        assert_eq!((img.width(), img.height()), (60, 80));
+        assert!(img
+            .to_rgb8()
+            .pixels()
+            .all(|p| return p.0.iter().all(|c| *c > 250)));
+        assert_eq!(
+            normalize_cover(&jpeg, ImageFormat::Jpeg, (60, 100), 90),
+            Ok(None)
+        );
+    }
+}
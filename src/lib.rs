@@ -0,0 +1,34 @@
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+pub mod anchor;
+pub mod budget;
+pub mod calibre;
+pub mod codes;
+pub mod config;
+pub mod converter;
+pub mod css;
+#[cfg(unix)]
+pub mod daemon;
+pub mod diff;
+pub mod disk;
+pub mod elem;
+pub mod errors;
+pub mod footnote;
+pub mod href;
+pub mod html5;
+pub mod images;
+pub mod logger;
+pub mod media;
+pub mod obfuscation;
+pub mod opf;
+pub mod overlay;
+pub mod pack;
+pub mod report;
+pub mod segment;
+pub mod sink;
+pub mod space;
+pub mod spanmap;
+pub mod text;
+pub mod validate;
+pub mod verify;
+pub mod workdir;
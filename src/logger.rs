@@ -0,0 +1,136 @@
+use std::{
+    fmt::Arguments,
+    fs::File,
+    io::{LineWriter, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+/// Severity of a log message, ordered from most to least important
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn label(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Writes messages to the console and, optionally, to a log file.
+/// The file always receives every message regardless of console verbosity
+struct Logger {
+    console: Level,
+    file: Option<LineWriter<File>>,
+    start: Instant,
+}
+
+static LOGGER: OnceLock<Mutex<Logger>> = OnceLock::new();
+
+fn logger() -> &'static Mutex<Logger> {
+    return LOGGER.get_or_init(|| {
+        Mutex::new(Logger {
+            console: Level::Info,
+            file: None,
+            start: Instant::now(),
+        })
+    });
+}
+
+/// Sets the console verbosity and opens the log file, if any.
+/// An existing file at `log_file` is truncated. The file is line-buffered
+/// so that it stays useful even if the process dies mid-conversion
+pub fn init(console: Level, log_file: Option<&Path>) -> Result<(), std::io::Error> {
+    let file = match log_file {
+        Some(p) => Some(LineWriter::new(File::create(p)?)),
+        None => None,
+    };
+
+    let mut l = logger().lock().unwrap_or_else(|e| e.into_inner());
+    l.console = console;
+    l.file = file;
+    return Ok(());
+}
+
+/// Flushes the log file. Called once the run is finished
+pub fn flush() {
+    let mut l = logger().lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(f) = l.file.as_mut() {
+        let _ = f.flush();
+    }
+}
+
+pub fn log(level: Level, args: Arguments) {
+    let mut l = logger().lock().unwrap_or_else(|e| e.into_inner());
+
+    if level <= l.console {
+        match level {
+            Level::Error => eprintln!("Error: {}", args),
+            Level::Warn => eprintln!("Warning: {}", args),
+            _ => println!("{}", args),
+        }
+    }
+
+    let elapsed = l.start.elapsed().as_secs_f64();
+    if let Some(f) = l.file.as_mut() {
+        // A failing log file must never abort a conversion
+        let _ = writeln!(f, "[{:>9.3}s] {:<5} {}", elapsed, level.label(), args);
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_error {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Error, format_args!($($arg)*))
+    };
+}
+pub use __log_error as error;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_warning {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Warn, format_args!($($arg)*))
+    };
+}
+pub use __log_warning as warning;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_info {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Info, format_args!($($arg)*))
+    };
+}
+pub use __log_info as info;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_debug {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Debug, format_args!($($arg)*))
+    };
+}
+pub use __log_debug as debug;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_trace {
+    ($($arg:tt)*) => {
+        $crate::logger::log($crate::logger::Level::Trace, format_args!($($arg)*))
+    };
+}
+pub use __log_trace as trace;
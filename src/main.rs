@@ -1,77 +1,1322 @@
-mod converter;
-mod errors;
-mod lmnt;
-use std::{fs::File, io::ErrorKind, path::Path};
+#![allow(clippy::needless_return)]
 
-use clap::Parser;
-use errors::{io_err, ConverterError};
+use std::{
+    fs::File,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use clap::{Parser, Subcommand};
+#[cfg(unix)]
+use kepub::daemon;
+use kepub::{
+    budget::{self, MemoryBudget},
+    codes::WarningCode,
+    config::Config,
+    converter::{self, OutputFormat, Preset, UnconvertedFile},
+    diff::{self, Difference},
+    errors::{self, io_err, ConverterError, Stage},
+    images::{self, ImageOptions},
+    logger::{self, debug, error, info, warning, Level},
+    media::MediaPolicy,
+    pack,
+    segment::Granularity,
+    spanmap::{self, SpanMap},
+    text::{Normalization, Replacement},
+    verify,
+    workdir::{self, WorkDir},
+};
 use zip::ZipArchive;
 
+/// Exit status when some, but not all, books in a run failed to convert.
+/// 2 is left to clap for usage errors
+const EXIT_PARTIAL_FAILURE: u8 = 3;
+
+/// Exit status when interrupted by SIGINT or SIGTERM
+const EXIT_INTERRUPTED: u8 = 130;
+
+/// Convert epub books to Kobo kepubs
 #[derive(Parser)]
-struct Args {
-    // Input epub zip
-    input: String,
+#[command(
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    after_help = "Exit status is 0 if every book converted, 1 if all failed \
+and 3 if only some failed, 130 if interrupted."
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Input epub zips. Patterns like *.epub or library/**/*.epub are
+    /// expanded, for shells that don't do it themselves
+    #[arg(required = true, num_args = 1..)]
+    inputs: Vec<String>,
 
     /// Output directory
-    out_dir: String,
+    #[arg(required = true)]
+    out_dir: Option<String>,
+
+    /// Number of books converted at the same time. Defaults to the number
+    /// of CPU cores
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: Option<u32>,
+
+    /// Keep the estimated memory needed by the books converted at the same
+    /// time under SIZE, in bytes or with a K, M or G suffix. Books wait for
+    /// memory to free up, and one needing more than SIZE is converted alone
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<u64>,
+
+    #[command(flatten)]
+    options: ConvertOptions,
+
+    #[command(flatten)]
+    output: OutputOptions,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Package a directory of Markdown or HTML files as a kepub. Files
+    /// directly in DIR become chapters in name order, book metadata is read
+    /// from DIR/metadata.txt (title, author, language, identifier, cover)
+    Pack {
+        /// Directory with the chapter files
+        dir: String,
+
+        /// Output directory
+        out_dir: String,
+
+        #[command(flatten)]
+        options: ConvertOptions,
+    },
+
+    /// Convert an epub, strip the kobo markup from the result and check
+    /// that no text was dropped, added or changed on the way
+    Verify {
+        /// Input epub
+        input: String,
+
+        #[command(flatten)]
+        options: ConvertOptions,
+    },
+
+    /// Check the packaging of an epub: manifest media types and files, the
+    /// unique identifier, the table of contents and files left out of the
+    /// manifest. Exits with 0 if nothing is left to fix and 1 otherwise
+    Validate {
+        /// Input epub
+        input: String,
+
+        /// Repair what can be repaired safely and write the book to OUT,
+        /// without converting it to a kepub
+        #[arg(long, value_name = "OUT")]
+        fix: Option<String>,
+
+        /// Don't report problems with these codes, like W025. Can be given
+        /// several times or as a comma separated list
+        #[arg(long, value_name = "CODE", value_delimiter = ',')]
+        allow: Vec<WarningCode>,
+    },
+
+    /// Keep running and convert the books sent with `kepub submit`, with
+    /// the options given here, on a unix socket. Saves starting a process
+    /// and loading the options for every book when they arrive one by one
+    #[cfg(unix)]
+    Daemon {
+        /// Socket to listen on [default: $XDG_RUNTIME_DIR/kepub-rs.sock, or
+        /// kepub-rs.sock in a private kepub-rs-UID temporary directory]
+        #[arg(long, value_name = "PATH")]
+        socket: Option<String>,
+
+        /// Number of books converted at the same time. Defaults to the
+        /// number of CPU cores
+        #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        jobs: Option<u32>,
+
+        /// Keep the estimated memory needed by the books converted at the same
+        /// time under SIZE, in bytes or with a K, M or G suffix. Books wait for
+        /// memory to free up, and one needing more than SIZE is converted alone
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_memory: Option<u64>,
+
+        #[command(flatten)]
+        options: ConvertOptions,
+    },
+
+    /// Convert books with a running `kepub daemon`, using its options.
+    /// Exits like a conversion run
+    #[cfg(unix)]
+    Submit {
+        /// Input epub zips
+        #[arg(required = true, num_args = 1..)]
+        inputs: Vec<String>,
+
+        /// Output directory
+        out_dir: String,
+
+        /// Socket of the daemon [default: $XDG_RUNTIME_DIR/kepub-rs.sock, or
+        /// kepub-rs.sock in a private kepub-rs-UID temporary directory]
+        #[arg(long, value_name = "PATH")]
+        socket: Option<String>,
+    },
+
+    /// Compare the content of two books, epubs or kepubs: main metadata,
+    /// reading order and the text of each chapter. Exits with 0 if they
+    /// match and 1 if they differ, ignoring kobo markup and styling
+    DiffBooks {
+        /// The book already converted
+        old: String,
+
+        /// The new download or edition
+        new: String,
+    },
+
+    /// Match the kobospan ids of a kepub to those of a newer conversion of
+    /// the same book by their text, so existing highlights can be moved to
+    /// it. Prints one "file, old id, new id" line per span, tab separated,
+    /// with "-" as the new id when the text is gone. Either book can also be
+    /// given as a map written with --span-map
+    Remap {
+        /// Kepub (or span map) the annotations were made on
+        old: String,
+
+        /// Re-converted kepub (or span map)
+        new: String,
+
+        /// Write the table to FILE instead of standard output
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<String>,
+    },
+}
+
+/// Options shared by every command that converts a book
+#[derive(clap::Args)]
+struct ConvertOptions {
+    /// Start from a set of options: archival (deterministic output at
+    /// compression level 9, images left as they are), device (the kobo
+    /// style, --fix-layout and --optimize-images) or fast (stored
+    /// uncompressed, no long text warnings). Options given as well add to
+    /// the preset or override it
+    #[arg(long, value_name = "PRESET")]
+    preset: Option<Preset>,
+
+    /// What to write: kepub, or epub for a standard epub with the cleanup
+    /// options applied but without kobospans, wrapper divs, the kobo style
+    /// or the cover fix, for other readers. Kobo markup already in the book
+    /// is removed
+    #[arg(long, value_name = "FORMAT", default_value = "kepub")]
+    format: OutputFormat,
+
+    /// Extension of the written books. Kobo devices only treat files ending
+    /// in .kepub.epub as kepubs [default: .kepub.epub, or .epub with
+    /// --format epub]
+    #[arg(long, value_name = "EXT")]
+    extension: Option<String>,
 
-    /// Remove calibre metadata
+    /// Remove calibre metadata and bookmarks, and the attributes and
+    /// unstyled classes calibre adds to the text
     #[arg(long, default_value_t = false)]
     strip_calibre: bool,
+
+    /// Unicode normalization applied to text content: nfc, nfkc or none.
+    /// Decomposed characters can break dictionary lookup and search on device
+    #[arg(long, default_value = "none")]
+    normalize: Normalization,
+
+    /// Warn about paragraphs and sentences longer than this many
+    /// characters, which render slowly on device. 0 disables the warning
+    /// [default: 10000]
+    #[arg(long, value_name = "CHARS")]
+    warn_length: Option<usize>,
+
+    /// Unit of text wrapped in each span: sentence, word or paragraph.
+    /// Word spans allow finer highlighting at the cost of a larger book,
+    /// paragraph spans keep page turns fast on old devices
+    #[arg(long, default_value = "sentence")]
+    granularity: Granularity,
+
+    /// Break sentences longer than this many characters into several spans
+    /// at word boundaries, for text with little or no punctuation.
+    /// 0 keeps sentences whole
+    #[arg(long, value_name = "CHARS", default_value_t = 0)]
+    chunk_length: usize,
+
+    /// Also write every file changed by the conversion to DIR/<book name>/,
+    /// with a changes.txt listing them, for debugging rendering problems
+    #[arg(long, value_name = "DIR")]
+    emit_intermediate: Option<String>,
+
+    /// Move publisher line height, margin and font size settings for body
+    /// text out of the way, so the device's reading settings work
+    #[arg(long, default_value_t = false)]
+    fix_layout: bool,
+
+    /// Take off the margins and widths that box the text into a column and
+    /// add the kobo style padding for Kobo's fullscreen reading mode.
+    /// Implies --kobo-style
+    #[arg(long, default_value_t = false)]
+    fullscreen_fixes: bool,
+
+    /// Remove documents that show nothing, like the blank pages of print
+    /// editions, from the reading order and table of contents
+    #[arg(long, default_value_t = false)]
+    remove_blank_pages: bool,
+
+    /// Scale JPEG and PNG images larger than the screen down to it and
+    /// encode them again, which can make image-heavy books much smaller
+    #[arg(long, default_value_t = false)]
+    optimize_images: bool,
+
+    /// Screen size images are scaled down to with --optimize-images, like
+    /// 1072x1448 for a Clara HD. Implies --optimize-images [default:
+    /// 1440x1920]
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = images::parse_dimensions)]
+    max_image_size: Option<(u32, u32)>,
+
+    /// JPEG quality of the images scaled down with --optimize-images, from
+    /// 1 to 100. Implies --optimize-images [default: 85]
+    #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+    image_quality: Option<u8>,
+
+    /// Make JPEG and PNG images 8-bit grayscale, which saves space on
+    /// image-heavy books for devices without a color screen
+    #[arg(long, default_value_t = false)]
+    grayscale_images: bool,
+
+    /// Make the cover a JPEG at least as large as the screen of a Kobo
+    /// Clara, and add the page showing it to the guide and landmarks, so
+    /// it shows in the library grid and on the sleep screen
+    #[arg(long, default_value_t = false)]
+    normalize_cover: bool,
+
+    /// Add a title page with the title, the authors and the cover to books
+    /// that open straight into their first chapter
+    #[arg(long, default_value_t = false)]
+    title_page: bool,
+
+    /// Remove the fonts embedded in the book and their @font-face rules,
+    /// for reading with the device's own fonts. Makes the book smaller
+    #[arg(long, default_value_t = false)]
+    remove_fonts: bool,
+
+    /// Show footnotes and endnotes in pop-ups instead of jumping to them.
+    /// Notes are found by the noteref links pointing to them, or by being
+    /// marked as notes and linked to
+    #[arg(long, default_value_t = false)]
+    footnote_popups: bool,
+
+    /// What to do with audio and video, which Kobo devices can't play: keep,
+    /// warn (keep and report them) or strip (replace them with their poster
+    /// image or fallback text and drop the media files)
+    #[arg(long, value_name = "POLICY", default_value = "warn")]
+    media: MediaPolicy,
+
+    /// Deflate level of the output, from 1 (fastest) to 9 (smallest), or 0
+    /// to store it uncompressed
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(0..=9))]
+    compression_level: Option<u8>,
+
+    /// Give every file in the output the same fixed timestamp, so that it
+    /// only changes when the content does
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+
+    /// Convert books that already are kepubs again, replacing their
+    /// kobospans. By default they are copied unchanged
+    #[arg(long, default_value_t = false)]
+    force_respan: bool,
+
+    /// Remove soft hyphens and zero-width characters from inside words,
+    /// where they keep the dictionary from finding the word. Without this
+    /// they are only reported
+    #[arg(long, default_value_t = false)]
+    strip_word_breaks: bool,
+
+    /// Replace straight quotes, double hyphens and three dots in the text
+    /// with typographic quotes, em dashes and ellipses. Code and
+    /// preformatted text are left as they are
+    #[arg(long, default_value_t = false)]
+    smarten_punctuation: bool,
+
+    /// Replace the matches of the regex PATTERN in the text with
+    /// REPLACEMENT, which can refer to groups as $1. Can be given several
+    /// times, replacements run in order before any other change to the text
+    #[arg(long, value_name = "PATTERN::REPLACEMENT")]
+    replace: Vec<Replacement>,
+
+    /// Read replacements from FILE, one PATTERN::REPLACEMENT per line.
+    /// They run after the ones given with --replace
+    #[arg(long, value_name = "FILE")]
+    replace_file: Option<String>,
+
+    /// Don't add kobospans to content documents larger than SIZE, in bytes
+    /// or with a K, M or G suffix. They still get the wrapper divs
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_span_file_size: Option<u64>,
+
+    /// Copy content documents that can't be converted, like ones without a
+    /// body, into the kepub unchanged and report them, instead of failing
+    /// the whole book. A package document that can't be read is rebuilt
+    /// from the files of the book, in file name order. Documents that
+    /// aren't well-formed XML are parsed as HTML either way
+    #[arg(long, default_value_t = false)]
+    lenient: bool,
+
+    /// Don't add kobospans. The text is left as it is, so --normalize and
+    /// --strip-word-breaks have no effect
+    #[arg(long, default_value_t = false)]
+    no_spans: bool,
+
+    /// Don't wrap the body of content documents in the book-columns and
+    /// book-inner divs
+    #[arg(long, default_value_t = false)]
+    no_wrapper: bool,
+
+    /// Add the style kepubify adds to every content document, which keeps
+    /// the wrapper divs from adding to the margins set on the device
+    #[arg(long, default_value_t = false)]
+    kobo_style: bool,
+
+    /// Force hyphenation on for body text, through the kobo style. Implies
+    /// --kobo-style
+    #[arg(long, default_value_t = false, conflicts_with = "no_hyphenate")]
+    hyphenate: bool,
+
+    /// Force hyphenation off, through the kobo style. Implies --kobo-style
+    #[arg(long, default_value_t = false)]
+    no_hyphenate: bool,
+
+    /// Append the CSS in FILE to the kobo style, for custom overrides.
+    /// Implies --kobo-style
+    #[arg(long, value_name = "FILE")]
+    css: Option<String>,
+
+    /// Don't mark the cover image with the cover-image property
+    #[arg(long, default_value_t = false)]
+    no_cover_fix: bool,
+
+    /// Also write <output>.report.json, with the warnings and the
+    /// transforms applied to each file
+    #[arg(long, default_value_t = false)]
+    report: bool,
+
+    /// Also write <output>.spans.json, mapping every kobospan id to its
+    /// chapter, text offsets and text, for annotation tools
+    #[arg(long, default_value_t = false)]
+    span_map: bool,
+
+    /// Don't report warnings with these codes, like W012, beyond the debug
+    /// log. Can be given several times or as a comma separated list
+    #[arg(long, value_name = "CODE", value_delimiter = ',')]
+    allow: Vec<WarningCode>,
+
+    /// Read the warning codes to allow and the problems to fix, for every
+    /// book and by book identifier, from the JSON file FILE
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+}
+
+impl ConvertOptions {
+    /// Extension of the written books, from --extension or the format
+    fn extension(&self) -> &str {
+        return match (&self.extension, self.format) {
+            (Some(ext), _) => ext,
+            (None, OutputFormat::Kepub) => ".kepub.epub",
+            (None, OutputFormat::Epub) => ".epub",
+        };
+    }
+}
+
+/// Console and log output, accepted by every command
+#[derive(clap::Args)]
+struct OutputOptions {
+    /// Write a detailed log of the conversion to this file,
+    /// independent of console verbosity
+    #[arg(long, global = true)]
+    log_file: Option<String>,
+
+    /// Increase console verbosity (-v for debug, -vv for trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Suppress human-readable output and print only the path of each
+    /// produced file, one per line. Errors are still reported on stderr
+    #[arg(
+        long,
+        global = true,
+        default_value_t = false,
+        conflicts_with = "verbose"
+    )]
+    porcelain: bool,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let output = &cli.output;
+
+    let console = match output.verbose {
+        _ if output.porcelain => Level::Error,
+        0 => Level::Info,
+        1 => Level::Debug,
+        _ => Level::Trace,
+    };
+    if let Err(e) = logger::init(console, output.log_file.as_deref().map(Path::new)) {
+        eprintln!("Error: Cannot open log file: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    // extracted books would be left behind in the temp dir otherwise
+    let interrupted = ctrlc::set_handler(|| {
+        workdir::remove_all();
+        std::process::exit(EXIT_INTERRUPTED.into());
+    });
+    if let Err(e) = interrupted {
+        debug!("Cannot handle interrupts: {}", e);
+    }
+
+    let status = match &cli.command {
+        Some(Command::Pack {
+            dir,
+            out_dir,
+            options,
+        }) => match pack_book(dir, out_dir, options) {
+            Ok(out_path) => {
+                if output.porcelain {
+                    println!("{}", out_path);
+                }
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                error!("{}: {}", dir, e);
+                ExitCode::FAILURE
+            }
+        },
+        Some(Command::Verify { input, options }) => match verify_book(input, options) {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => ExitCode::FAILURE,
+            Err(e) => {
+                error!("{}: {}", input, e);
+                ExitCode::FAILURE
+            }
+        },
+        Some(Command::Validate { input, fix, allow }) => {
+            match validate_book(input, fix.as_deref(), allow) {
+                Ok(true) => ExitCode::SUCCESS,
+                Ok(false) => ExitCode::FAILURE,
+                Err(e) => {
+                    error!("{}: {}", input, e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Some(Command::DiffBooks { old, new }) => match compare_editions(old, new) {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => ExitCode::FAILURE,
+            Err(e) => {
+                error!("{}", e);
+                ExitCode::FAILURE
+            }
+        },
+        Some(Command::Remap { old, new, output }) => {
+            match remap_spans(old, new, output.as_deref()) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    error!("{}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        #[cfg(unix)]
+        Some(Command::Daemon {
+            socket,
+            jobs,
+            max_memory,
+            options,
+        }) => match run_daemon(socket.as_deref(), *jobs, *max_memory, options) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!("{}", e);
+                ExitCode::FAILURE
+            }
+        },
+        #[cfg(unix)]
+        Some(Command::Submit {
+            inputs,
+            out_dir,
+            socket,
+        }) => submit_books(inputs, out_dir, socket.as_deref(), output.porcelain),
+        None => convert_books(&cli),
+    };
+    logger::flush();
+    return status;
+}
+
+/// Converts every input, returning the exit status for the batch
+fn convert_books(cli: &Cli) -> ExitCode {
+    let out_dir = cli.out_dir.as_deref().unwrap_or_default();
+    let inputs = expand_inputs(&cli.inputs);
+    if inputs.is_empty() {
+        error!("No input files");
+        return ExitCode::FAILURE;
+    }
+
+    let setup = match Setup::load(&cli.options) {
+        Ok(setup) => setup,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let budget = cli.max_memory.map(MemoryBudget::new);
+    let results = convert_parallel(
+        &inputs,
+        out_dir,
+        &cli.options,
+        &setup,
+        budget.as_ref(),
+        jobs(cli.jobs).min(inputs.len()),
+        cli.output.porcelain,
+    );
+    let mut failures = Vec::new();
+    let mut unconverted = Vec::new();
+    for (input, res) in inputs.iter().zip(results) {
+        match res {
+            Ok(converted) => unconverted.extend(
+                converted
+                    .unconverted
+                    .into_iter()
+                    .map(|f| return (input.as_str(), f)),
+            ),
+            Err(e) => failures.push((input.as_str(), e)),
+        }
+    }
+
+    if inputs.len() > 1 {
+        info!("");
+        info!(
+            "Converted {} of {} books",
+            inputs.len() - failures.len(),
+            inputs.len()
+        );
+        if !unconverted.is_empty() {
+            print_unconverted(&unconverted);
+        }
+        if !failures.is_empty() {
+            print_failures(&failures, inputs.len());
+        }
+    }
+
+    return batch_status(failures.len(), inputs.len());
 }
 
-fn main() -> Result<(), ConverterError> {
-    let mut args = Args::parse();
-    if !std::fs::metadata(&args.input).is_ok_and(|m| m.is_file()) {
+/// Exit status of a run that failed to convert `failed` of `total` books
+fn batch_status(failed: usize, total: usize) -> ExitCode {
+    return match failed {
+        0 => ExitCode::SUCCESS,
+        n if n == total => ExitCode::FAILURE,
+        _ => ExitCode::from(EXIT_PARTIAL_FAILURE),
+    };
+}
+
+/// Number of books converted at the same time: `jobs`, or else the number
+/// of CPU cores
+fn jobs(jobs: Option<u32>) -> usize {
+    return match jobs {
+        Some(n) => n as usize,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+}
+
+/// Converts `inputs` on `jobs` threads, each taking the next book when it
+/// is done with one, and returns the results in input order
+fn convert_parallel(
+    inputs: &[String],
+    out_dir: &str,
+    options: &ConvertOptions,
+    setup: &Setup,
+    budget: Option<&MemoryBudget>,
+    jobs: usize,
+    porcelain: bool,
+) -> Vec<Result<Converted, ConverterError>> {
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let results = Mutex::new((0..inputs.len()).map(|_| None).collect::<Vec<_>>());
+
+    let worker = || loop {
+        let i = next.fetch_add(1, Ordering::SeqCst);
+        let Some(input) = inputs.get(i) else {
+            return;
+        };
+        let res = convert_reserved(input, out_dir, options, setup, budget);
+        let n = done.fetch_add(1, Ordering::SeqCst) + 1;
+        match &res {
+            Ok(converted) => {
+                if porcelain {
+                    println!("{}", converted.out_path);
+                } else if inputs.len() > 1 {
+                    info!("[{}/{}] Converted {}", n, inputs.len(), input);
+                }
+            }
+            Err(e) => error!("[{}/{}] {}: {}", n, inputs.len(), input, e),
+        }
+        results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(res);
+    };
+    std::thread::scope(|s| {
+        for _ in 1..jobs {
+            s.spawn(worker);
+        }
+        worker();
+    });
+
+    return results
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .map(|r| return r.expect("every input is converted"))
+        .collect();
+}
+
+/// Expands the glob patterns among `inputs`. Paths that exist are kept as
+/// they are, even if they contain pattern characters, and patterns matching
+/// nothing are reported and dropped
+fn expand_inputs(inputs: &[String]) -> Vec<String> {
+    let mut expanded = Vec::new();
+    for input in inputs {
+        let is_pattern = input.contains(['*', '?', '[']);
+        if !is_pattern || Path::new(input).exists() {
+            expanded.push(input.clone());
+            continue;
+        }
+        let paths = match glob::glob(input) {
+            Ok(paths) => paths,
+            Err(e) => {
+                warning!("Invalid pattern {}: {}", input, e);
+                continue;
+            }
+        };
+        let before = expanded.len();
+        for path in paths {
+            match path {
+                Ok(p) if p.is_file() => expanded.push(p.to_string_lossy().to_string()),
+                Ok(_) => {}
+                Err(e) => warning!("Cannot read {:?}: {}", e.path(), e.error()),
+            }
+        }
+        if expanded.len() == before {
+            warning!("No files match {}", input);
+        }
+    }
+    return expanded;
+}
+
+/// A book that was converted
+struct Converted {
+    /// Path of the written kepub
+    out_path: String,
+    /// Its content documents that were copied unchanged
+    unconverted: Vec<UnconvertedFile>,
+}
+
+/// Like [`convert_book`], once `budget` has memory for the book. A panic
+/// fails the book instead of the whole run
+fn convert_reserved(
+    input: &str,
+    out_dir: &str,
+    options: &ConvertOptions,
+    setup: &Setup,
+    budget: Option<&MemoryBudget>,
+) -> Result<Converted, ConverterError> {
+    // unreadable books fail when converting
+    let _reservation = budget.map(|b| {
+        let bytes = budget::footprint(Path::new(input)).unwrap_or(0);
+        debug!("{}: needs about {} bytes of memory", input, bytes);
+        return b.reserve(bytes);
+    });
+    return errors::catch_panic(|| return convert_book(input, out_dir, options, setup));
+}
+
+/// Converts one epub
+fn convert_book(
+    input: &str,
+    out_dir: &str,
+    options: &ConvertOptions,
+    setup: &Setup,
+) -> Result<Converted, ConverterError> {
+    if !std::fs::metadata(input).is_ok_and(|m| m.is_file()) {
         return Err(io_err!(
             ErrorKind::NotFound,
             "Path {} does not exist or is not a file",
-            args.input
-        ));
+            input
+        )
+        .in_stage(Stage::Input));
     }
 
     // If dest is empty, set to parent dir of input file
-    if args.out_dir.is_empty() {
-        let p = Path::new(&args.input);
-        args.out_dir = match p.parent().and_then(|pd| pd.to_str()) {
-            Some(d) => d.to_string(),
+    let out_dir = match out_dir {
+        "" => match Path::new(input).parent().and_then(|pd| pd.to_str()) {
+            Some(d) => d,
             None => {
                 return Err(io_err!(
                     ErrorKind::Other,
                     "Cannot get parent directory of file {}",
-                    args.input
-                ));
+                    input
+                )
+                .in_stage(Stage::Input));
             }
-        };
+        },
+        d => d,
+    };
+
+    let out_path = get_out_file_path(input, out_dir, options.extension())
+        .map_err(|e| e.in_stage(Stage::Input))?;
+    let same_file = std::fs::canonicalize(input)
+        .ok()
+        .zip(std::fs::canonicalize(&out_path).ok())
+        .is_some_and(|(i, o)| i == o);
+    if same_file {
+        return Err(io_err!(
+            ErrorKind::AlreadyExists,
+            "Output {} would overwrite the input, choose another output directory or --extension",
+            out_path
+        )
+        .in_stage(Stage::Input));
     }
+    let unconverted = convert_file(Path::new(input), &out_path, options, setup)?;
+    return Ok(Converted {
+        out_path,
+        unconverted,
+    });
+}
+
+/// Converts the books sent to `socket` until the process is stopped
+#[cfg(unix)]
+fn run_daemon(
+    socket: Option<&str>,
+    jobs: Option<u32>,
+    max_memory: Option<u64>,
+    options: &ConvertOptions,
+) -> Result<(), ConverterError> {
+    let socket = match socket {
+        Some(s) => PathBuf::from(s),
+        None => daemon::default_socket()?,
+    };
+    let setup = Setup::load(options)?;
+    let budget = max_memory.map(MemoryBudget::new);
+    return daemon::serve(&socket, self::jobs(jobs), |request| {
+        return convert_reserved(
+            &request.input,
+            &request.out_dir,
+            options,
+            &setup,
+            budget.as_ref(),
+        )
+        .map(|converted| return converted.out_path);
+    });
+}
+
+/// Sends `inputs` to the daemon at `socket`, returning the exit status for
+/// the batch
+#[cfg(unix)]
+fn submit_books(
+    inputs: &[String],
+    out_dir: &str,
+    socket: Option<&str>,
+    porcelain: bool,
+) -> ExitCode {
+    let socket = match socket
+        .map(PathBuf::from)
+        .map_or_else(daemon::default_socket, Ok)
+    {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    // the daemon runs in another directory
+    let absolute = |p: &str| {
+        return std::path::absolute(p).map_or_else(
+            |_| p.to_string(),
+            |p| return p.to_string_lossy().to_string(),
+        );
+    };
+    let inputs = expand_inputs(inputs);
+    let requests = inputs
+        .iter()
+        .map(|input| daemon::Request {
+            input: absolute(input),
+            out_dir: absolute(out_dir),
+        })
+        .collect::<Vec<_>>();
+    if requests.is_empty() {
+        error!("No input files");
+        return ExitCode::FAILURE;
+    }
+
+    let mut failed = 0;
+    let res = daemon::submit(&socket, &requests, |i, response| match response {
+        Ok(out_path) => {
+            if porcelain {
+                println!("{}", out_path);
+            } else {
+                info!("[{}/{}] Converted {}", i + 1, inputs.len(), inputs[i]);
+            }
+        }
+        Err(e) => {
+            failed += 1;
+            error!("[{}/{}] {}: {}", i + 1, inputs.len(), inputs[i], e);
+        }
+    });
+    if let Err(e) = res {
+        error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    return batch_status(failed, inputs.len());
+}
 
-    let out_path = get_out_file_path(&args)?;
-    let in_file = File::open(args.input)?;
-    let mut zip_arch = ZipArchive::new(in_file)?;
+/// Packs `dir` into an epub in a private temporary directory and converts
+/// that, returning the path of the written kepub
+fn pack_book(dir: &str, out_dir: &str, options: &ConvertOptions) -> Result<String, ConverterError> {
+    let out_path = get_out_file_path(dir, out_dir, options.extension())
+        .map_err(|e| e.in_stage(Stage::Input))?;
+    let tmp = WorkDir::create_in(&std::env::temp_dir(), "kepub-rs-pack")
+        .map_err(|e| ConverterError::from(e).in_stage(Stage::Pack))?;
+    let epub_path = tmp.join("book.epub");
 
-    let conv = converter::Converter::new()?;
-    conv.convert(&mut zip_arch, &out_path)?;
+    let setup = Setup::load(options)?;
+    pack::pack(Path::new(dir), &epub_path).map_err(|e| e.in_stage(Stage::Pack))?;
+    convert_file(&epub_path, &out_path, options, &setup)?;
+    return Ok(out_path);
+}
+
+/// Converts `input` to a temporary kepub and compares their text, returning
+/// true if nothing was lost
+fn verify_book(input: &str, options: &ConvertOptions) -> Result<bool, ConverterError> {
+    let tmp = WorkDir::create_in(&std::env::temp_dir(), "kepub-rs-verify")
+        .map_err(|e| ConverterError::from(e).in_stage(Stage::Input))?;
+    let kepub_path = tmp.join("book.kepub");
+    let kepub = kepub_path.to_string_lossy().to_string();
+
+    let setup = Setup::load(options)?;
+    convert_file(Path::new(input), &kepub, options, &setup)?;
+    let (compared, diffs) = verify::compare_books(
+        Path::new(input),
+        &kepub_path,
+        &setup.replacements,
+        options.normalize,
+        options.strip_word_breaks,
+        options.smarten_punctuation,
+    )
+    .map_err(|e| e.in_stage(Stage::Input))?;
+
+    for d in &diffs {
+        warning!(
+            "{}: text {} at word {}: original \"{}\", converted \"{}\"",
+            d.file,
+            d.kind,
+            d.word,
+            snippet(&d.original),
+            snippet(&d.converted)
+        );
+    }
+    info!(
+        "Compared {} content documents, {} differ",
+        compared,
+        diffs.len()
+    );
+    return Ok(diffs.is_empty());
+}
+
+/// Reports the packaging problems of `input`, repairing them into `fix`
+/// if given. Returns true if none is left
+fn validate_book(
+    input: &str,
+    fix: Option<&str>,
+    allow: &[WarningCode],
+) -> Result<bool, ConverterError> {
+    let mut zip_arch = File::open(input)
+        .map_err(ConverterError::from)
+        .and_then(|f| Ok(ZipArchive::new(f)?))
+        .map_err(|e| e.in_stage(Stage::Input))?;
+    let conv = converter::Converter::builder()
+        .with_allowed_warnings(allow.iter().copied())
+        .build()
+        .map_err(|e| ConverterError::from(e).in_stage(Stage::Setup))?;
+    let findings = conv.validate(&mut zip_arch, fix)?;
+
+    for (f, fixed) in &findings {
+        match fixed {
+            true => info!("fixed: {}: {}", f.code(), f),
+            false => warning!("{}: {}", f.code(), f),
+        }
+    }
+    let left = findings.iter().filter(|(_, fixed)| !fixed).count();
+    match (findings.len(), fix) {
+        (0, _) => info!("Found no problems"),
+        (n, Some(out)) => info!("Fixed {} of {} problems, wrote {}", n - left, n, out),
+        (n, None) => info!("Found {} problems", n),
+    }
+    return Ok(left == 0);
+}
 
+/// Reports how the content of `new` differs from `old`, returning true if
+/// it doesn't
+fn compare_editions(old: &str, new: &str) -> Result<bool, ConverterError> {
+    let diffs = diff::diff_books(Path::new(old), Path::new(new))
+        .map_err(|e| ConverterError::Other(format!("{} or {}: {}", old, new, e)))?;
+    for d in &diffs {
+        match d {
+            Difference::Metadata { name, old, new } => info!(
+                "metadata {}: \"{}\" -> \"{}\"",
+                name,
+                old.join("; "),
+                new.join("; ")
+            ),
+            Difference::Removed(h) => info!("{}: only in the old book", h),
+            Difference::Added(h) => info!("{}: only in the new book", h),
+            Difference::Reordered => info!("spine: chapters are in a different order"),
+            Difference::Text(t) => info!(
+                "{}: text {} at word {}: old \"{}\", new \"{}\"",
+                t.file,
+                t.kind,
+                t.word,
+                snippet(&t.original),
+                snippet(&t.converted)
+            ),
+        }
+    }
+    match diffs.len() {
+        0 => info!("The content of both books is the same"),
+        n => info!("Found {} differences", n),
+    }
+    return Ok(diffs.is_empty());
+}
+
+fn remap_spans(old: &str, new: &str, output: Option<&str>) -> Result<(), ConverterError> {
+    let open = |path: &str| {
+        return SpanMap::open(Path::new(path))
+            .map_err(|e| ConverterError::Other(format!("{}: {}", path, e)).in_stage(Stage::Input));
+    };
+    let anchors = spanmap::remap(&open(old)?, &open(new)?);
+
+    let mut table = String::new();
+    for a in &anchors {
+        table.push_str(&format!(
+            "{}\t{}\t{}\n",
+            a.file,
+            a.old,
+            a.new.as_deref().unwrap_or("-")
+        ));
+    }
+    match output {
+        Some(path) => std::fs::write(path, table)?,
+        None => print!("{}", table),
+    }
+
+    let lost = anchors.iter().filter(|a| a.new.is_none()).count();
+    if lost > 0 {
+        warning!(
+            "{} of {} spans have no match in {}",
+            lost,
+            anchors.len(),
+            new
+        );
+    }
+    // info goes to stdout, where it would end up in the table
+    if output.is_some() {
+        info!(
+            "Matched {} of {} spans",
+            anchors.len() - lost,
+            anchors.len()
+        );
+    }
     return Ok(());
 }
 
-fn get_out_file_path(args: &Args) -> Result<String, ConverterError> {
-    let og_fname = match Path::new(&args.input)
-        .file_name()
-        .and_then(|oss| oss.to_str())
+/// Shortens text for a one-line report
+fn snippet(text: &str) -> String {
+    const MAX: usize = 60;
+    if text.chars().count() <= MAX {
+        return text.to_string();
+    }
+    return format!("{}...", text.chars().take(MAX).collect::<String>());
+}
+
+fn convert_file(
+    input: &Path,
+    out_path: &str,
+    options: &ConvertOptions,
+    setup: &Setup,
+) -> Result<Vec<UnconvertedFile>, ConverterError> {
+    debug!("Input: {:?}, output: {}", input, out_path);
+    let mut zip_arch = File::open(input)
+        .map_err(ConverterError::from)
+        .and_then(|f| Ok(ZipArchive::new(f)?))
+        .map_err(|e| e.in_stage(Stage::Input))?;
+
+    let mut builder = converter::Converter::builder();
+    if let Some(preset) = options.preset {
+        builder = builder.with_preset(preset);
+    }
+    builder = builder
+        .with_segmenter(options.granularity)
+        .with_normalization(options.normalize)
+        .with_chunking(options.chunk_length)
+        .with_word_break_removal(options.strip_word_breaks)
+        .with_punctuation_smartening(options.smarten_punctuation)
+        .with_replacements(setup.replacements.clone())
+        .with_respan(options.force_respan)
+        .with_fullscreen_fixes(options.fullscreen_fixes)
+        .with_blank_page_removal(options.remove_blank_pages)
+        .with_footnote_popups(options.footnote_popups)
+        .with_font_removal(options.remove_fonts)
+        .with_cover_normalization(options.normalize_cover)
+        .with_title_page(options.title_page)
+        .with_media_policy(options.media)
+        .with_calibre_removal(options.strip_calibre)
+        .with_spans(!options.no_spans)
+        .with_wrapper(!options.no_wrapper)
+        .with_cover_fix(!options.no_cover_fix)
+        .with_max_span_file_size(options.max_span_file_size)
+        .with_lenient(options.lenient)
+        .with_format(options.format)
+        .with_allowed_warnings(options.allow.iter().copied())
+        .with_config(&setup.config);
+    // the options a preset sets are only changed when given
+    if let Some(chars) = options.warn_length {
+        builder = builder.with_long_text_warning(chars);
+    }
+    if options.compression_level.is_some() {
+        builder = builder.with_compression_level(options.compression_level);
+    }
+    if options.deterministic {
+        builder = builder.with_deterministic(true);
+    }
+    if options.fix_layout {
+        builder = builder.with_layout_fix(true);
+    }
+    if options.grayscale_images {
+        builder = builder.with_grayscale_images(true);
+    }
+    if options.optimize_images
+        || options.max_image_size.is_some()
+        || options.image_quality.is_some()
     {
+        let mut images = ImageOptions::default();
+        if let Some((width, height)) = options.max_image_size {
+            images.max_width = width;
+            images.max_height = height;
+        }
+        images.quality = options.image_quality.unwrap_or(images.quality);
+        builder = builder.with_image_optimization(Some(images));
+    }
+    if setup.style.is_some() {
+        builder = builder.with_style(setup.style.clone());
+    }
+    // sidecar files are named after the book, without its extension
+    let base = out_path
+        .strip_suffix(&output_extension(options.extension()))
+        .unwrap_or(out_path);
+    if let Some(dir) = &options.emit_intermediate {
+        let stem = Path::new(base).file_name().unwrap_or_default();
+        builder = builder.with_intermediate_dir(Path::new(dir).join(stem));
+    }
+    if options.report {
+        builder = builder.with_report(format!("{}.report.json", base));
+    }
+    if options.span_map {
+        builder = builder.with_span_map(format!("{}.spans.json", base));
+    }
+    let conv = builder
+        .build()
+        .map_err(|e| ConverterError::from(e).in_stage(Stage::Setup))?;
+    return conv.convert(&mut zip_arch, out_path);
+}
+
+/// What the conversion options read from files, loaded once for every
+/// book converted with them
+struct Setup {
+    style: Option<String>,
+    replacements: Vec<Replacement>,
+    config: Config,
+}
+
+impl Setup {
+    fn load(options: &ConvertOptions) -> Result<Self, ConverterError> {
+        return Ok(Self {
+            style: kobo_style(options).map_err(|e| e.in_stage(Stage::Setup))?,
+            replacements: replacements(options).map_err(|e| e.in_stage(Stage::Setup))?,
+            config: config(options).map_err(|e| e.in_stage(Stage::Setup))?,
+        });
+    }
+}
+
+/// The --config file, or an empty config without one
+fn config(options: &ConvertOptions) -> Result<Config, ConverterError> {
+    let Some(path) = &options.config else {
+        return Ok(Config::default());
+    };
+    return Config::open(Path::new(path)).map_err(|e| {
+        return ConverterError::Other(format!("Cannot read config {}: {}", path, e));
+    });
+}
+
+/// The --replace replacements followed by those of the --replace-file
+fn replacements(options: &ConvertOptions) -> Result<Vec<Replacement>, ConverterError> {
+    let mut replacements = options.replace.clone();
+    if let Some(path) = &options.replace_file {
+        let from_file = Replacement::read_file(Path::new(path)).map_err(|e| {
+            return ConverterError::Other(format!("Cannot read replacements {}: {}", path, e));
+        })?;
+        replacements.extend(from_file);
+    }
+    return Ok(replacements);
+}
+
+/// The style added to content documents, if any: the kobo style followed
+/// by the fullscreen fixes, the hyphenation setting and the --css overrides
+fn kobo_style(options: &ConvertOptions) -> Result<Option<String>, ConverterError> {
+    let custom = match &options.css {
+        Some(path) => Some(std::fs::read_to_string(path).map_err(|e| {
+            return io_err!(e.kind(), "Cannot read stylesheet {}: {}", path, e);
+        })?),
+        None => None,
+    };
+    let hyphens = match (options.hyphenate, options.no_hyphenate) {
+        (true, _) => Some(converter::HYPHENATE_STYLE),
+        (_, true) => Some(converter::NO_HYPHENATE_STYLE),
+        _ => None,
+    };
+    if !options.kobo_style && !options.fullscreen_fixes && hyphens.is_none() && custom.is_none() {
+        return Ok(None);
+    }
+    let mut css = converter::KOBO_STYLE.to_string();
+    if options.fullscreen_fixes {
+        css.push('\n');
+        css.push_str(converter::FULLSCREEN_STYLE);
+    }
+    if let Some(hyphens) = hyphens {
+        css.push('\n');
+        css.push_str(hyphens);
+    }
+    if let Some(custom) = custom {
+        css.push('\n');
+        css.push_str(custom.trim_end());
+    }
+    return Ok(Some(css));
+}
+
+/// Lists the content documents that were copied unchanged into the
+/// kepubs, with why
+fn print_unconverted(unconverted: &[(&str, UnconvertedFile)]) {
+    info!("Copied {} content documents unchanged:", unconverted.len());
+    for (book, f) in unconverted {
+        info!("{}: {}: {}", book, f.file, f.error);
+    }
+}
+
+/// Prints a table of the books that failed to convert, so they can be
+/// retried without re-running the whole batch
+fn print_failures(failures: &[(&str, ConverterError)], total: usize) {
+    let rows = failures
+        .iter()
+        .map(|(f, e)| {
+            let stage = e.stage().map(|s| s.to_string()).unwrap_or_default();
+            [
+                f.to_string(),
+                stage,
+                e.category().to_string(),
+                e.to_string(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let header = ["FILE", "STAGE", "CATEGORY", "ERROR"];
+    let mut widths = header.map(|h| h.len());
+    for r in &rows {
+        for (w, c) in widths.iter_mut().zip(r.iter()) {
+            *w = (*w).max(c.chars().count());
+        }
+    }
+
+    info!("{} of {} books failed to convert:", failures.len(), total);
+    for r in std::iter::once(header.map(|h| h.to_string())).chain(rows) {
+        info!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            r[0],
+            r[1],
+            r[2],
+            r[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2]
+        );
+    }
+}
+
+/// Parses a size in bytes, with an optional binary K, M or G suffix
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, 'B'),
+    };
+    let shift = match unit {
+        'B' => 0,
+        'K' => 10,
+        'M' => 20,
+        'G' => 30,
+        _ => return Err(format!("unknown size unit '{}', expected K, M or G", unit)),
+    };
+    let n = digits
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size '{}': {}", s, e))?;
+    return n
+        .checked_mul(1 << shift)
+        .ok_or_else(|| return format!("size '{}' is too large", s));
+}
+
+/// `extension` with a leading dot, unless empty
+fn output_extension(extension: &str) -> String {
+    if extension.is_empty() || extension.starts_with('.') {
+        return extension.to_string();
+    }
+    return format!(".{}", extension);
+}
+
+/// Path of the book written for `input` in `out_dir`: the file name of
+/// `input` with its extension replaced by `extension`. A `.kepub.epub`
+/// double extension is replaced as a whole
+fn get_out_file_path(
+    input: &str,
+    out_dir: &str,
+    extension: &str,
+) -> Result<String, ConverterError> {
+    let og_fname = match Path::new(input).file_name().and_then(|oss| oss.to_str()) {
         Some(s) => s,
         None => {
             return Err(io_err!(
                 std::io::ErrorKind::Other,
                 "Unable to separate filename from {}",
-                args.input
+                input
             ))
         }
     };
 
-    let mut out_fname = Path::new(&args.out_dir).join(og_fname);
-    out_fname.set_extension("kepub");
+    let stem = match og_fname.to_ascii_lowercase().strip_suffix(".kepub.epub") {
+        Some(s) => &og_fname[..s.len()],
+        None => Path::new(og_fname)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or(og_fname),
+    };
+    let out_fname = Path::new(out_dir).join(format!("{}{}", stem, output_extension(extension)));
     return match out_fname.to_str() {
         Some(o) => Ok(o.to_string()),
         None => Err(io_err!(
@@ -0,0 +1,167 @@
+//! Handling of `<audio>` and `<video>` elements, which Kobo devices can't
+//! play.
+
+use std::str::FromStr;
+
+use xmltree::{Element, XMLNode};
+
+use crate::{
+    elem::{El, ElementExt, Rewriter},
+    href,
+};
+
+/// What to do with audio and video in content documents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MediaPolicy {
+    /// Leave them as they are
+    Keep,
+    /// Leave them, but report them
+    #[default]
+    Warn,
+    /// Replace them with their poster image or fallback content and drop
+    /// the media files from the book
+    Strip,
+}
+
+impl FromStr for MediaPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_ascii_lowercase().as_str() {
+            "keep" => Ok(MediaPolicy::Keep),
+            "warn" => Ok(MediaPolicy::Warn),
+            "strip" => Ok(MediaPolicy::Strip),
+            _ => Err(format!(
+                "unknown media policy '{}', expected keep, warn or strip",
+                s
+            )),
+        };
+    }
+}
+
+impl std::fmt::Display for MediaPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            MediaPolicy::Keep => "keep",
+            MediaPolicy::Warn => "warn",
+            MediaPolicy::Strip => "strip",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn is_media(elem: &Element) -> bool {
+    let name = elem.name.to_ascii_lowercase();
+    return name == "audio" || name == "video";
+}
+
+/// Counts the audio and video elements under `root`
+pub fn count_media(root: &Element) -> usize {
+    return root.find_where(|e| is_media(e)).count();
+}
+
+/// Replaces every audio and video element under `root`, a document at
+/// `doc_href`, with the video's poster image, or else with the fallback
+/// content inside the element. The id of the element moves to what
+/// replaces it, so links to it still lead there. Returns how many were
+/// replaced and the media files they played, as normalized hrefs relative
+/// to the same directory as `doc_href`
+pub fn strip_media(root: &mut Element, doc_href: &str) -> (usize, Vec<String>) {
+    struct Strip<'a> {
+        doc_href: &'a str,
+        count: usize,
+        sources: Vec<String>,
+    }
+
+    impl Rewriter for Strip<'_> {
+        fn leave(&mut self, elem: Element, out: &mut Vec<XMLNode>) {
+            if !is_media(&elem) {
+                out.push(XMLNode::Element(elem));
+                return;
+            }
+            self.count += 1;
+
+            let srcs = elem.attributes.get("src").into_iter().chain(
+                elem.find_children("source")
+                    .filter_map(|s| s.attributes.get("src")),
+            );
+            for src in srcs {
+                self.sources.push(href::resolve(self.doc_href, src));
+            }
+
+            let id = elem.attributes.get("id").cloned();
+            if let Some(poster) = elem.attributes.get("poster") {
+                let alt = elem.attributes.get("title").cloned().unwrap_or_default();
+                let mut img = El::new("img").attr("src", poster).attr("alt", &alt);
+                if let Some(id) = &id {
+                    img = img.id(id);
+                }
+                out.push(img.into());
+                return;
+            }
+            let mut fallback = elem
+                .children
+                .into_iter()
+                .filter(|c| match c {
+                    XMLNode::Element(e) => e.name != "source" && e.name != "track",
+                    _ => true,
+                })
+                .collect::<Vec<_>>();
+            if let Some(id) = id {
+                // on the first element of the fallback, or else on an empty
+                // span before it
+                let first = fallback.iter_mut().find_map(|c| return c.as_mut_element());
+                match first {
+                    Some(e) if !e.attributes.contains_key("id") => {
+                        e.attributes.insert("id".to_string(), id);
+                    }
+                    _ => fallback.insert(0, El::new("span").id(&id).into()),
+                }
+            }
+            out.extend(fallback);
+        }
+    }
+
+    let mut strip = Strip {
+        doc_href,
+        count: 0,
+        sources: Vec::new(),
+    };
+    root.rewrite(&mut strip);
+    return (strip.count, strip.sources);
+}
+
+#[cfg(test)]
+mod test {
+    use xmltree::Element;
+
+    use super::{count_media, strip_media};
+    use crate::elem::ElementExt;
+
+    #[test]
+    fn test_strip_media() {
+        let mut body = Element::parse(
+            r#"<body><video id="v1" src="../media/a.mp4" poster="../images/a.jpg"/>
+            <p>Listen: <audio id="a1" controls="controls"><source src="b.mp3"/><source src="b.ogg"/>
+            Your reader can't play audio.</audio></p></body>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(count_media(&body), 2);
+
+        let (count, sources) = strip_media(&mut body, "text/ch1.xhtml");
+        assert_eq!(count, 2);
+        assert_eq!(sources, ["media/a.mp4", "text/b.mp3", "text/b.ogg"]);
+        assert_eq!(count_media(&body), 0);
+        let img = body.find_first("img").unwrap();
+        assert_eq!(img.attributes["src"], "../images/a.jpg");
+        assert_eq!(img.attributes["id"], "v1");
+        assert_eq!(body.find_first("span").unwrap().attributes["id"], "a1");
+        assert!(body.find_first("source").is_none());
+        let p = body.get_child("p").unwrap();
+        assert!(p
+            .children
+            .iter()
+            .any(|c| c.as_text().is_some_and(|t| t.contains("can't play audio"))));
+    }
+}
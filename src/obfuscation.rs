@@ -0,0 +1,175 @@
+//! Font obfuscation, listed in `META-INF/encryption.xml`. Publishers XOR the
+//! start of embedded fonts with a key made from the book's identifier so
+//! they can't be copied out as is. The algorithms are public, so the
+//! converter restores the fonts and drops their entries, since Kobo devices
+//! don't always undo it and show the book with fallback fonts.
+
+use sha1::{Digest, Sha1};
+use xmltree::Element;
+
+use crate::elem::ElementExt;
+
+/// Algorithm URI of IDPF font obfuscation
+pub const IDPF_ALGORITHM: &str = "http://www.idpf.org/2008/embedding";
+/// Algorithm URI of Adobe font obfuscation
+pub const ADOBE_ALGORITHM: &str = "http://ns.adobe.com/pdf/enc#RC";
+
+/// The ways of obfuscating a font
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// The first 1040 bytes XORed with the SHA-1 of the unique identifier
+    Idpf,
+    /// The first 1024 bytes XORed with the bytes of the book's UUID
+    Adobe,
+}
+
+impl Algorithm {
+    pub fn from_uri(uri: &str) -> Option<Self> {
+        return match uri {
+            IDPF_ALGORITHM => Some(Algorithm::Idpf),
+            ADOBE_ALGORITHM => Some(Algorithm::Adobe),
+            _ => None,
+        };
+    }
+
+    /// How many bytes at the start of the font are obfuscated
+    fn len(self) -> usize {
+        return match self {
+            Algorithm::Idpf => 1040,
+            Algorithm::Adobe => 1024,
+        };
+    }
+
+    /// The key for a book with the identifiers `identifiers`, the unique
+    /// identifier first. None when the book has no identifier this
+    /// algorithm can use
+    pub fn key(self, identifiers: &[String]) -> Option<Vec<u8>> {
+        return match self {
+            Algorithm::Idpf => {
+                let uid = identifiers.first()?;
+                // the spec removes these four characters, not all whitespace
+                let uid = uid
+                    .chars()
+                    .filter(|c| return !matches!(c, ' ' | '\t' | '\r' | '\n'))
+                    .collect::<String>();
+                Some(Sha1::digest(uid.as_bytes()).to_vec())
+            }
+            Algorithm::Adobe => identifiers.iter().find_map(|id| return uuid_bytes(id)),
+        };
+    }
+}
+
+/// The obfuscated fonts of a parsed `encryption.xml`, as their URI relative
+/// to the archive root with the algorithm used. Entries with other
+/// algorithms, like real encryption, are left out
+pub fn obfuscated_fonts(root: &Element) -> Vec<(String, Algorithm)> {
+    return root
+        .find_children("EncryptedData")
+        .filter_map(|data| {
+            let method = data.find_first("EncryptionMethod")?;
+            let algorithm = Algorithm::from_uri(method.attributes.get("Algorithm")?)?;
+            let uri = data.find_first("CipherReference")?.attributes.get("URI")?;
+            return Some((uri.clone(), algorithm));
+        })
+        .collect();
+}
+
+/// Undoes the obfuscation of `font` with `key`. Returns false, leaving the
+/// font unchanged, when the result doesn't start like a font, as happens
+/// with the key of another identifier
+pub fn deobfuscate(font: &mut [u8], algorithm: Algorithm, key: &[u8]) -> bool {
+    let len = algorithm.len().min(font.len());
+    let xor = |font: &mut [u8]| {
+        for (i, b) in font[..len].iter_mut().enumerate() {
+            *b ^= key[i % key.len()];
+        }
+    };
+    xor(font);
+    if is_font(font) {
+        return true;
+    }
+    xor(font);
+    return false;
+}
+
+/// Whether `data` starts with the signature of a TrueType, OpenType or WOFF
+/// font
+pub fn is_font(data: &[u8]) -> bool {
+    return [
+        b"\x00\x01\x00\x00",
+        b"OTTO",
+        b"true",
+        b"typ1",
+        b"ttcf",
+        b"wOFF",
+        b"wOF2",
+    ]
+    .iter()
+    .any(|sig| return data.starts_with(*sig));
+}
+
+/// The 16 bytes of a UUID identifier like `urn:uuid:...`
+fn uuid_bytes(id: &str) -> Option<Vec<u8>> {
+    let id = id.trim();
+    let hex = id
+        .strip_prefix("urn:uuid:")
+        .unwrap_or(id)
+        .chars()
+        .filter(|c| return *c != '-')
+        .collect::<String>();
+    if hex.len() != 32 {
+        return None;
+    }
+    return (0..32)
+        .step_by(2)
+        .map(|i| return u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect();
+}
+
+#[cfg(test)]
+mod test {
+    use xmltree::Element;
+
+    use super::{deobfuscate, obfuscated_fonts, uuid_bytes, Algorithm};
+
+    #[test]
+    fn test_deobfuscate() {
+        let root = Element::parse(
+            r#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container"
+            xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+            <enc:EncryptedData><enc:EncryptionMethod Algorithm="http://www.idpf.org/2008/embedding"/>
+            <enc:CipherData><enc:CipherReference URI="OEBPS/fonts/a.otf"/></enc:CipherData></enc:EncryptedData>
+            <enc:EncryptedData><enc:EncryptionMethod Algorithm="http://www.w3.org/2001/04/xmlenc#aes128-cbc"/>
+            <enc:CipherData><enc:CipherReference URI="OEBPS/text/ch1.xhtml"/></enc:CipherData></enc:EncryptedData>
+            </encryption>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            obfuscated_fonts(&root),
+            [("OEBPS/fonts/a.otf".to_string(), Algorithm::Idpf)]
+        );
+
+        let font = [b"OTTO".as_slice(), &[7; 2000]].concat();
+        let ids = [" urn:uuid:0f6e2b4a-1c3d-4e5f-8a9b-0c1d2e3f4a5b\n".to_string()];
+        for algorithm in [Algorithm::Idpf, Algorithm::Adobe] {
+            let key = algorithm.key(&ids).unwrap();
+            let mut data = font.clone();
+            // obfuscating is the same XOR
+            assert!(!deobfuscate(&mut data, algorithm, &key));
+            for (i, b) in data[..algorithm.len()].iter_mut().enumerate() {
+                *b ^= key[i % key.len()];
+            }
+            assert_ne!(data, font);
+            assert!(deobfuscate(&mut data, algorithm, &key));
+            assert_eq!(data, font);
+        }
+        // keyed on the identifier without whitespace
+        assert_eq!(
+            Algorithm::Idpf.key(&ids),
+            Algorithm::Idpf.key(&["urn:uuid:0f6e2b4a-1c3d-4e5f-8a9b-0c1d2e3f4a5b".to_string()])
+        );
+        assert_eq!(uuid_bytes("isbn:9780000000000"), None);
+        assert_eq!(Algorithm::Adobe.key(&["isbn:1".to_string()]), None);
+    }
+}
@@ -0,0 +1,803 @@
+//! Reading and editing the package document (`.opf`) of an epub, without
+//! running the rest of the conversion.
+
+use std::path::Path;
+
+use xmltree::{Element, XMLNode};
+
+use crate::{
+    converter::serialize_xml,
+    elem::{ElementExt, Selector},
+    errors::{xml_err, ConverterError},
+    href,
+    logger::debug,
+};
+
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+
+/// An `<item>` of the manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestItem {
+    pub id: String,
+    /// As written in the package document, relative to it
+    pub href: String,
+    pub media_type: String,
+    pub properties: Vec<String>,
+}
+
+/// An `<itemref>` of the spine
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpineItem {
+    pub idref: String,
+    /// False for items marked `linear="no"`, like pop-up notes
+    pub linear: bool,
+}
+
+/// A parsed package document
+#[derive(Debug, Clone)]
+pub struct Package {
+    root: Element,
+}
+
+impl Package {
+    pub fn load(path: &Path) -> Result<Self, ConverterError> {
+        return Self::parse(std::fs::File::open(path)?);
+    }
+
+    pub fn parse(r: impl std::io::Read) -> Result<Self, ConverterError> {
+        let root = Element::parse(r)?;
+        if root.name != "package" {
+            return Err(xml_err!(
+                "Expected a <package> root element, found <{}>",
+                root.name
+            ));
+        }
+        return Ok(Self { root });
+    }
+
+    /// Writes the package document to `path`
+    pub fn save(&self, path: &Path) -> Result<(), ConverterError> {
+        std::fs::write(path, serialize_xml(&self.root)?)?;
+        return Ok(());
+    }
+
+    pub fn root(&self) -> &Element {
+        return &self.root;
+    }
+
+    pub fn root_mut(&mut self) -> &mut Element {
+        return &mut self.root;
+    }
+
+    /// Manifest items in document order. Items without an href are skipped,
+    /// a missing id is left empty
+    pub fn manifest(&self) -> Vec<ManifestItem> {
+        return self
+            .manifest_elems()
+            .filter(|item| item.attributes.contains_key("href"))
+            .map(|item| {
+                return ManifestItem {
+                    id: item.attributes.get("id").cloned().unwrap_or_default(),
+                    href: item.attributes["href"].clone(),
+                    media_type: item
+                        .attributes
+                        .get("media-type")
+                        .cloned()
+                        .unwrap_or_default(),
+                    properties: item
+                        .attributes
+                        .get("properties")
+                        .map(|p| p.split_whitespace().map(String::from).collect())
+                        .unwrap_or_default(),
+                };
+            })
+            .collect();
+    }
+
+    /// Manifest items with the given media type
+    pub fn manifest_by_type(&self, media_type: &str) -> Vec<ManifestItem> {
+        return self
+            .manifest()
+            .into_iter()
+            .filter(|i| i.media_type == media_type)
+            .collect();
+    }
+
+    pub fn item(&self, id: &str) -> Option<ManifestItem> {
+        return self.manifest().into_iter().find(|i| i.id == id);
+    }
+
+    /// The spine in reading order
+    pub fn spine(&self) -> Vec<SpineItem> {
+        let spine = match self.root.get_child("spine") {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        return spine
+            .find_children("itemref")
+            .filter(|i| i.attributes.contains_key("idref"))
+            .map(|i| {
+                return SpineItem {
+                    idref: i.attributes["idref"].clone(),
+                    linear: i.attributes.get("linear").is_none_or(|l| l != "no"),
+                };
+            })
+            .collect();
+    }
+
+    /// The `page-progression-direction` of the spine, `ltr` or `rtl`, if
+    /// it is set
+    pub fn page_progression(&self) -> Option<String> {
+        return self
+            .root
+            .get_child("spine")?
+            .attributes
+            .get("page-progression-direction")
+            .map(|d| d.trim().to_ascii_lowercase())
+            .filter(|d| !d.is_empty() && d != "default");
+    }
+
+    /// The content of `<meta name="primary-writing-mode">`, like
+    /// `vertical-rl`, which some books set for Kindle
+    pub fn primary_writing_mode(&self) -> Option<String> {
+        return self
+            .root
+            .get_child("metadata")?
+            .find_children("meta")
+            .find(|m| {
+                m.attributes
+                    .get("name")
+                    .is_some_and(|n| n == "primary-writing-mode")
+            })?
+            .attributes
+            .get("content")
+            .map(|c| c.trim().to_ascii_lowercase());
+    }
+
+    /// Text of the `<meta property="PROPERTY">` of the metadata, like
+    /// `rendition:layout`
+    pub fn meta_property(&self, property: &str) -> Option<String> {
+        return self
+            .root
+            .get_child("metadata")?
+            .find_children("meta")
+            .find(|m| m.attributes.get("property").is_some_and(|p| p == property))
+            .map(|m| m.get_text().unwrap_or_default().trim().to_string());
+    }
+
+    /// Sets the text of the `<meta property="PROPERTY">` of the metadata,
+    /// adding it if needed
+    pub fn set_meta_property(&mut self, property: &str, value: &str) -> Result<(), ConverterError> {
+        let metadata = match self.root.get_mut_child("metadata") {
+            Some(m) => m,
+            None => return Err(xml_err!("Cannot find <metadata> in package document")),
+        };
+        let pos = metadata.children.iter().position(|c| {
+            return c.as_element().is_some_and(|e| {
+                e.name == "meta" && e.attributes.get("property").is_some_and(|p| p == property)
+            });
+        });
+        let meta = match pos {
+            Some(i) => metadata.children[i].as_mut_element().unwrap(),
+            None => {
+                let mut meta = Element::new("meta");
+                meta.attributes
+                    .insert("property".to_string(), property.to_string());
+                metadata.children.push(XMLNode::Element(meta));
+                metadata
+                    .children
+                    .last_mut()
+                    .unwrap()
+                    .as_mut_element()
+                    .unwrap()
+            }
+        };
+        meta.children = vec![XMLNode::Text(value.to_string())];
+        return Ok(());
+    }
+
+    /// Whether the pages of the book are pre-paginated: its
+    /// `rendition:layout` says so, every document of the spine does, or it
+    /// has the `fixed-layout` meta of Kindle books
+    pub fn is_fixed_layout(&self) -> bool {
+        if self.meta_property("rendition:layout").as_deref() == Some("pre-paginated") {
+            return true;
+        }
+        let kindle = self.root.get_child("metadata").is_some_and(|m| {
+            return m.find_children("meta").any(|meta| {
+                return meta
+                    .attributes
+                    .get("name")
+                    .is_some_and(|n| n == "fixed-layout")
+                    && meta
+                        .attributes
+                        .get("content")
+                        .is_some_and(|c| c.trim() == "true");
+            });
+        });
+        if kindle {
+            return true;
+        }
+        let itemrefs = self
+            .root
+            .get_child("spine")
+            .map(|s| s.find_children("itemref").collect::<Vec<_>>())
+            .unwrap_or_default();
+        return !itemrefs.is_empty()
+            && itemrefs.iter().all(|i| {
+                return i.attributes.get("properties").is_some_and(|p| {
+                    return p
+                        .split_whitespace()
+                        .any(|p| p == "rendition:layout-pre-paginated");
+                });
+            });
+    }
+
+    /// Removes the `<itemref>`s of item `idref` from the spine. Returns
+    /// false if it wasn't in the spine
+    pub fn remove_from_spine(&mut self, idref: &str) -> bool {
+        let Some(spine) = self.root.get_mut_child("spine") else {
+            return false;
+        };
+        let before = spine.children.len();
+        spine.children.retain(|c| {
+            return !matches!(c, XMLNode::Element(e)
+                if e.name == "itemref" && e.attributes.get("idref").is_some_and(|i| i == idref));
+        });
+        return spine.children.len() != before;
+    }
+
+    /// Adds an `<itemref>` of item `idref` to the start of the spine
+    pub fn prepend_to_spine(&mut self, idref: &str) -> Result<(), ConverterError> {
+        let spine = match self.root.get_mut_child("spine") {
+            Some(s) => s,
+            None => return Err(xml_err!("Cannot find <spine> in package document")),
+        };
+        let mut itemref = Element::new("itemref");
+        itemref
+            .attributes
+            .insert("idref".to_string(), idref.to_string());
+        spine.children.insert(0, XMLNode::Element(itemref));
+        return Ok(());
+    }
+
+    /// Removes item `id` from the manifest. Returns false if there was none
+    pub fn remove_item(&mut self, id: &str) -> bool {
+        let Some(manifest) = self.root.get_mut_child("manifest") else {
+            return false;
+        };
+        let before = manifest.children.len();
+        manifest.children.retain(|c| {
+            return !matches!(c, XMLNode::Element(e)
+                if e.name == "item" && e.attributes.get("id").is_some_and(|i| i == id));
+        });
+        return manifest.children.len() != before;
+    }
+
+    /// Adds `item` to the end of the manifest
+    pub fn add_item(&mut self, item: &ManifestItem) -> Result<(), ConverterError> {
+        let manifest = match self.root.get_mut_child("manifest") {
+            Some(m) => m,
+            None => return Err(xml_err!("Cannot find <manifest> in package document")),
+        };
+        let mut elem = Element::new("item");
+        elem.attributes.insert("id".to_string(), item.id.clone());
+        elem.attributes
+            .insert("href".to_string(), item.href.clone());
+        elem.attributes
+            .insert("media-type".to_string(), item.media_type.clone());
+        if !item.properties.is_empty() {
+            elem.attributes
+                .insert("properties".to_string(), item.properties.join(" "));
+        }
+        manifest.children.push(XMLNode::Element(elem));
+        return Ok(());
+    }
+
+    /// `base` if no manifest item has that id, or else the first of
+    /// `base-2`, `base-3`... that is free
+    pub fn unused_id(&self, base: &str) -> String {
+        let ids = self
+            .manifest()
+            .into_iter()
+            .map(|i| i.id)
+            .collect::<Vec<_>>();
+        let mut id = base.to_string();
+        let mut n = 1;
+        while ids.contains(&id) {
+            n += 1;
+            id = format!("{}-{}", base, n);
+        }
+        return id;
+    }
+
+    /// Text of every `dc:identifier`, the one the `unique-identifier` of the
+    /// package names first
+    pub fn identifiers(&self) -> Vec<String> {
+        let Some(metadata) = self.root.get_child("metadata") else {
+            return Vec::new();
+        };
+        let uid = self.root.attributes.get("unique-identifier");
+        let mut ids = metadata
+            .find_children("identifier")
+            .map(|e| {
+                let unique = uid.is_some() && e.attributes.get("id") == uid;
+                return (!unique, e.get_text().unwrap_or_default().trim().to_string());
+            })
+            .collect::<Vec<_>>();
+        ids.sort_by_key(|(other, _)| return *other);
+        return ids.into_iter().map(|(_, id)| return id).collect();
+    }
+
+    /// Whether the `version` of the package is 3.x
+    pub fn is_epub3(&self) -> bool {
+        return self
+            .root
+            .attributes
+            .get("version")
+            .is_some_and(|v| v.trim().starts_with('3'));
+    }
+
+    /// Sets the media type of item `id`. Returns false if there is no such
+    /// item
+    pub fn set_media_type(&mut self, id: &str, media_type: &str) -> bool {
+        let item = self
+            .manifest_elems_mut()
+            .find(|e| e.attributes.get("id").is_some_and(|i| i == id));
+        return match item {
+            Some(item) => {
+                item.attributes
+                    .insert("media-type".to_string(), media_type.to_string());
+                true
+            }
+            None => false,
+        };
+    }
+
+    /// Href of the first `<reference>` of the guide with the type `kind`,
+    /// like `cover` or `text`
+    pub fn guide_reference(&self, kind: &str) -> Option<String> {
+        return self
+            .root
+            .get_child("guide")?
+            .find_children("reference")
+            .find(|r| return r.attributes.get("type").is_some_and(|t| t == kind))
+            .and_then(|r| return r.attributes.get("href").cloned());
+    }
+
+    /// Adds a `<reference>` to the guide, adding the guide after the spine
+    /// if there is none
+    pub fn add_guide_reference(&mut self, kind: &str, title: &str, href: &str) {
+        if self.root.get_child("guide").is_none() {
+            self.root
+                .children
+                .push(XMLNode::Element(Element::new("guide")));
+        }
+        let guide = self.root.get_mut_child("guide").unwrap();
+        let mut reference = Element::new("reference");
+        for (key, value) in [("type", kind), ("title", title), ("href", href)] {
+            reference
+                .attributes
+                .insert(key.to_string(), value.to_string());
+        }
+        guide.children.push(XMLNode::Element(reference));
+    }
+
+    /// Text of every metadata element with the local name `name`, e.g.
+    /// `title` or `creator`
+    pub fn metadata(&self, name: &str) -> Vec<String> {
+        let metadata = match self.root.get_child("metadata") {
+            Some(m) => m,
+            None => return Vec::new(),
+        };
+        return metadata
+            .find_children(name)
+            .map(|e| e.get_text().unwrap_or_default().trim().to_string())
+            .collect();
+    }
+
+    /// Sets the text of the first `dc:<name>` element, adding one if there
+    /// is none
+    pub fn set_metadata(&mut self, name: &str, value: &str) -> Result<(), ConverterError> {
+        let metadata = match self.root.get_mut_child("metadata") {
+            Some(m) => m,
+            None => return Err(xml_err!("Cannot find <metadata> in package document")),
+        };
+        let pos = metadata
+            .children
+            .iter()
+            .position(|c| c.as_element().is_some_and(|e| e.name == name));
+        let elem = match pos {
+            Some(i) => metadata.children[i].as_mut_element().unwrap(),
+            None => {
+                let mut e = Element::new(name);
+                e.prefix = Some("dc".to_string());
+                e.namespace = Some(DC_NAMESPACE.to_string());
+                metadata.children.push(XMLNode::Element(e));
+                metadata
+                    .children
+                    .last_mut()
+                    .unwrap()
+                    .as_mut_element()
+                    .unwrap()
+            }
+        };
+        elem.children = vec![XMLNode::Text(value.to_string())];
+        return Ok(());
+    }
+
+    /// The cover image: the item with the `cover-image` property, or else
+    /// the one `<meta name="cover">` refers to, by id or by href
+    pub fn cover(&self) -> Option<ManifestItem> {
+        let items = self.manifest();
+        if let Some(i) = items
+            .iter()
+            .find(|i| i.properties.iter().any(|p| p == "cover-image"))
+        {
+            return Some(i.clone());
+        }
+        let content = self.cover_meta()?.attributes.get("content")?;
+        return items
+            .iter()
+            .find(|i| i.id == *content)
+            .or_else(|| find_by_href(&items, content))
+            .cloned();
+    }
+
+    /// An image that looks like the cover when the package doesn't say
+    /// which one is: the one with the `cover-image` property, or else the
+    /// first whose id or file name contains "cover"
+    pub fn guess_cover(&self) -> Option<ManifestItem> {
+        let images = self
+            .manifest()
+            .into_iter()
+            .filter(|i| i.media_type.starts_with("image/"))
+            .collect::<Vec<_>>();
+        if let Some(i) = images
+            .iter()
+            .find(|i| i.properties.iter().any(|p| p == "cover-image"))
+        {
+            return Some(i.clone());
+        }
+        let has_cover = |s: &str| s.to_ascii_lowercase().contains("cover");
+        return images
+            .iter()
+            .find(|i| has_cover(&i.id))
+            .or_else(|| {
+                return images.iter().find(|i| {
+                    let href = href::normalize(&i.href);
+                    return has_cover(href.rsplit('/').next().unwrap_or_default());
+                });
+            })
+            .cloned();
+    }
+
+    /// Makes item `id` the cover: points `<meta name="cover">` at it, adding
+    /// the meta if needed, and moves the `cover-image` property to it
+    pub fn set_cover(&mut self, id: &str) -> Result<(), ConverterError> {
+        if self.item(id).is_none() {
+            return Err(xml_err!("Cannot find <item id='{}'> in manifest", id));
+        }
+
+        for item in self.manifest_elems_mut() {
+            let is_cover = item.attributes.get("id").is_some_and(|i| i == id);
+            let mut props = item
+                .attributes
+                .get("properties")
+                .map(|p| {
+                    p.split_whitespace()
+                        .filter(|p| *p != "cover-image")
+                        .map(String::from)
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            if is_cover {
+                props.push("cover-image".to_string());
+            }
+            if props.is_empty() {
+                item.attributes.remove("properties");
+            } else {
+                item.attributes
+                    .insert("properties".to_string(), props.join(" "));
+            }
+        }
+
+        let meta_sel = Selector::parse("metadata > meta[name=cover]")?;
+        match self.root.select_first_mut(&meta_sel) {
+            Some(meta) => {
+                meta.attributes
+                    .insert("content".to_string(), id.to_string());
+            }
+            None => {
+                let metadata = match self.root.get_mut_child("metadata") {
+                    Some(m) => m,
+                    None => return Err(xml_err!("Cannot find <metadata> in package document")),
+                };
+                let mut meta = Element::new("meta");
+                meta.attributes
+                    .insert("name".to_string(), "cover".to_string());
+                meta.attributes
+                    .insert("content".to_string(), id.to_string());
+                metadata.children.push(XMLNode::Element(meta));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Returns the id of the manifest item `<meta name="cover">` points to,
+    /// or None if the package has no such meta. Some books put the image's
+    /// href in the meta instead of its id, in which case the meta is fixed
+    /// to use the id
+    pub fn resolve_cover_meta(&mut self) -> Result<Option<String>, ConverterError> {
+        let content = match self.cover_meta() {
+            Some(meta) => match meta.attributes.get("content") {
+                Some(c) => c.clone(),
+                None => {
+                    return Err(xml_err!(
+                    "Cannot read content attribute in <meta name='cover'> element in content.opf"
+                ))
+                }
+            },
+            None => return Ok(None),
+        };
+
+        let items = self.manifest();
+        if items.iter().any(|i| i.id == content) {
+            return Ok(Some(content));
+        }
+
+        return match find_by_href(&items, &content) {
+            Some(item) => {
+                debug!(
+                    "<meta name='cover'> refers to '{}' by href, changing it to the item id '{}'",
+                    content, item.id
+                );
+                let meta_sel = Selector::parse("metadata > meta[name=cover]")?;
+                if let Some(meta) = self.root.select_first_mut(&meta_sel) {
+                    meta.attributes
+                        .insert("content".to_string(), item.id.clone());
+                }
+                Ok(Some(item.id.clone()))
+            }
+            None => Err(xml_err!(
+                "Cannot find <item id='{}'> element in content.opf",
+                content
+            )),
+        };
+    }
+
+    /// The `<meta name='cover'>` element of the metadata
+    pub(crate) fn cover_meta(&self) -> Option<&Element> {
+        return self
+            .root
+            .get_child("metadata")?
+            .find_children("meta")
+            .find(|m| m.attributes.get("name").is_some_and(|n| n == "cover"));
+    }
+
+    fn manifest_elems(&self) -> impl Iterator<Item = &Element> {
+        return self
+            .root
+            .get_child("manifest")
+            .into_iter()
+            .flat_map(|m| m.find_children("item"));
+    }
+
+    fn manifest_elems_mut(&mut self) -> impl Iterator<Item = &mut Element> {
+        return self
+            .root
+            .get_mut_child("manifest")
+            .into_iter()
+            .flat_map(|m| m.children.iter_mut())
+            .filter_map(|c| c.as_mut_element())
+            .filter(|e| e.name == "item");
+    }
+}
+
+/// Finds the item whose href is `path`, which may also be given relative to
+/// the archive root rather than the package document
+fn find_by_href<'a>(items: &'a [ManifestItem], path: &str) -> Option<&'a ManifestItem> {
+    let path = href::normalize(path);
+    return items.iter().find(|item| {
+        let h = href::normalize(&item.href);
+        return h == path || path.ends_with(&format!("/{}", h));
+    });
+}
+
+/// The `full-path` of the package document in a parsed `container.xml`.
+/// Rootfiles of other media types, for alternate renditions, are skipped
+pub fn rootfile_path(container: &Element) -> Option<String> {
+    let mut rootfiles = container.find_where(|e| e.name == "rootfile");
+    return rootfiles
+        .find(|e| {
+            return e
+                .attributes
+                .get("media-type")
+                .is_none_or(|t| t == "application/oebps-package+xml");
+        })
+        .and_then(|e| e.attributes.get("full-path"))
+        .filter(|p| !p.is_empty())
+        .cloned();
+}
+
+#[cfg(test)]
+mod test {
+    use xmltree::Element;
+
+    use super::{rootfile_path, ManifestItem, Package};
+    use crate::elem::ElementExt;
+
+    const TEST_OPF: &str = r#"<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+        <dc:title>Title</dc:title>
+        <meta name="cover" content="img"/>
+    </metadata>
+    <manifest>
+        <item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/>
+        <item id="img" href="images/cover%20art.jpg" media-type="image/jpeg"/>
+        <item id="other" href="other.jpg" media-type="image/jpeg" properties="svg"/>
+    </manifest>
+    <spine><itemref idref="c1"/><itemref idref="other" linear="no"/></spine>
+</package>"#;
+
+    fn package(cover: &str) -> Package {
+        let opf = TEST_OPF.replace(r#"content="img""#, &format!(r#"content="{}""#, cover));
+        return Package::parse(opf.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_query() {
+        let pkg = package("img");
+        let ids = pkg.manifest().into_iter().map(|i| i.id).collect::<Vec<_>>();
+        assert_eq!(ids, ["c1", "img", "other"]);
+        assert_eq!(pkg.manifest_by_type("image/jpeg").len(), 2);
+        assert_eq!(pkg.item("other").unwrap().properties, ["svg"]);
+
+        let spine = pkg.spine();
+        assert_eq!(spine.len(), 2);
+        assert!(spine[0].linear && !spine[1].linear);
+
+        assert_eq!(pkg.metadata("title"), ["Title"]);
+        assert_eq!(pkg.cover().unwrap().id, "img");
+        assert!(Package::parse("<html/>".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut pkg = package("img");
+        pkg.set_metadata("title", "New").unwrap();
+        pkg.set_metadata("creator", "Someone").unwrap();
+        assert_eq!(pkg.metadata("title"), ["New"]);
+        assert_eq!(pkg.metadata("creator"), ["Someone"]);
+
+        pkg.set_cover("other").unwrap();
+        let cover = pkg.cover().unwrap();
+        assert_eq!(cover.id, "other");
+        assert_eq!(cover.properties, ["svg", "cover-image"]);
+        assert!(pkg.set_cover("missing").is_err());
+
+        assert!(pkg.set_media_type("c1", "text/html"));
+        assert_eq!(pkg.item("c1").unwrap().media_type, "text/html");
+        assert!(!pkg.set_media_type("missing", "text/html"));
+
+        assert_eq!(pkg.unused_id("c1"), "c1-2");
+        let nav = ManifestItem {
+            id: pkg.unused_id("nav"),
+            href: "nav.xhtml".to_string(),
+            media_type: "application/xhtml+xml".to_string(),
+            properties: vec!["nav".to_string()],
+        };
+        pkg.add_item(&nav).unwrap();
+        assert_eq!(pkg.item("nav"), Some(nav));
+        assert!(!pkg.is_epub3());
+
+        assert!(pkg.remove_item("img"));
+        assert!(pkg.item("img").is_none());
+        assert!(!pkg.remove_item("img"));
+
+        assert!(pkg.remove_from_spine("c1"));
+        assert!(!pkg.remove_from_spine("c1"));
+        assert_eq!(pkg.spine().len(), 1);
+        pkg.prepend_to_spine("nav").unwrap();
+        assert_eq!(pkg.spine()[0].idref, "nav");
+
+        // survives a round trip
+        let mut buf = Vec::new();
+        pkg.root().write(&mut buf).unwrap();
+        let pkg = Package::parse(buf.as_slice()).unwrap();
+        assert_eq!(pkg.metadata("creator"), ["Someone"]);
+        assert_eq!(pkg.cover().unwrap().id, "other");
+    }
+
+    #[test]
+    fn test_fixed_layout() {
+        let mut pkg = package("img");
+        assert!(!pkg.is_fixed_layout());
+        assert_eq!(pkg.meta_property("rendition:layout"), None);
+        pkg.set_meta_property("rendition:layout", "reflowable")
+            .unwrap();
+        pkg.set_meta_property("rendition:layout", "pre-paginated")
+            .unwrap();
+        assert_eq!(
+            pkg.meta_property("rendition:layout").as_deref(),
+            Some("pre-paginated")
+        );
+        assert!(pkg.is_fixed_layout());
+        assert_eq!(pkg.root().find_all("meta").len(), 2);
+
+        let kindle = TEST_OPF.replace(
+            "<dc:title>",
+            r#"<meta name="fixed-layout" content="true"/><dc:title>"#,
+        );
+        assert!(Package::parse(kindle.as_bytes()).unwrap().is_fixed_layout());
+        let spine = TEST_OPF.replace(
+            "linear=\"no\"",
+            "properties=\"rendition:layout-pre-paginated\"",
+        );
+        assert!(!Package::parse(spine.as_bytes()).unwrap().is_fixed_layout());
+        let spine = spine.replace(
+            r#"idref="c1""#,
+            r#"idref="c1" properties="page-spread-left rendition:layout-pre-paginated""#,
+        );
+        assert!(Package::parse(spine.as_bytes()).unwrap().is_fixed_layout());
+    }
+
+    #[test]
+    fn test_resolve_cover_meta() {
+        assert_eq!(
+            package("img").resolve_cover_meta().unwrap().as_deref(),
+            Some("img")
+        );
+        for href in ["images/cover art.jpg", "OEBPS/images/cover%20art.jpg"] {
+            let mut pkg = package(href);
+            assert_eq!(pkg.cover().unwrap().id, "img");
+            assert_eq!(pkg.resolve_cover_meta().unwrap().as_deref(), Some("img"));
+            let meta = pkg.cover_meta().unwrap();
+            assert_eq!(meta.attributes["content"], "img");
+        }
+        assert!(package("missing.jpg").resolve_cover_meta().is_err());
+
+        let mut no_cover = Package::parse("<package><metadata/></package>".as_bytes()).unwrap();
+        assert_eq!(no_cover.resolve_cover_meta().unwrap(), None);
+    }
+
+    #[test]
+    fn test_guess_cover() {
+        let pkg = package("img");
+        assert_eq!(pkg.guess_cover().unwrap().id, "img");
+
+        let opf = TEST_OPF
+            .replace(r#"id="img""#, r#"id="i1""#)
+            .replace(r#"properties="svg""#, r#"properties="cover-image""#);
+        let pkg = Package::parse(opf.as_bytes()).unwrap();
+        assert_eq!(pkg.guess_cover().unwrap().id, "other");
+
+        let opf = TEST_OPF.replace(r#"id="img""#, r#"id="i1""#);
+        let pkg = Package::parse(opf.as_bytes()).unwrap();
+        assert_eq!(pkg.guess_cover().unwrap().id, "i1");
+
+        let opf = opf.replace("cover%20art", "front");
+        let pkg = Package::parse(opf.as_bytes()).unwrap();
+        assert_eq!(pkg.guess_cover(), None);
+    }
+
+    #[test]
+    fn test_rootfile_path() {
+        let container = Element::parse(
+            r#"<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+            <rootfiles>
+            <rootfile full-path="OEBPS/book.pdf" media-type="application/pdf"/>
+            <rootfile full-path="OEBPS/package.opf" media-type="application/oebps-package+xml"/>
+            </rootfiles></container>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            rootfile_path(&container).as_deref(),
+            Some("OEBPS/package.opf")
+        );
+
+        let empty = Element::parse("<container><rootfiles/></container>".as_bytes()).unwrap();
+        assert_eq!(rootfile_path(&empty), None);
+    }
+}
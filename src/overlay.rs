@@ -0,0 +1,97 @@
+//! EPUB 3 media overlays: SMIL documents that play recorded narration in
+//! step with the text, by pointing at elements of the content documents by
+//! id. Kobo devices don't play them, but the converter keeps them working
+//! for the readers that do.
+
+use std::collections::BTreeSet;
+
+use xmltree::Element;
+
+use crate::{
+    anchor::{self, Targets},
+    elem::ElementExt,
+    href,
+};
+
+/// Media type of media overlay documents
+pub const SMIL_TYPE: &str = "application/smil+xml";
+
+/// What a media overlay document syncs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overlay {
+    /// The ids it points to, by content document. Hrefs are normalized and
+    /// relative to the same directory as the overlay's own href
+    pub targets: Targets,
+    /// The audio files it plays, as normalized hrefs like the targets
+    pub audio: BTreeSet<String>,
+}
+
+impl Overlay {
+    /// Reads the media overlay `root`, a document at `smil_href`
+    pub fn read(root: &Element, smil_href: &str) -> Self {
+        let mut overlay = Overlay::default();
+        for e in root.descendants() {
+            // <text src> points at the text, <body> and <seq> can point at
+            // the part of the document they cover with epub:textref
+            let text = match e.name.as_str() {
+                "text" => e.attributes.get("src"),
+                _ => e.attributes.get("textref"),
+            };
+            if let Some(src) = text {
+                let doc = href::resolve(smil_href, src);
+                let ids = overlay.targets.entry(doc).or_default();
+                if let (_, Some(id)) = href::split_fragment(src) {
+                    ids.insert(href::percent_decode(id));
+                }
+            }
+            if let Some(src) = e.attributes.get("src").filter(|_| e.name == "audio") {
+                overlay.audio.insert(href::resolve(smil_href, src));
+            }
+        }
+        return overlay;
+    }
+
+    /// Adds the targets and audio of `other`
+    pub fn merge(&mut self, other: Overlay) {
+        anchor::merge(&mut self.targets, other.targets);
+        self.audio.extend(other.audio);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use xmltree::Element;
+
+    use super::Overlay;
+
+    #[test]
+    fn test_read() {
+        let smil = Element::parse(
+            r#"<smil xmlns="http://www.w3.org/ns/SMIL" xmlns:epub="http://www.idpf.org/2007/ops" version="3.0">
+            <body epub:textref="../text/ch1.xhtml">
+            <seq epub:textref="../text/ch1.xhtml#sec1">
+            <par id="p1"><text src="../text/ch1.xhtml#s1"/>
+            <audio src="../audio/ch1.mp3" clipBegin="0s" clipEnd="2.5s"/></par>
+            <par id="p2"><text src="../text/ch1.xhtml#caf%C3%A9"/>
+            <audio src="../audio/ch1.mp3" clipBegin="2.5s" clipEnd="4s"/></par>
+            </seq></body></smil>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut overlay = Overlay::read(&smil, "smil/ch1.smil");
+        assert_eq!(
+            overlay.targets["text/ch1.xhtml"],
+            BTreeSet::from(["sec1".to_string(), "s1".to_string(), "café".to_string()])
+        );
+        assert_eq!(overlay.audio, BTreeSet::from(["audio/ch1.mp3".to_string()]));
+
+        let mut other = Overlay::default();
+        other
+            .targets
+            .insert("text/ch2.xhtml".to_string(), BTreeSet::new());
+        overlay.merge(other);
+        assert_eq!(overlay.targets.len(), 2);
+    }
+}
@@ -0,0 +1,555 @@
+//! Packaging a directory of Markdown or HTML files as an EPUB.
+//!
+//! Every `.md`, `.markdown`, `.html`, `.htm` and `.xhtml` file directly in the
+//! directory becomes a chapter, in file name order. Images, stylesheets and
+//! fonts anywhere below it are copied along, keeping their relative paths so
+//! that links between the files keep working. Book metadata is read from an
+//! optional `metadata.txt` of `key: value` lines.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use pulldown_cmark::{html, Options, Parser};
+use xmltree::{Element, EmitterConfig, XMLNode};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    elem::{El, ElementExt},
+    errors::{io_err, xml_err, ConverterError},
+    logger::{debug, info, warning},
+};
+
+/// Name of the metadata file read from the packed directory
+pub const METADATA_FILE: &str = "metadata.txt";
+
+/// Book metadata written to the package document
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookMeta {
+    pub title: String,
+    pub author: Option<String>,
+    pub language: String,
+    pub identifier: String,
+    /// Path of the cover image, relative to the packed directory
+    pub cover: Option<String>,
+}
+
+impl BookMeta {
+    /// Parses `key: value` lines. Lines starting with `#` are comments.
+    /// `default_title` is used when no title is given
+    pub fn parse(text: &str, default_title: &str) -> Self {
+        let mut meta = Self {
+            title: default_title.to_string(),
+            author: None,
+            language: "en".to_string(),
+            identifier: String::new(),
+            cover: None,
+        };
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once(':') {
+                Some((k, v)) => (k.trim().to_ascii_lowercase(), v.trim().to_string()),
+                None => {
+                    warning!("{}: ignoring line without ':': {}", METADATA_FILE, line);
+                    continue;
+                }
+            };
+            match key.as_str() {
+                "title" => meta.title = value,
+                "author" => meta.author = Some(value),
+                "language" => meta.language = value,
+                "identifier" => meta.identifier = value,
+                "cover" => meta.cover = Some(value),
+                _ => warning!("{}: unknown key '{}'", METADATA_FILE, key),
+            }
+        }
+
+        if meta.identifier.is_empty() {
+            meta.identifier = title_identifier(&meta.title);
+        }
+        return meta;
+    }
+}
+
+/// Identifier for a book that has none, made from its title
+pub(crate) fn title_identifier(title: &str) -> String {
+    let slug = title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    return format!("urn:kepub-rs:{}", slug);
+}
+
+/// A generated content document
+struct Chapter {
+    href: String,
+    title: String,
+    body: Element,
+}
+
+/// A file copied into the book as is
+struct Resource {
+    href: String,
+    media_type: &'static str,
+    path: PathBuf,
+}
+
+/// Builds an EPUB at `out_path` from the files in `dir`
+pub fn pack(dir: &Path, out_path: &Path) -> Result<(), ConverterError> {
+    if !dir.is_dir() {
+        return Err(io_err!(
+            std::io::ErrorKind::NotFound,
+            "{:?} is not a directory",
+            dir
+        ));
+    }
+    let dir_name = dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("Untitled");
+    let meta = match std::fs::read_to_string(dir.join(METADATA_FILE)) {
+        Ok(t) => BookMeta::parse(&t, dir_name),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BookMeta::parse("", dir_name),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut sources = std::fs::read_dir(dir)?
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && chapter_kind(p).is_some())
+        .collect::<Vec<_>>();
+    sources.sort();
+
+    let mut chapters: Vec<Chapter> = Vec::new();
+    for src in &sources {
+        let stem = src
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("chapter");
+        let mut href = format!("{}.xhtml", stem);
+        let mut n = 1;
+        while chapters.iter().any(|c| c.href == href) {
+            n += 1;
+            href = format!("{}-{}.xhtml", stem, n);
+        }
+        let (title, body) = read_chapter(src)?;
+        debug!("Packing {:?} as {}", src, href);
+        chapters.push(Chapter {
+            title: title.unwrap_or_else(|| stem.to_string()),
+            href,
+            body,
+        });
+    }
+    if chapters.is_empty() {
+        return Err(io_err!(
+            std::io::ErrorKind::NotFound,
+            "No Markdown or HTML files found in {:?}",
+            dir
+        ));
+    }
+
+    let mut resources = Vec::new();
+    for entry in walkdir::WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .flatten()
+    {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel = entry.path().strip_prefix(dir).unwrap();
+        let media_type = match resource_type(rel) {
+            Some(t) => t,
+            None => continue,
+        };
+        let href = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if href == "style.css" {
+            warning!("Skipping style.css, the name is used by the generated stylesheet");
+            continue;
+        }
+        resources.push(Resource {
+            href,
+            media_type,
+            path: entry.path().to_path_buf(),
+        });
+    }
+    if let Some(cover) = &meta.cover {
+        if !resources.iter().any(|r| r.href == *cover) {
+            return Err(io_err!(
+                std::io::ErrorKind::NotFound,
+                "Cover image {} not found in {:?}",
+                cover,
+                dir
+            ));
+        }
+    }
+
+    write_epub(out_path, &meta, &chapters, &resources)?;
+    info!(
+        "Packed {} chapters and {} resources from {:?}",
+        chapters.len(),
+        resources.len(),
+        dir
+    );
+    return Ok(());
+}
+
+enum ChapterKind {
+    Markdown,
+    Html,
+}
+
+fn chapter_kind(path: &Path) -> Option<ChapterKind> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    return match ext.as_str() {
+        "md" | "markdown" => Some(ChapterKind::Markdown),
+        "html" | "htm" | "xhtml" => Some(ChapterKind::Html),
+        _ => None,
+    };
+}
+
+fn resource_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    return match ext.as_str() {
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "png" => Some("image/png"),
+        "gif" => Some("image/gif"),
+        "svg" => Some("image/svg+xml"),
+        "webp" => Some("image/webp"),
+        "css" => Some("text/css"),
+        "ttf" => Some("font/ttf"),
+        "otf" => Some("font/otf"),
+        "woff" => Some("font/woff"),
+        "woff2" => Some("font/woff2"),
+        _ => None,
+    };
+}
+
+/// Reads a chapter source file, returning its title, if it has one, and a
+/// `<body>` element with its content
+fn read_chapter(path: &Path) -> Result<(Option<String>, Element), ConverterError> {
+    let src = std::fs::read_to_string(path)?;
+    let (title, children) = match chapter_kind(path) {
+        Some(ChapterKind::Markdown) => (None, markdown_to_nodes(&src, path)?),
+        _ => {
+            let root = Element::parse(src.as_bytes())
+                .map_err(|e| xml_err!("{:?} is not well-formed XHTML: {}", path, e))?;
+            if root.name == "html" {
+                let title = root
+                    .get_child("head")
+                    .and_then(|h| h.get_child("title"))
+                    .map(text_content)
+                    .filter(|t| !t.trim().is_empty());
+                let body = match root.get_child("body") {
+                    Some(b) => b.children.clone(),
+                    None => return Err(xml_err!("Cannot find <body> in {:?}", path)),
+                };
+                (title, body)
+            } else {
+                (None, vec![XMLNode::Element(root)])
+            }
+        }
+    };
+
+    let body = El::new("body").children(children).build();
+    let title = title.or_else(|| {
+        ["h1", "h2", "h3"]
+            .iter()
+            .find_map(|h| body.find_first(h))
+            .map(text_content)
+            .map(|t| t.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|t| !t.is_empty())
+    });
+    return Ok((title, body));
+}
+
+/// Renders Markdown to XHTML nodes
+fn markdown_to_nodes(src: &str, path: &Path) -> Result<Vec<XMLNode>, ConverterError> {
+    let opts = Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES | Options::ENABLE_STRIKETHROUGH;
+    let mut out = String::new();
+    html::push_html(&mut out, Parser::new_ext(src, opts));
+    let root = Element::parse(format!("<body>{}</body>", out).as_bytes()).map_err(|e| {
+        xml_err!(
+            "{:?}: inline HTML in the Markdown is not well-formed XHTML: {}",
+            path,
+            e
+        )
+    })?;
+    return Ok(root.children);
+}
+
+fn text_content(elem: &Element) -> String {
+    let mut s = String::new();
+    for c in &elem.children {
+        match c {
+            XMLNode::Text(t) | XMLNode::CData(t) => s.push_str(t),
+            XMLNode::Element(e) => s.push_str(&text_content(e)),
+            _ => {}
+        }
+    }
+    return s;
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    return s
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+}
+
+/// Escapes characters that aren't allowed unescaped in an href
+fn escape_href(s: &str) -> String {
+    return escape(&encode_href(s));
+}
+
+/// Percent-encodes the characters of a file path that can't appear as is
+/// in an href
+pub(crate) fn encode_href(s: &str) -> String {
+    return s.replace('%', "%25").replace(' ', "%20");
+}
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+const STYLE_CSS: &str = "body { margin: 0 1em; }
+h1, h2, h3 { text-align: center; }
+img { max-width: 100%; }
+pre { white-space: pre-wrap; }
+";
+
+fn write_epub(
+    out_path: &Path,
+    meta: &BookMeta,
+    chapters: &[Chapter],
+    resources: &[Resource],
+) -> Result<(), ConverterError> {
+    let mut zip = ZipWriter::new(File::create(out_path)?);
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let opts = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"application/epub+zip")?;
+    zip.start_file("META-INF/container.xml", opts)?;
+    zip.write_all(CONTAINER_XML.as_bytes())?;
+    zip.start_file("OEBPS/content.opf", opts)?;
+    zip.write_all(package_doc(meta, chapters, resources).as_bytes())?;
+    zip.start_file("OEBPS/nav.xhtml", opts)?;
+    zip.write_all(nav_doc(meta, chapters).as_bytes())?;
+    zip.start_file("OEBPS/style.css", opts)?;
+    zip.write_all(STYLE_CSS.as_bytes())?;
+
+    for c in chapters {
+        zip.start_file(format!("OEBPS/{}", c.href), opts)?;
+        zip.write_all(chapter_doc(meta, c)?.as_bytes())?;
+    }
+    for r in resources {
+        zip.start_file(format!("OEBPS/{}", r.href), opts)?;
+        zip.write_all(&std::fs::read(&r.path)?)?;
+    }
+    zip.finish()?;
+    return Ok(());
+}
+
+fn package_doc(meta: &BookMeta, chapters: &[Chapter], resources: &[Resource]) -> String {
+    let mut metadata = format!(
+        "    <dc:identifier id=\"bookid\">{}</dc:identifier>\n    <dc:title>{}</dc:title>\n    <dc:language>{}</dc:language>\n",
+        escape(&meta.identifier),
+        escape(&meta.title),
+        escape(&meta.language)
+    );
+    if let Some(a) = &meta.author {
+        metadata.push_str(&format!("    <dc:creator>{}</dc:creator>\n", escape(a)));
+    }
+    metadata.push_str(&format!(
+        "    <meta property=\"dcterms:modified\">{}</meta>\n",
+        utc_timestamp(SystemTime::now())
+    ));
+
+    let mut manifest = String::from(
+        "    <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n    <item id=\"style\" href=\"style.css\" media-type=\"text/css\"/>\n",
+    );
+    let mut spine = String::new();
+    for (i, c) in chapters.iter().enumerate() {
+        manifest.push_str(&format!(
+            "    <item id=\"ch{:03}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>\n",
+            i + 1,
+            escape_href(&c.href)
+        ));
+        spine.push_str(&format!("    <itemref idref=\"ch{:03}\"/>\n", i + 1));
+    }
+    for (i, r) in resources.iter().enumerate() {
+        if meta.cover.as_ref() == Some(&r.href) {
+            metadata.push_str("    <meta name=\"cover\" content=\"cover-image\"/>\n");
+            manifest.push_str(&format!(
+                "    <item id=\"cover-image\" href=\"{}\" media-type=\"{}\" properties=\"cover-image\"/>\n",
+                escape_href(&r.href),
+                r.media_type
+            ));
+        } else {
+            manifest.push_str(&format!(
+                "    <item id=\"res{:03}\" href=\"{}\" media-type=\"{}\"/>\n",
+                i + 1,
+                escape_href(&r.href),
+                r.media_type
+            ));
+        }
+    }
+
+    return format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="bookid" xml:lang="{}">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+{}  </metadata>
+  <manifest>
+{}  </manifest>
+  <spine>
+{}  </spine>
+</package>
+"#,
+        escape(&meta.language),
+        metadata,
+        manifest,
+        spine
+    );
+}
+
+fn xhtml_doc(meta: &BookMeta, title: &str, head: &str, body: &str) -> String {
+    return format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="{lang}" xml:lang="{lang}">
+<head>
+  <title>{}</title>
+{}</head>
+{}
+</html>
+"#,
+        escape(title),
+        head,
+        body,
+        lang = escape(&meta.language)
+    );
+}
+
+fn nav_doc(meta: &BookMeta, chapters: &[Chapter]) -> String {
+    let mut items = String::new();
+    for c in chapters {
+        items.push_str(&format!(
+            "      <li><a href=\"{}\">{}</a></li>\n",
+            escape_href(&c.href),
+            escape(&c.title)
+        ));
+    }
+    let body = format!(
+        "<body>\n  <nav epub:type=\"toc\" id=\"toc\">\n    <h1>{}</h1>\n    <ol>\n{}    </ol>\n  </nav>\n</body>",
+        escape(&meta.title),
+        items
+    );
+    return xhtml_doc(meta, &meta.title, "", &body);
+}
+
+fn chapter_doc(meta: &BookMeta, chapter: &Chapter) -> Result<String, ConverterError> {
+    let mut buf = Vec::new();
+    chapter.body.write_with_config(
+        &mut buf,
+        EmitterConfig::new()
+            .write_document_declaration(false)
+            .perform_indent(true),
+    )?;
+    let head = "  <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\"/>\n";
+    return Ok(xhtml_doc(
+        meta,
+        &chapter.title,
+        head,
+        &String::from_utf8_lossy(&buf),
+    ));
+}
+
+/// Formats `time` as `YYYY-MM-DDThh:mm:ssZ`, as required by `dcterms:modified`
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    return format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{markdown_to_nodes, utc_timestamp, BookMeta};
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_metadata() {
+        let meta = BookMeta::parse(
+            "# comment\nTitle: A Serial: Part 1\nauthor: Someone\ncover: img/c.jpg\n",
+            "dir",
+        );
+        assert_eq!(meta.title, "A Serial: Part 1");
+        assert_eq!(meta.author.as_deref(), Some("Someone"));
+        assert_eq!(meta.language, "en");
+        assert_eq!(meta.cover.as_deref(), Some("img/c.jpg"));
+        assert_eq!(meta.identifier, "urn:kepub-rs:a-serial-part-1");
+
+        assert_eq!(BookMeta::parse("", "notes").title, "notes");
+    }
+
+    #[test]
+    fn test_markdown() {
+        let nodes = markdown_to_nodes("# Title\n\nSome *text* & more.\n", Path::new("a.md"));
+        let elems = nodes
+            .unwrap()
+            .into_iter()
+            .filter_map(|n| n.as_element().map(|e| e.name.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(elems, ["h1", "p"]);
+
+        assert!(markdown_to_nodes("<p>unclosed\n", Path::new("b.md")).is_err());
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let t = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(utc_timestamp(t), "2024-02-29T12:34:56Z");
+    }
+}
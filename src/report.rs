@@ -0,0 +1,257 @@
+//! Machine-readable summary of a conversion, written next to the kepub for
+//! scripts and for tracking down why a chapter renders oddly.
+//!
+//! The JSON layout is versioned by [`REPORT_VERSION`]. New fields can be
+//! added without changing it, so readers should ignore fields they don't
+//! know; the version only changes when a field is removed, renamed or
+//! changes meaning. Every field has a default, so reports written by older
+//! versions still load.
+
+use std::{collections::BTreeMap, io::Read, path::Path};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zip::ZipArchive;
+
+use crate::{codes::WarningCode, errors::ConverterError};
+
+/// Version of the report layout written by this library
+pub const REPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct Report {
+    /// Layout version, [`REPORT_VERSION`] when written by this library and
+    /// 0 for reports from before the field existed
+    pub version: u32,
+    /// Path of the written kepub
+    pub output: String,
+    /// Every book-wide warning, as logged, starting with its code
+    pub warnings: Vec<String>,
+    /// How many times each warning code was logged, by code like `W012`
+    pub codes: BTreeMap<String, usize>,
+    /// Book-wide counters of the conversion
+    pub stats: Stats,
+    /// The transforms that changed or removed each file, by path within the
+    /// archive. Transforms are named `rebuild`, `opf-cover`, `media-type`,
+    /// `wrapper`, `kobo-style`, `spans`, `respan`, `replace`,
+    /// `punctuation`, `normalize`, `word-breaks`, `media`, `blank-pages`,
+    /// `line-endings`, `layout`, `fullscreen`, `fixed-layout`, `repair`,
+    /// `footnotes`, `deobfuscate`, `fonts`, `images`, `cover`, `title-page` and
+    /// `cleanup`
+    pub files: BTreeMap<String, Vec<String>>,
+    /// SHA-256 of the written kepub and of every file in it
+    pub checksums: Checksums,
+}
+
+/// Counters of a conversion, summed over every content document
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Content documents converted
+    pub documents: usize,
+    /// Kobospans added
+    pub spans: usize,
+    /// Characters changed by unicode normalization
+    pub normalized_chars: usize,
+    /// Paragraphs and sentences over the length warning
+    pub long_texts: usize,
+    /// Soft hyphens and zero-width characters found inside words
+    pub word_breaks: usize,
+    /// Words split across two kobospans by inline markup
+    pub split_words: usize,
+    /// Matches of the replacements
+    pub replaced: usize,
+    /// Quotes, dashes and ellipses made typographic
+    pub smartened: usize,
+    /// Length of the text in each sentence span, in characters
+    pub sentence_lengths: Histogram,
+    /// Number of sentence spans in each paragraph
+    pub spans_per_paragraph: Histogram,
+}
+
+/// Counts of values in buckets that double in width: 1, 2-3, 4-7, 8-15 and
+/// so on, keyed by the smallest value of each bucket. Empty buckets are left
+/// out
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Histogram(pub BTreeMap<usize, usize>);
+
+/// SHA-256 checksums, as lowercase hex
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct Checksums {
+    /// Of the kepub
+    pub output: String,
+    /// Of every file in the kepub, by path within the archive
+    pub files: BTreeMap<String, String>,
+}
+
+impl Report {
+    /// A report of the kepub written to `output`, with its checksums
+    pub fn new(
+        output: &str,
+        warnings: Vec<(WarningCode, String)>,
+        stats: Stats,
+        files: BTreeMap<String, Vec<String>>,
+    ) -> Result<Self, ConverterError> {
+        let mut codes = BTreeMap::new();
+        for (code, _) in &warnings {
+            *codes.entry(code.to_string()).or_default() += 1;
+        }
+        return Ok(Self {
+            version: REPORT_VERSION,
+            output: output.to_string(),
+            warnings: warnings
+                .into_iter()
+                .map(|(code, message)| return format!("{}: {}", code, message))
+                .collect(),
+            codes,
+            stats,
+            files,
+            checksums: Checksums::of(Path::new(output))?,
+        });
+    }
+
+    pub fn open(path: &Path) -> Result<Self, ConverterError> {
+        let json = std::fs::read_to_string(path)?;
+        return serde_json::from_str(&json).map_err(|e| ConverterError::Other(e.to_string()));
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConverterError> {
+        let json =
+            serde_json::to_string_pretty(self).map_err(|e| ConverterError::Other(e.to_string()))?;
+        std::fs::write(path, json)?;
+        return Ok(());
+    }
+}
+
+impl Histogram {
+    pub fn add(&mut self, value: usize) {
+        let bucket = match value {
+            0 => 0,
+            v => 1 << v.ilog2(),
+        };
+        *self.0.entry(bucket).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in &other.0 {
+            *self.0.entry(*bucket).or_default() += count;
+        }
+    }
+
+    /// One line per bucket, with a bar scaled to the largest count
+    pub fn lines(&self) -> Vec<String> {
+        const WIDTH: usize = 40;
+        let max = self.0.values().copied().max().unwrap_or(0);
+        return self
+            .0
+            .iter()
+            .map(|(&bucket, &count)| {
+                let range = match bucket {
+                    0 | 1 => bucket.to_string(),
+                    b => format!("{}-{}", b, 2 * b - 1),
+                };
+                let bar = "#".repeat((count * WIDTH).div_ceil(max));
+                return format!("{:>11} {:<w$} {}", range, bar, count, w = WIDTH);
+            })
+            .collect();
+    }
+}
+
+impl Checksums {
+    /// Checksums of the archive at `path` and of every file in it
+    fn of(path: &Path) -> Result<Self, ConverterError> {
+        let data = std::fs::read(path)?;
+        let mut zip = ZipArchive::new(std::io::Cursor::new(&data))?;
+        let mut files = BTreeMap::new();
+        let mut buf = Vec::new();
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i)?;
+            if entry.is_dir() {
+                continue;
+            }
+            buf.clear();
+            entry.read_to_end(&mut buf)?;
+            files.insert(entry.name().to_string(), sha256_hex(&buf));
+        }
+        return Ok(Self {
+            output: sha256_hex(&data),
+            files,
+        });
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    return Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sha256_hex, Histogram, Report, REPORT_VERSION};
+
+    #[test]
+    fn test_compatibility() {
+        // a report from before the version field, with a field from a later
+        // version
+        let old = r#"{"output": "a.kepub.epub", "warnings": ["w"],
+            "files": {"a.xhtml": ["spans"]}, "unknown": 1}"#;
+        let report: Report = serde_json::from_str(old).unwrap();
+        assert_eq!(report.version, 0);
+        assert_eq!(report.files["a.xhtml"], ["spans"]);
+        assert_eq!(report.stats.spans, 0);
+
+        let json = serde_json::to_value(Report {
+            version: REPORT_VERSION,
+            ..report
+        })
+        .unwrap();
+        for key in [
+            "version",
+            "output",
+            "warnings",
+            "codes",
+            "stats",
+            "files",
+            "checksums",
+        ] {
+            assert!(json.get(key).is_some(), "{}", key);
+        }
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::default();
+        for v in [1, 2, 3, 5, 7, 40] {
+            h.add(v);
+        }
+        assert_eq!(
+            h.0.iter().collect::<Vec<_>>(),
+            [(&1, &1), (&2, &2), (&4, &2), (&32, &1)]
+        );
+        let mut other = Histogram::default();
+        other.add(6);
+        h.merge(&other);
+        assert_eq!(h.0[&4], 3);
+
+        let lines = h.lines();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[2].trim_start().starts_with("4-7 ####"));
+        assert!(lines[2].ends_with(" 3"));
+        assert_eq!(
+            serde_json::to_string(&h).unwrap(),
+            r#"{"1":1,"2":2,"4":3,"32":1}"#
+        );
+    }
+}
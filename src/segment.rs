@@ -0,0 +1,509 @@
+//! Text segmentation used to decide where kobospans start and end.
+//!
+//! Kobo highlights and reading positions reference span ids of the form
+//! `kobo.<paragraph>.<segment>`. Tools that need to compute the same ids
+//! (annotation exporters, TTS pipelines) can reuse the exact segmentation
+//! applied during conversion through [`Segmenter`].
+
+use std::str::FromStr;
+
+/// Splits a text node into segments, each of which becomes one kobospan.
+///
+/// Implementations must be lossless: concatenating the returned segments has
+/// to reproduce the input, otherwise converted books would lose text.
+pub trait Segmenter: Send + Sync {
+    fn segment(&self, text: &str) -> Vec<String>;
+
+    /// Splits text in language `lang`, the BCP 47 tag of the nearest
+    /// `xml:lang` or `lang` attribute or of the book. Segmenters without
+    /// rules of their own for some languages ignore it
+    fn segment_in(&self, text: &str, lang: Option<&str>) -> Vec<String> {
+        let _ = lang;
+        return self.segment(text);
+    }
+}
+
+/// The sentence segmentation used by default when converting books
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SentenceSegmenter;
+
+impl Segmenter for SentenceSegmenter {
+    fn segment(&self, text: &str) -> Vec<String> {
+        return segment_sentences(text);
+    }
+
+    fn segment_in(&self, text: &str, lang: Option<&str>) -> Vec<String> {
+        return segment_sentences_in(text, lang);
+    }
+}
+
+/// Wraps each word, with the whitespace after it, in its own kobospan
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WordSegmenter;
+
+impl Segmenter for WordSegmenter {
+    fn segment(&self, text: &str) -> Vec<String> {
+        return split_words(text).into_iter().map(String::from).collect();
+    }
+}
+
+/// The unit of text wrapped in each kobospan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Granularity {
+    #[default]
+    Sentence,
+    Word,
+    /// Each text node is wrapped whole, so a paragraph without inline markup
+    /// gets a single span
+    Paragraph,
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_ascii_lowercase().as_str() {
+            "sentence" => Ok(Granularity::Sentence),
+            "word" => Ok(Granularity::Word),
+            "paragraph" => Ok(Granularity::Paragraph),
+            _ => Err(format!(
+                "unknown granularity '{}', expected sentence, word or paragraph",
+                s
+            )),
+        };
+    }
+}
+
+impl std::fmt::Display for Granularity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Granularity::Sentence => "sentence",
+            Granularity::Word => "word",
+            Granularity::Paragraph => "paragraph",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Segmenter for Granularity {
+    fn segment(&self, text: &str) -> Vec<String> {
+        return self.segment_in(text, None);
+    }
+
+    fn segment_in(&self, text: &str, lang: Option<&str>) -> Vec<String> {
+        return match self {
+            Granularity::Sentence => SentenceSegmenter.segment_in(text, lang),
+            Granularity::Word => WordSegmenter.segment(text),
+            Granularity::Paragraph => vec![text.to_string()],
+        };
+    }
+}
+
+/// Sentence terminators of languages that have their own on top of `.`,
+/// `!` and `?`, by primary language subtag
+const LANGUAGE_TERMINATORS: &[(&str, &[char])] = &[
+    // the Greek question mark looks like a semicolon, and usually is one
+    ("el", &[';', '\u{37E}']),
+    ("hy", &['\u{589}']),
+    ("hi", &['\u{964}', '\u{965}']),
+    ("mr", &['\u{964}', '\u{965}']),
+    ("ne", &['\u{964}', '\u{965}']),
+    ("sa", &['\u{964}', '\u{965}']),
+    ("bn", &['\u{964}', '\u{965}']),
+    ("ar", &['\u{61F}']),
+    ("fa", &['\u{61F}']),
+    ("ur", &['\u{61F}', '\u{6D4}']),
+    ("am", &['\u{1362}', '\u{1367}']),
+];
+
+/// The terminators `lang` has on top of `.`, `!` and `?`
+fn language_terminators(lang: Option<&str>) -> &'static [char] {
+    let Some(lang) = lang else {
+        return &[];
+    };
+    let primary = lang.split(['-', '_']).next().unwrap_or_default();
+    return LANGUAGE_TERMINATORS
+        .iter()
+        .find(|(l, _)| l.eq_ignore_ascii_case(primary))
+        .map_or(&[], |(_, t)| *t);
+}
+
+/// Splits text content into the sentences kepub-rs wraps in kobospans.
+///
+/// A sentence ends after a run of `.`, `!` or `?` (optionally followed by
+/// closing quotes, brackets or ellipses) and the whitespace after it.
+/// Closing punctuation separated from the terminator by whitespace, as in
+/// `« Oui. »`, also stays with the sentence it closes. The full-width `。`,
+/// `！` and `？` of Chinese and Japanese end a sentence in any language, and
+/// need no whitespace after them: the next sentence starts after them and
+/// any closing brackets or quotes that follow. Whitespace stays
+/// attached to the end of the sentence it follows, so concatenating the
+/// result always reproduces `text` exactly. Text without any sentence break
+/// is returned as a single sentence.
+pub fn segment_sentences(text: &str) -> Vec<String> {
+    return segment_sentences_in(text, None);
+}
+
+/// [`segment_sentences`] for text in language `lang`, a BCP 47 tag. Some
+/// languages end sentences with more than `.`, `!` and `?`, like `;` in
+/// Greek or `।` in Hindi
+pub fn segment_sentences_in(text: &str, lang: Option<&str>) -> Vec<String> {
+    #[derive(PartialEq)]
+    enum Input {
+        PunctStandard,
+        /// Quotes that may open the next sentence
+        PunctExtra,
+        /// Closing punctuation followed by whitespace or the end of the text
+        PunctClose,
+        Whitespace,
+        Other,
+        EOS,
+    }
+
+    const TERMINATORS: [char; 3] = ['.', '!', '?'];
+    const FULL_WIDTH_TERMINATORS: [char; 4] = ['。', '！', '？', '｡'];
+    let extra = language_terminators(lang);
+    let is_terminator = |c: &char| {
+        return TERMINATORS.contains(c) || FULL_WIDTH_TERMINATORS.contains(c) || extra.contains(c);
+    };
+    const CLOSING: [char; 9] = ['\'', '"', '”', '’', '»', '›', ')', ']', '…'];
+    const FULL_WIDTH_CLOSING: [char; 10] =
+        ['」', '』', '）', '】', '》', '〉', '〕', '］', '｝', '〙'];
+
+    enum Output {
+        None,
+        Next,
+        Rest,
+    }
+
+    #[derive(PartialEq)]
+    enum State {
+        Default,
+        AfterPunct,
+        AfterPunctExtra,
+        AfterSpace,
+        Finished,
+    }
+
+    let mut sentences = Vec::new();
+    let characters = text.chars().collect::<Vec<_>>();
+
+    // true if the run of punctuation starting at `i` is followed by
+    // whitespace or the end of the text. Tells closing quotes apart from
+    // opening ones and from apostrophes, as in `’Twas`
+    let closes_at = |i: usize| {
+        let rest = characters[i..]
+            .iter()
+            .find(|c| !CLOSING.contains(c) && !is_terminator(c));
+        return rest.is_none_or(|c| c.is_whitespace());
+    };
+
+    let mut seg_begin = 0;
+    let mut i = 0;
+    let mut state = State::Default;
+    // set in a run of punctuation with a full-width terminator, after which
+    // the next sentence starts without whitespace
+    let mut full_width = false;
+    while state != State::Finished {
+        let input = if i >= characters.len() {
+            Input::EOS
+        } else {
+            let c = characters[i];
+            if FULL_WIDTH_TERMINATORS.contains(&c) {
+                full_width = true;
+            }
+            match c {
+                _ if is_terminator(&c) => Input::PunctStandard,
+                _ if full_width && (CLOSING.contains(&c) || FULL_WIDTH_CLOSING.contains(&c)) => {
+                    Input::PunctClose
+                }
+                _ if CLOSING.contains(&c) && closes_at(i) => Input::PunctClose,
+                _ if ['\'', '"', '”', '’', '“', '…'].contains(&c) => Input::PunctExtra,
+                _ if c.is_whitespace() => Input::Whitespace,
+                _ => Input::Other,
+            }
+        };
+
+        let output: Output;
+
+        (output, state) = match state {
+            State::Default => match input {
+                Input::PunctStandard => (Output::None, State::AfterPunct),
+                Input::PunctExtra | Input::PunctClose => (Output::None, State::Default),
+                Input::Whitespace => (Output::None, State::Default),
+                Input::Other => (Output::None, State::Default),
+                Input::EOS => (Output::Rest, State::Finished), //
+            },
+            State::AfterPunct | State::AfterPunctExtra
+                if full_width && matches!(input, Input::PunctExtra | Input::Other) =>
+            {
+                (Output::Next, State::Default)
+            }
+            State::AfterPunct => match input {
+                Input::PunctStandard => (Output::None, State::AfterPunct),
+                Input::PunctExtra | Input::PunctClose => (Output::None, State::AfterPunctExtra),
+                Input::Whitespace => (Output::None, State::AfterSpace),
+                Input::Other => (Output::None, State::Default),
+                Input::EOS => (Output::Rest, State::Finished), //
+            },
+            State::AfterPunctExtra => match input {
+                Input::PunctStandard => (Output::None, State::AfterPunct),
+                // runs like `.’”` or `.…)` all close the same sentence
+                Input::PunctExtra | Input::PunctClose => (Output::None, State::AfterPunctExtra),
+                Input::Whitespace => (Output::None, State::AfterSpace),
+                Input::Other => (Output::None, State::Default),
+                Input::EOS => (Output::Rest, State::Finished), //
+            },
+            State::AfterSpace => match input {
+                Input::PunctStandard => (Output::Next, State::AfterPunct),
+                Input::PunctExtra => (Output::Next, State::Default),
+                // closing punctuation after a space still belongs to the
+                // sentence before it rather than starting the next one
+                Input::PunctClose => (Output::None, State::AfterPunctExtra),
+                Input::Whitespace => (Output::None, State::AfterSpace),
+                Input::Other => (Output::Next, State::Default),
+                Input::EOS => (Output::Rest, State::Finished), //
+            },
+            State::Finished => (Output::Rest, state),
+        };
+
+        if state == State::Default {
+            full_width = false;
+        }
+        match output {
+            Output::None => i += 1,
+            Output::Next => {
+                sentences.push(
+                    text.chars()
+                        .skip(seg_begin)
+                        .take(i - seg_begin)
+                        .collect::<String>(),
+                );
+                seg_begin = i;
+                i += 1;
+            }
+            Output::Rest => {
+                // if we've reached the end of the string but found no sentences
+                // treat the input text as one sentence and push it
+                if sentences.is_empty() {
+                    sentences.push(text.to_string());
+                } else if i > seg_begin {
+                    sentences.push(text.chars().skip(seg_begin).collect::<String>());
+                }
+            }
+        }
+    }
+
+    return sentences;
+}
+
+/// Splits text into words, each keeping the whitespace that follows it.
+/// Leading whitespace stays attached to the first word, so concatenating the
+/// result reproduces `text` exactly
+pub fn split_words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut prev_ws = false;
+    for (i, c) in text.char_indices() {
+        let ws = c.is_whitespace();
+        if prev_ws && !ws && text[start..i].chars().any(|c| !c.is_whitespace()) {
+            words.push(&text[start..i]);
+            start = i;
+        }
+        prev_ws = ws;
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+    return words;
+}
+
+/// Breaks `text` into chunks of at most `max_chars` characters, splitting
+/// only between words. A single word longer than `max_chars` becomes a chunk
+/// of its own. Trailing whitespace isn't counted against the limit
+pub fn chunk(text: &str, max_chars: usize) -> Vec<String> {
+    if max_chars == 0 || text.chars().count() <= max_chars {
+        return vec![text.to_string()];
+    }
+
+    let mut chunks = Vec::new();
+    let mut cur = String::new();
+    let mut cur_len = 0;
+    for w in split_words(text) {
+        let len = w.chars().count();
+        let word_len = w.trim_end().chars().count();
+        if !cur.is_empty() && cur_len + word_len > max_chars {
+            chunks.push(std::mem::take(&mut cur));
+            cur_len = 0;
+        }
+        cur.push_str(w);
+        cur_len += len;
+    }
+    if !cur.is_empty() {
+        chunks.push(cur);
+    }
+    return chunks;
+}
+
+/// Joins segments that end and start inside the same word, so no span
+/// boundary falls within a word whatever the segmenter did. A word split
+/// across spans can't be looked up in the dictionary on device
+pub fn join_split_words(segments: Vec<String>) -> Vec<String> {
+    let mut out: Vec<String> = Vec::with_capacity(segments.len());
+    for s in segments {
+        match out.last_mut() {
+            Some(prev) if is_word_char(prev.chars().last()) && is_word_char(s.chars().next()) => {
+                prev.push_str(&s);
+            }
+            _ => out.push(s),
+        }
+    }
+    return out;
+}
+
+pub(crate) fn is_word_char(c: Option<char>) -> bool {
+    return c.is_some_and(char::is_alphanumeric);
+}
+
+/// Whether `text` has something to show but no letters or digits, like a
+/// dash or quote between two inline elements
+pub(crate) fn is_punctuation_only(text: &str) -> bool {
+    return text.chars().any(|c| !c.is_whitespace()) && !text.chars().any(char::is_alphanumeric);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        chunk, join_split_words, segment_sentences, segment_sentences_in, split_words, Granularity,
+        Segmenter,
+    };
+
+    #[test]
+    fn test_segment_sentences() {
+        let text = r#"Left Munich at 8:35 P.M., on 1st May, arriving at Vienna early next morning; should have arrived at 6:46, but train was an hour late. Buda-Pesth seems a wonderful place, from the glimpse which I got of it from the train and the little I could walk through the streets. I feared to go very far from the station, as we had arrived late and would start as near the correct time as possible."#;
+
+        assert_eq!(segment_sentences(text).len(), 3);
+    }
+
+    #[test]
+    fn test_segment_sentences_lossless() {
+        let text = "  One. Two!  \"Three?\" Four";
+        let segs = segment_sentences(text);
+        assert_eq!(segs.concat(), text);
+        assert_eq!(segs.len(), 4);
+
+        // single character trailing sentence
+        assert_eq!(segment_sentences("One. A"), vec!["One. ", "A"]);
+    }
+
+    #[test]
+    fn test_segment_sentences_trailing_punct() {
+        for (text, expected) in [
+            (
+                "He said “go.” Then left.",
+                vec!["He said “go.” ", "Then left."],
+            ),
+            ("Stop.’” Next", vec!["Stop.’” ", "Next"]),
+            ("Wait.… Then", vec!["Wait.… ", "Then"]),
+            ("Really?!…” Yes", vec!["Really?!…” ", "Yes"]),
+            ("(Like this.) Next", vec!["(Like this.) ", "Next"]),
+            ("« Oui. » Non.", vec!["« Oui. » ", "Non."]),
+            ("It ended. ” Next", vec!["It ended. ” ", "Next"]),
+            ("Done.\u{a0}Next", vec!["Done.\u{a0}", "Next"]),
+            // opening quotes and apostrophes start the next sentence
+            ("He came. ’Twas late.", vec!["He came. ", "’Twas late."]),
+            ("Go. \"Now,\" she said.", vec!["Go. ", "\"Now,\" she said."]),
+            ("Go. 'Tis time.", vec!["Go. ", "'Tis time."]),
+        ] {
+            assert_eq!(segment_sentences(text), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_segment_sentences_in() {
+        let greek = "Τι κάνεις; Καλά.";
+        assert_eq!(
+            segment_sentences_in(greek, Some("el")),
+            vec!["Τι κάνεις; ", "Καλά."]
+        );
+        assert_eq!(
+            segment_sentences_in(greek, Some("EL-gr")),
+            vec!["Τι κάνεις; ", "Καλά."]
+        );
+        assert_eq!(segment_sentences_in(greek, Some("en")), vec![greek]);
+        assert_eq!(segment_sentences(greek), vec![greek]);
+
+        let hindi = "यह एक वाक्य है। यह दूसरा है।";
+        assert_eq!(
+            segment_sentences_in(hindi, Some("hi-IN")),
+            vec!["यह एक वाक्य है। ", "यह दूसरा है।"]
+        );
+        assert_eq!(Granularity::Sentence.segment_in(hindi, Some("hi")).len(), 2);
+    }
+
+    #[test]
+    fn test_segment_sentences_cjk() {
+        for (text, expected) in [
+            (
+                "今日は晴れです。明日は雨でしょう！本当？",
+                vec!["今日は晴れです。", "明日は雨でしょう！", "本当？"],
+            ),
+            (
+                "他说：“我们走吧。”然后就走了。",
+                vec!["他说：“我们走吧。”", "然后就走了。"],
+            ),
+            (
+                "「はい。」「いいえ！？」と彼は言った。",
+                vec!["「はい。」", "「いいえ！？」", "と彼は言った。"],
+            ),
+            ("我很好。 你呢？", vec!["我很好。 ", "你呢？"]),
+            ("半角｡次", vec!["半角｡", "次"]),
+            (
+                "English. 日本語。Mixed",
+                vec!["English. ", "日本語。", "Mixed"],
+            ),
+            // without a full-width terminator, closing brackets still need
+            // whitespace after them
+            ("括弧）の中", vec!["括弧）の中"]),
+        ] {
+            let segs = segment_sentences(text);
+            assert_eq!(segs.concat(), text);
+            assert_eq!(segs, expected, "{}", text);
+        }
+        assert_eq!(segment_sentences_in("一。二。", Some("zh")).len(), 2);
+    }
+
+    #[test]
+    fn test_granularity() {
+        let text = "One two. Three";
+        let g = |s: &str| s.parse::<Granularity>().unwrap();
+        assert_eq!(g("Sentence").segment(text), vec!["One two. ", "Three"]);
+        assert_eq!(g("word").segment(text), vec!["One ", "two. ", "Three"]);
+        assert_eq!(g("paragraph").segment(text), vec![text]);
+        assert!("letter".parse::<Granularity>().is_err());
+    }
+
+    #[test]
+    fn test_join_split_words() {
+        let segs = ["Dic", "tion", "ary. ", "Next"].map(String::from).to_vec();
+        assert_eq!(join_split_words(segs), ["Dictionary. ", "Next"]);
+    }
+
+    #[test]
+    fn test_chunk() {
+        assert_eq!(split_words(" a bc  d "), vec![" a ", "bc  ", "d "]);
+        assert!(split_words("").is_empty());
+
+        let text = "one two three four five six";
+        assert_eq!(chunk(text, 0), vec![text]);
+        assert_eq!(chunk(text, 100), vec![text]);
+        assert_eq!(
+            chunk(text, 9),
+            vec!["one two ", "three ", "four five ", "six"]
+        );
+        assert_eq!(chunk("abcdefgh ij", 4), vec!["abcdefgh ", "ij"]);
+        for n in 1..text.len() {
+            assert_eq!(chunk(text, n).concat(), text);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Where a converted book is written. The converter walks the finished
+//! book once, in output order with the mimetype first, and hands every
+//! entry to an [`OutputSink`], which decides how it is stored: as an epub
+//! file, an expanded directory, a tar stream or an epub in memory.
+
+use std::{
+    fs::File,
+    io::{Cursor, ErrorKind, Seek, Write},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use zip::{write::SimpleFileOptions, CompressionMethod, DateTime, ZipWriter};
+
+/// A file or directory of the book, by its path within the archive
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputEntry<'a> {
+    /// Directories end with a `/`
+    pub name: &'a str,
+    /// Whether the entry should be stored uncompressed, like the mimetype
+    pub stored: bool,
+    /// None lets the sink pick, usually the current time
+    pub modified: Option<DateTime>,
+}
+
+/// Receives the entries of a converted book
+pub trait OutputSink {
+    /// What is left once the book is written, like the bytes of the epub
+    type Output;
+
+    fn add_directory(&mut self, entry: &OutputEntry) -> std::io::Result<()>;
+
+    fn add_file(&mut self, entry: &OutputEntry, data: &[u8]) -> std::io::Result<()>;
+
+    /// Completes the book, after the last entry
+    fn finish(self) -> std::io::Result<Self::Output>;
+}
+
+/// Writes an epub archive. Files are written with mode 0644 and
+/// directories with 0755
+pub struct ZipSink<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    opts: SimpleFileOptions,
+}
+
+impl<W: Write + Seek> ZipSink<W> {
+    /// Entries that aren't stored are deflated at `compression_level`, or
+    /// stored too if it is 0
+    pub fn new(writer: W, compression_level: Option<i64>) -> Self {
+        let opts = match compression_level {
+            Some(0) => SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+            level => SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Deflated)
+                .compression_level(level),
+        }
+        .unix_permissions(0o644);
+        return Self {
+            zip: ZipWriter::new(writer),
+            opts,
+        };
+    }
+
+    fn options(&self, entry: &OutputEntry) -> SimpleFileOptions {
+        let mut opts = match entry.stored {
+            true => self
+                .opts
+                .compression_method(CompressionMethod::Stored)
+                .compression_level(None),
+            false => self.opts,
+        };
+        if let Some(t) = entry.modified {
+            opts = opts.last_modified_time(t);
+        }
+        return opts;
+    }
+}
+
+impl ZipSink<File> {
+    /// Creates the epub at `path`
+    pub fn create(path: &Path, compression_level: Option<i64>) -> std::io::Result<Self> {
+        return Ok(Self::new(File::create(path)?, compression_level));
+    }
+}
+
+impl<W: Write + Seek> OutputSink for ZipSink<W> {
+    type Output = W;
+
+    fn add_directory(&mut self, entry: &OutputEntry) -> std::io::Result<()> {
+        let opts = self
+            .options(entry)
+            .compression_level(None)
+            .unix_permissions(0o755);
+        self.zip.add_directory(entry.name, opts)?;
+        return Ok(());
+    }
+
+    fn add_file(&mut self, entry: &OutputEntry, data: &[u8]) -> std::io::Result<()> {
+        self.zip.start_file(entry.name, self.options(entry))?;
+        return self.zip.write_all(data);
+    }
+
+    fn finish(self) -> std::io::Result<W> {
+        return Ok(self.zip.finish()?);
+    }
+}
+
+/// Builds the epub in memory, for callers that serve or store it
+/// themselves
+pub struct MemorySink(ZipSink<Cursor<Vec<u8>>>);
+
+impl MemorySink {
+    pub fn new(compression_level: Option<i64>) -> Self {
+        return Self(ZipSink::new(Cursor::new(Vec::new()), compression_level));
+    }
+}
+
+impl OutputSink for MemorySink {
+    /// The bytes of the epub
+    type Output = Vec<u8>;
+
+    fn add_directory(&mut self, entry: &OutputEntry) -> std::io::Result<()> {
+        return self.0.add_directory(entry);
+    }
+
+    fn add_file(&mut self, entry: &OutputEntry, data: &[u8]) -> std::io::Result<()> {
+        return self.0.add_file(entry, data);
+    }
+
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        return Ok(self.0.finish()?.into_inner());
+    }
+}
+
+/// Writes the book as an expanded epub: a directory with one file per
+/// entry. Timestamps aren't kept
+pub struct DirSink {
+    root: PathBuf,
+}
+
+impl DirSink {
+    /// Writes into `root`, which is created if needed. Files already in it
+    /// are overwritten, others are left alone
+    pub fn new(root: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(root)?;
+        return Ok(Self {
+            root: root.to_path_buf(),
+        });
+    }
+
+    /// Path of the entry `name`, which must stay inside the root
+    fn path(&self, name: &str) -> std::io::Result<PathBuf> {
+        let relative = Path::new(name.trim_end_matches('/'));
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Entry {} is outside of the output directory", name),
+            ));
+        }
+        return Ok(self.root.join(relative));
+    }
+}
+
+impl OutputSink for DirSink {
+    /// The directory written to
+    type Output = PathBuf;
+
+    fn add_directory(&mut self, entry: &OutputEntry) -> std::io::Result<()> {
+        return std::fs::create_dir_all(self.path(entry.name)?);
+    }
+
+    fn add_file(&mut self, entry: &OutputEntry, data: &[u8]) -> std::io::Result<()> {
+        let path = self.path(entry.name)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        return std::fs::write(path, data);
+    }
+
+    fn finish(self) -> std::io::Result<PathBuf> {
+        return Ok(self.root);
+    }
+}
+
+/// Writes the entries of the book to a tar stream, with the same modes as
+/// [`ZipSink`]
+pub struct TarSink<W: Write> {
+    tar: tar::Builder<W>,
+}
+
+impl<W: Write> TarSink<W> {
+    pub fn new(writer: W) -> Self {
+        return Self {
+            tar: tar::Builder::new(writer),
+        };
+    }
+
+    fn header(entry: &OutputEntry, kind: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(kind);
+        header.set_mode(mode);
+        header.set_size(size);
+        let modified = match entry.modified {
+            Some(t) => unix_time(t),
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+        header.set_mtime(modified);
+        return header;
+    }
+}
+
+impl<W: Write> OutputSink for TarSink<W> {
+    type Output = W;
+
+    fn add_directory(&mut self, entry: &OutputEntry) -> std::io::Result<()> {
+        let mut header = Self::header(entry, tar::EntryType::Directory, 0o755, 0);
+        return self
+            .tar
+            .append_data(&mut header, entry.name, std::io::empty());
+    }
+
+    fn add_file(&mut self, entry: &OutputEntry, data: &[u8]) -> std::io::Result<()> {
+        let size = data.len() as u64;
+        let mut header = Self::header(entry, tar::EntryType::Regular, 0o644, size);
+        return self.tar.append_data(&mut header, entry.name, data);
+    }
+
+    fn finish(self) -> std::io::Result<W> {
+        return self.tar.into_inner();
+    }
+}
+
+/// Seconds since the unix epoch of a zip timestamp, which has no time zone
+/// and is taken as UTC
+fn unix_time(t: DateTime) -> u64 {
+    // days from the civil date, after Howard Hinnant's algorithm
+    let (month, day) = (t.month() as i64, t.day() as i64);
+    let year = t.year() as i64 - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    let seconds = t.hour() as i64 * 3600 + t.minute() as i64 * 60 + t.second() as i64;
+    return (days * 86400 + seconds).max(0) as u64;
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read};
+
+    use zip::{DateTime, ZipArchive};
+
+    use super::{unix_time, DirSink, MemorySink, OutputEntry, OutputSink, TarSink};
+
+    /// Writes a small book to `sink`
+    fn write_book<S: OutputSink>(mut sink: S) -> S::Output {
+        let modified = DateTime::from_date_and_time(2001, 2, 3, 4, 5, 6).ok();
+        let entry = |name, stored| {
+            return OutputEntry {
+                name,
+                stored,
+                modified,
+            };
+        };
+        sink.add_file(&entry("mimetype", true), b"application/epub+zip")
+            .unwrap();
+        sink.add_directory(&entry("OEBPS/", false)).unwrap();
+        sink.add_file(&entry("OEBPS/ch1.xhtml", false), b"<html/>")
+            .unwrap();
+        return sink.finish().unwrap();
+    }
+
+    #[test]
+    fn test_memory_sink() {
+        let data = write_book(MemorySink::new(None));
+        assert!(data.starts_with(b"PK"));
+        assert_eq!(&data[30..38], b"mimetype");
+        let mut zip = ZipArchive::new(Cursor::new(data)).unwrap();
+        assert_eq!(zip.len(), 3);
+        let mut text = String::new();
+        let mut ch1 = zip.by_name("OEBPS/ch1.xhtml").unwrap();
+        ch1.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "<html/>");
+        assert_eq!(ch1.last_modified().unwrap().year(), 2001);
+    }
+
+    #[test]
+    fn test_dir_sink() {
+        let root = std::env::temp_dir().join(format!("kepub-rs-dir-sink-{}", std::process::id()));
+        let written = write_book(DirSink::new(&root).unwrap());
+        assert_eq!(written, root);
+        assert_eq!(
+            std::fs::read_to_string(root.join("OEBPS/ch1.xhtml")).unwrap(),
+            "<html/>"
+        );
+        assert!(root.join("mimetype").is_file());
+
+        let mut sink = DirSink::new(&root).unwrap();
+        let outside = OutputEntry {
+            name: "../escape.txt",
+            stored: false,
+            modified: None,
+        };
+        assert!(sink.add_file(&outside, b"").is_err());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_tar_sink() {
+        let data = write_book(TarSink::new(Vec::new()));
+        let mut archive = tar::Archive::new(Cursor::new(data));
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                let header = e.header();
+                return (
+                    e.path().unwrap().to_string_lossy().into_owned(),
+                    header.mode().unwrap(),
+                    header.mtime().unwrap(),
+                );
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                ("mimetype".to_string(), 0o644, 981173106),
+                ("OEBPS/".to_string(), 0o755, 981173106),
+                ("OEBPS/ch1.xhtml".to_string(), 0o644, 981173106),
+            ]
+        );
+        assert_eq!(unix_time(DateTime::default()), 315532800);
+    }
+}
@@ -0,0 +1,206 @@
+//! Regions of a content document whose whitespace is significant: `<pre>`
+//! elements and elements with `xml:space="preserve"`, with everything in
+//! them.
+//!
+//! Whitespace-only text is kept when parsing only inside these regions, and
+//! they are written back without indentation, so their line breaks and
+//! spacing come out as they went in.
+
+use std::{borrow::Cow, io::Read};
+
+use xml::{
+    name::OwnedName,
+    reader::{EventReader, XmlEvent},
+};
+use xmltree::{Element, ParseError, ParserConfig, XMLNode};
+
+use crate::elem::{ElementExt, Walk, OPS_NAMESPACE};
+
+/// Name xmltree stores the `xml:space` attribute under, without its prefix
+const SPACE_ATTR: &str = "space";
+
+/// Whether whitespace is significant inside `elem`, given whether it is
+/// inside its parent
+pub fn preserves_space(elem: &Element, inherited: bool) -> bool {
+    if elem.name.eq_ignore_ascii_case("pre") {
+        return true;
+    }
+    return match elem.attributes.get(SPACE_ATTR).map(String::as_str) {
+        Some("preserve") => true,
+        Some("default") => false,
+        _ => inherited,
+    };
+}
+
+/// Visits every element of `root` with whether whitespace is significant
+/// inside it
+fn walk_regions<F>(root: &mut Element, mut f: F)
+where
+    F: FnMut(&mut Element, bool),
+{
+    let mut stack = Vec::new();
+    root.walk_mut(|e, depth| {
+        stack.truncate(depth);
+        let preserve = preserves_space(e, stack.last().copied().unwrap_or(false));
+        stack.push(preserve);
+        f(e, preserve);
+        return Walk::Descend;
+    });
+}
+
+/// Parses a document, dropping whitespace-only text everywhere but in the
+/// regions where it is significant. Builds the same tree as xmltree, see
+/// [`attribute_key`] for the one difference, with an explicit stack rather
+/// than recursion
+pub fn parse<R: Read>(r: R) -> Result<Element, ParseError> {
+    let config = ParserConfig::new()
+        .ignore_comments(false)
+        .whitespace_to_characters(true);
+    let mut reader = EventReader::new_with_config(r, config);
+    let mut stack: Vec<Element> = Vec::new();
+    loop {
+        let node = match reader.next().map_err(ParseError::MalformedXml)? {
+            XmlEvent::StartElement {
+                name,
+                attributes,
+                namespace,
+            } => {
+                let mut elem = Element::new(&name.local_name);
+                elem.prefix = name.prefix;
+                elem.namespace = name.namespace;
+                if !namespace.is_essentially_empty() {
+                    elem.namespaces = Some(namespace);
+                }
+                for attr in attributes {
+                    elem.attributes
+                        .insert(attribute_key(&attr.name), attr.value);
+                }
+                stack.push(elem);
+                continue;
+            }
+            XmlEvent::EndElement { .. } => {
+                let elem = stack.pop().ok_or(ParseError::CannotParse)?;
+                if stack.is_empty() {
+                    let mut root = elem;
+                    strip_insignificant(&mut root);
+                    return Ok(root);
+                }
+                XMLNode::Element(elem)
+            }
+            XmlEvent::Characters(s) => XMLNode::Text(s),
+            XmlEvent::CData(s) => XMLNode::CData(s),
+            XmlEvent::Comment(s) => XMLNode::Comment(s),
+            XmlEvent::ProcessingInstruction { name, data } => {
+                XMLNode::ProcessingInstruction(name, data)
+            }
+            XmlEvent::StartDocument { .. } | XmlEvent::Whitespace(_) => continue,
+            XmlEvent::EndDocument => return Err(ParseError::CannotParse),
+        };
+        // comments and instructions around the root element are dropped
+        if let Some(parent) = stack.last_mut() {
+            parent.children.push(node);
+        }
+    }
+}
+
+/// Attributes are kept by their local name, as xmltree does, except the
+/// ones of the epub namespace, which keep their prefix: `epub:type` is not
+/// the `type` of links and lists
+fn attribute_key(name: &OwnedName) -> String {
+    return match (&name.prefix, name.namespace.as_deref()) {
+        (Some(prefix), Some(OPS_NAMESPACE)) => format!("{}:{}", prefix, name.local_name),
+        _ => name.local_name.clone(),
+    };
+}
+
+/// Drops whitespace-only text everywhere but in the regions where it is
+/// significant
+pub fn strip_insignificant(root: &mut Element) {
+    walk_regions(root, |e, preserve| {
+        if !preserve {
+            e.children
+                .retain(|c| !matches!(c, XMLNode::Text(t) if t.trim().is_empty()));
+        }
+    });
+}
+
+/// Returns `root` ready to be written: `xml:space` gets its prefix back and
+/// the children of elements in a region are separated by empty text, which
+/// keeps the emitter from indenting them. Borrowed if `root` has no region
+pub fn for_output(root: &Element) -> Cow<'_, Element> {
+    let has_region = root
+        .descendants()
+        .any(|e| e.attributes.contains_key(SPACE_ATTR) || e.name.eq_ignore_ascii_case("pre"));
+    if !has_region {
+        return Cow::Borrowed(root);
+    }
+
+    let mut root = root.clone();
+    walk_regions(&mut root, |e, preserve| {
+        if let Some(v) = e.attributes.remove(SPACE_ATTR) {
+            e.attributes.insert(format!("xml:{}", SPACE_ATTR), v);
+        }
+        if !preserve || e.children.is_empty() {
+            return;
+        }
+        let mut children = Vec::with_capacity(e.children.len() * 2 + 1);
+        for c in e.children.drain(..) {
+            let is_text = matches!(c, XMLNode::Text(_));
+            if !is_text && !matches!(children.last(), Some(XMLNode::Text(_))) {
+                children.push(XMLNode::Text(String::new()));
+            }
+            children.push(c);
+        }
+        if !matches!(children.last(), Some(XMLNode::Text(_))) {
+            children.push(XMLNode::Text(String::new()));
+        }
+        e.children = children;
+    });
+    return Cow::Owned(root);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{for_output, parse};
+    use crate::converter::serialize_xml;
+
+    #[test]
+    fn test_epub_attributes() {
+        let xml = r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+            <body xml:lang="en"><!-- c --><a epub:type="noteref" type="text/html" href="n.xhtml#n1">1</a></body></html>"#;
+        let root = parse(xml.as_bytes()).unwrap();
+        let body = root.children[0].as_element().unwrap();
+        assert_eq!(body.attributes["lang"], "en");
+        assert!(body.children[0].as_comment().is_some());
+        let a = body.children[1].as_element().unwrap();
+        assert_eq!(a.attributes["epub:type"], "noteref");
+        assert_eq!(a.attributes["type"], "text/html");
+
+        let out = serialize_xml(&root).unwrap();
+        assert!(out.contains(r#"xmlns:epub="http://www.idpf.org/2007/ops""#));
+        assert!(out.contains(r#"epub:type="noteref""#));
+        assert!(parse("<a><b></a>".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_regions() {
+        let xml = "<body>\n  <p><em>a</em> <em>b</em></p>\n  \
+            <div xml:space=\"preserve\"><i>x</i> <i>y</i>\n<br/>\n<br/>  z</div>\n\
+            <pre>a\n<b>b</b></pre></body>";
+        let root = parse(xml.as_bytes()).unwrap();
+        let p = &root.children[0].as_element().unwrap();
+        assert_eq!(p.children.len(), 2);
+        let div = root.children[1].as_element().unwrap();
+        assert_eq!(div.children.len(), 8);
+
+        let out = for_output(&root);
+        let div = out.children[1].as_element().unwrap();
+        assert_eq!(div.attributes["xml:space"], "preserve");
+
+        let out = serialize_xml(&root).unwrap();
+        assert!(
+            out.contains("<div xml:space=\"preserve\"><i>x</i> <i>y</i>\n<br />\n<br />  z</div>")
+        );
+        assert!(out.contains("<pre>a\n<b>b</b></pre>"));
+    }
+}
@@ -0,0 +1,344 @@
+//! Sidecar file mapping the generated kobospan ids back to the text they
+//! wrap, for tools that translate Kobo highlights (which reference span
+//! ids) into positions in the source text.
+//!
+//! Span maps can also be read back from an existing kepub, and the spans of
+//! two conversions of the same book matched up by their text with [`remap`],
+//! so that highlights made on the old conversion can be moved to the new one.
+
+use std::{fs::File, path::Path};
+
+use serde::{Deserialize, Serialize};
+use xmltree::{Element, XMLNode};
+use zip::ZipArchive;
+
+use crate::{
+    errors::ConverterError,
+    href,
+    logger::debug,
+    verify::{read_body, text_content},
+};
+
+/// One kobospan
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpanRecord {
+    /// `kobo.<paragraph>.<sentence>`
+    pub id: String,
+    /// Character offsets of the span's text within the text content of the
+    /// chapter's `<body>`, after normalization. Image spans are empty
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// Numbers the kobospans of one content document as
+/// `kobo.<paragraph>.<sentence>`, both counted from 1 in document order the
+/// way Kobo and kepubify number them, so reading positions and highlights
+/// carry over between their kepubs and ours. Each document gets a counter
+/// of its own, so every chapter starts at `kobo.1.1`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpanCounter {
+    para: usize,
+    sent: usize,
+}
+
+impl SpanCounter {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Starts the next paragraph
+    pub fn next_para(&mut self) {
+        self.para += 1;
+        self.sent = 0;
+    }
+
+    /// Moves on to the next sentence, in paragraph 1 if none was started,
+    /// and returns its id
+    pub fn next_sentence(&mut self) -> String {
+        self.para = self.para.max(1);
+        self.sent += 1;
+        return self.id();
+    }
+
+    /// Id of the current span. An image is wrapped in sentence 0 of a
+    /// paragraph of its own
+    pub fn id(&self) -> String {
+        return format!("kobo.{}.{}", self.para, self.sent);
+    }
+
+    /// Paragraphs started so far
+    pub fn para(&self) -> usize {
+        return self.para;
+    }
+
+    /// Sentences in the current paragraph so far
+    pub fn sentence(&self) -> usize {
+        return self.sent;
+    }
+}
+
+/// The spans of one content document
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChapterSpans {
+    /// Path of the document within the archive
+    pub file: String,
+    /// Table of contents label or first heading, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub spans: Vec<SpanRecord>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpanMap {
+    pub chapters: Vec<ChapterSpans>,
+}
+
+impl SpanMap {
+    pub fn load(path: &Path) -> Result<Self, ConverterError> {
+        let f = std::fs::File::open(path)?;
+        return serde_json::from_reader(f)
+            .map_err(|e| ConverterError::Other(format!("Invalid span map {:?}: {}", path, e)));
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConverterError> {
+        let json =
+            serde_json::to_string_pretty(self).map_err(|e| ConverterError::Other(e.to_string()))?;
+        std::fs::write(path, json)?;
+        return Ok(());
+    }
+
+    /// Reads the kobospans of every content document in the kepub at `path`.
+    /// Offsets count all the text of the `<body>`, as in the maps written
+    /// during conversion
+    pub fn from_kepub(path: &Path) -> Result<Self, ConverterError> {
+        let mut zip = ZipArchive::new(File::open(path)?)?;
+        let mut names = zip
+            .file_names()
+            .filter(|n| !n.starts_with("META-INF/") && href::has_content_extension(n))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        names.sort();
+
+        let mut chapters = Vec::new();
+        for name in names {
+            let Some(body) = read_body(&mut zip, &name)? else {
+                continue;
+            };
+            let mut spans = Vec::new();
+            collect_spans(&body, &mut 0, &mut spans);
+            if !spans.is_empty() {
+                chapters.push(ChapterSpans {
+                    file: name,
+                    title: None,
+                    spans,
+                });
+            }
+        }
+        return Ok(SpanMap { chapters });
+    }
+
+    /// Loads a span map written during conversion if `path` is a `.json`
+    /// file, or reads one from the kepub at `path` otherwise
+    pub fn open(path: &Path) -> Result<Self, ConverterError> {
+        if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+        {
+            return Self::load(path);
+        }
+        return Self::from_kepub(path);
+    }
+}
+
+fn collect_spans(elem: &Element, offset: &mut usize, spans: &mut Vec<SpanRecord>) {
+    for c in &elem.children {
+        match c {
+            XMLNode::Text(t) | XMLNode::CData(t) => *offset += t.chars().count(),
+            XMLNode::Element(e) => {
+                let id = e.attributes.get("id");
+                let is_span = e.name == "span"
+                    && e.attributes
+                        .get("class")
+                        .is_some_and(|c| c.split_whitespace().any(|c| c == "kobospan"));
+                match id {
+                    Some(id) if is_span => {
+                        let text = text_content(e);
+                        let start = *offset;
+                        *offset += text.chars().count();
+                        spans.push(SpanRecord {
+                            id: id.clone(),
+                            start,
+                            end: *offset,
+                            text,
+                        });
+                    }
+                    _ => collect_spans(e, offset, spans),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Where a span of the old conversion ended up in the new one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anchor {
+    pub file: String,
+    pub old: String,
+    /// The new span holding the start of the old span's text, or None if
+    /// that text is gone
+    pub new: Option<String>,
+}
+
+/// How far ahead of the previous match the text of a span is looked for
+const SEARCH_WINDOW: usize = 1000;
+
+/// Matches every span of `old` to a span of `new` with the same text.
+/// Whitespace is ignored and spans are matched in order, so changes to how
+/// the text was split into spans, and small edits, are followed. Spans
+/// without text, around images, are matched by their position among the
+/// other empty spans of the document
+pub fn remap(old: &SpanMap, new: &SpanMap) -> Vec<Anchor> {
+    let mut anchors = Vec::new();
+    for chapter in &old.chapters {
+        let target = new.chapters.iter().find(|c| c.file == chapter.file);
+        let Some(target) = target else {
+            debug!("{}: not in the new book", chapter.file);
+            anchors.extend(chapter.spans.iter().map(|s| Anchor {
+                file: chapter.file.clone(),
+                old: s.id.clone(),
+                new: None,
+            }));
+            continue;
+        };
+
+        // the text of the new spans, without whitespace, and the span each
+        // character belongs to
+        let mut chars = Vec::new();
+        let mut owner = Vec::new();
+        for (i, s) in target.spans.iter().enumerate() {
+            for c in s.text.chars().filter(|c| !c.is_whitespace()) {
+                chars.push(c);
+                owner.push(i);
+            }
+        }
+        let mut empty = target.spans.iter().filter(|s| s.text.is_empty());
+
+        let mut cursor = 0;
+        for span in &chapter.spans {
+            let key = span
+                .text
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect::<Vec<_>>();
+            let new = if span.text.is_empty() {
+                empty.next().map(|s| s.id.clone())
+            } else if key.is_empty() {
+                // whitespace only, anchored wherever the text continues
+                owner.get(cursor).map(|&i| target.spans[i].id.clone())
+            } else {
+                let found = find(&chars, &key, cursor)
+                    .map(|at| (at, key.len()))
+                    .or_else(|| {
+                        let prefix = &key[..key.len().min(16)];
+                        return find(&chars, prefix, cursor).map(|at| (at, prefix.len()));
+                    });
+                found.map(|(at, len)| {
+                    cursor = at + len;
+                    return target.spans[owner[at]].id.clone();
+                })
+            };
+            anchors.push(Anchor {
+                file: chapter.file.clone(),
+                old: span.id.clone(),
+                new,
+            });
+        }
+    }
+    return anchors;
+}
+
+/// Finds `key` in `chars` at or after `from`, within [`SEARCH_WINDOW`]
+fn find(chars: &[char], key: &[char], from: usize) -> Option<usize> {
+    let end = chars.len().min(from + key.len() + SEARCH_WINDOW);
+    if from >= end || end - from < key.len() {
+        return None;
+    }
+    return chars[from..end]
+        .windows(key.len())
+        .position(|w| w == key)
+        .map(|i| from + i);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{remap, ChapterSpans, SpanCounter, SpanMap, SpanRecord};
+
+    #[test]
+    fn test_span_counter() {
+        let mut counter = SpanCounter::new();
+        assert_eq!(counter.next_sentence(), "kobo.1.1");
+        assert_eq!(counter.next_sentence(), "kobo.1.2");
+        counter.next_para();
+        assert_eq!(counter.id(), "kobo.2.0");
+        assert_eq!(counter.next_sentence(), "kobo.2.1");
+        assert_eq!((counter.para(), counter.sentence()), (2, 1));
+        // the next document starts over
+        assert_eq!(SpanCounter::new().next_sentence(), "kobo.1.1");
+    }
+
+    fn map(spans: &[(&str, &str)]) -> SpanMap {
+        let spans = spans
+            .iter()
+            .map(|(id, text)| SpanRecord {
+                id: id.to_string(),
+                start: 0,
+                end: 0,
+                text: text.to_string(),
+            })
+            .collect();
+        return SpanMap {
+            chapters: vec![ChapterSpans {
+                file: "ch1.xhtml".to_string(),
+                title: None,
+                spans,
+            }],
+        };
+    }
+
+    #[test]
+    fn test_remap() {
+        let old = map(&[
+            ("kobo.1.1", "Title"),
+            ("kobo.2.1", "One. "),
+            ("kobo.2.2", "Two. Three."),
+            ("kobo.3.0", ""),
+            ("kobo.4.1", "Gone."),
+            ("kobo.5.1", "Two. "),
+        ]);
+        let new = map(&[
+            ("kobo.1.1", "A new intro."),
+            ("kobo.2.1", "Title"),
+            ("kobo.3.1", "One. "),
+            ("kobo.3.2", "Two. "),
+            ("kobo.3.3", "Three."),
+            ("kobo.4.0", ""),
+            ("kobo.5.1", "Two. "),
+        ]);
+        let anchors = remap(&old, &new)
+            .into_iter()
+            .map(|a| (a.old, a.new))
+            .collect::<Vec<_>>();
+        let expected = [
+            ("kobo.1.1", Some("kobo.2.1")),
+            ("kobo.2.1", Some("kobo.3.1")),
+            ("kobo.2.2", Some("kobo.3.2")),
+            ("kobo.3.0", Some("kobo.4.0")),
+            ("kobo.4.1", None),
+            ("kobo.5.1", Some("kobo.5.1")),
+        ]
+        .map(|(o, n)| (o.to_string(), n.map(String::from)));
+        assert_eq!(anchors, expected);
+    }
+}
@@ -0,0 +1,379 @@
+//! Transforms applied to the text nodes of content documents.
+
+use std::{path::Path, str::FromStr};
+
+use regex::Regex;
+use unicode_normalization::{
+    char::canonical_combining_class, is_nfc_quick, is_nfkc_quick, IsNormalized,
+    UnicodeNormalization,
+};
+
+/// Unicode normalization form applied to text content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Normalization {
+    #[default]
+    None,
+    Nfc,
+    Nfkc,
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Normalization::None),
+            "nfc" => Ok(Normalization::Nfc),
+            "nfkc" => Ok(Normalization::Nfkc),
+            _ => Err(format!(
+                "unknown normalization form '{}', expected nfc, nfkc or none",
+                s
+            )),
+        };
+    }
+}
+
+impl std::fmt::Display for Normalization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Normalization::None => "none",
+            Normalization::Nfc => "NFC",
+            Normalization::Nfkc => "NFKC",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Normalization {
+    /// The form that leaves compatibility characters, like the full-width
+    /// letters and digits of vertical text, as they are
+    pub fn canonical(self) -> Normalization {
+        return match self {
+            Normalization::Nfkc => Normalization::Nfc,
+            n => n,
+        };
+    }
+
+    fn apply(&self, text: &str) -> String {
+        return match self {
+            Normalization::None => text.to_string(),
+            Normalization::Nfc => text.nfc().collect(),
+            Normalization::Nfkc => text.nfkc().collect(),
+        };
+    }
+
+    fn is_normalized(&self, text: &str) -> bool {
+        let quick = match self {
+            Normalization::None => return true,
+            Normalization::Nfc => is_nfc_quick(text.chars()),
+            Normalization::Nfkc => is_nfkc_quick(text.chars()),
+        };
+        return match quick {
+            IsNormalized::Yes => true,
+            IsNormalized::No => false,
+            IsNormalized::Maybe => self.apply(text) == text,
+        };
+    }
+
+    /// Normalizes `text`, returning the result and how many characters of the
+    /// input were changed. Characters are counted per base character and its
+    /// combining marks, so a decomposed `e` + accent counts as 2
+    pub fn normalize(&self, text: &str) -> (String, usize) {
+        if self.is_normalized(text) {
+            return (text.to_string(), 0);
+        }
+
+        let mut changed = 0;
+        let mut run = String::new();
+        let mut count_run = |run: &str| {
+            if !run.is_empty() && self.apply(run) != run {
+                changed += run.chars().count();
+            }
+        };
+        for c in text.chars() {
+            if canonical_combining_class(c) == 0 {
+                count_run(&run);
+                run.clear();
+            }
+            run.push(c);
+        }
+        count_run(&run);
+
+        return (self.apply(text), changed);
+    }
+}
+
+/// Invisible characters that stop a word from being found in the dictionary
+/// when they appear inside it
+const WORD_BREAKS: [char; 4] = ['\u{AD}', '\u{200B}', '\u{2060}', '\u{FEFF}'];
+
+/// Removes soft hyphens and zero-width spaces, joiners and no-break spaces
+/// found between two letters or digits, which keep Kobo's dictionary from
+/// recognizing the word. Returns the result and how many were removed
+pub fn strip_word_breaks(text: &str) -> (String, usize) {
+    if !text.contains(WORD_BREAKS) {
+        return (text.to_string(), 0);
+    }
+
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut out = String::with_capacity(text.len());
+    let mut removed = 0;
+    for (i, &c) in chars.iter().enumerate() {
+        if WORD_BREAKS.contains(&c) {
+            let after = chars[i + 1..].iter().find(|c| !WORD_BREAKS.contains(c));
+            let inside = out.chars().last().is_some_and(char::is_alphanumeric)
+                && after.is_some_and(|c| c.is_alphanumeric());
+            if inside {
+                removed += 1;
+                continue;
+            }
+        }
+        out.push(c);
+    }
+    return (out, removed);
+}
+
+/// A regex substitution run over the text of content documents, given as
+/// `PATTERN::REPLACEMENT`. The replacement can refer to groups as `$1` or
+/// `${name}`
+#[derive(Debug, Clone)]
+pub struct Replacement {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl FromStr for Replacement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pattern, replacement)) = s.split_once("::") else {
+            return Err(format!("expected PATTERN::REPLACEMENT, found '{}'", s));
+        };
+        let pattern = Regex::new(pattern).map_err(|e| e.to_string())?;
+        return Ok(Self {
+            pattern,
+            replacement: replacement.to_string(),
+        });
+    }
+}
+
+impl Replacement {
+    /// Reads the replacements in the file at `path`, one per line. Empty
+    /// lines and lines starting with `#` are skipped
+    pub fn read_file(path: &Path) -> Result<Vec<Self>, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        return text
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+            .map(|(i, l)| return l.parse().map_err(|e| format!("line {}: {}", i + 1, e)))
+            .collect();
+    }
+
+    /// Returns `text` with every match replaced, and how many there were
+    pub fn apply(&self, text: &str) -> (String, usize) {
+        let found = self.pattern.find_iter(text).count();
+        if found == 0 {
+            return (text.to_string(), 0);
+        }
+        let replaced = self.pattern.replace_all(text, self.replacement.as_str());
+        return (replaced.into_owned(), found);
+    }
+}
+
+/// Whether a quote after `prev` opens rather than closes
+fn opens_quote(prev: Option<char>) -> bool {
+    return prev.is_none_or(|c| {
+        return c.is_whitespace() || "([{/-\u{2013}\u{2014}\u{201C}\u{2018}".contains(c);
+    });
+}
+
+/// Replaces straight quotes with typographic ones, `--` and `---` with an em
+/// dash and `...` with an ellipsis. `prev` is the character before `text`,
+/// which tells opening quotes from closing ones at its start. Returns the
+/// result and how many replacements were made
+pub fn smarten_punctuation(text: &str, prev: Option<char>) -> (String, usize) {
+    if !text.contains(['"', '\'', '-', '.']) {
+        return (text.to_string(), 0);
+    }
+
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut out = String::with_capacity(text.len());
+    let mut last = prev;
+    let mut replaced = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let run = chars[i..].iter().take_while(|d| **d == c).count();
+        let (smart, len) = match c {
+            '.' if run == 3 => ('\u{2026}', 3),
+            '-' if run == 2 || run == 3 => ('\u{2014}', run),
+            '"' if opens_quote(last) => ('\u{201C}', 1),
+            '"' => ('\u{201D}', 1),
+            // an apostrophe that starts a word, as in '90s, isn't a quote
+            '\'' if opens_quote(last) && !chars.get(i + 1).is_some_and(char::is_ascii_digit) => {
+                ('\u{2018}', 1)
+            }
+            '\'' => ('\u{2019}', 1),
+            _ => {
+                out.extend(&chars[i..i + run]);
+                last = Some(c);
+                i += run;
+                continue;
+            }
+        };
+        out.push(smart);
+        last = Some(smart);
+        replaced += 1;
+        i += len;
+    }
+    return (out, replaced);
+}
+
+/// Converts CRLF and lone CR line endings to LF and strips trailing spaces
+/// and tabs from every line. Lines ending inside a `<pre>` element or one
+/// with `xml:space="preserve"` only have their line ending converted, since
+/// their whitespace is significant
+pub fn normalize_lines(text: &str) -> String {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let mut out = String::with_capacity(text.len());
+    let mut region = None;
+
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        update_preserved(line, &mut region);
+        match region {
+            None => out.push_str(line.trim_end_matches([' ', '\t'])),
+            Some(_) => out.push_str(line),
+        }
+    }
+    return out;
+}
+
+/// Tracks the whitespace-preserving region open at the end of `line`: the
+/// name of the element that opened it and how many elements of that name
+/// are open
+fn update_preserved(line: &str, region: &mut Option<(String, usize)>) {
+    let mut rest = line;
+    while let Some(i) = rest.find('<') {
+        rest = &rest[i + 1..];
+        let (closing, name_start) = match rest.strip_prefix('/') {
+            Some(r) => (true, r),
+            None => (false, rest),
+        };
+        let name_end = name_start
+            .find(|c: char| c == '>' || c == '/' || c.is_whitespace())
+            .unwrap_or(name_start.len());
+        let name = &name_start[..name_end];
+        if name.is_empty() || name.starts_with(['!', '?']) {
+            continue;
+        }
+        let tag = &rest[..rest.find('>').unwrap_or(rest.len())];
+        let self_closing = tag.ends_with('/');
+
+        match region {
+            None if !closing
+                && !self_closing
+                && (name == "pre" || tag.contains(r#"xml:space="preserve""#)) =>
+            {
+                *region = Some((name.to_string(), 1));
+            }
+            Some((open, depth)) if open == name => {
+                if closing {
+                    *depth -= 1;
+                } else if !self_closing {
+                    *depth += 1;
+                }
+                if *depth == 0 {
+                    *region = None;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        normalize_lines, smarten_punctuation, strip_word_breaks, Normalization, Replacement,
+    };
+
+    #[test]
+    fn test_normalize() {
+        let decomposed = "Cafe\u{301} au lait";
+        assert_eq!(
+            Normalization::Nfc.normalize(decomposed),
+            ("Café au lait".to_string(), 2)
+        );
+        assert_eq!(
+            Normalization::None.normalize(decomposed),
+            (decomposed.to_string(), 0)
+        );
+        assert_eq!(Normalization::Nfc.normalize("ﬁne"), ("ﬁne".to_string(), 0));
+        assert_eq!(
+            Normalization::Nfkc.normalize("ﬁne"),
+            ("fine".to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn test_strip_word_breaks() {
+        assert_eq!(
+            strip_word_breaks("dic\u{AD}tion\u{200B}\u{2060}ary"),
+            ("dictionary".to_string(), 3)
+        );
+        assert_eq!(
+            strip_word_breaks("\u{FEFF}a \u{200B}b\u{AD}"),
+            ("\u{FEFF}a \u{200B}b\u{AD}".to_string(), 0)
+        );
+    }
+
+    #[test]
+    fn test_replacement() {
+        let r = "(\\w+)  +(\\w+)::$1 $2".parse::<Replacement>().unwrap();
+        assert_eq!(r.apply("a  b c   d"), ("a b c d".to_string(), 2));
+        assert_eq!(r.apply("a b"), ("a b".to_string(), 0));
+        let watermark = "Licensed to [^.]*\\.::".parse::<Replacement>().unwrap();
+        assert_eq!(watermark.apply("Licensed to A. B"), (" B".to_string(), 1));
+        assert!("no separator".parse::<Replacement>().is_err());
+        assert!("(::x".parse::<Replacement>().is_err());
+    }
+
+    #[test]
+    fn test_smarten_punctuation() {
+        assert_eq!(
+            smarten_punctuation("\"Don't--wait...\" 'Twas the '90s, 'quoted' --- ok", None),
+            (
+                "\u{201C}Don\u{2019}t\u{2014}wait\u{2026}\u{201D} \u{2018}Twas the \u{2019}90s, \u{2018}quoted\u{2019} \u{2014} ok"
+                    .to_string(),
+                10
+            )
+        );
+        // the first quote closes the text before it
+        assert_eq!(
+            smarten_punctuation("\" she said", Some('!')),
+            ("\u{201D} she said".to_string(), 1)
+        );
+        assert_eq!(
+            smarten_punctuation("---- a.... b-c", None),
+            ("---- a.... b-c".to_string(), 0)
+        );
+    }
+
+    #[test]
+    fn test_normalize_lines() {
+        assert_eq!(normalize_lines("a  \r\nb\t\rc \n"), "a\nb\nc\n");
+        assert_eq!(
+            normalize_lines("<p>x </p>\r\n<pre>a  \r\n b  \r\n</pre>  \n<pre/> \nz "),
+            "<p>x </p>\n<pre>a  \n b  \n</pre>\n<pre/>\nz"
+        );
+        assert_eq!(normalize_lines("<prefix> \n"), "<prefix>\n");
+        assert_eq!(
+            normalize_lines("<div xml:space=\"preserve\">a \n<div>b </div> \n</div> \nc "),
+            "<div xml:space=\"preserve\">a \n<div>b </div> \n</div>\nc"
+        );
+    }
+}
@@ -0,0 +1,749 @@
+//! Checks of an extracted epub for the packaging mistakes that readers
+//! reject or trip over, and repairs for the ones that can be made without
+//! guessing at the content.
+
+use std::{collections::HashSet, fmt::Display, fs::File, path::Path};
+
+use regex::Regex;
+use xmltree::XMLNode;
+
+use crate::{
+    codes::WarningCode,
+    converter::{first_heading, has_root_element},
+    elem::ElementExt,
+    errors::{xml_err, ConverterError},
+    href,
+    opf::{ManifestItem, Package},
+    pack::{self, escape},
+    space,
+};
+
+const XHTML_TYPE: &str = "application/xhtml+xml";
+const NCX_TYPE: &str = "application/x-dtbncx+xml";
+
+/// Media types by file extension. The first is the one set when repairing,
+/// the others are accepted as well
+const MEDIA_TYPES: [(&str, &[&str]); 16] = [
+    ("xhtml", &[XHTML_TYPE]),
+    ("html", &[XHTML_TYPE]),
+    ("htm", &[XHTML_TYPE]),
+    ("ncx", &[NCX_TYPE]),
+    ("css", &["text/css"]),
+    ("jpg", &["image/jpeg"]),
+    ("jpeg", &["image/jpeg"]),
+    ("png", &["image/png"]),
+    ("gif", &["image/gif"]),
+    ("svg", &["image/svg+xml"]),
+    ("webp", &["image/webp"]),
+    (
+        "ttf",
+        &[
+            "font/ttf",
+            "application/x-font-ttf",
+            "application/x-font-truetype",
+            "application/font-sfnt",
+        ],
+    ),
+    (
+        "otf",
+        &[
+            "font/otf",
+            "application/vnd.ms-opentype",
+            "application/x-font-opentype",
+            "application/font-sfnt",
+        ],
+    ),
+    ("woff", &["font/woff", "application/font-woff"]),
+    ("woff2", &["font/woff2"]),
+    ("smil", &["application/smil+xml"]),
+];
+
+/// A problem found in a book
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    /// A manifest item whose media type doesn't match its file extension
+    MediaType {
+        id: String,
+        href: String,
+        declared: String,
+        expected: &'static str,
+    },
+    /// A manifest item whose file is not in the archive
+    MissingFile { id: String, href: String },
+    /// An EPUB 3 book without a navigation document, or an EPUB 2 book
+    /// whose spine names no NCX
+    MissingNav,
+    /// The `unique-identifier` of the package, if it has one, names no
+    /// `dc:identifier`
+    UniqueIdentifier(Option<String>),
+    /// A file of the archive, by path within it, that the manifest doesn't
+    /// list. `referenced` if a content document or stylesheet mentions it
+    Orphan { name: String, referenced: bool },
+}
+
+/// Codes of the findings [`repair`] can repair, in some cases at least
+pub const REPAIRABLE: [WarningCode; 4] = [
+    WarningCode::MediaType,
+    WarningCode::MissingNav,
+    WarningCode::UniqueIdentifier,
+    WarningCode::Orphan,
+];
+
+impl Finding {
+    pub fn code(&self) -> WarningCode {
+        return match self {
+            Finding::MediaType { .. } => WarningCode::MediaType,
+            Finding::MissingFile { .. } => WarningCode::MissingFile,
+            Finding::MissingNav => WarningCode::MissingNav,
+            Finding::UniqueIdentifier(_) => WarningCode::UniqueIdentifier,
+            Finding::Orphan { .. } => WarningCode::Orphan,
+        };
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Finding::MediaType {
+                href,
+                declared,
+                expected,
+                ..
+            } if declared.is_empty() => {
+                write!(f, "{}: no media type, expected {}", href, expected)
+            }
+            Finding::MediaType {
+                href,
+                declared,
+                expected,
+                ..
+            } => write!(
+                f,
+                "{}: declared as {}, expected {}",
+                href, declared, expected
+            ),
+            Finding::MissingFile { href, .. } => {
+                write!(f, "{}: in the manifest but not in the archive", href)
+            }
+            Finding::MissingNav => write!(f, "the book has no table of contents"),
+            Finding::UniqueIdentifier(None) => {
+                write!(f, "the package has no unique-identifier")
+            }
+            Finding::UniqueIdentifier(Some(id)) => {
+                write!(f, "unique-identifier \"{}\" names no dc:identifier", id)
+            }
+            Finding::Orphan {
+                name,
+                referenced: true,
+            } => write!(
+                f,
+                "{}: not in the manifest, but the content refers to it",
+                name
+            ),
+            Finding::Orphan { name, .. } => write!(f, "{}: not in the manifest", name),
+        };
+    }
+}
+
+/// Accepted media types for the file at `path`, by its extension
+fn media_types(path: &str) -> Option<&'static [&'static str]> {
+    let (_, ext) = path.rsplit_once('.')?;
+    let ext = ext.to_ascii_lowercase();
+    return MEDIA_TYPES
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .map(|(_, types)| *types);
+}
+
+/// Every problem found in the book extracted to `dir`, whose package
+/// document `pkg` was read from `opf_path`
+pub fn check(dir: &Path, opf_path: &Path, pkg: &Package) -> Vec<Finding> {
+    let opf_dir = opf_path.parent().unwrap_or(dir);
+    let mut findings = Vec::new();
+    for item in pkg.manifest() {
+        if item.href.contains("://") {
+            continue;
+        }
+        let path = href::normalize(&item.href);
+        if !opf_dir.join(&path).is_file() {
+            findings.push(Finding::MissingFile {
+                id: item.id,
+                href: item.href,
+            });
+            continue;
+        }
+        let Some(types) = media_types(&path) else {
+            continue;
+        };
+        let declared = item.media_type.split(';').next().unwrap_or_default();
+        if !types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(declared.trim()))
+        {
+            findings.push(Finding::MediaType {
+                id: item.id,
+                href: item.href,
+                declared: item.media_type,
+                expected: types[0],
+            });
+        }
+    }
+    findings.extend(check_unique_identifier(pkg));
+    if !has_nav(pkg) {
+        findings.push(Finding::MissingNav);
+    }
+    findings.extend(orphans(dir, opf_path, pkg));
+    return findings;
+}
+
+fn check_unique_identifier(pkg: &Package) -> Option<Finding> {
+    let uid = pkg.root().attributes.get("unique-identifier");
+    let found = uid.is_some_and(|uid| {
+        return pkg.root().get_child("metadata").is_some_and(|m| {
+            m.find_children("identifier")
+                .any(|e| e.attributes.get("id") == Some(uid))
+        });
+    });
+    return match found {
+        true => None,
+        false => Some(Finding::UniqueIdentifier(uid.cloned())),
+    };
+}
+
+/// Whether the table of contents the version of the package requires is
+/// there: a navigation document for EPUB 3, an NCX named by the spine for
+/// EPUB 2
+fn has_nav(pkg: &Package) -> bool {
+    if pkg.is_epub3() {
+        return pkg
+            .manifest()
+            .iter()
+            .any(|i| i.properties.iter().any(|p| p == "nav"));
+    }
+    return pkg
+        .root()
+        .get_child("spine")
+        .and_then(|s| s.attributes.get("toc"))
+        .and_then(|id| pkg.item(id))
+        .is_some_and(|i| i.media_type == NCX_TYPE);
+}
+
+/// The files of `dir` the manifest doesn't list, besides the mimetype, the
+/// package document and `META-INF`
+fn orphans(dir: &Path, opf_path: &Path, pkg: &Package) -> Vec<Finding> {
+    let opf_dir = opf_path.parent().unwrap_or(dir);
+    let opf_name = archive_name(dir, opf_path).unwrap_or_default();
+    let items = pkg.manifest();
+    // by archive name, as hrefs can lead out of the package directory
+    let listed = items
+        .iter()
+        .map(|i| href::resolve(&opf_name, &i.href))
+        .collect::<HashSet<_>>();
+    let unlisted = walkdir::WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .flatten()
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| archive_name(dir, e.path()))
+        .filter(|n| !listed.contains(n) && *n != opf_name)
+        .filter(|n| n != "mimetype" && !n.starts_with("META-INF/"))
+        .collect::<Vec<_>>();
+    if unlisted.is_empty() {
+        return Vec::new();
+    }
+
+    // files the content mentions by name are kept, they were only left out
+    // of the manifest
+    let texts = items
+        .iter()
+        .filter(|i| i.media_type.ends_with("xml") || i.media_type == "text/css")
+        .filter_map(|i| std::fs::read_to_string(opf_dir.join(href::normalize(&i.href))).ok())
+        .collect::<Vec<_>>();
+    return unlisted
+        .into_iter()
+        .map(|name| {
+            let file_name = name.rsplit('/').next().unwrap_or_default();
+            let referenced = texts
+                .iter()
+                .any(|t| t.contains(file_name) || t.contains(&pack::encode_href(file_name)));
+            return Finding::Orphan { name, referenced };
+        })
+        .collect();
+}
+
+/// Path of a file within the archive extracted to `dir`, with `/` separators
+fn archive_name(dir: &Path, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(dir).ok()?;
+    let parts = rel
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+    return Some(parts.join("/"));
+}
+
+/// Repairs `finding` in the book extracted to `dir`, editing `pkg`, which
+/// the caller saves. Returns false for the findings that can't be repaired
+/// safely: missing files, and unlisted files the content refers to whose
+/// media type is unknown
+pub fn repair(
+    dir: &Path,
+    opf_path: &Path,
+    pkg: &mut Package,
+    finding: &Finding,
+) -> Result<bool, ConverterError> {
+    let opf_dir = opf_path.parent().unwrap_or(dir);
+    match finding {
+        Finding::MediaType { id, expected, .. } => return Ok(pkg.set_media_type(id, expected)),
+        Finding::MissingFile { .. } => return Ok(false),
+        Finding::MissingNav => add_nav(opf_dir, pkg)?,
+        Finding::UniqueIdentifier(uid) => fix_unique_identifier(pkg, uid.as_deref())?,
+        Finding::Orphan {
+            name,
+            referenced: false,
+        } => std::fs::remove_file(dir.join(name))?,
+        Finding::Orphan { name, .. } => {
+            let Some(types) = media_types(name) else {
+                return Ok(false);
+            };
+            let opf_name = archive_name(dir, opf_path).unwrap_or_default();
+            let item = ManifestItem {
+                id: pkg.unused_id("item"),
+                href: pack::encode_href(&relative_href(&opf_name, name)),
+                media_type: types[0].to_string(),
+                properties: Vec::new(),
+            };
+            pkg.add_item(&item)?;
+        }
+    }
+    return Ok(true);
+}
+
+/// A package document made from the files of the book extracted to `dir`,
+/// for books whose own, `raw`, can't be parsed. Content documents, found by
+/// extension, make the spine in name order and every other file is listed
+/// with the media type of its extension. The title and language are
+/// salvaged from `raw` when possible, and the identifier and table of
+/// contents are made as when repairing
+pub fn rebuild_package(dir: &Path, opf_path: &Path, raw: &str) -> Result<Package, ConverterError> {
+    let opf_name = archive_name(dir, opf_path).unwrap_or_default();
+    let names = walkdir::WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .flatten()
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| archive_name(dir, e.path()))
+        .filter(|n| *n != "mimetype" && *n != opf_name && !n.starts_with("META-INF/"))
+        .collect::<Vec<_>>();
+
+    let mut manifest = String::new();
+    let mut spine = String::new();
+    for (i, name) in names.iter().enumerate() {
+        let is_xml = name.to_ascii_lowercase().ends_with(".xml");
+        let is_doc = href::has_content_extension(name)
+            && (!is_xml || has_root_element(&dir.join(name), "html"));
+        let media_type = match is_doc {
+            true => XHTML_TYPE,
+            false => media_types(name).map_or("application/octet-stream", |t| t[0]),
+        };
+        let id = format!("item{}", i + 1);
+        manifest.push_str(&format!(
+            "    <item id=\"{}\" href=\"{}\" media-type=\"{}\"/>\n",
+            id,
+            escape(&pack::encode_href(&relative_href(&opf_name, name))),
+            media_type
+        ));
+        if is_doc {
+            spine.push_str(&format!("    <itemref idref=\"{}\"/>\n", id));
+        }
+    }
+
+    // right to left books page the wrong way without it
+    let direction = Regex::new(r#"page-progression-direction\s*=\s*["'](ltr|rtl)["']"#)
+        .ok()
+        .and_then(|r| return r.captures(raw))
+        .map_or(String::new(), |c| {
+            return format!(" page-progression-direction=\"{}\"", &c[1]);
+        });
+    let title = salvage_metadata(raw, "title").unwrap_or_else(|| "Untitled".to_string());
+    let lang = salvage_metadata(raw, "language").unwrap_or_else(|| "und".to_string());
+    let opf = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>{}</dc:title>
+    <dc:language>{}</dc:language>
+  </metadata>
+  <manifest>
+{}  </manifest>
+  <spine{}>
+{}  </spine>
+</package>
+"#,
+        escape(&title),
+        escape(&lang),
+        manifest,
+        direction,
+        spine
+    );
+    let mut pkg = Package::parse(opf.as_bytes())?;
+    fix_unique_identifier(&mut pkg, None)?;
+    add_nav(opf_path.parent().unwrap_or(dir), &mut pkg)?;
+    return Ok(pkg);
+}
+
+/// Text of the first `<dc:NAME>` element of a package document that can't
+/// be parsed, if it has one with plain text
+fn salvage_metadata(raw: &str, name: &str) -> Option<String> {
+    let pattern = format!(r"(?s)<(?:\w+:)?{}\b[^>]*>([^<]*)</", name);
+    let text = Regex::new(&pattern).ok()?.captures(raw)?.get(1)?.as_str();
+    let text = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    return Some(text).filter(|t| !t.is_empty());
+}
+
+/// Href of the archive file `name` from the document at `base`
+fn relative_href(base: &str, name: &str) -> String {
+    let from = base.split('/').collect::<Vec<_>>();
+    let from = &from[..from.len() - 1];
+    let to = name.split('/').collect::<Vec<_>>();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts = vec![".."; from.len() - common];
+    parts.extend(&to[common..]);
+    return parts.join("/");
+}
+
+/// Points the `unique-identifier` of the package at a `dc:identifier`: the
+/// first one with an id, or else the first one, given the id `uid` or
+/// `bookid`. A book without identifiers gets one made from its title
+fn fix_unique_identifier(pkg: &mut Package, uid: Option<&str>) -> Result<(), ConverterError> {
+    if pkg.metadata("identifier").is_empty() {
+        let title = pkg.metadata("title").into_iter().next().unwrap_or_default();
+        pkg.set_metadata("identifier", &pack::title_identifier(&title))?;
+    }
+    let root = pkg.root_mut();
+    let Some(metadata) = root.get_mut_child("metadata") else {
+        return Err(xml_err!("Cannot find <metadata> in package document"));
+    };
+    let mut identifiers = metadata
+        .children
+        .iter_mut()
+        .filter_map(XMLNode::as_mut_element)
+        .filter(|e| e.name == "identifier")
+        .collect::<Vec<_>>();
+    let id = match identifiers.iter().find_map(|e| e.attributes.get("id")) {
+        Some(id) => id.clone(),
+        None => {
+            let id = uid
+                .filter(|u| !u.is_empty())
+                .unwrap_or("bookid")
+                .to_string();
+            identifiers[0]
+                .attributes
+                .insert("id".to_string(), id.clone());
+            id
+        }
+    };
+    root.attributes.insert("unique-identifier".to_string(), id);
+    return Ok(());
+}
+
+/// Adds the missing table of contents. An EPUB 2 book with an NCX in its
+/// manifest gets the spine pointed at it, others get one generated from
+/// the spine, titled by the first heading of each document
+fn add_nav(opf_dir: &Path, pkg: &mut Package) -> Result<(), ConverterError> {
+    let epub3 = pkg.is_epub3();
+    if !epub3 {
+        let ncx = pkg.manifest().into_iter().find(|i| {
+            return i.media_type == NCX_TYPE || href::normalize(&i.href).ends_with(".ncx");
+        });
+        if let Some(ncx) = ncx {
+            pkg.set_media_type(&ncx.id, NCX_TYPE);
+            return set_spine_toc(pkg, &ncx.id);
+        }
+    }
+
+    let mut chapters = Vec::new();
+    let mut seen = HashSet::new();
+    for itemref in pkg.spine().into_iter().filter(|i| i.linear) {
+        let Some(item) = pkg.item(&itemref.idref) else {
+            continue;
+        };
+        if !seen.insert(item.href.clone()) {
+            continue;
+        }
+        let path = opf_dir.join(href::normalize(&item.href));
+        let title = File::open(&path)
+            .ok()
+            .and_then(|f| space::parse(f).ok())
+            .and_then(|root| first_heading(&root))
+            .or_else(|| {
+                return Path::new(&href::normalize(&item.href))
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().to_string());
+            })
+            .unwrap_or_default();
+        chapters.push((item.href, title));
+    }
+
+    let title = pkg.metadata("title").into_iter().next().unwrap_or_default();
+    let (stem, ext, media_type, properties, doc) = match epub3 {
+        true => {
+            let lang = pkg.metadata("language").into_iter().next();
+            let doc = nav_doc(&title, lang.as_deref().unwrap_or("en"), &chapters);
+            ("nav", "xhtml", XHTML_TYPE, vec!["nav".to_string()], doc)
+        }
+        false => {
+            let uid = pkg.metadata("identifier").into_iter().next();
+            let doc = ncx_doc(&title, &uid.unwrap_or_default(), &chapters);
+            ("toc", "ncx", NCX_TYPE, Vec::new(), doc)
+        }
+    };
+    let href = unused_file(opf_dir, stem, ext);
+    std::fs::write(opf_dir.join(&href), doc)?;
+    let id = pkg.unused_id(stem);
+    pkg.add_item(&ManifestItem {
+        id: id.clone(),
+        href: pack::encode_href(&href),
+        media_type: media_type.to_string(),
+        properties,
+    })?;
+    if !epub3 {
+        set_spine_toc(pkg, &id)?;
+    }
+    return Ok(());
+}
+
+/// `stem.ext` if there is no such file in `dir`, or else the first of
+/// `stem-2.ext`, `stem-3.ext`... that is free
+fn unused_file(dir: &Path, stem: &str, ext: &str) -> String {
+    let mut name = format!("{}.{}", stem, ext);
+    let mut n = 1;
+    while dir.join(&name).exists() {
+        n += 1;
+        name = format!("{}-{}.{}", stem, n, ext);
+    }
+    return name;
+}
+
+fn set_spine_toc(pkg: &mut Package, id: &str) -> Result<(), ConverterError> {
+    let Some(spine) = pkg.root_mut().get_mut_child("spine") else {
+        return Err(xml_err!("Cannot find <spine> in package document"));
+    };
+    spine.attributes.insert("toc".to_string(), id.to_string());
+    return Ok(());
+}
+
+/// A navigation document listing `chapters`, by href and title
+fn nav_doc(title: &str, lang: &str, chapters: &[(String, String)]) -> String {
+    let mut items = String::new();
+    for (href, label) in chapters {
+        items.push_str(&format!(
+            "      <li><a href=\"{}\">{}</a></li>\n",
+            escape(href),
+            escape(label)
+        ));
+    }
+    return format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="{lang}" xml:lang="{lang}">
+<head>
+  <title>{title}</title>
+</head>
+<body>
+  <nav epub:type="toc" id="toc">
+    <h1>{title}</h1>
+    <ol>
+{items}    </ol>
+  </nav>
+</body>
+</html>
+"#,
+        lang = escape(lang),
+        title = escape(title),
+        items = items
+    );
+}
+
+/// An NCX listing `chapters`, by href and title
+fn ncx_doc(title: &str, uid: &str, chapters: &[(String, String)]) -> String {
+    let mut points = String::new();
+    for (i, (href, label)) in chapters.iter().enumerate() {
+        points.push_str(&format!(
+            "    <navPoint id=\"navpoint-{n}\" playOrder=\"{n}\">\n      <navLabel><text>{}</text></navLabel>\n      <content src=\"{}\"/>\n    </navPoint>\n",
+            escape(label),
+            escape(href),
+            n = i + 1
+        ));
+    }
+    return format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <head>
+    <meta name="dtb:uid" content="{}"/>
+  </head>
+  <docTitle><text>{}</text></docTitle>
+  <navMap>
+{}  </navMap>
+</ncx>
+"#,
+        escape(uid),
+        escape(title),
+        points
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{check, rebuild_package, relative_href, repair, Finding};
+    use crate::{opf::Package, workdir::WorkDir};
+
+    const OPF: &str = r#"<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="uid">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+        <dc:title>Title</dc:title>
+        <dc:identifier>isbn</dc:identifier>
+    </metadata>
+    <manifest>
+        <item id="c1" href="Text/c1.xhtml" media-type="application/xhtml+xml"/>
+        <item id="css" href="style.css" media-type="text/plain"/>
+        <item id="gone" href="gone.jpg" media-type="image/jpeg"/>
+    </manifest>
+    <spine><itemref idref="c1"/></spine>
+</package>"#;
+
+    #[test]
+    fn test_relative_href() {
+        assert_eq!(
+            relative_href("OEBPS/content.opf", "OEBPS/a/b.png"),
+            "a/b.png"
+        );
+        assert_eq!(
+            relative_href("OEBPS/content.opf", "img/b.png"),
+            "../img/b.png"
+        );
+        assert_eq!(relative_href("content.opf", "b.png"), "b.png");
+    }
+
+    #[test]
+    fn test_check_and_repair() {
+        let dir = WorkDir::create_in(&std::env::temp_dir(), "kepub-rs-test").unwrap();
+        let write = |name: &str, content: &str| {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        write("mimetype", "application/epub+zip");
+        write("META-INF/container.xml", "<container/>");
+        write("OEBPS/content.opf", OPF);
+        write(
+            "OEBPS/Text/c1.xhtml",
+            "<html><body><h1>One</h1><img src=\"../cover.png\"/></body></html>",
+        );
+        write("OEBPS/style.css", "p {}");
+        write("OEBPS/cover.png", "");
+        write("OEBPS/junk.txt", "");
+
+        let opf_path = dir.join("OEBPS/content.opf");
+        let mut pkg = Package::load(&opf_path).unwrap();
+        let findings = check(&dir, &opf_path, &pkg);
+        assert_eq!(
+            findings,
+            [
+                Finding::MediaType {
+                    id: "css".to_string(),
+                    href: "style.css".to_string(),
+                    declared: "text/plain".to_string(),
+                    expected: "text/css",
+                },
+                Finding::MissingFile {
+                    id: "gone".to_string(),
+                    href: "gone.jpg".to_string(),
+                },
+                Finding::UniqueIdentifier(Some("uid".to_string())),
+                Finding::MissingNav,
+                Finding::Orphan {
+                    name: "OEBPS/cover.png".to_string(),
+                    referenced: true,
+                },
+                Finding::Orphan {
+                    name: "OEBPS/junk.txt".to_string(),
+                    referenced: false,
+                },
+            ]
+        );
+
+        let fixed = findings
+            .iter()
+            .map(|f| repair(&dir, &opf_path, &mut pkg, f).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(fixed, [true, false, true, true, true, true]);
+        assert_eq!(pkg.item("css").unwrap().media_type, "text/css");
+        assert_eq!(pkg.item("item").unwrap().href, "cover.png");
+        assert!(!dir.join("OEBPS/junk.txt").exists());
+        let ncx = std::fs::read_to_string(dir.join("OEBPS/toc.ncx")).unwrap();
+        assert!(ncx.contains("<text>One</text>"));
+        assert!(ncx.contains("<content src=\"Text/c1.xhtml\"/>"));
+        assert!(ncx.contains("content=\"isbn\""));
+
+        pkg.save(&opf_path).unwrap();
+        let pkg = Package::load(Path::new(&opf_path)).unwrap();
+        let remaining = check(&dir, &opf_path, &pkg);
+        assert_eq!(remaining.len(), 1);
+        assert!(matches!(remaining[0], Finding::MissingFile { .. }));
+    }
+
+    #[test]
+    fn test_rebuild_package() {
+        let dir = WorkDir::create_in(&std::env::temp_dir(), "kepub-rs-test").unwrap();
+        let write = |name: &str, content: &str| {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        let raw = "<package><metadata><dc:title>Fish &amp; \n Chips</dc:title>\
+            <dc:language>en</dc:language><manifest><item id=";
+        write("mimetype", "application/epub+zip");
+        write("META-INF/container.xml", "<container/>");
+        write("OEBPS/content.opf", raw);
+        write("OEBPS/b.xhtml", "<html><body><h1>Two</h1></body></html>");
+        write("OEBPS/a.htm", "<html><body><p>One & more</p></body></html>");
+        write("OEBPS/data.xml", "<data/>");
+        write("images/c.jpg", "");
+
+        let opf_path = dir.join("OEBPS/content.opf");
+        let pkg = rebuild_package(&dir, &opf_path, raw).unwrap();
+        assert_eq!(pkg.metadata("title"), ["Fish & Chips"]);
+        assert_eq!(pkg.metadata("language"), ["en"]);
+        let items = pkg
+            .manifest()
+            .into_iter()
+            .map(|i| return (i.href, i.media_type))
+            .collect::<Vec<_>>();
+        let item = |href: &str, media_type: &str| (href.to_string(), media_type.to_string());
+        assert_eq!(
+            items,
+            [
+                item("a.htm", "application/xhtml+xml"),
+                item("b.xhtml", "application/xhtml+xml"),
+                item("data.xml", "application/octet-stream"),
+                item("../images/c.jpg", "image/jpeg"),
+                item("toc.ncx", "application/x-dtbncx+xml"),
+            ]
+        );
+        let spine = pkg.spine().into_iter().map(|i| i.idref).collect::<Vec<_>>();
+        assert_eq!(spine, ["item1", "item2"]);
+        assert_eq!(check(&dir, &opf_path, &pkg), []);
+        assert_eq!(pkg.page_progression(), None);
+
+        let raw = "<package><spine page-progression-direction='rtl' toc=";
+        let pkg = rebuild_package(&dir, &opf_path, raw).unwrap();
+        assert_eq!(pkg.page_progression().as_deref(), Some("rtl"));
+    }
+}
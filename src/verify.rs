@@ -0,0 +1,239 @@
+//! Round-trip checks that a conversion kept the text of a book intact.
+//!
+//! The kobo markup is stripped from each converted content document and its
+//! text is compared, word by word, with the text of the original. Differences
+//! in whitespace and markup are ignored.
+
+use std::{fs::File, io::Read, path::Path};
+
+use xmltree::{Element, XMLNode};
+use zip::ZipArchive;
+
+use crate::{
+    converter,
+    elem::{ElementExt, Rewriter},
+    errors::ConverterError,
+    href,
+    logger::{debug, warning},
+    text::{self, Normalization, Replacement},
+};
+
+/// How the converted text differs from the original
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffKind {
+    /// Text of the original is missing from the converted book
+    Dropped,
+    /// The converted book has text that wasn't in the original
+    Added,
+    /// Text was replaced by something else
+    Changed,
+}
+
+impl std::fmt::Display for DiffKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            DiffKind::Dropped => "dropped",
+            DiffKind::Added => "added",
+            DiffKind::Changed => "changed",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The first differing stretch of text in a content document
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextDiff {
+    pub file: String,
+    pub kind: DiffKind,
+    /// Index of the first differing word in the original
+    pub word: usize,
+    pub original: String,
+    pub converted: String,
+}
+
+/// Removes the kobospans and wrapper divs added during conversion, keeping
+/// their content
+pub fn strip_kobo(root: &mut Element) {
+    struct Strip;
+    impl Rewriter for Strip {
+        fn leave(&mut self, elem: Element, out: &mut Vec<XMLNode>) {
+            let is_span = elem.name == "span"
+                && elem
+                    .attributes
+                    .get("class")
+                    .is_some_and(|c| c.split_whitespace().any(|c| c == "kobospan"));
+            let is_wrapper = elem.name == "div"
+                && elem
+                    .attributes
+                    .get("id")
+                    .is_some_and(|id| id == "book-columns" || id == "book-inner");
+            if is_span || is_wrapper {
+                out.extend(elem.children);
+            } else {
+                out.push(XMLNode::Element(elem));
+            }
+        }
+    }
+    root.rewrite(&mut Strip);
+}
+
+/// Compares the text of every content document of `original` with the
+/// same document in `converted`. Returns the number of documents compared
+/// and the differences found. `replacements`, `normalization` and, with
+/// `strip_word_breaks` and `smarten_punctuation`, [`text::strip_word_breaks`]
+/// and punctuation smartening are applied to the original text first, as
+/// they were during conversion
+pub fn compare_books(
+    original: &Path,
+    converted: &Path,
+    replacements: &[Replacement],
+    normalization: Normalization,
+    strip_word_breaks: bool,
+    smarten_punctuation: bool,
+) -> Result<(usize, Vec<TextDiff>), ConverterError> {
+    let mut orig = ZipArchive::new(File::open(original)?)?;
+    let mut conv = ZipArchive::new(File::open(converted)?)?;
+
+    let names = orig
+        .file_names()
+        .filter(|n| !n.starts_with("META-INF/") && href::has_content_extension(n))
+        .map(String::from)
+        .collect::<Vec<_>>();
+
+    let mut compared = 0;
+    let mut diffs = Vec::new();
+    for name in names {
+        let orig_text = match read_body(&mut orig, &name) {
+            Ok(Some(mut body)) => {
+                converter::replace_text(&mut body, replacements);
+                if smarten_punctuation {
+                    converter::smarten_punctuation(&mut body, &mut None);
+                }
+                let t = normalization.normalize(&text_content(&body)).0;
+                match strip_word_breaks {
+                    true => text::strip_word_breaks(&t).0,
+                    false => t,
+                }
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                warning!("{}: cannot read original, skipping: {}", name, e);
+                continue;
+            }
+        };
+        let conv_text = match read_body(&mut conv, &name) {
+            Ok(Some(mut body)) => {
+                strip_kobo(&mut body);
+                text_content(&body)
+            }
+            Ok(None) => String::new(),
+            Err(e) => {
+                warning!("{}: cannot read converted document: {}", name, e);
+                String::new()
+            }
+        };
+
+        compared += 1;
+        if let Some((kind, word, o, c)) = diff_words(&orig_text, &conv_text) {
+            diffs.push(TextDiff {
+                file: name,
+                kind,
+                word,
+                original: o,
+                converted: c,
+            });
+        } else {
+            debug!("{}: text matches", name);
+        }
+    }
+    return Ok((compared, diffs));
+}
+
+/// Parses `name` from `zip`, returning its `<body>`, or None if it has none
+pub(crate) fn read_body(
+    zip: &mut ZipArchive<File>,
+    name: &str,
+) -> Result<Option<Element>, ConverterError> {
+    let mut buf = Vec::new();
+    zip.by_name(name)?.read_to_end(&mut buf)?;
+    let mut root = Element::parse(buf.as_slice())?;
+    return Ok(root.take_child("body"));
+}
+
+pub(crate) fn text_content(elem: &Element) -> String {
+    let mut s = String::new();
+    for c in &elem.children {
+        match c {
+            XMLNode::Text(t) | XMLNode::CData(t) => s.push_str(t),
+            XMLNode::Element(e) => s.push_str(&text_content(e)),
+            _ => {}
+        }
+    }
+    return s;
+}
+
+/// Finds the stretch of words that differs between `a` and `b`, after
+/// dropping their common prefix and suffix. Returns its kind, the index of
+/// its first word in `a` and the differing text of each side
+pub(crate) fn diff_words(a: &str, b: &str) -> Option<(DiffKind, usize, String, String)> {
+    let a = a.split_whitespace().collect::<Vec<_>>();
+    let b = b.split_whitespace().collect::<Vec<_>>();
+    if a == b {
+        return None;
+    }
+
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let a_mid = a[prefix..a.len() - suffix].join(" ");
+    let b_mid = b[prefix..b.len() - suffix].join(" ");
+
+    let kind = match (a_mid.is_empty(), b_mid.is_empty()) {
+        (false, true) => DiffKind::Dropped,
+        (true, false) => DiffKind::Added,
+        _ => DiffKind::Changed,
+    };
+    return Some((kind, prefix, a_mid, b_mid));
+}
+
+#[cfg(test)]
+mod test {
+    use xmltree::Element;
+
+    use super::{diff_words, strip_kobo, text_content, DiffKind};
+
+    #[test]
+    fn test_strip_kobo() {
+        let mut body = Element::parse(
+            r#"<body><div id="book-columns"><div id="book-inner"><p><span class="kobospan" id="kobo.1.1">Hi <em>there</em></span></p></div></div></body>"#
+                .as_bytes(),
+        )
+        .unwrap();
+        strip_kobo(&mut body);
+        let p = body.get_child("p").unwrap();
+        assert_eq!(text_content(p), "Hi there");
+        assert!(p.get_child("em").is_some());
+        assert!(p.get_child("span").is_none());
+    }
+
+    #[test]
+    fn test_diff_words() {
+        assert_eq!(diff_words("a  b\nc", " a b c "), None);
+
+        let (kind, word, o, c) = diff_words("a b c d", "a d").unwrap();
+        assert_eq!(
+            (kind, word, o.as_str(), c.as_str()),
+            (DiffKind::Dropped, 1, "b c", "")
+        );
+
+        let (kind, word, _, c) = diff_words("a b", "a b b").unwrap();
+        assert_eq!((kind, word, c.as_str()), (DiffKind::Added, 2, "b"));
+
+        let (kind, ..) = diff_words("foobar", "foo bar").unwrap();
+        assert_eq!(kind, DiffKind::Changed);
+    }
+}
@@ -0,0 +1,130 @@
+//! Temporary directories books are extracted to while they are converted.
+
+use std::{
+    collections::hash_map::RandomState,
+    fs::{create_dir_all, remove_dir_all, DirBuilder},
+    hash::BuildHasher,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::logger::{debug, warning};
+
+/// Work directories that still exist, for [`remove_all`]
+static LIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// A temporary directory that is removed, with everything in it, when
+/// dropped. That covers errors and panics as well as success
+#[derive(Debug)]
+pub struct WorkDir {
+    path: PathBuf,
+}
+
+impl WorkDir {
+    /// Creates a new, randomly named directory in `base`, so that several
+    /// conversions can run at once. On unix only the owner can access it
+    pub fn create_in(base: &Path, prefix: &str) -> Result<Self, std::io::Error> {
+        create_dir_all(base)?;
+        let mut builder = DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+        let mut attempts = 0;
+        loop {
+            // RandomState is seeded randomly for every instance
+            let suffix = RandomState::new().hash_one(std::process::id());
+            let path = base.join(format!("{}-{:016x}", prefix, suffix));
+            match builder.create(&path) {
+                Ok(()) => {
+                    live().push(path.clone());
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempts < 10 => {
+                    attempts += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        live().retain(|p| p != &self.path);
+        match remove_dir_all(&self.path) {
+            Ok(()) => debug!("Removed {:?}", self.path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warning!("Cannot remove {:?}: {}", self.path, e),
+        }
+    }
+}
+
+impl Deref for WorkDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        return &self.path;
+    }
+}
+
+impl AsRef<Path> for WorkDir {
+    fn as_ref(&self) -> &Path {
+        return &self.path;
+    }
+}
+
+fn live() -> std::sync::MutexGuard<'static, Vec<PathBuf>> {
+    return LIVE.lock().unwrap_or_else(|e| e.into_inner());
+}
+
+/// Removes every work directory that still exists. Destructors don't run
+/// when the process is killed by a signal, so this is meant for signal
+/// handlers, right before exiting
+pub fn remove_all() {
+    for path in live().drain(..) {
+        let _ = remove_dir_all(path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WorkDir;
+
+    #[test]
+    fn test_unique() {
+        let base = std::env::temp_dir();
+        let a = WorkDir::create_in(&base, "kepub-rs-test").unwrap();
+        let b = WorkDir::create_in(&base, "kepub-rs-test").unwrap();
+        assert_ne!(a.path(), b.path());
+        assert!(a.path().is_dir() && b.path().is_dir());
+    }
+
+    #[test]
+    fn test_removed_on_drop() {
+        let dir = WorkDir::create_in(&std::env::temp_dir(), "kepub-rs-test").unwrap();
+        let path = dir.path().to_path_buf();
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+
+        let res = std::panic::catch_unwind(move || {
+            let _dir = dir;
+            panic!("conversion failed");
+        });
+        assert!(res.is_err());
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = WorkDir::create_in(&std::env::temp_dir(), "kepub-rs-test").unwrap();
+        let mode = std::fs::metadata(dir.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+}